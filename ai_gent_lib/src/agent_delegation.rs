@@ -0,0 +1,291 @@
+// Delegating the work of a state to another agent.
+//
+// A state with `delegate_agent` (a config file) or `delegate_agent_id` (an agent stored by the
+// web app) in its `state_config` does not send its own LLM request. It runs the other agent
+// with a task, waits for the agent's final output and uses it as its own `llm_output`, so
+// `save_to`, `save_to_context`, ... apply to it as usual:
+//
+//   [state_config.CheckRegulation]
+//   delegate_agent = "regulatory_expert.toml"   # relative to the directory of this file
+//   delegate_task = "Check this request against the regulations: {{ message }}"
+//   forward_delegate_events = true              # stream the events as "CheckRegulation/<state>"
+//   save_to = ["regulation"]
+//
+// `delegate_task` is a Tera template with `task`, `message` (the last user message),
+// `summary`, `context` and the latest value of every memory slot, it defaults to the task
+// or the last message. Agents stored by id are resolved with the loader registered by
+// `set_agent_config_loader`, among the agents of the `user_id` of the delegating agent.
+// `delegate_agent` files are only allowed in config files, not in the configs submitted to the
+// web app (see `LlmFsmAgentConfigBuilder::from_toml`). The delegate agent runs with the state
+// type of the delegating one, e.g. the web app's states, which do not run code.
+
+use std::sync::{Arc, RwLock};
+
+use async_trait::async_trait;
+use once_cell::sync::Lazy;
+use serde_json::Value;
+use tera::Tera;
+use tokio::sync::mpsc::{self, Sender};
+use tracing::instrument::WithSubscriber;
+use tracing::Instrument;
+
+use crate::fsm::FsmState;
+use crate::llm_agent::{
+    LlmFsmAgent, LlmFsmAgentConfig, LlmFsmAgentConfigBuilder, LlmFsmStateInit, LlmReqSetting,
+    StateConfig,
+};
+
+// an agent delegating to itself would never finish
+pub const MAX_DELEGATION_DEPTH: u32 = 4;
+
+// the events of the delegate agent shown to the user of the delegating one, the others
+// (`llm_output`, `summary`, `code`, ...) would change the memory of the delegating agent
+const FORWARDED_EVENTS: [&str; 6] = [
+    "state",
+    "token",
+    "reasoning",
+    "reasoning_output",
+    "message",
+    "exec_output",
+];
+
+#[async_trait]
+pub trait AgentConfigLoader: Send + Sync {
+    async fn load_agent_config(
+        &self,
+        user_id: Option<i32>,
+        agent_id: i32,
+    ) -> Result<LlmFsmAgentConfig, anyhow::Error>;
+}
+
+static AGENT_CONFIG_LOADER: Lazy<RwLock<Option<Arc<dyn AgentConfigLoader>>>> =
+    Lazy::new(|| RwLock::new(None));
+
+pub fn set_agent_config_loader(loader: Arc<dyn AgentConfigLoader>) {
+    *AGENT_CONFIG_LOADER.write().unwrap() = Some(loader);
+}
+
+async fn load_delegate_config(
+    config: &StateConfig,
+    user_id: Option<i32>,
+) -> Result<LlmFsmAgentConfig, anyhow::Error> {
+    if let Some(path) = config.delegate_agent.as_ref() {
+        return LlmFsmAgentConfigBuilder::from_toml_file(path)?.build();
+    }
+    let agent_id = config
+        .delegate_agent_id
+        .ok_or_else(|| anyhow::anyhow!("no agent to delegate to"))?;
+    let loader = AGENT_CONFIG_LOADER
+        .read()
+        .unwrap()
+        .clone()
+        .ok_or_else(|| anyhow::anyhow!("agent {}: no agent config loader is set", agent_id))?;
+    loader.load_agent_config(user_id, agent_id).await
+}
+
+fn last_memory_value(llm_req_setting: &LlmReqSetting, slot: &str) -> String {
+    llm_req_setting
+        .memory
+        .get(slot)
+        .and_then(|values| values.last())
+        .map(|value| match value {
+            Value::String(s) => s.clone(),
+            other => other.to_string(),
+        })
+        .unwrap_or_default()
+}
+
+pub fn render_delegate_task(
+    config: &StateConfig,
+    llm_req_setting: &LlmReqSetting,
+) -> Result<String, anyhow::Error> {
+    let task = llm_req_setting.task.clone().unwrap_or_default();
+    let message = llm_req_setting
+        .messages
+        .iter()
+        .rev()
+        .find(|(role, _)| role == "user")
+        .map(|(_, msg)| msg.clone())
+        .unwrap_or_default();
+
+    let Some(template) = config.delegate_task.as_ref() else {
+        return Ok(if task.is_empty() { message } else { task });
+    };
+
+    let mut tera_context = tera::Context::new();
+    llm_req_setting.memory.keys().for_each(|slot| {
+        tera_context.insert(slot, &last_memory_value(llm_req_setting, slot));
+    });
+    tera_context.insert("summary", &last_memory_value(llm_req_setting, "summary"));
+    tera_context.insert("context", &last_memory_value(llm_req_setting, "context"));
+    tera_context.insert("task", &task);
+    tera_context.insert("message", &message);
+    Ok(Tera::one_off(template, &tera_context, false)?)
+}
+
+// run the delegate agent of `config` with the states `S` on `task` and return its final output
+pub async fn run_delegate_agent<S: LlmFsmStateInit + FsmState + 'static>(
+    state_name: &str,
+    config: &StateConfig,
+    llm_req_setting: &LlmReqSetting,
+    task: String,
+    tx: &Sender<(String, String, String)>,
+) -> Result<String, anyhow::Error> {
+    if llm_req_setting.delegation_depth >= MAX_DELEGATION_DEPTH {
+        return Err(anyhow::anyhow!(
+            "max delegation depth ({}) reached",
+            MAX_DELEGATION_DEPTH
+        ));
+    }
+
+    let agent_config = load_delegate_config(config, llm_req_setting.user_id).await?;
+    // the delegate agent runs on its own model if it has one, otherwise on the caller's
+    let mut agent = LlmFsmAgent::from_config::<S>(
+        &agent_config,
+        &llm_req_setting.model,
+        llm_req_setting.provider.clone(),
+        &llm_req_setting.api_key,
+    )?;
+    agent.llm_req_settings.session_id = llm_req_setting.session_id.clone();
+    agent.llm_req_settings.audit = llm_req_setting.audit.clone();
+    agent.llm_req_settings.agent_name = config
+        .delegate_agent
+        .clone()
+        .or(config.delegate_agent_id.map(|id| format!("agent-{}", id)));
+    agent.llm_req_settings.delegation_depth = llm_req_setting.delegation_depth + 1;
+    agent.llm_req_settings.user_id = llm_req_setting.user_id;
+
+    let temperature = llm_req_setting.temperature;
+    let (agent_tx, mut agent_rx) = mpsc::channel::<(String, String, String)>(16);
+    let (send_msg, rcv_msg) = mpsc::channel::<(String, String)>(4);
+    // the delegate's turns are traced under the delegating state
+    let agent_handle = tokio::spawn(
+        async move {
+            agent
+                .fsm_message_service(rcv_msg, agent_tx, temperature)
+                .await
+        }
+        .in_current_span()
+        .with_current_subscriber(),
+    );
+
+    let _ = send_msg.send(("task".into(), task.clone())).await;
+    let _ = send_msg.send(("message".into(), task)).await;
+
+    let forward_events = config.forward_delegate_events.unwrap_or(false);
+    let mut output = None;
+    let mut error = None;
+    while let Some((sub_state, t, r)) = agent_rx.recv().await {
+        match t.as_str() {
+            "message_processed" => break,
+            "llm_output" => output = Some(r),
+            // errors and token counts are always passed on, the caller may not see them otherwise
+            "error" | "usage" => {
+                let _ = tx
+                    .send((format!("{}/{}", state_name, sub_state), t.clone(), r.clone()))
+                    .await;
+                if t == "error" {
+                    error = Some(r);
+                }
+            }
+            "state" if forward_events => {
+                let _ = tx
+                    .send((
+                        format!("{}/{}", state_name, sub_state),
+                        t,
+                        format!("{}/{}", state_name, r),
+                    ))
+                    .await;
+            }
+            _ if forward_events && FORWARDED_EVENTS.contains(&t.as_str()) => {
+                let _ = tx
+                    .send((format!("{}/{}", state_name, sub_state), t, r))
+                    .await;
+            }
+            _ => {}
+        }
+    }
+
+    let _ = send_msg.send(("terminate".into(), "".into())).await;
+    agent_handle.await??;
+
+    match (output, error) {
+        (Some(output), _) => Ok(output),
+        (None, Some(error)) => Err(anyhow::anyhow!(error)),
+        (None, None) => Ok(String::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fsm_chat_state::FSMChatState;
+
+    #[test]
+    fn test_render_delegate_task() {
+        let mut llm_req_setting = LlmReqSetting {
+            messages: vec![
+                ("user".into(), "first question".into()),
+                ("bot".into(), "an answer".into()),
+                ("user".into(), "is this allowed?".into()),
+            ],
+            ..Default::default()
+        };
+        llm_req_setting
+            .memory
+            .insert("draft".into(), vec![Value::String("v1".into()), Value::String("v2".into())]);
+
+        let mut config = StateConfig {
+            delegate_agent: Some("regulatory_expert.toml".into()),
+            ..Default::default()
+        };
+        assert!(config.delegates());
+        assert_eq!(
+            render_delegate_task(&config, &llm_req_setting).unwrap(),
+            "is this allowed?"
+        );
+
+        llm_req_setting.task = Some("review the draft".into());
+        config.delegate_task = Some("{{ task }}: {{ draft }} ({{ message }})".into());
+        assert_eq!(
+            render_delegate_task(&config, &llm_req_setting).unwrap(),
+            "review the draft: v2 (is this allowed?)"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delegation_depth() {
+        let config = StateConfig {
+            delegate_agent: Some("missing.toml".into()),
+            ..Default::default()
+        };
+        let llm_req_setting = LlmReqSetting {
+            delegation_depth: MAX_DELEGATION_DEPTH,
+            ..Default::default()
+        };
+        let (tx, _rx) = mpsc::channel(4);
+        let result = run_delegate_agent::<FSMChatState>(
+            "Check",
+            &config,
+            &llm_req_setting,
+            "task".into(),
+            &tx,
+        )
+        .await;
+        assert!(result.is_err());
+
+        let config = StateConfig {
+            delegate_agent_id: Some(1),
+            ..Default::default()
+        };
+        let result = run_delegate_agent::<FSMChatState>(
+            "Check",
+            &config,
+            &LlmReqSetting::default(),
+            "task".into(),
+            &tx,
+        )
+        .await;
+        assert!(result.is_err());
+    }
+}
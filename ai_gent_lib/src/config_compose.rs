@@ -0,0 +1,366 @@
+// Composition of agent TOML configurations.
+//
+// An agent configuration can be assembled from several TOML documents:
+//
+//   extends = "base.toml"                       # at most one base configuration
+//   include = ["tools.toml", "states.toml"]     # zero or more fragments
+//
+// Paths are resolved relative to the directory of the file that references them, so are the
+// `delegate_agent` paths of `[state_config.<state>]`. Only the configuration files, the trusted
+// path of the command line tools, are composed: the configurations given as a string (e.g.
+// submitted by the users of the web app) are parsed as they are, see `parse_toml_str`.
+//
+// The override rules are deterministic. The base configuration is loaded first,
+// then each fragment in `include` is merged in the listed order, and finally the
+// keys of the current document are merged on top. Merging works as follows:
+//
+//   - tables (e.g. `[state_prompts.Generate]`, `[tools.websearch]`) are merged key by key
+//   - the top level `states` and `transitions` arrays are appended, skipping duplicates
+//   - any other value (strings, numbers, other arrays) is replaced by the later one
+//
+// After composition, `${NAME}` in a string value is replaced by the value of the
+// environment variable `NAME`; `${NAME:-default}` falls back to `default` when it is not
+// set. Use `$${` to write a literal `${`, a `${...}` that is not a variable name is kept as
+// it is. The code and the prompts (see `NOT_INTERPOLATED_KEYS`) are never interpolated, so
+// a prompt or a script can not read the environment of the tools.
+
+use std::path::{Path, PathBuf};
+
+use toml::{Table, Value};
+
+const EXTENDS_KEY: &str = "extends";
+const INCLUDE_KEY: &str = "include";
+const APPENDED_ARRAYS: &[&str] = &["states", "transitions"];
+const STATE_CONFIG_KEY: &str = "state_config";
+const DELEGATE_AGENT_KEY: &str = "delegate_agent";
+const NOT_INTERPOLATED_KEYS: &[&str] = &[
+    "code",
+    "fsm_code",
+    "delegate_task",
+    "system_prompt",
+    "fsm_prompt",
+    "summary_prompt",
+    "state_prompts",
+];
+
+// no file is read and no environment variable is interpolated
+pub fn parse_toml_str(toml_str: &str) -> Result<Table, anyhow::Error> {
+    let table: Table = toml::from_str(toml_str)?;
+    for key in [EXTENDS_KEY, INCLUDE_KEY] {
+        if table.contains_key(key) {
+            return Err(anyhow::anyhow!(
+                "`{}` is only supported in configuration files",
+                key
+            ));
+        }
+    }
+    Ok(table)
+}
+
+pub fn compose_toml_file(path: &Path) -> Result<Table, anyhow::Error> {
+    let mut visiting = Vec::new();
+    let mut table = compose_file(path, &mut visiting)?;
+    interpolate_table(&mut table)?;
+    Ok(table)
+}
+
+fn compose_file(path: &Path, visiting: &mut Vec<PathBuf>) -> Result<Table, anyhow::Error> {
+    let canonical = path
+        .canonicalize()
+        .map_err(|e| anyhow::anyhow!("fail to open config file {}: {}", path.display(), e))?;
+    if visiting.contains(&canonical) {
+        return Err(anyhow::anyhow!(
+            "circular config reference: {}",
+            canonical.display()
+        ));
+    }
+    let toml_str = std::fs::read_to_string(&canonical)?;
+    let base_dir = canonical
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_default();
+
+    visiting.push(canonical.clone());
+    let table = compose_document(&toml_str, &base_dir, visiting)
+        .map_err(|e| anyhow::anyhow!("{}: {}", canonical.display(), e));
+    visiting.pop();
+    table
+}
+
+fn compose_document(
+    toml_str: &str,
+    base_dir: &Path,
+    visiting: &mut Vec<PathBuf>,
+) -> Result<Table, anyhow::Error> {
+    let mut document: Table = toml::from_str(toml_str)?;
+    resolve_delegate_paths(&mut document, base_dir);
+
+    let extends = match document.remove(EXTENDS_KEY) {
+        Some(Value::String(path)) => Some(path),
+        Some(_) => return Err(anyhow::anyhow!("`extends` must be a path string")),
+        None => None,
+    };
+
+    let includes = match document.remove(INCLUDE_KEY) {
+        Some(Value::String(path)) => vec![path],
+        Some(Value::Array(paths)) => paths
+            .into_iter()
+            .map(|p| match p {
+                Value::String(path) => Ok(path),
+                _ => Err(anyhow::anyhow!("`include` must be a list of path strings")),
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+        Some(_) => return Err(anyhow::anyhow!("`include` must be a list of path strings")),
+        None => vec![],
+    };
+
+    let mut composed = if let Some(path) = extends {
+        compose_file(&base_dir.join(path), visiting)?
+    } else {
+        Table::new()
+    };
+
+    for path in includes {
+        let fragment = compose_file(&base_dir.join(path), visiting)?;
+        merge_tables(&mut composed, fragment, true);
+    }
+
+    merge_tables(&mut composed, document, true);
+    Ok(composed)
+}
+
+fn resolve_delegate_paths(document: &mut Table, base_dir: &Path) {
+    let Some(Value::Table(state_config)) = document.get_mut(STATE_CONFIG_KEY) else {
+        return;
+    };
+    for (_, config) in state_config.iter_mut() {
+        if let Some(Value::String(path)) = config.get_mut(DELEGATE_AGENT_KEY) {
+            if Path::new(path.as_str()).is_relative() {
+                *path = base_dir.join(path.as_str()).to_string_lossy().into_owned();
+            }
+        }
+    }
+}
+
+fn merge_tables(dst: &mut Table, src: Table, top_level: bool) {
+    for (key, value) in src {
+        match (dst.get_mut(&key), value) {
+            (Some(Value::Table(dst_table)), Value::Table(src_table)) => {
+                merge_tables(dst_table, src_table, false);
+            }
+            (Some(Value::Array(dst_array)), Value::Array(src_array))
+                if top_level && APPENDED_ARRAYS.contains(&key.as_str()) =>
+            {
+                for v in src_array {
+                    if !dst_array.contains(&v) {
+                        dst_array.push(v);
+                    }
+                }
+            }
+            (_, value) => {
+                dst.insert(key, value);
+            }
+        }
+    }
+}
+
+fn interpolate_table(table: &mut Table) -> Result<(), anyhow::Error> {
+    for (key, value) in table.iter_mut() {
+        if NOT_INTERPOLATED_KEYS.contains(&key.as_str()) {
+            continue;
+        }
+        interpolate_value(value)?;
+    }
+    Ok(())
+}
+
+fn interpolate_value(value: &mut Value) -> Result<(), anyhow::Error> {
+    match value {
+        Value::String(s) => {
+            if s.contains('$') {
+                *s = interpolate_env(s)?;
+            }
+        }
+        Value::Array(array) => {
+            for v in array.iter_mut() {
+                interpolate_value(v)?;
+            }
+        }
+        Value::Table(table) => interpolate_table(table)?,
+        _ => {}
+    }
+    Ok(())
+}
+
+pub fn interpolate_env(input: &str) -> Result<String, anyhow::Error> {
+    let mut output = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(pos) = rest.find('$') {
+        output.push_str(&rest[..pos]);
+        let tail = &rest[pos..];
+        if tail.starts_with("$${") {
+            output.push_str("${");
+            rest = &tail[3..];
+        } else if let Some(expr) = tail.strip_prefix("${") {
+            let end = expr
+                .find('}')
+                .ok_or_else(|| anyhow::anyhow!("unclosed `${{` in config value"))?;
+            let (name, default) = match expr[..end].split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (&expr[..end], None),
+            };
+            if !is_variable_name(name) {
+                output.push_str("${");
+                rest = expr;
+                continue;
+            }
+            match (std::env::var(name), default) {
+                (Ok(v), _) => output.push_str(&v),
+                (Err(_), Some(default)) => output.push_str(default),
+                (Err(_), None) => {
+                    return Err(anyhow::anyhow!(
+                        "environment variable `{}` used in config is not set",
+                        name
+                    ))
+                }
+            }
+            rest = &expr[end + 1..];
+        } else {
+            output.push('$');
+            rest = &tail[1..];
+        }
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+fn is_variable_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dev_config_dir() -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("dev_config")
+    }
+
+    #[test]
+    fn test_interpolate_env() {
+        std::env::set_var("AI_GENT_TEST_MODEL", "gpt-4o");
+        assert_eq!(
+            interpolate_env("model: ${AI_GENT_TEST_MODEL}").unwrap(),
+            "model: gpt-4o"
+        );
+        assert_eq!(
+            interpolate_env("${AI_GENT_TEST_UNSET:-fallback}").unwrap(),
+            "fallback"
+        );
+        assert_eq!(interpolate_env("price $5, $${HOME}").unwrap(), "price $5, ${HOME}");
+        assert!(interpolate_env("${AI_GENT_TEST_UNSET}").is_err());
+        assert_eq!(interpolate_env("`${x + 1}`").unwrap(), "`${x + 1}`");
+    }
+
+    #[test]
+    fn test_code_and_prompts_not_interpolated() {
+        std::env::set_var("AI_GENT_TEST_PROVIDER", "openai");
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("agent.toml"),
+            r#"system_prompt = "${AI_GENT_TEST_PROVIDER}"
+
+[state_prompts.Generate]
+system = "${AI_GENT_TEST_PROVIDER}"
+
+[state_config.Generate]
+provider = "${AI_GENT_TEST_PROVIDER}"
+code = "print('${AI_GENT_TEST_UNSET}')"
+"#,
+        )
+        .unwrap();
+
+        let table = compose_toml_file(&dir.path().join("agent.toml")).unwrap();
+        let generate = &table["state_config"]["Generate"];
+        assert_eq!(generate["provider"].as_str().unwrap(), "openai");
+        assert_eq!(
+            generate["code"].as_str().unwrap(),
+            "print('${AI_GENT_TEST_UNSET}')"
+        );
+        assert_eq!(
+            table["system_prompt"].as_str().unwrap(),
+            "${AI_GENT_TEST_PROVIDER}"
+        );
+        assert_eq!(
+            table["state_prompts"]["Generate"]["system"]
+                .as_str()
+                .unwrap(),
+            "${AI_GENT_TEST_PROVIDER}"
+        );
+    }
+
+    #[test]
+    fn test_parse_toml_str() {
+        std::env::set_var("AI_GENT_TEST_SECRET", "secret");
+        let table = parse_toml_str(r#"system_prompt = "${AI_GENT_TEST_SECRET}""#).unwrap();
+        assert_eq!(table["system_prompt"].as_str().unwrap(), "${AI_GENT_TEST_SECRET}");
+        assert!(parse_toml_str(r#"extends = "/etc/hosts""#).is_err());
+        assert!(parse_toml_str(r#"include = ["rag.toml"]"#).is_err());
+    }
+
+    #[test]
+    fn test_extends_and_include() {
+        let table = compose_toml_file(&dev_config_dir().join("composed_config.toml")).unwrap();
+
+        // states are appended from the base, the included fragment and the document itself
+        let states = table["states"].as_array().unwrap();
+        let states = states.iter().map(|s| s.as_str().unwrap()).collect::<Vec<_>>();
+        assert_eq!(states, vec!["StandBy", "Generate", "Retrieval", "Review"]);
+
+        // the document overrides the base system prompt but keeps the base fsm prompt
+        let generate = &table["state_prompts"]["Generate"];
+        assert_eq!(generate["system"].as_str().unwrap(), "reviewed answer");
+        assert_eq!(
+            generate["fsm"].as_str().unwrap(),
+            r#"JUST output a json string {"next_state": "StandBy"}"#
+        );
+
+        // shared tools come from the included fragment
+        assert!(table["tools"].get("websearch").is_some());
+        assert!(table.get("extends").is_none());
+        assert!(table.get("include").is_none());
+    }
+
+    #[test]
+    fn test_delegate_agent_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("agents")).unwrap();
+        std::fs::write(
+            dir.path().join("agents").join("base.toml"),
+            "[state_config.Check]\ndelegate_agent = \"expert.toml\"\n\n[state_config.Abs]\ndelegate_agent = \"/etc/agents/expert.toml\"\n",
+        )
+        .unwrap();
+        std::fs::write(dir.path().join("agent.toml"), r#"extends = "agents/base.toml""#).unwrap();
+
+        let table = compose_toml_file(&dir.path().join("agent.toml")).unwrap();
+        let expected = dir.path().canonicalize().unwrap().join("agents").join("expert.toml");
+        assert_eq!(
+            table["state_config"]["Check"]["delegate_agent"].as_str().unwrap(),
+            expected.to_string_lossy()
+        );
+        assert_eq!(
+            table["state_config"]["Abs"]["delegate_agent"].as_str().unwrap(),
+            "/etc/agents/expert.toml"
+        );
+    }
+
+    #[test]
+    fn test_circular_extends() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.toml"), r#"extends = "b.toml""#).unwrap();
+        std::fs::write(dir.path().join("b.toml"), r#"extends = "a.toml""#).unwrap();
+        assert!(compose_toml_file(&dir.path().join("a.toml")).is_err());
+    }
+}
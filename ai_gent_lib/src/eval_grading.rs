@@ -0,0 +1,790 @@
+// Grading the results of an evaluation run, and comparing two runs.
+//
+// The graders are listed in a TOML file:
+//
+//   [[graders]]
+//   type = "exact_match"           # the output is the reference, or contains it with `contains`
+//   contains = true
+//
+//   [[graders]]
+//   type = "regex"
+//   pattern = "(?i)not (allowed|permitted)"
+//
+//   [[graders]]
+//   type = "embedding_similarity"  # the similarity of the output and the reference
+//   url = "http://localhost:8080/api/service/text_to_embedding"
+//   threshold = 0.8
+//
+//   [[graders]]
+//   type = "llm_judge"
+//   name = "correctness"
+//   model = "gpt-4o"
+//   rubric = "The answer is correct and cites the guidance it is based on."
+//   scale = 5
+//   pass_score = 4
+//
+// Each grader gives a score between 0 and 1 and passes or fails an item. The graders that
+// need a reference skip the items without one. The embeddings come from the web app's
+// `EmbeddingService`, so the same model as the asset search is used.
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use async_trait::async_trait;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::evaluation::EvalResult;
+use crate::llm_agent::LlmClient;
+use crate::llm_cache::with_default_cache;
+use crate::llm_provider::{provider_api_key, provider_for_model};
+use crate::llm_service::{GenerationOptions, LlmChatRequest};
+use crate::model_registry::model_info;
+use crate::GenaiLlmclient;
+
+pub const DEFAULT_EMBEDDING_URL: &str = "http://localhost:8080/api/service/text_to_embedding";
+
+const JUDGE_PROMPT: &str = "You are grading the answer of an AI agent to a question, \
+following the rubric below.";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum GraderConfig {
+    ExactMatch {
+        name: Option<String>,
+        #[serde(default)]
+        contains: bool,
+        #[serde(default)]
+        case_sensitive: bool,
+    },
+    Regex {
+        name: Option<String>,
+        pattern: String,
+    },
+    EmbeddingSimilarity {
+        name: Option<String>,
+        url: Option<String>,
+        threshold: Option<f32>,
+    },
+    LlmJudge {
+        name: Option<String>,
+        model: Option<String>,
+        provider: Option<String>,
+        rubric: String,
+        scale: Option<u32>,
+        pass_score: Option<f32>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct GradingConfig {
+    pub graders: Vec<GraderConfig>,
+}
+
+impl GradingConfig {
+    pub fn from_toml(toml_str: &str) -> Result<Self, anyhow::Error> {
+        Ok(toml::from_str(toml_str)?)
+    }
+
+    pub fn from_toml_file<P: AsRef<Path>>(path: P) -> Result<Self, anyhow::Error> {
+        let path = path.as_ref();
+        let toml_str = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("fail to open graders file {}: {}", path.display(), e))?;
+        Self::from_toml(&toml_str)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Grade {
+    pub grader: String,
+    pub score: f32,
+    pub passed: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[async_trait]
+pub trait TextEmbedder: Send + Sync {
+    async fn embed(&self, text: &str) -> Result<Vec<f32>, anyhow::Error>;
+}
+
+// the `/api/service/text_to_embedding` endpoint of the web app, a long text is split into
+// chunks and its embedding is the mean of the chunks'
+pub struct HttpEmbedder {
+    pub url: String,
+    client: reqwest::Client,
+}
+
+impl HttpEmbedder {
+    pub fn new(url: &str) -> Self {
+        HttpEmbedder {
+            url: url.to_string(),
+            client: reqwest::Client::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl TextEmbedder for HttpEmbedder {
+    async fn embed(&self, text: &str) -> Result<Vec<f32>, anyhow::Error> {
+        let response = self
+            .client
+            .post(&self.url)
+            .header("Content-Type", "application/json")
+            .body(json!({ "text": text }).to_string())
+            .send()
+            .await?
+            .text()
+            .await?;
+        let response: Value = serde_json::from_str(&response)?;
+        let vectors = response
+            .get("data")
+            .and_then(|data| data.as_array())
+            .ok_or_else(|| anyhow::anyhow!("embedding service: {}", response["message"]))?
+            .iter()
+            .filter_map(|chunk| {
+                serde_json::from_value::<Vec<f32>>(chunk.get("embedding_vec")?.clone()).ok()
+            })
+            .collect::<Vec<_>>();
+        mean_vector(&vectors).ok_or_else(|| anyhow::anyhow!("embedding service: no embedding"))
+    }
+}
+
+fn mean_vector(vectors: &[Vec<f32>]) -> Option<Vec<f32>> {
+    let first = vectors.first()?;
+    let mut mean = vec![0.0; first.len()];
+    vectors.iter().for_each(|v| {
+        mean.iter_mut().zip(v.iter()).for_each(|(m, x)| *m += x);
+    });
+    mean.iter_mut().for_each(|m| *m /= vectors.len() as f32);
+    Some(mean)
+}
+
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot = a.iter().zip(b.iter()).map(|(x, y)| x * y).sum::<f32>();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        0.0
+    } else {
+        dot / (norm_a * norm_b)
+    }
+}
+
+pub enum Grader {
+    ExactMatch {
+        name: String,
+        contains: bool,
+        case_sensitive: bool,
+    },
+    Regex {
+        name: String,
+        regex: Regex,
+    },
+    EmbeddingSimilarity {
+        name: String,
+        embedder: Box<dyn TextEmbedder>,
+        threshold: f32,
+    },
+    LlmJudge {
+        name: String,
+        client: Box<dyn LlmClient>,
+        json_mode: bool,
+        rubric: String,
+        scale: u32,
+        pass_score: f32,
+    },
+}
+
+impl Grader {
+    // `model` is the judge model of the graders that do not set one
+    pub fn from_config(config: &GraderConfig, model: &str) -> Result<Self, anyhow::Error> {
+        let grader = match config {
+            GraderConfig::ExactMatch {
+                name,
+                contains,
+                case_sensitive,
+            } => Grader::ExactMatch {
+                name: name.clone().unwrap_or("exact_match".into()),
+                contains: *contains,
+                case_sensitive: *case_sensitive,
+            },
+            GraderConfig::Regex { name, pattern } => Grader::Regex {
+                name: name.clone().unwrap_or("regex".into()),
+                regex: Regex::new(pattern)?,
+            },
+            GraderConfig::EmbeddingSimilarity {
+                name,
+                url,
+                threshold,
+            } => Grader::EmbeddingSimilarity {
+                name: name.clone().unwrap_or("embedding_similarity".into()),
+                embedder: Box::new(HttpEmbedder::new(
+                    url.as_deref().unwrap_or(DEFAULT_EMBEDDING_URL),
+                )),
+                threshold: threshold.unwrap_or(0.8),
+            },
+            GraderConfig::LlmJudge {
+                name,
+                model: judge_model,
+                provider,
+                rubric,
+                scale,
+                pass_score,
+            } => {
+                let judge_model = judge_model.clone().unwrap_or(model.to_string());
+                let api_key = provider_api_key(
+                    &provider
+                        .clone()
+                        .unwrap_or_else(|| provider_for_model(&judge_model)),
+                )
+                .unwrap_or_default();
+                let client = GenaiLlmclient {
+                    model: judge_model.clone(),
+                    api_key,
+                    options: GenerationOptions {
+                        provider: provider.clone(),
+                        ..Default::default()
+                    },
+                };
+                let scale = scale.unwrap_or(5).max(1);
+                Grader::LlmJudge {
+                    name: name.clone().unwrap_or("llm_judge".into()),
+                    // the same answer gets the same grade in the later runs
+                    client: Box::new(with_default_cache(client, true)),
+                    json_mode: model_info(&judge_model)
+                        .map(|info| info.supports_json_mode)
+                        .unwrap_or(false),
+                    rubric: rubric.clone(),
+                    scale,
+                    pass_score: pass_score.unwrap_or(scale as f32 * 0.6),
+                }
+            }
+        };
+        Ok(grader)
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Grader::ExactMatch { name, .. }
+            | Grader::Regex { name, .. }
+            | Grader::EmbeddingSimilarity { name, .. }
+            | Grader::LlmJudge { name, .. } => name,
+        }
+    }
+
+    // `None` if the grader does not apply, e.g. without a reference
+    pub async fn grade(&self, result: &EvalResult) -> Result<Option<Grade>, anyhow::Error> {
+        let grade = match self {
+            Grader::ExactMatch {
+                name,
+                contains,
+                case_sensitive,
+            } => {
+                let Some(reference) = result.reference.as_ref() else {
+                    return Ok(None);
+                };
+                let normalize = |s: &str| {
+                    let s = s.split_whitespace().collect::<Vec<_>>().join(" ");
+                    if *case_sensitive {
+                        s
+                    } else {
+                        s.to_lowercase()
+                    }
+                };
+                let (output, reference) = (normalize(&result.output), normalize(reference));
+                let passed = if *contains {
+                    output.contains(&reference)
+                } else {
+                    output == reference
+                };
+                score_grade(name, passed)
+            }
+            Grader::Regex { name, regex } => score_grade(name, regex.is_match(&result.output)),
+            Grader::EmbeddingSimilarity {
+                name,
+                embedder,
+                threshold,
+            } => {
+                let Some(reference) = result.reference.as_ref() else {
+                    return Ok(None);
+                };
+                let similarity = cosine_similarity(
+                    &embedder.embed(&result.output).await?,
+                    &embedder.embed(reference).await?,
+                );
+                Grade {
+                    grader: name.clone(),
+                    score: similarity.clamp(0.0, 1.0),
+                    passed: similarity >= *threshold,
+                    reason: None,
+                }
+            }
+            Grader::LlmJudge {
+                name,
+                client,
+                json_mode,
+                rubric,
+                scale,
+                pass_score,
+            } => {
+                let prompt = format!(
+                    "{}\n\nRubric:\n{}\n\nGive the answer a score from 0 to {}. Reply only with a JSON \
+                    object: {{\"score\": <number>, \"reason\": \"<one sentence>\"}}",
+                    JUDGE_PROMPT, rubric, scale
+                );
+                let message = format!(
+                    "Question:\n{}\n\nReference answer:\n{}\n\nAnswer to grade:\n{}",
+                    result.input,
+                    result.reference.as_deref().unwrap_or("(none)"),
+                    result.output
+                );
+                let mut req =
+                    LlmChatRequest::from_prompt(&prompt, &[("user".into(), message)], Some(0.0));
+                req.json_mode = *json_mode;
+                let reply = client.chat(&req).await?;
+                let (score, reason) = parse_judge_reply(&reply.text)
+                    .ok_or_else(|| anyhow::anyhow!("no score in the judge's reply: {}", reply.text))?;
+                let score = score.clamp(0.0, *scale as f32);
+                Grade {
+                    grader: name.clone(),
+                    score: score / *scale as f32,
+                    passed: score >= *pass_score,
+                    reason,
+                }
+            }
+        };
+        Ok(Some(grade))
+    }
+}
+
+fn score_grade(name: &str, passed: bool) -> Grade {
+    Grade {
+        grader: name.to_string(),
+        score: if passed { 1.0 } else { 0.0 },
+        passed,
+        reason: None,
+    }
+}
+
+fn parse_judge_reply(reply: &str) -> Option<(f32, Option<String>)> {
+    let start = reply.find('{')?;
+    let end = reply.rfind('}')?;
+    let value: Value = serde_json::from_str(reply.get(start..=end)?).ok()?;
+    let score = match value.get("score")? {
+        Value::Number(n) => n.as_f64()? as f32,
+        Value::String(s) => s.trim().parse::<f32>().ok()?,
+        _ => return None,
+    };
+    let reason = value
+        .get("reason")
+        .and_then(|reason| reason.as_str())
+        .map(|reason| reason.to_string());
+    Some((score, reason))
+}
+
+pub fn graders_from_config(config: &GradingConfig, model: &str) -> Result<Vec<Grader>, anyhow::Error> {
+    config
+        .graders
+        .iter()
+        .map(|grader| Grader::from_config(grader, model))
+        .collect()
+}
+
+// replace the grades of a result, a grader that fails gives a failed grade with the error
+pub async fn grade_result(graders: &[Grader], result: &mut EvalResult) {
+    let mut grades = Vec::with_capacity(graders.len());
+    for grader in graders {
+        match grader.grade(result).await {
+            Ok(Some(grade)) => grades.push(grade),
+            Ok(None) => {}
+            Err(e) => grades.push(Grade {
+                grader: grader.name().to_string(),
+                score: 0.0,
+                passed: false,
+                reason: Some(format!("grader error: {}", e)),
+            }),
+        }
+    }
+    result.grades = grades;
+}
+
+// the mean of the grades, `None` if there is no grade
+pub fn result_score(result: &EvalResult) -> Option<f32> {
+    if result.grades.is_empty() {
+        return None;
+    }
+    Some(result.grades.iter().map(|g| g.score).sum::<f32>() / result.grades.len() as f32)
+}
+
+pub fn result_passed(result: &EvalResult) -> bool {
+    !result.failed() && result.grades.iter().all(|g| g.passed)
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Change {
+    Regressed,
+    Improved,
+    Unchanged,
+    Added,
+    Removed,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ItemComparison {
+    pub id: String,
+    pub input: String,
+    pub change: Change,
+    pub baseline_score: Option<f32>,
+    pub candidate_score: Option<f32>,
+    pub baseline_passed: Option<bool>,
+    pub candidate_passed: Option<bool>,
+    pub baseline_output: Option<String>,
+    pub candidate_output: Option<String>,
+    pub failed_graders: Vec<String>,
+}
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct GraderStats {
+    pub grader: String,
+    pub baseline_pass_rate: Option<f32>,
+    pub candidate_pass_rate: Option<f32>,
+    pub baseline_mean_score: Option<f32>,
+    pub candidate_mean_score: Option<f32>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct RunComparison {
+    pub items: Vec<ItemComparison>,
+    pub graders: Vec<GraderStats>,
+    pub baseline_pass_rate: f32,
+    pub candidate_pass_rate: f32,
+    pub baseline_tokens: u64,
+    pub candidate_tokens: u64,
+    pub baseline_mean_latency_ms: f64,
+    pub candidate_mean_latency_ms: f64,
+}
+
+fn pass_rate(results: &[EvalResult]) -> f32 {
+    if results.is_empty() {
+        return 0.0;
+    }
+    results.iter().filter(|r| result_passed(r)).count() as f32 / results.len() as f32
+}
+
+fn mean_latency_ms(results: &[EvalResult]) -> f64 {
+    if results.is_empty() {
+        return 0.0;
+    }
+    results.iter().map(|r| r.latency_ms as f64).sum::<f64>() / results.len() as f64
+}
+
+fn grader_stats(results: &[EvalResult], grader: &str) -> (Option<f32>, Option<f32>) {
+    let grades = results
+        .iter()
+        .flat_map(|r| r.grades.iter().filter(|g| g.grader == grader))
+        .collect::<Vec<_>>();
+    if grades.is_empty() {
+        return (None, None);
+    }
+    let n = grades.len() as f32;
+    (
+        Some(grades.iter().filter(|g| g.passed).count() as f32 / n),
+        Some(grades.iter().map(|g| g.score).sum::<f32>() / n),
+    )
+}
+
+// The items are matched by id. An item regressed if it passed in the baseline and fails in
+// the candidate, or if its score dropped by more than `tolerance`.
+pub fn compare_runs(baseline: &[EvalResult], candidate: &[EvalResult], tolerance: f32) -> RunComparison {
+    let baseline_by_id = baseline
+        .iter()
+        .map(|r| (r.id.as_str(), r))
+        .collect::<HashMap<_, _>>();
+    let candidate_ids = candidate.iter().map(|r| r.id.as_str()).collect::<HashSet<_>>();
+
+    let mut items = candidate
+        .iter()
+        .map(|c| {
+            let b = baseline_by_id.get(c.id.as_str());
+            let candidate_score = result_score(c);
+            let candidate_passed = result_passed(c);
+            let change = match b {
+                None => Change::Added,
+                Some(b) => {
+                    let baseline_passed = result_passed(b);
+                    let score_delta = match (result_score(b), candidate_score) {
+                        (Some(b), Some(c)) => c - b,
+                        _ => 0.0,
+                    };
+                    if (baseline_passed && !candidate_passed) || score_delta < -tolerance {
+                        Change::Regressed
+                    } else if (!baseline_passed && candidate_passed) || score_delta > tolerance {
+                        Change::Improved
+                    } else {
+                        Change::Unchanged
+                    }
+                }
+            };
+            let mut failed_graders = c
+                .grades
+                .iter()
+                .filter(|g| !g.passed)
+                .map(|g| g.grader.clone())
+                .collect::<Vec<_>>();
+            if c.failed() {
+                failed_graders.push("error".into());
+            }
+            ItemComparison {
+                id: c.id.clone(),
+                input: c.input.clone(),
+                change,
+                baseline_score: b.and_then(|b| result_score(b)),
+                candidate_score,
+                baseline_passed: b.map(|b| result_passed(b)),
+                candidate_passed: Some(candidate_passed),
+                baseline_output: b.map(|b| b.output.clone()),
+                candidate_output: Some(c.output.clone()),
+                failed_graders,
+            }
+        })
+        .collect::<Vec<_>>();
+    items.extend(
+        baseline
+            .iter()
+            .filter(|b| !candidate_ids.contains(b.id.as_str()))
+            .map(|b| ItemComparison {
+                id: b.id.clone(),
+                input: b.input.clone(),
+                change: Change::Removed,
+                baseline_score: result_score(b),
+                candidate_score: None,
+                baseline_passed: Some(result_passed(b)),
+                candidate_passed: None,
+                baseline_output: Some(b.output.clone()),
+                candidate_output: None,
+                failed_graders: vec![],
+            }),
+    );
+
+    let mut grader_names = baseline
+        .iter()
+        .chain(candidate.iter())
+        .flat_map(|r| r.grades.iter().map(|g| g.grader.clone()))
+        .collect::<Vec<_>>();
+    let mut seen = HashSet::new();
+    grader_names.retain(|name| seen.insert(name.clone()));
+    let graders = grader_names
+        .into_iter()
+        .map(|grader| {
+            let (baseline_pass_rate, baseline_mean_score) = grader_stats(baseline, &grader);
+            let (candidate_pass_rate, candidate_mean_score) = grader_stats(candidate, &grader);
+            GraderStats {
+                grader,
+                baseline_pass_rate,
+                candidate_pass_rate,
+                baseline_mean_score,
+                candidate_mean_score,
+            }
+        })
+        .collect();
+
+    RunComparison {
+        items,
+        graders,
+        baseline_pass_rate: pass_rate(baseline),
+        candidate_pass_rate: pass_rate(candidate),
+        baseline_tokens: baseline.iter().map(|r| r.total_tokens as u64).sum(),
+        candidate_tokens: candidate.iter().map(|r| r.total_tokens as u64).sum(),
+        baseline_mean_latency_ms: mean_latency_ms(baseline),
+        candidate_mean_latency_ms: mean_latency_ms(candidate),
+    }
+}
+
+impl RunComparison {
+    pub fn regressions(&self) -> impl Iterator<Item = &ItemComparison> {
+        self.items.iter().filter(|item| item.change == Change::Regressed)
+    }
+
+    pub fn to_markdown(&self) -> String {
+        let percent = |v: Option<f32>| {
+            v.map(|v| format!("{:.1}%", v * 100.0))
+                .unwrap_or("-".into())
+        };
+        let score = |v: Option<f32>| v.map(|v| format!("{:.2}", v)).unwrap_or("-".into());
+        let count = |change: Change| self.items.iter().filter(|i| i.change == change).count();
+
+        let mut lines = vec![
+            "# Evaluation Comparison".to_string(),
+            "".into(),
+            "| | baseline | candidate |".into(),
+            "|---|---|---|".into(),
+            format!(
+                "| pass rate | {} | {} |",
+                percent(Some(self.baseline_pass_rate)),
+                percent(Some(self.candidate_pass_rate))
+            ),
+            format!(
+                "| tokens | {} | {} |",
+                self.baseline_tokens, self.candidate_tokens
+            ),
+            format!(
+                "| mean latency | {:.0} ms | {:.0} ms |",
+                self.baseline_mean_latency_ms, self.candidate_mean_latency_ms
+            ),
+        ];
+        self.graders.iter().for_each(|g| {
+            lines.push(format!(
+                "| {} pass rate / score | {} / {} | {} / {} |",
+                g.grader,
+                percent(g.baseline_pass_rate),
+                score(g.baseline_mean_score),
+                percent(g.candidate_pass_rate),
+                score(g.candidate_mean_score)
+            ));
+        });
+        lines.push("".into());
+        lines.push(format!(
+            "{} regressed, {} improved, {} unchanged, {} added, {} removed",
+            count(Change::Regressed),
+            count(Change::Improved),
+            count(Change::Unchanged),
+            count(Change::Added),
+            count(Change::Removed)
+        ));
+
+        for (title, change) in [("Regressions", Change::Regressed), ("Improvements", Change::Improved)] {
+            let items = self.items.iter().filter(|i| i.change == change).collect::<Vec<_>>();
+            if items.is_empty() {
+                continue;
+            }
+            lines.push("".into());
+            lines.push(format!("## {}", title));
+            items.iter().for_each(|item| {
+                lines.push("".into());
+                lines.push(format!(
+                    "### {} (score {} -> {})",
+                    item.id,
+                    score(item.baseline_score),
+                    score(item.candidate_score)
+                ));
+                lines.push(format!("**Input:** {}", item.input));
+                if !item.failed_graders.is_empty() {
+                    lines.push(format!("**Failed:** {}", item.failed_graders.join(", ")));
+                }
+                lines.push(format!(
+                    "**Baseline:** {}",
+                    item.baseline_output.as_deref().unwrap_or("-")
+                ));
+                lines.push(format!(
+                    "**Candidate:** {}",
+                    item.candidate_output.as_deref().unwrap_or("-")
+                ));
+            });
+        }
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(id: &str, output: &str, reference: Option<&str>) -> EvalResult {
+        EvalResult {
+            id: id.into(),
+            input: format!("question {}", id),
+            output: output.into(),
+            reference: reference.map(|r| r.into()),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_match_graders() {
+        let config = GradingConfig::from_toml(
+            r#"
+            [[graders]]
+            type = "exact_match"
+            contains = true
+
+            [[graders]]
+            type = "regex"
+            name = "refusal"
+            pattern = "(?i)not (allowed|permitted)"
+            "#,
+        )
+        .unwrap();
+        let graders = graders_from_config(&config, "gpt-4o").unwrap();
+
+        let mut r = result("1", "This is  NOT allowed under 21 CFR 700.", Some("not allowed"));
+        grade_result(&graders, &mut r).await;
+        assert_eq!(r.grades.len(), 2);
+        assert!(result_passed(&r));
+
+        // the exact match needs a reference
+        let mut r = result("2", "It is fine.", None);
+        grade_result(&graders, &mut r).await;
+        assert_eq!(r.grades.len(), 1);
+        assert_eq!(r.grades[0].grader, "refusal");
+        assert!(!result_passed(&r));
+        assert_eq!(result_score(&r), Some(0.0));
+    }
+
+    #[test]
+    fn test_parse_judge_reply() {
+        assert_eq!(
+            parse_judge_reply(r#"```json {"score": 4, "reason": "cites the guidance"} ```"#),
+            Some((4.0, Some("cites the guidance".into())))
+        );
+        assert_eq!(parse_judge_reply(r#"{"score": "3.5"}"#), Some((3.5, None)));
+        assert_eq!(parse_judge_reply("four out of five"), None);
+    }
+
+    #[test]
+    fn test_cosine_similarity() {
+        assert!((cosine_similarity(&[1.0, 0.0], &[1.0, 0.0]) - 1.0).abs() < 1e-6);
+        assert!(cosine_similarity(&[1.0, 0.0], &[0.0, 1.0]).abs() < 1e-6);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 0.0]), 0.0);
+        assert_eq!(mean_vector(&[vec![1.0, 3.0], vec![3.0, 1.0]]), Some(vec![2.0, 2.0]));
+    }
+
+    #[test]
+    fn test_compare_runs() {
+        let graded = |id: &str, score: f32, passed: bool| {
+            let mut r = result(id, "answer", None);
+            r.grades = vec![Grade {
+                grader: "judge".into(),
+                score,
+                passed,
+                reason: None,
+            }];
+            r
+        };
+        let baseline = vec![
+            graded("a", 1.0, true),
+            graded("b", 0.4, false),
+            graded("c", 0.8, true),
+            graded("d", 0.9, true),
+        ];
+        let candidate = vec![
+            graded("a", 0.2, false),
+            graded("b", 1.0, true),
+            graded("c", 0.75, true),
+            graded("e", 1.0, true),
+        ];
+        let comparison = compare_runs(&baseline, &candidate, 0.1);
+        let change = |id: &str| comparison.items.iter().find(|i| i.id == id).unwrap().change;
+        assert_eq!(change("a"), Change::Regressed);
+        assert_eq!(change("b"), Change::Improved);
+        assert_eq!(change("c"), Change::Unchanged);
+        assert_eq!(change("d"), Change::Removed);
+        assert_eq!(change("e"), Change::Added);
+        assert_eq!(comparison.regressions().count(), 1);
+        assert_eq!(comparison.baseline_pass_rate, 0.75);
+        assert_eq!(comparison.graders[0].candidate_pass_rate, Some(0.75));
+        assert!(comparison.to_markdown().contains("### a (score 1.00 -> 0.20)"));
+    }
+}
@@ -0,0 +1,283 @@
+// Running an agent over a dataset of tasks or questions.
+//
+// A dataset is a JSONL file, one item per line:
+//
+//   {"id": "q1", "input": "what is a cosmetic guidance?", "reference": "..."}
+//
+// `question` can be used for `input`, and `task` sets the agent's task if it differs from
+// the input. Each item runs on a new agent until the message is processed, and gives an
+// `EvalResult` with the output, the states the agent went through, the token usage of its
+// LLM requests, the latency and the errors.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::eval_grading::Grade;
+use crate::llm_agent::LlmFsmAgent;
+use crate::llm_service::LlmUsage;
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct EvalItem {
+    pub id: Option<String>,
+    #[serde(alias = "question")]
+    pub input: String,
+    pub task: Option<String>,
+    pub reference: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct EvalResult {
+    pub index: usize,
+    pub id: String,
+    pub input: String,
+    pub reference: Option<String>,
+    pub output: String,
+    pub state_path: Vec<String>,
+    pub fsm_state: Option<String>,
+    pub input_tokens: u32,
+    pub output_tokens: u32,
+    pub total_tokens: u32,
+    pub latency_ms: u64,
+    pub errors: Vec<String>,
+    // set by the graders, see `eval_grading`
+    #[serde(default)]
+    pub grades: Vec<Grade>,
+}
+
+impl EvalResult {
+    pub fn failed(&self) -> bool {
+        !self.errors.is_empty()
+    }
+
+    fn add_usage(&mut self, usage: &LlmUsage) {
+        self.input_tokens += usage.input_tokens.unwrap_or(0);
+        self.output_tokens += usage.output_tokens.unwrap_or(0);
+        self.total_tokens += usage.total_tokens.unwrap_or(0);
+    }
+}
+
+pub fn load_dataset<P: AsRef<Path>>(path: P) -> Result<Vec<EvalItem>, anyhow::Error> {
+    let path = path.as_ref();
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("fail to open dataset {}: {}", path.display(), e))?;
+    parse_dataset(&content)
+}
+
+pub fn parse_dataset(content: &str) -> Result<Vec<EvalItem>, anyhow::Error> {
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(line_number, line)| {
+            serde_json::from_str::<EvalItem>(line)
+                .map_err(|e| anyhow::anyhow!("dataset line {}: {}", line_number + 1, e))
+        })
+        .collect()
+}
+
+pub fn load_results<P: AsRef<Path>>(path: P) -> Result<Vec<EvalResult>, anyhow::Error> {
+    let path = path.as_ref();
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("fail to open results {}: {}", path.display(), e))?;
+    content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| Ok(serde_json::from_str::<EvalResult>(line)?))
+        .collect()
+}
+
+// run one item on `agent`, a new agent for each item so the items do not share memory
+pub async fn run_eval_item(
+    mut agent: LlmFsmAgent,
+    index: usize,
+    item: EvalItem,
+    temperature: Option<f32>,
+    timeout: Option<Duration>,
+) -> EvalResult {
+    let mut result = EvalResult {
+        index,
+        id: item.id.clone().unwrap_or_else(|| index.to_string()),
+        input: item.input.clone(),
+        reference: item.reference.clone(),
+        ..Default::default()
+    };
+    let start = Instant::now();
+
+    let (tx, mut rx) = mpsc::channel::<(String, String, String)>(16);
+    let (send_msg, rcv_msg) = mpsc::channel::<(String, String)>(4);
+    let agent_handle = tokio::spawn(async move {
+        let service_result = agent.fsm_message_service(rcv_msg, tx, temperature).await;
+        (service_result, agent.get_current_state().await)
+    });
+
+    let task = item.task.unwrap_or(item.input.clone());
+    let _ = send_msg.send(("task".into(), task)).await;
+    let _ = send_msg.send(("message".into(), item.input)).await;
+
+    let collect_events = async {
+        while let Some((state_name, t, r)) = rx.recv().await {
+            // the events of delegate agents are named "<state>/<sub state>"
+            let own_state = !state_name.contains('/');
+            match t.as_str() {
+                "state" if own_state => result.state_path.push(r),
+                "llm_output" if own_state => result.output = r,
+                "usage" => {
+                    if let Ok(usage) = serde_json::from_str::<LlmUsage>(&r) {
+                        result.add_usage(&usage);
+                    }
+                }
+                "error" => result.errors.push(format!("{}: {}", state_name, r)),
+                "message_processed" => break,
+                _ => {}
+            }
+        }
+    };
+    let timed_out = match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, collect_events).await.is_err(),
+        None => {
+            collect_events.await;
+            false
+        }
+    };
+
+    if timed_out {
+        agent_handle.abort();
+        result
+            .errors
+            .push(format!("timed out after {}s", timeout.unwrap_or_default().as_secs()));
+    } else {
+        let _ = send_msg.send(("terminate".into(), "".into())).await;
+        match agent_handle.await {
+            Ok((Ok(()), fsm_state)) => result.fsm_state = fsm_state,
+            Ok((Err(e), _)) => result.errors.push(e.to_string()),
+            Err(e) => result.errors.push(e.to_string()),
+        }
+    }
+    result.latency_ms = start.elapsed().as_millis() as u64;
+    result
+}
+
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct EvalSummary {
+    pub items: usize,
+    pub failed: usize,
+    pub total_tokens: u64,
+    pub mean_tokens: f64,
+    pub mean_latency_ms: f64,
+    pub p50_latency_ms: u64,
+    pub p95_latency_ms: u64,
+    pub final_states: HashMap<String, usize>,
+}
+
+impl EvalSummary {
+    pub fn from_results(results: &[EvalResult]) -> Self {
+        if results.is_empty() {
+            return EvalSummary::default();
+        }
+        let items = results.len();
+        let total_tokens = results.iter().map(|r| r.total_tokens as u64).sum::<u64>();
+        let mut latencies = results.iter().map(|r| r.latency_ms).collect::<Vec<_>>();
+        latencies.sort_unstable();
+        let percentile = |p: f64| latencies[((items - 1) as f64 * p).round() as usize];
+        let mut final_states = HashMap::<String, usize>::default();
+        results.iter().for_each(|r| {
+            let state = r.fsm_state.clone().unwrap_or("-".into());
+            *final_states.entry(state).or_default() += 1;
+        });
+        EvalSummary {
+            items,
+            failed: results.iter().filter(|r| r.failed()).count(),
+            total_tokens,
+            mean_tokens: total_tokens as f64 / items as f64,
+            mean_latency_ms: latencies.iter().sum::<u64>() as f64 / items as f64,
+            p50_latency_ms: percentile(0.5),
+            p95_latency_ms: percentile(0.95),
+            final_states,
+        }
+    }
+}
+
+pub const RESULT_CSV_COLUMNS: [&str; 13] = [
+    "index",
+    "id",
+    "input",
+    "reference",
+    "output",
+    "state_path",
+    "fsm_state",
+    "input_tokens",
+    "output_tokens",
+    "total_tokens",
+    "latency_ms",
+    "errors",
+    "grades",
+];
+
+// a flat row of a result for the CSV output, in the order of `RESULT_CSV_COLUMNS`
+pub fn result_csv_record(result: &EvalResult) -> Vec<String> {
+    vec![
+        result.index.to_string(),
+        result.id.clone(),
+        result.input.clone(),
+        result.reference.clone().unwrap_or_default(),
+        result.output.clone(),
+        result.state_path.join(" > "),
+        result.fsm_state.clone().unwrap_or_default(),
+        result.input_tokens.to_string(),
+        result.output_tokens.to_string(),
+        result.total_tokens.to_string(),
+        result.latency_ms.to_string(),
+        result.errors.join("; "),
+        result
+            .grades
+            .iter()
+            .map(|g| format!("{}={:.2}{}", g.grader, g.score, if g.passed { "" } else { " (failed)" }))
+            .collect::<Vec<_>>()
+            .join("; "),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_dataset() {
+        let dataset = r#"{"id": "q1", "question": "what is a cosmetic?", "reference": "a product"}
+
+{"input": "summarize the guidance", "task": "write a summary"}"#;
+        let items = parse_dataset(dataset).unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].input, "what is a cosmetic?");
+        assert_eq!(items[0].reference.as_deref(), Some("a product"));
+        assert_eq!(items[1].task.as_deref(), Some("write a summary"));
+        assert!(parse_dataset("{\"id\": \"no input\"}").is_err());
+    }
+
+    #[test]
+    fn test_eval_summary() {
+        let results = (0..10)
+            .map(|i| EvalResult {
+                index: i,
+                total_tokens: 100,
+                latency_ms: (i as u64 + 1) * 100,
+                fsm_state: Some(if i < 7 { "Answer" } else { "FollowUp" }.into()),
+                errors: if i == 3 { vec!["timed out after 60s".into()] } else { vec![] },
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        let summary = EvalSummary::from_results(&results);
+        assert_eq!(summary.items, 10);
+        assert_eq!(summary.failed, 1);
+        assert_eq!(summary.total_tokens, 1000);
+        assert_eq!(summary.mean_latency_ms, 550.0);
+        assert_eq!(summary.p50_latency_ms, 600);
+        assert_eq!(summary.p95_latency_ms, 1000);
+        assert_eq!(summary.final_states.get("FollowUp"), Some(&3));
+        assert_eq!(EvalSummary::from_results(&[]), EvalSummary::default());
+    }
+}
@@ -17,13 +17,14 @@ pub trait FsmState: Send + Sync {
     async fn on_exit(&self) {}
     async fn on_enter_mut(&mut self) {}
     async fn on_exit_mut(&mut self) {}
-    // each state can provide some service if this function is called
+    // each state can provide some service if this function is called, it returns the next state
+    // if the state determines it; an error (e.g. a failed LLM request) ends the agent's turn
     async fn start_service(
         &mut self,
         _tx: Sender<(String, String, String)>,
         _rx: Option<Receiver<(String, String, String)>>,
         _next_states: Option<Vec<String>>
-    ) -> Option<String> {
+    ) -> Result<Option<String>, anyhow::Error> {
         unimplemented!()
     }
     async fn set_service_context(&mut self, _context: Value) {
@@ -42,6 +43,11 @@ pub trait FsmState: Send + Sync {
     fn name(&self) -> String {
         unimplemented!()
     }
+    // what the state will use when it runs, e.g. its rendered prompt, for the debugger; it is
+    // called after `set_service_context`
+    async fn debug_view(&mut self, _next_states: Option<Vec<String>>) -> Option<Value> {
+        None
+    }
 
 }
 
@@ -1,8 +1,7 @@
 use std::collections::HashMap;
 
 use async_trait::async_trait;
-use futures::StreamExt;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 use tera::Tera;
 use tokio::{
@@ -10,11 +9,15 @@ use tokio::{
     task::JoinHandle,
 };
 use serde_json::Value;
+use tracing::instrument::WithSubscriber;
+use tracing::Instrument;
 
 use crate::{
+    agent_delegation::{render_delegate_task, run_delegate_agent},
     fsm::FsmState,
     llm_agent::{self, *},
-    GenaiLlmclient,
+    llm_cache::with_default_cache,
+    llm_service::{LlmChatRequest, LlmError},
 };
 
 type Messages = Vec<(String, String)>;
@@ -34,7 +37,7 @@ pub struct FSMChatState {
     attributes: HashMap<String, String>,
     prompts: StatePrompts,
     config: StateConfig,
-    handle: Option<JoinHandle<String>>,
+    handle: Option<JoinHandle<Result<String, LlmError>>>,
     state_data: FSMChatStateData,
     llm_req_setting: LlmReqSetting
 }
@@ -47,6 +50,9 @@ impl LlmFsmStateInit for FSMChatState {
                 attributes.insert("wait_for_msg".into(), "true".into());
             }
         }
+        if config.require_approval.unwrap_or(false) {
+            attributes.insert("require_approval".into(), "true".into());
+        }
         FSMChatState {
             name: name.to_string(),
             attributes,
@@ -57,6 +63,7 @@ impl LlmFsmStateInit for FSMChatState {
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn get_llm_req_process_handle(
     state_name: String,
     tx: Sender<(String, String, String)>,
@@ -64,15 +71,12 @@ async fn get_llm_req_process_handle(
     full_prompt: String,
     temperature: Option<f32>,
     ignore_llm_output: bool,
-    model_api_key: (String, String),
-) -> JoinHandle<String> {
+    save_reasoning: bool,
+    llm_client: impl LlmClient + 'static,
+) -> JoinHandle<Result<String, LlmError>> {
     // let messages = llm_req_settings.messages.clone();
     // let temperature = llm_req_settings.temperature;
     // let ignore_llm_output = self.config.ignore_llm_output.unwrap_or(false);
-    let llm_client = GenaiLlmclient {
-        model: model_api_key.0,
-        api_key: model_api_key.1,
-    };
     tokio::spawn(async move {
         let _ = tx
             .send((
@@ -81,26 +85,14 @@ async fn get_llm_req_process_handle(
                 "LLM request sent, waiting for response\n".into(),
             ))
             .await;
-        let mut llm_output = String::default();
         //println!(" --- state: {}; full prompt: {}", state_name, full_prompt);
-        let mut llm_stream = llm_client
-            .generate_stream(&full_prompt, &messages, temperature)
-            .await;
-        while let Some(result) = llm_stream.next().await {
-            if let Some(output) = result {
-                llm_output.push_str(&output);
-                if !ignore_llm_output {
-                    let _ = tx.send((state_name.clone(), "token".into(), output)).await;
-                };
-            };
-        }
-        if !ignore_llm_output {
-            let _ = tx
-                .send((state_name.clone(), "llm_output".into(), llm_output.clone()))
-                .await;
-        };
-        llm_output
-    })
+        let req = LlmChatRequest::from_prompt(&full_prompt, &messages, temperature);
+        let llm_stream = llm_client.chat_stream(&req).await;
+        // on errors, the truncated answer is not passed on
+        forward_llm_stream(llm_stream, &state_name, &tx, ignore_llm_output, save_reasoning).await
+    }
+    .in_current_span()
+    .with_current_subscriber())
 }
 
 fn extract_code(input: &str) -> String {
@@ -116,11 +108,22 @@ fn extract_code(input: &str) -> String {
     }
 }
 
-fn run_code_in_docker(code: &str) -> (String, String) {
+// `kind` is `code` or `fsm_code`, for the span of the run
+fn run_code_in_docker(code: &str, kind: &str) -> (String, String) {
     use std::io::Write;
     use std::process::Command;
+    use std::time::Instant;
     use tempfile::NamedTempFile;
 
+    let span = tracing::info_span!(
+        "code_execution",
+        kind,
+        exit_code = tracing::field::Empty,
+        latency_ms = tracing::field::Empty
+    );
+    let _entered = span.enter();
+    let start = Instant::now();
+
     // Create a temporary file to store the Python code
     let mut temp_file = NamedTempFile::new().unwrap();
     write!(temp_file, "{}", code).unwrap();
@@ -138,6 +141,10 @@ fn run_code_in_docker(code: &str) -> (String, String) {
         ])
         .output()
         .expect("Failed to execute Docker command");
+    span.record("latency_ms", start.elapsed().as_millis() as u64);
+    if let Some(exit_code) = output.status.code() {
+        span.record("exit_code", exit_code);
+    }
 
     // Capture stdin and stdout
     let stdout = String::from_utf8_lossy(&output.stdout).to_string();
@@ -169,6 +176,120 @@ fn _format_messages(messages: &[(String, String)]) -> String {
         .join("\n")
 }
 
+// the prompts and the code of a state as they would be sent or run, see `render_prompts`
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct RenderedPrompts {
+    pub state: String,
+    pub system: Option<String>,
+    pub chat: Option<String>,
+    pub fsm: Option<String>,
+    pub delegate_task: Option<String>,
+    pub code: Option<String>,
+    pub fsm_code: Option<String>,
+    pub errors: Vec<String>,
+}
+
+// what the fsm prompt and the fsm code get for the state's LLM output
+pub const DRY_RUN_RESPONSE: &str = "<the LLM output of the state>";
+
+fn template_error(e: &tera::Error) -> String {
+    // tera puts the cause, e.g. the missing variable, in the source
+    let mut message = e.to_string();
+    let mut source = std::error::Error::source(e);
+    while let Some(e) = source {
+        message = format!("{}: {}", message, e);
+        source = e.source();
+    }
+    message
+}
+
+// Render the prompts of every state of `config` with the messages, the task and the memory of
+// `sample`, and expand the `code` and `fsm_code` of the states, without any LLM request or
+// code run. The template errors of a state are in its `errors`.
+pub async fn render_prompts(
+    config: &LlmFsmAgentConfig,
+    sample: &LlmReqSetting,
+) -> Vec<RenderedPrompts> {
+    let mut sample = sample.clone();
+    if sample.tools.is_none() {
+        sample.tools = config.tools.clone();
+    }
+    let mut rendered_states = vec![];
+    for state_name in config.states.iter() {
+        let prompts = config.state_prompts.get(state_name).cloned().unwrap_or_default();
+        let state_config = config
+            .state_config
+            .as_ref()
+            .and_then(|state_config| state_config.get(state_name))
+            .cloned()
+            .unwrap_or_default();
+        let mut state = FSMChatState::new(state_name, prompts, state_config);
+        state.state_data = state.prepare_context(&sample).await;
+        let mut next_states = config
+            .transitions
+            .iter()
+            .filter(|(from, _)| from == state_name)
+            .map(|(_, to)| to.clone())
+            .collect::<Vec<_>>();
+        next_states.sort();
+
+        let mut rendered = RenderedPrompts {
+            state: state_name.clone(),
+            ..Default::default()
+        };
+        let mut render = |kind: &str, result: Result<String, tera::Error>| match result {
+            Ok(text) => Some(text),
+            Err(e) => {
+                rendered.errors.push(format!("{}: {}", kind, template_error(&e)));
+                None
+            }
+        };
+        let prompt_context = state.prompt_context();
+        let system = state.prompts.system.as_ref().and_then(|system| {
+            render("system prompt", Tera::one_off(system, &prompt_context, false))
+        });
+        let chat = state
+            .prompts
+            .chat
+            .as_ref()
+            .and_then(|chat| render("chat prompt", Tera::one_off(chat, &prompt_context, false)));
+        let fsm = state
+            .render_fsm_prompt(&next_states, DRY_RUN_RESPONSE)
+            .and_then(|fsm| render("fsm prompt", fsm));
+        let code = state.config.code.clone().and_then(|code| {
+            render("code", state.wrap_code(&sample, Some(&next_states), None, code))
+        });
+        let fsm_code = state.config.fsm_code.clone().and_then(|fsm_code| {
+            let response = DRY_RUN_RESPONSE.to_string();
+            render(
+                "fsm_code",
+                state.wrap_code(&sample, Some(&next_states), Some(&response), fsm_code),
+            )
+        });
+        let delegate_task = if state.config.delegates() {
+            match render_delegate_task(&state.config, &sample) {
+                Ok(task) => Some(task),
+                Err(e) => {
+                    rendered.errors.push(format!("delegate task: {}", e));
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        rendered_states.push(RenderedPrompts {
+            system,
+            chat,
+            fsm,
+            delegate_task,
+            code,
+            fsm_code,
+            ..rendered
+        });
+    }
+    rendered_states
+}
+
 #[async_trait]
 impl FsmState for FSMChatState {
     async fn start_service(
@@ -184,14 +305,15 @@ impl FsmState for FSMChatState {
 
         self.state_data = self.prepare_context(&llm_req_setting).await;
 
-        let llm_output = self.handle_llm_output(&llm_req_setting, &tx).await;
+        let llm_output = self.handle_llm_output(&llm_req_setting, &tx).await?;
 
         let (stdout, stderr) = self.execute_code(&llm_req_setting, &tx).await;
 
         self.save_execution_output(&tx, &stdout, &stderr).await;
 
-        self.determine_next_state(&llm_req_setting, &tx, &next_states, &llm_output)
-            .await
+        Ok(self
+            .determine_next_state(&llm_req_setting, &tx, &next_states, &llm_output)
+            .await)
     }
 
     async fn set_service_context(&mut self, context: Value) {
@@ -217,9 +339,75 @@ impl FsmState for FSMChatState {
     fn name(&self) -> String {
         self.name.clone()
     }
+
+    async fn debug_view(&mut self, next_states: Option<Vec<String>>) -> Option<Value> {
+        // the overrides of an earlier pause that was cancelled
+        self.attributes.remove("prompt_override");
+        self.attributes.remove("skip_llm");
+
+        let llm_req_setting = self.llm_req_setting.clone();
+        self.state_data = self.prepare_context(&llm_req_setting).await;
+        let prompt = match self.render_prompt() {
+            Ok(prompt) => json!(prompt),
+            Err(e) => json!(format!("template error: {}", e)),
+        };
+        let code = self
+            .config
+            .code
+            .clone()
+            .filter(|_| self.config.execute_code.unwrap_or(false))
+            .map(|code| {
+                self.wrap_code(&llm_req_setting, next_states.as_ref(), None, code)
+                    .unwrap_or_else(|e| format!("template error: {}", e))
+            });
+        Some(json!({
+            "prompt": prompt,
+            "messages": self.llm_messages(),
+            "memory_slots": self.state_data.memory,
+            "context": self.state_data.context,
+            "summary": self.state_data.summary,
+            "llm_request": !self.config.delegates() && !self.config.disable_llm_request.unwrap_or(false),
+            "delegates": self.config.delegates(),
+            "code": code,
+            "fsm_code": self.config.fsm_code.is_some(),
+        }))
+    }
 }
 
 impl FSMChatState {
+    fn prompt_context(&self) -> tera::Context {
+        let mut tera_context = tera::Context::new();
+        tera_context.insert("context", &self.state_data.context);
+        tera_context.insert("summary", &self.state_data.summary);
+        tera_context.insert("task", &self.state_data.task);
+        tera_context.insert("tools", &self.state_data.tools);
+
+        self.state_data.memory.iter().for_each( |(slot_name, m)| {
+            tera_context.insert(slot_name, m);
+        } );
+        tera_context
+    }
+
+    // the system and the chat prompts rendered with the state data, `None` without prompts
+    fn render_prompt(&self) -> Result<Option<String>, tera::Error> {
+        let system_prompt = self.prompts.system.clone().unwrap_or("".into());
+        let chat_prompt = self.prompts.chat.as_ref().unwrap_or(&"".into()).clone();
+        if system_prompt.len() + chat_prompt.len() == 0 {
+            return Ok(None);
+        }
+        let full_prompt = [system_prompt, chat_prompt].join("\n");
+        Tera::one_off(&full_prompt, &self.prompt_context(), false).map(Some)
+    }
+
+    // the messages sent with the prompt
+    fn llm_messages(&self) -> Messages {
+        if self.config.ignore_messages.unwrap_or(false) {
+            vec![]
+        } else {
+            self.state_data.messages.clone()
+        }
+    }
+
     async fn prepare_context(
         &self,
         llm_req_settings: &llm_agent::LlmReqSetting,
@@ -300,34 +488,32 @@ impl FSMChatState {
         &mut self,
         llm_req_settings: &llm_agent::LlmReqSetting,
         tx: &Sender<(String, String, String)>,
-    ) -> String {
-        let llm_output = if !self.config.disable_llm_request.unwrap_or(false) {
-            let system_prompt = self.prompts.system.clone().unwrap_or("".into());
-            let chat_prompt = self.prompts.chat.as_ref().unwrap_or(&"".into()).clone();
-
-            if system_prompt.len() + chat_prompt.len() > 0 {
-                let mut tera_context = tera::Context::new();
-                tera_context.insert("context", &self.state_data.context);
-                tera_context.insert("summary", &self.state_data.summary);
-                tera_context.insert("task", &self.state_data.task);
-                tera_context.insert("tools", &self.state_data.tools);
-
-                self.state_data.memory.iter().for_each( |(slot_name, m)| {
-                    tera_context.insert(slot_name, m);
-                } );
-
-                let full_prompt = [system_prompt, chat_prompt].join("\n");
-                let full_prompt = Tera::one_off(&full_prompt, &tera_context, false).unwrap();
-
-                let model = llm_req_settings.model.clone();
-                let api_key = llm_req_settings.api_key.clone();
-                let temperature = llm_req_settings.temperature;
+    ) -> Result<String, anyhow::Error> {
+        let llm_output = if let Some(llm_output) = self.attributes.remove("skip_llm") {
+            // the debugger skipped the LLM request, the given output takes its place
+            if !llm_output.is_empty() {
+                let _ = tx
+                    .send((self.name.clone(), "llm_output".into(), llm_output.clone()))
+                    .await;
+            }
+            self.set_attribute("llm_output", llm_output.clone()).await;
+            llm_output
+        } else if self.config.delegates() {
+            self.delegate(llm_req_settings, tx).await?
+        } else if !self.config.disable_llm_request.unwrap_or(false) {
+            if let Some(full_prompt) = self.render_prompt().unwrap() {
+                // the prompt edited in the debugger
+                let full_prompt = self.attributes.remove("prompt_override").unwrap_or(full_prompt);
+
+                let llm_client = with_default_cache(
+                    self.config
+                        .llm_client(llm_req_settings)
+                        .with_audit_purpose(&self.name, "answer"),
+                    self.config.cache_llm.unwrap_or(false),
+                );
+                let temperature = self.config.temperature(llm_req_settings);
                 let ignore_llm_output = self.config.ignore_llm_output.unwrap_or(false);
-                let messages = if self.config.ignore_messages.unwrap_or(false) {
-                    vec![]
-                } else {
-                    self.state_data.messages.clone() 
-                };
+                let messages = self.llm_messages();
                 self.handle = Some(
                     get_llm_req_process_handle(
                         self.name.clone(),
@@ -336,14 +522,16 @@ impl FSMChatState {
                         full_prompt,
                         temperature,
                         ignore_llm_output,
-                        (model, api_key),
+                        self.config.save_reasoning.unwrap_or(false),
+                        llm_client,
                     )
                     .await,
                 );
 
                 if let Some(handle) = self.handle.take() {
-                    let llm_output = tokio::join!(handle);
-                    let llm_output = llm_output.0.unwrap();
+                    let _abort_on_drop = AbortOnDrop(handle.abort_handle());
+                    // a failed request ends the turn, its (empty) output is not used
+                    let llm_output = handle.await??;
                     self.set_attribute("llm_output", llm_output.clone()).await;
                     llm_output
                 } else {
@@ -387,7 +575,36 @@ impl FSMChatState {
             };
 
         }
-        llm_output
+        Ok(llm_output)
+    }
+
+    // the output of the delegate agent takes the place of the state's own LLM output
+    async fn delegate(
+        &mut self,
+        llm_req_settings: &llm_agent::LlmReqSetting,
+        tx: &Sender<(String, String, String)>,
+    ) -> Result<String, anyhow::Error> {
+        let result = match render_delegate_task(&self.config, llm_req_settings) {
+            Ok(task) => {
+                run_delegate_agent::<FSMChatState>(
+                    &self.name,
+                    &self.config,
+                    llm_req_settings,
+                    task,
+                    tx,
+                )
+                .await
+            }
+            Err(e) => Err(e),
+        };
+        let llm_output = result.map_err(|e| anyhow::anyhow!("delegation failed: {}", e))?;
+        if !self.config.ignore_llm_output.unwrap_or(false) && !llm_output.is_empty() {
+            let _ = tx
+                .send((self.name.clone(), "llm_output".into(), llm_output.clone()))
+                .await;
+        }
+        self.set_attribute("llm_output", llm_output.clone()).await;
+        Ok(llm_output)
     }
 
     async fn execute_code(
@@ -402,7 +619,7 @@ impl FSMChatState {
 
         if self.config.execute_code.unwrap_or(false) {
             let code = if let Some(code) = self.config.code.clone() {
-                self.wrap_code(llm_req_settings, None, None, code)
+                self.wrap_code(llm_req_settings, None, None, code).unwrap()
             } else {
                 let code = llm_req_settings
                     .memory
@@ -430,7 +647,7 @@ impl FSMChatState {
                                 "\nconditionally, run code from the context:\n".into(),
                             ))
                             .await;
-                        let (stdout, stderr) = run_code_in_docker(&code);
+                        let (stdout, stderr) = run_code_in_docker(&code, "code");
                         let _ = tx
                             .send((
                                 self.name.clone(),
@@ -459,7 +676,7 @@ impl FSMChatState {
                 }
                 false => {
                     // just execute the code without a user input
-                    let (stdout, stderr) = run_code_in_docker(&code);
+                    let (stdout, stderr) = run_code_in_docker(&code, "code");
                     let _ = tx
                         .send((
                             self.name.clone(),
@@ -535,13 +752,15 @@ impl FSMChatState {
     ) -> Option<String> {
         if let Some(fsm_code) = self.config.fsm_code.clone() {
             
-            let code = self.wrap_code(
-                llm_req_settings,
-                next_states.as_ref(),
-                Some(llm_output),
-                fsm_code,
-            );
-            let (stdout, stderr) = run_code_in_docker(&code);
+            let code = self
+                .wrap_code(
+                    llm_req_settings,
+                    next_states.as_ref(),
+                    Some(llm_output),
+                    fsm_code,
+                )
+                .unwrap();
+            let (stdout, stderr) = run_code_in_docker(&code, "fsm_code");
             let _ = tx
                 .send((
                     self.name.clone(),
@@ -560,50 +779,38 @@ impl FSMChatState {
         } else if let Some(next_states) = next_states {
             if next_states.len() == 1 {
                 Some(next_states.first().unwrap().clone())
-            } else if let Some(fsm_prompt) = self.prompts.fsm.clone() {
-                let available_transitions = next_states.join(", ");
-                let msg = format!(
-                    r#"
-Given these information, you need to determine the next state following the instructions below:
-
-Current State: {}
-
-Available Next States: {}
+            } else if let Some(fsm_prompt) = self.render_fsm_prompt(next_states, llm_output) {
+                let fsm_prompt = fsm_prompt.unwrap();
+
+                let llm_client = with_default_cache(
+                    self.config
+                        .fsm_llm_client(llm_req_settings)
+                        .with_audit_purpose(&self.name, "routing"),
+                    self.config.cache_fsm.unwrap_or(false),
+                );
 
-Make sure the output is just a simple valid JSON string in 
-the format following the instruction above: `{{"next_state": SOME_NEXT_STATE}}`. 
-The "SOME_NEXT_STATE" is one of the Available Next States.
-"#,
-                    self.name, available_transitions
+                let req = LlmChatRequest::from_prompt(
+                    &fsm_prompt,
+                    &[("user".into(), "determine the next state".into())],
+                    self.config.fsm_temperature(llm_req_settings),
                 );
-                let fsm_prompt = [msg, fsm_prompt].join("\n");
-                let mut tera_context = tera::Context::new();
-                tera_context.insert("task", &self.state_data.task);
-                tera_context.insert("messages", &self.state_data.messages);
-                tera_context.insert("summary", &self.state_data.summary);
-                tera_context.insert("context", &self.state_data.context);
-                tera_context.insert("summary", &self.state_data.summary);
-                tera_context.insert("response", &llm_output);
-
-                self.state_data.memory.iter().for_each( |(slot_name, m)| {
-                    tera_context.insert(slot_name, m);
-                } );
-                
-                let fsm_prompt = Tera::one_off(&fsm_prompt, &tera_context, false).unwrap();
-
-                let llm_client = GenaiLlmclient {
-                    model: llm_req_settings.model.clone(),
-                    api_key: llm_req_settings.api_key.clone(),
+                let next_state = match llm_client.chat(&req).await {
+                    Ok(response) => {
+                        send_usage(tx, &self.name, &response.usage).await;
+                        response.text
+                    }
+                    Err(e) => {
+                        // the agent stays in the current state
+                        let _ = tx
+                            .send((
+                                self.name.clone(),
+                                "error".into(),
+                                format!("fail to determine the next state: {}", e),
+                            ))
+                            .await;
+                        return None;
+                    }
                 };
-
-                let next_state = llm_client
-                    .generate(
-                        &fsm_prompt,
-                        &[("user".into(), "determine the next state".into())],
-                        llm_req_settings.temperature,
-                    )
-                    .await
-                    .unwrap();
                 // println!("\nllm nextstep raw response: {}", next_state );
                 let next_fsm_state_response = serde_json::from_str::<LlmResponse>(&next_state.trim());
                 // println!("\nllm next_fsm_state_response: {:?}", next_fsm_state_response );
@@ -622,13 +829,50 @@ The "SOME_NEXT_STATE" is one of the Available Next States.
         }
     }
 
+    // the prompt choosing the next state, `None` without an fsm prompt
+    fn render_fsm_prompt(
+        &self,
+        next_states: &[String],
+        llm_output: &str,
+    ) -> Option<Result<String, tera::Error>> {
+        let fsm_prompt = self.prompts.fsm.clone()?;
+        let available_transitions = next_states.join(", ");
+        let msg = format!(
+            r#"
+Given these information, you need to determine the next state following the instructions below:
+
+Current State: {}
+
+Available Next States: {}
+
+Make sure the output is just a simple valid JSON string in 
+the format following the instruction above: `{{"next_state": SOME_NEXT_STATE}}`. 
+The "SOME_NEXT_STATE" is one of the Available Next States.
+"#,
+            self.name, available_transitions
+        );
+        let fsm_prompt = [msg, fsm_prompt].join("\n");
+        let mut tera_context = tera::Context::new();
+        tera_context.insert("task", &self.state_data.task);
+        tera_context.insert("messages", &self.state_data.messages);
+        tera_context.insert("summary", &self.state_data.summary);
+        tera_context.insert("context", &self.state_data.context);
+        tera_context.insert("response", &llm_output);
+
+        self.state_data.memory.iter().for_each( |(slot_name, m)| {
+            tera_context.insert(slot_name, m);
+        } );
+        
+        Some(Tera::one_off(&fsm_prompt, &tera_context, false))
+    }
+
     fn wrap_code(
         &self,
         llm_req_settings: &LlmReqSetting,
         next_states: Option<&Vec<String>>,
         llm_output: Option<&String>,
         fsm_code: String,
-    ) -> String {
+    ) -> Result<String, tera::Error> {
         let mut tera_context = tera::Context::new();
         let messages = escape_json_string(&json!(&self.state_data.messages).to_string());
         let context = escape_json_string(&json!(&self.state_data.context).to_string());
@@ -652,6 +896,70 @@ The "SOME_NEXT_STATE" is one of the Available Next States.
         self.state_data.memory.iter().for_each( |(slot_name, m)| {
             tera_context.insert(slot_name, &escape_json_string(&json!(m).to_string()));
         });
-        Tera::one_off(&fsm_code, &tera_context, false).unwrap()
+        Tera::one_off(&fsm_code, &tera_context, false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_render_prompts() {
+        let config = LlmFsmAgentConfigBuilder::from_toml(
+            r#"
+            states = ["Answer", "Check", "Done"]
+            transitions = [["Answer", "Check"], ["Answer", "Done"], ["Check", "Done"]]
+            initial_state = "Answer"
+            system_prompt = ""
+            fsm_prompt = ""
+            summary_prompt = ""
+
+            [state_prompts.Answer]
+            system = "Answer the task: {{ task }}"
+            chat = "Use the notes: {{ notes }}"
+            fsm = "The answer was: {{ response }}"
+
+            [state_prompts.Check]
+            system = "Check {{ missing_slot }}"
+
+            [state_config.Answer]
+            use_memory = [["notes", 1]]
+
+            [state_config.Check]
+            execute_code = true
+            code = "print({{ task }})"
+            "#,
+        )
+        .unwrap()
+        .build()
+        .unwrap();
+        let mut sample = LlmReqSetting {
+            task: Some("write a haiku".into()),
+            ..Default::default()
+        };
+        sample.messages.push(("user".into(), "a haiku please".into()));
+        sample
+            .memory
+            .insert("notes".into(), vec![Value::from("about the sea")]);
+
+        let rendered = render_prompts(&config, &sample).await;
+        assert_eq!(rendered.len(), 3);
+
+        let answer = &rendered[0];
+        assert_eq!(answer.system.as_deref(), Some("Answer the task: write a haiku"));
+        assert_eq!(answer.chat.as_deref(), Some(r#"Use the notes: "about the sea""#));
+        let fsm = answer.fsm.as_deref().unwrap();
+        assert!(fsm.contains("Available Next States: Check, Done"));
+        assert!(fsm.contains(DRY_RUN_RESPONSE));
+        assert!(answer.errors.is_empty());
+
+        let check = &rendered[1];
+        assert_eq!(check.system, None);
+        assert_eq!(check.errors.len(), 1);
+        assert!(check.errors[0].starts_with("system prompt"));
+        assert_eq!(check.code.as_deref(), Some(r#"print(\"write a haiku\")"#));
+
+        assert_eq!(rendered[2], RenderedPrompts { state: "Done".into(), ..Default::default() });
     }
 }
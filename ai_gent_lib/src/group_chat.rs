@@ -0,0 +1,520 @@
+// A conversation between the user and several agents taking turns.
+//
+// Every member is its own `LlmFsmAgent` with its own states and memory. After each user message,
+// a moderator policy picks the speakers one turn at a time:
+//
+//   moderator = "round_robin"        # or "llm_selected", "mention"
+//   turns_per_message = 2            # defaults to the number of members
+//   moderator_model = "gpt-4o-mini"  # for "llm_selected"
+//
+//   [[members]]
+//   name = "Scientist"
+//   description = "answers with the data and the studies"
+//   config = "rag.toml"              # relative to the group config file
+//
+// With "mention", a member speaks when the previous message addresses it as `@Name` (not
+// `@Names`, the name is followed by a character that can't be in a name); a user
+// message without a mention goes round-robin, an agent message without one ends the round.
+// With "llm_selected", the moderator model reads the transcript and names the next speaker or
+// hands the turn back to the user.
+//
+// Each member sees the others' messages as user messages prefixed with their names. The
+// messages in the transcript are attributed to the agent and the state that produced them.
+
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::mpsc::{self, Sender};
+
+use crate::fsm::FsmState;
+use crate::llm_agent::{LlmClient, LlmFsmAgent, LlmFsmAgentConfigBuilder, LlmFsmStateInit};
+use crate::llm_cache::with_default_cache;
+use crate::llm_provider::{provider_api_key, provider_for_model};
+use crate::llm_service::GenerationOptions;
+use crate::GenaiLlmclient;
+
+const DEFAULT_MODERATOR_PROMPT: &str = "You are the moderator of a group chat between a user \
+and the participants listed below. Read the conversation and decide who should speak next.";
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ModeratorPolicy {
+    #[default]
+    RoundRobin,
+    LlmSelected,
+    Mention,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct GroupMemberConfig {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    pub config: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct GroupChatConfig {
+    #[serde(default)]
+    pub moderator: ModeratorPolicy,
+    pub moderator_prompt: Option<String>,
+    pub moderator_model: Option<String>,
+    pub turns_per_message: Option<usize>,
+    pub members: Vec<GroupMemberConfig>,
+}
+
+impl GroupChatConfig {
+    pub fn from_toml(toml_str: &str) -> Result<Self, anyhow::Error> {
+        Ok(toml::from_str(toml_str)?)
+    }
+
+    // the member config paths are resolved relative to the directory of the group config
+    pub fn from_toml_file<P: AsRef<Path>>(path: P) -> Result<Self, anyhow::Error> {
+        let path = path.as_ref();
+        let mut config = Self::from_toml(&std::fs::read_to_string(path)?)?;
+        let base_dir = path.parent().unwrap_or(Path::new(""));
+        config.members.iter_mut().for_each(|member| {
+            let member_path = PathBuf::from(&member.config);
+            if member_path.is_relative() {
+                member.config = base_dir.join(member_path).to_string_lossy().into_owned();
+            }
+        });
+        Ok(config)
+    }
+}
+
+// `agent` is `None` for the user's messages
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct GroupMessage {
+    pub agent: Option<String>,
+    pub state: Option<String>,
+    pub content: String,
+}
+
+pub struct GroupMember {
+    pub name: String,
+    pub description: String,
+    pub agent: LlmFsmAgent,
+}
+
+pub struct GroupChat {
+    pub members: Vec<GroupMember>,
+    pub transcript: Vec<GroupMessage>,
+    policy: ModeratorPolicy,
+    moderator_prompt: String,
+    moderator_client: Option<Box<dyn LlmClient>>,
+    turns_per_message: usize,
+    next_speaker: usize,
+}
+
+impl GroupChat {
+    pub fn new(members: Vec<GroupMember>, policy: ModeratorPolicy) -> Self {
+        let turns_per_message = members.len().max(1);
+        GroupChat {
+            members,
+            transcript: Vec::default(),
+            policy,
+            moderator_prompt: DEFAULT_MODERATOR_PROMPT.into(),
+            moderator_client: None,
+            turns_per_message,
+            next_speaker: 0,
+        }
+    }
+
+    // the members run on their own models if their configs set one, otherwise on `model`
+    pub fn from_config<S: LlmFsmStateInit + FsmState + 'static>(
+        config: &GroupChatConfig,
+        model: &str,
+        provider: Option<String>,
+        api_key: &str,
+    ) -> Result<Self, anyhow::Error> {
+        if config.members.is_empty() {
+            return Err(anyhow::anyhow!("a group chat needs at least one member"));
+        }
+        let members = config
+            .members
+            .iter()
+            .map(|member| {
+                let agent_config =
+                    LlmFsmAgentConfigBuilder::from_toml_file(&member.config)?.build()?;
+                let agent =
+                    LlmFsmAgent::from_config::<S>(&agent_config, model, provider.clone(), api_key)?;
+                Ok(GroupMember {
+                    name: member.name.clone(),
+                    description: member.description.clone(),
+                    agent,
+                })
+            })
+            .collect::<Result<Vec<_>, anyhow::Error>>()?;
+
+        let mut group_chat = GroupChat::new(members, config.moderator);
+        if let Some(turns_per_message) = config.turns_per_message {
+            group_chat.turns_per_message = turns_per_message.max(1);
+        }
+        if let Some(moderator_prompt) = config.moderator_prompt.clone() {
+            group_chat.moderator_prompt = moderator_prompt;
+        }
+        if config.moderator == ModeratorPolicy::LlmSelected {
+            let moderator_model = config.moderator_model.clone().unwrap_or(model.to_string());
+            let moderator_provider = if moderator_model == model {
+                provider.clone()
+            } else {
+                None
+            };
+            let api_key = provider_api_key(
+                &moderator_provider
+                    .clone()
+                    .unwrap_or_else(|| provider_for_model(&moderator_model)),
+            )
+            .unwrap_or(api_key.to_string());
+            let client = GenaiLlmclient {
+                model: moderator_model,
+                api_key,
+                options: GenerationOptions {
+                    provider: moderator_provider,
+                    ..Default::default()
+                },
+            };
+            group_chat = group_chat.with_moderator_client(with_default_cache(client, true));
+        }
+        Ok(group_chat)
+    }
+
+    pub fn with_moderator_client(mut self, client: impl LlmClient + 'static) -> Self {
+        self.moderator_client = Some(Box::new(client));
+        self
+    }
+
+    // send a user message and let the members answer, the events of the members are sent to
+    // `tx` under "<member>/<state>" and every answer as a "group_message" event
+    pub async fn send(
+        &mut self,
+        user_message: &str,
+        tx: &Sender<(String, String, String)>,
+    ) -> Result<Vec<GroupMessage>, anyhow::Error> {
+        self.transcript.push(GroupMessage {
+            agent: None,
+            state: None,
+            content: user_message.to_string(),
+        });
+
+        let mut answers = vec![];
+        for _ in 0..self.turns_per_message {
+            let Some(speaker) = self.select_speaker().await else {
+                break;
+            };
+            self.next_speaker = (speaker + 1) % self.members.len();
+            let answer = self.run_turn(speaker, tx).await?;
+            let _ = tx
+                .send((
+                    format!(
+                        "{}/{}",
+                        self.members[speaker].name,
+                        answer.state.clone().unwrap_or_default()
+                    ),
+                    "group_message".into(),
+                    answer.content.clone(),
+                ))
+                .await;
+            self.transcript.push(answer.clone());
+            answers.push(answer);
+        }
+
+        let _ = tx
+            .send(("".into(), "message_processed".into(), "".into()))
+            .await;
+        Ok(answers)
+    }
+
+    async fn select_speaker(&self) -> Option<usize> {
+        if self.members.is_empty() {
+            return None;
+        }
+        let last_message = self.transcript.last()?;
+        match self.policy {
+            ModeratorPolicy::RoundRobin => Some(self.next_speaker),
+            ModeratorPolicy::Mention => match self.mentioned_member(last_message) {
+                Some(speaker) => Some(speaker),
+                None if last_message.agent.is_none() => Some(self.next_speaker),
+                None => None,
+            },
+            ModeratorPolicy::LlmSelected => {
+                let Some(client) = self.moderator_client.as_ref() else {
+                    return Some(self.next_speaker);
+                };
+                let transcript = vec![("user".to_string(), self.format_transcript())];
+                match client
+                    .generate(&self.moderator_instructions(), &transcript, Some(0.0))
+                    .await
+                {
+                    Ok(reply) => match parse_next_speaker(&reply) {
+                        Some(name) if name.eq_ignore_ascii_case("user") => None,
+                        Some(name) => self
+                            .member_index(&name)
+                            .or(Some(self.next_speaker)),
+                        None => Some(self.next_speaker),
+                    },
+                    Err(e) => {
+                        tracing::info!(target: "log", "group chat moderator error: {}", e);
+                        Some(self.next_speaker)
+                    }
+                }
+            }
+        }
+    }
+
+    fn member_index(&self, name: &str) -> Option<usize> {
+        self.members
+            .iter()
+            .position(|member| member.name.eq_ignore_ascii_case(name.trim()))
+    }
+
+    // the member addressed first as `@Name` in `message`, not counting its author
+    fn mentioned_member(&self, message: &GroupMessage) -> Option<usize> {
+        let content = message.content.to_lowercase();
+        self.members
+            .iter()
+            .enumerate()
+            .filter(|(_, member)| message.agent.as_ref() != Some(&member.name))
+            .filter_map(|(idx, member)| {
+                let mention = format!("@{}", member.name.to_lowercase());
+                content
+                    .match_indices(&mention)
+                    .map(|(pos, _)| pos)
+                    .find(|pos| {
+                        !content[pos + mention.len()..]
+                            .starts_with(|c: char| c.is_alphanumeric() || c == '_' || c == '-')
+                    })
+                    .map(|pos| (pos, idx))
+            })
+            .min()
+            .map(|(_, idx)| idx)
+    }
+
+    fn moderator_instructions(&self) -> String {
+        let members = self
+            .members
+            .iter()
+            .map(|member| format!("- {}: {}", member.name, member.description))
+            .collect::<Vec<_>>()
+            .join("\n");
+        format!(
+            "{}\n\nParticipants:\n{}\n\nReply with JSON only: {{\"next_speaker\": \"<participant name>\"}}, \
+             or {{\"next_speaker\": \"user\"}} if the participants should wait for the user.",
+            self.moderator_prompt, members
+        )
+    }
+
+    fn format_transcript(&self) -> String {
+        self.transcript
+            .iter()
+            .map(|message| {
+                format!(
+                    "{}: {}",
+                    message.agent.as_deref().unwrap_or("user"),
+                    message.content
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n\n")
+    }
+
+    async fn run_turn(
+        &mut self,
+        speaker: usize,
+        tx: &Sender<(String, String, String)>,
+    ) -> Result<GroupMessage, anyhow::Error> {
+        let member = &mut self.members[speaker];
+        let (history, message) = member_view(&self.transcript, &member.name);
+        member.agent.llm_req_settings.messages = history;
+        let temperature = member.agent.llm_req_settings.temperature;
+
+        let (send_msg, rcv_msg) = mpsc::channel::<(String, String)>(4);
+        let (agent_tx, mut agent_rx) = mpsc::channel::<(String, String, String)>(16);
+        let _ = send_msg.send(("task".into(), message.clone())).await;
+        let _ = send_msg.send(("message".into(), message)).await;
+        // the agent stops once it has processed the message
+        drop(send_msg);
+
+        let name = member.name.clone();
+        let collect_events = async {
+            let mut state = None;
+            let mut llm_output = None;
+            let mut error = None;
+            while let Some((state_name, t, r)) = agent_rx.recv().await {
+                match t.as_str() {
+                    "message_processed" => continue,
+                    "state" => state = Some(r.clone()),
+                    "llm_output" => llm_output = Some(r.clone()),
+                    "error" if error.is_none() => error = Some(r.clone()),
+                    _ => {}
+                }
+                let r = if t == "state" {
+                    format!("{}/{}", name, r)
+                } else {
+                    r
+                };
+                let _ = tx.send((format!("{}/{}", name, state_name), t, r)).await;
+            }
+            (state, llm_output, error)
+        };
+
+        let (result, (state, llm_output, error)) = tokio::join!(
+            member
+                .agent
+                .fsm_message_service(rcv_msg, agent_tx, temperature),
+            collect_events
+        );
+        result?;
+        // a failed turn is not added to the transcript, the error was sent with the events
+        if let Some(error) = error {
+            return Err(anyhow::anyhow!("{}: {}", member.name, error));
+        }
+
+        Ok(GroupMessage {
+            agent: Some(member.name.clone()),
+            state,
+            content: llm_output.unwrap_or_default(),
+        })
+    }
+}
+
+// the transcript as the chat history of `member_name`, and the last message to answer
+fn member_view(transcript: &[GroupMessage], member_name: &str) -> (Vec<(String, String)>, String) {
+    let mut messages = transcript
+        .iter()
+        .map(|message| match message.agent.as_deref() {
+            None => ("user".to_string(), message.content.clone()),
+            Some(agent) if agent == member_name => {
+                ("assistant".to_string(), message.content.clone())
+            }
+            Some(agent) => ("user".to_string(), format!("{}: {}", agent, message.content)),
+        })
+        .collect::<Vec<_>>();
+    let last_message = messages.pop().map(|(_, msg)| msg).unwrap_or_default();
+    (messages, last_message)
+}
+
+fn parse_next_speaker(reply: &str) -> Option<String> {
+    let start = reply.find('{')?;
+    let end = reply.rfind('}')?;
+    let value: Value = serde_json::from_str(reply.get(start..=end)?).ok()?;
+    value
+        .get("next_speaker")
+        .and_then(|name| name.as_str())
+        .map(|name| name.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fsm::FiniteStateMachine;
+    use crate::llm_agent::AgentSettings;
+    use std::collections::HashMap;
+
+    fn member(name: &str) -> GroupMember {
+        let agent = LlmFsmAgent::new(
+            FiniteStateMachine {
+                states: HashMap::new(),
+                transitions: HashMap::new(),
+                current_state: None,
+            },
+            AgentSettings {
+                sys_prompt: "".into(),
+                fsm_prompt: "".into(),
+                summary_prompt: "".into(),
+                fsm_initial_state: "".into(),
+                model: "gpt-4o".into(),
+                api_key: "".into(),
+                provider: None,
+                tools: None,
+                total_state_transition_limit: None,
+            },
+        );
+        GroupMember {
+            name: name.into(),
+            description: "".into(),
+            agent,
+        }
+    }
+
+    fn message(agent: Option<&str>, content: &str) -> GroupMessage {
+        GroupMessage {
+            agent: agent.map(|a| a.to_string()),
+            state: None,
+            content: content.into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mention_policy() {
+        let mut group_chat = GroupChat::new(
+            vec![member("Scientist"), member("Consultant")],
+            ModeratorPolicy::Mention,
+        );
+
+        group_chat.transcript.push(message(None, "what is a cosmetic guidance?"));
+        assert_eq!(group_chat.select_speaker().await, Some(0));
+
+        group_chat
+            .transcript
+            .push(message(None, "@consultant, and then @Scientist?"));
+        assert_eq!(group_chat.select_speaker().await, Some(1));
+
+        // an agent does not hand the turn to itself, and without a mention the user is next
+        group_chat
+            .transcript
+            .push(message(Some("Consultant"), "as @Consultant, I say so"));
+        assert_eq!(group_chat.select_speaker().await, None);
+
+        // a longer name starting with a member's name is not a mention of it
+        group_chat
+            .transcript
+            .push(message(None, "ask the @Consultants, or @Consultant_2, or @consultant."));
+        assert_eq!(group_chat.select_speaker().await, Some(1));
+        group_chat
+            .transcript
+            .push(message(None, "@Consultants and @Scientist-2, please"));
+        assert_eq!(group_chat.select_speaker().await, Some(0));
+    }
+
+    #[tokio::test]
+    async fn test_round_robin_policy() {
+        let mut group_chat = GroupChat::new(
+            vec![member("A"), member("B"), member("C")],
+            ModeratorPolicy::RoundRobin,
+        );
+        assert_eq!(group_chat.turns_per_message, 3);
+        group_chat.transcript.push(message(None, "hi"));
+        group_chat.next_speaker = 2;
+        assert_eq!(group_chat.select_speaker().await, Some(2));
+    }
+
+    #[test]
+    fn test_member_view() {
+        let transcript = vec![
+            message(None, "is it allowed?"),
+            message(Some("Scientist"), "the data says yes"),
+            message(Some("Consultant"), "the law says no"),
+        ];
+        let (history, last) = member_view(&transcript, "Scientist");
+        assert_eq!(
+            history,
+            vec![
+                ("user".to_string(), "is it allowed?".to_string()),
+                ("assistant".to_string(), "the data says yes".to_string()),
+            ]
+        );
+        assert_eq!(last, "Consultant: the law says no");
+    }
+
+    #[test]
+    fn test_parse_next_speaker() {
+        assert_eq!(
+            parse_next_speaker("```json\n{\"next_speaker\": \"Scientist\"}\n```").as_deref(),
+            Some("Scientist")
+        );
+        assert_eq!(parse_next_speaker("Scientist"), None);
+    }
+}
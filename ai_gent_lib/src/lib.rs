@@ -1,28 +1,81 @@
 use async_trait::async_trait;
 use llm_agent::LlmClient;
-use llm_service::{genai_service, genai_stream_service, LLMStreamOut};
+use llm_audit::{audit_cache_hit, audit_chat, audit_chat_stream};
+use llm_service::{
+    genai_chat, genai_chat_stream, GenerationOptions, LlmChatRequest, LlmChatResponse, LlmChunkStream,
+};
 
+pub mod config_compose;
 pub mod fsm;
 pub mod llm_service;
+pub mod llm_provider;
+pub mod llm_rate_limit;
+pub mod llm_cache;
+pub mod llm_audit;
+pub mod model_registry;
 pub mod llm_agent;
 pub mod fsm_chat_state;
+pub mod agent_delegation;
+pub mod group_chat;
+pub mod evaluation;
+pub mod eval_grading;
+pub mod telemetry;
+pub mod metrics;
 
 
+#[derive(Default, Clone)]
 pub struct GenaiLlmclient {
     pub model: String,
     pub api_key: String,
+    pub options: GenerationOptions,
 }
 
 
 #[async_trait]
 impl LlmClient for GenaiLlmclient {
-    async fn generate(&self, prompt: &str, msgs: &[(String, String)], temperature: Option<f32>) -> Result<String, anyhow::Error> {
-        let t = temperature.unwrap_or(0.5); 
-        genai_service(prompt, msgs, &self.model, &self.api_key, t).await
+    async fn chat(&self, req: &LlmChatRequest) -> Result<LlmChatResponse, anyhow::Error> {
+        let req = with_default_temperature(req);
+        let chat = genai_chat(&req, &self.model, &self.api_key, &self.options);
+        audit_chat(&self.model, &self.options, &req, chat).await
     }
 
-    async fn generate_stream(&self, prompt: &str, msgs: &[(String, String)], temperature: Option<f32>) -> LLMStreamOut {
-        let t = temperature.unwrap_or(0.5); 
-        genai_stream_service(prompt, msgs, &self.model, &self.api_key, t).await
+    async fn chat_stream(&self, req: &LlmChatRequest) -> LlmChunkStream {
+        let req = with_default_temperature(req);
+        let chat_stream = genai_chat_stream(&req, &self.model, &self.api_key, &self.options);
+        audit_chat_stream(&self.model, &self.options, &req, chat_stream).await
+    }
+
+    fn cache_scope(&self) -> String {
+        serde_json::json!({
+            "model": self.model,
+            "provider": self.options.provider,
+            "max_tokens": self.options.max_tokens,
+            "stop_sequences": self.options.stop_sequences,
+            "reasoning_effort": self.options.reasoning_effort,
+        })
+        .to_string()
+    }
+
+    fn record_cache_hit(&self, req: &LlmChatRequest, response: &LlmChatResponse) {
+        let req = with_default_temperature(req);
+        audit_cache_hit(&self.model, &self.options, &req, response);
+    }
+}
+
+impl GenaiLlmclient {
+    // tag the audited requests of the client with the state and the purpose of the requests
+    pub fn with_audit_purpose(mut self, fsm_state: &str, purpose: &str) -> Self {
+        if let Some(audit) = self.options.audit.as_mut() {
+            audit.fsm_state = Some(fsm_state.to_string());
+            audit.purpose = Some(purpose.to_string());
+        }
+        self
+    }
+}
+
+fn with_default_temperature(req: &LlmChatRequest) -> LlmChatRequest {
+    LlmChatRequest {
+        temperature: req.temperature.or(Some(0.5)),
+        ..req.clone()
     }
 }
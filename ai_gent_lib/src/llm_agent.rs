@@ -1,12 +1,23 @@
 use crate::{
+    config_compose::{compose_toml_file, parse_toml_str},
     fsm::{FiniteStateMachine, FsmState, TransitionResult},
-    llm_service::LLMStreamOut,
+    llm_audit::LlmAuditContext,
+    llm_provider::{provider_api_key, provider_for_model, register_providers, ProviderConfig},
+    llm_service::{
+        GenerationOptions, LLMStreamOut, LlmChatRequest, LlmChatResponse, LlmChunkStream,
+        LlmError, LlmStreamChunk, LlmUsage,
+    },
+    metrics, GenaiLlmclient,
 };
 use async_trait::async_trait;
+use futures::StreamExt;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::Path;
+use std::time::Instant;
 use tokio::sync::mpsc::{self, Receiver, Sender};
+use tracing::Instrument;
 
 #[derive(Serialize, Deserialize, Default, Debug)]
 pub struct ExecutionOutput {
@@ -38,6 +49,90 @@ pub struct StateConfig {
     pub wait_for_msg: Option<bool>,
     pub save_to: Option<Vec<String>>,
     pub use_memory: Option<Vec<(String, usize)>>,
+    pub model: Option<String>,
+    pub fsm_model: Option<String>,
+    pub provider: Option<String>,
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<u32>,
+    pub stop_sequences: Option<Vec<String>>,
+    pub reasoning_effort: Option<String>,
+    pub fsm_temperature: Option<f32>,
+    pub cache_llm: Option<bool>,
+    pub cache_fsm: Option<bool>,
+    pub save_reasoning: Option<bool>,
+    pub delegate_agent: Option<String>,
+    pub delegate_agent_id: Option<i32>,
+    pub delegate_task: Option<String>,
+    pub forward_delegate_events: Option<bool>,
+    pub require_approval: Option<bool>,
+}
+
+impl StateConfig {
+    pub fn generation_options(&self, llm_req_settings: &LlmReqSetting) -> GenerationOptions {
+        GenerationOptions {
+            provider: self
+                .provider
+                .clone()
+                .or(llm_req_settings.provider.clone()),
+            max_tokens: self.max_tokens,
+            stop_sequences: self.stop_sequences.clone(),
+            reasoning_effort: self.reasoning_effort.clone(),
+            session_id: llm_req_settings.session_id.clone(),
+            audit: llm_req_settings.audit.clone(),
+        }
+    }
+
+    // the state runs another agent instead of its own LLM request, see `agent_delegation`
+    pub fn delegates(&self) -> bool {
+        self.delegate_agent.is_some() || self.delegate_agent_id.is_some()
+    }
+
+    pub fn temperature(&self, llm_req_settings: &LlmReqSetting) -> Option<f32> {
+        self.temperature.or(llm_req_settings.temperature)
+    }
+
+    // set `fsm_temperature = 0` to make the next state decision deterministic and cacheable
+    pub fn fsm_temperature(&self, llm_req_settings: &LlmReqSetting) -> Option<f32> {
+        self.fsm_temperature.or(self.temperature(llm_req_settings))
+    }
+
+    // the client for the state's own LLM request, the state's model overrides the agent's
+    pub fn llm_client(&self, llm_req_settings: &LlmReqSetting) -> GenaiLlmclient {
+        self.client_for_model(self.model.as_ref(), llm_req_settings)
+    }
+
+    // the client for the next state decision, `fsm_model` falls back to the state's model
+    pub fn fsm_llm_client(&self, llm_req_settings: &LlmReqSetting) -> GenaiLlmclient {
+        self.client_for_model(self.fsm_model.as_ref().or(self.model.as_ref()), llm_req_settings)
+    }
+
+    fn client_for_model(
+        &self,
+        model: Option<&String>,
+        llm_req_settings: &LlmReqSetting,
+    ) -> GenaiLlmclient {
+        let options = self.generation_options(llm_req_settings);
+        if model.is_none() && self.provider.is_none() {
+            return GenaiLlmclient {
+                model: llm_req_settings.model.clone(),
+                api_key: llm_req_settings.api_key.clone(),
+                options,
+            };
+        }
+        let model = model.unwrap_or(&llm_req_settings.model).clone();
+        let provider = options
+            .provider
+            .clone()
+            .unwrap_or_else(|| provider_for_model(&model));
+        // use the key of the state's provider if it is set, otherwise the agent's key
+        let api_key =
+            provider_api_key(&provider).unwrap_or_else(|| llm_req_settings.api_key.clone());
+        GenaiLlmclient {
+            model,
+            api_key,
+            options,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Default, Debug, Clone)]
@@ -61,6 +156,21 @@ pub struct LlmReqSetting {
     pub temperature: Option<f32>,
     pub model: String,
     pub api_key: String,
+    #[serde(default)]
+    pub provider: Option<String>,
+    #[serde(default)]
+    pub session_id: Option<String>,
+    // the `agent` label of the metrics
+    #[serde(default)]
+    pub agent_name: Option<String>,
+    // the chat the requests are made for, see `llm_audit`
+    #[serde(default)]
+    pub audit: Option<LlmAuditContext>,
+    #[serde(default)]
+    pub delegation_depth: u32,
+    // the user whose stored agents can be delegated to, see `agent_delegation`
+    #[serde(default)]
+    pub user_id: Option<i32>,
     pub fsm_initial_state: String,
 }
 
@@ -142,6 +252,10 @@ impl LlmFsmBuilder {
         config: &LlmFsmAgentConfig,
         mut state_map: HashMap<String, S>,
     ) -> Result<Self, anyhow::Error> {
+        if let Some(providers) = config.providers.as_ref() {
+            register_providers(providers)?;
+        }
+
         let mut builder = LlmFsmBuilder {
             states: HashMap::new(),
             transitions: HashMap::new(),
@@ -237,6 +351,9 @@ pub struct LlmFsmAgentConfig {
     pub summary_prompt: String,
     pub fsm_prompt: String,
     pub tools: Option<HashMap<String, Tool>>,
+    pub model: Option<String>,
+    pub provider: Option<String>,
+    pub providers: Option<HashMap<String, ProviderConfig>>,
 }
 
 impl LlmFsmAgentConfig {
@@ -264,6 +381,9 @@ pub struct LlmFsmAgentConfigBuilder {
     summary_prompt: String,
     system_prompt: String,
     tools: Option<HashMap<String, Tool>>,
+    model: Option<String>,
+    provider: Option<String>,
+    providers: Option<HashMap<String, ProviderConfig>>,
 }
 
 impl LlmFsmAgentConfigBuilder {
@@ -318,11 +438,43 @@ impl LlmFsmAgentConfigBuilder {
             system_prompt: config.system_prompt,
             summary_prompt: config.summary_prompt,
             tools: config.tools,
+            model: config.model,
+            provider: config.provider,
+            providers: config.providers,
         })
     }
 
-    pub fn from_toml(toml_str: &str) -> Result<Self, toml::de::Error> {
-        let config: LlmFsmAgentConfig = toml::from_str(toml_str)?;
+    // for the configurations from the users, no `extends`/`include` and no `${ENV}` interpolation,
+    // no `[providers]`: they are declared in the models file of the server, and no
+    // `delegate_agent` files: they can only delegate to their own stored agents
+    pub fn from_toml(toml_str: &str) -> Result<Self, anyhow::Error> {
+        let table = parse_toml_str(toml_str)?;
+        if table.contains_key("providers") {
+            anyhow::bail!("`providers` can only be set in the models file of the server");
+        }
+        let builder = Self::from_toml_table(table)?;
+        if let Some((state, _)) = builder
+            .state_config
+            .iter()
+            .flatten()
+            .find(|(_, config)| config.delegate_agent.is_some())
+        {
+            anyhow::bail!(
+                "state {}: `delegate_agent` can only be set in a config file, use `delegate_agent_id`",
+                state
+            );
+        }
+        Ok(builder)
+    }
+
+    // `extends`/`include` paths are resolved relative to the directory of the config file, see
+    // `config_compose` for the composition rules and the interpolation
+    pub fn from_toml_file<P: AsRef<Path>>(path: P) -> Result<Self, anyhow::Error> {
+        Self::from_toml_table(compose_toml_file(path.as_ref())?)
+    }
+
+    fn from_toml_table(table: toml::Table) -> Result<Self, anyhow::Error> {
+        let config: LlmFsmAgentConfig = toml::Value::Table(table).try_into()?;
         // if the fsm of system prompt is not set for a state, replace it with the global one
         let state_prompts = config
             .state_prompts
@@ -352,6 +504,9 @@ impl LlmFsmAgentConfigBuilder {
             system_prompt: config.system_prompt,
             summary_prompt: config.summary_prompt,
             tools: config.tools,
+            model: config.model,
+            provider: config.provider,
+            providers: config.providers,
         })
     }
 
@@ -370,6 +525,9 @@ impl LlmFsmAgentConfigBuilder {
             system_prompt: self.system_prompt,
             summary_prompt: self.summary_prompt,
             tools: self.tools,
+            model: self.model,
+            provider: self.provider,
+            providers: self.providers,
         })
     }
 }
@@ -391,22 +549,114 @@ pub struct LlmFsmAgent {
     pub fsm_prompt: String,
     pub summary_prompt: String,
     pub total_state_transition_limit: u32,
+    // the states to pause before, "*" for all of them
+    pub breakpoints: HashSet<String>,
 }
 
+// `chat` and `chat_stream` are the primitives, `generate` and `generate_stream` are the
+// shorthands for a system prompt, `(role, message)` pairs and a temperature
 #[async_trait]
-pub trait LlmClient {
+pub trait LlmClient: Send + Sync {
+    async fn chat(&self, req: &LlmChatRequest) -> Result<LlmChatResponse, anyhow::Error>;
+    async fn chat_stream(&self, req: &LlmChatRequest) -> LlmChunkStream;
+
     async fn generate(
         &self,
         prompt: &str,
         msg: &[(String, String)],
         temperature: Option<f32>,
-    ) -> Result<String, anyhow::Error>;
+    ) -> Result<String, anyhow::Error> {
+        let req = LlmChatRequest::from_prompt(prompt, msg, temperature);
+        self.chat(&req).await.map(|response| response.text)
+    }
+
     async fn generate_stream(
         &self,
         prompt: &str,
         msg: &[(String, String)],
         temperature: Option<f32>,
-    ) -> LLMStreamOut;
+    ) -> LLMStreamOut {
+        let req = LlmChatRequest::from_prompt(prompt, msg, temperature);
+        let llm_stream = self.chat_stream(&req).await;
+        // only the answer, use `chat_stream` for the reasoning of the model
+        Box::pin(llm_stream.filter_map(|chunk| async move {
+            match chunk {
+                Ok(LlmStreamChunk::Text(text)) => Some(Ok(text)),
+                Ok(_) => None,
+                Err(err) => Some(Err(err)),
+            }
+        }))
+    }
+
+    // what, besides the request, determines the response (model, options), used by caches
+    fn cache_scope(&self) -> String {
+        String::new()
+    }
+
+    // called by `CachedLlmClient` when a request is answered from the cache, for `llm_audit`
+    fn record_cache_hit(&self, _req: &LlmChatRequest, _response: &LlmChatResponse) {}
+}
+
+// the "usage" event of a LLM request, the token counts as JSON, e.g. for the evaluation runs
+pub async fn send_usage(tx: &Sender<(String, String, String)>, state_name: &str, usage: &LlmUsage) {
+    let usage = serde_json::to_string(usage).unwrap_or_default();
+    let _ = tx.send((state_name.into(), "usage".into(), usage)).await;
+}
+
+// Forward a LLM stream of a state as agent events: "token" for the answer, "reasoning" for the
+// reasoning of the model, then "usage", "reasoning_output" and "llm_output" with the complete texts.
+// The reasoning is only kept in the "reasoning" memory slot with `save_reasoning`. On an error,
+// the (truncated) output is not sent, the error is returned for the caller to report.
+pub async fn forward_llm_stream(
+    mut llm_stream: LlmChunkStream,
+    state_name: &str,
+    tx: &Sender<(String, String, String)>,
+    ignore_llm_output: bool,
+    save_reasoning: bool,
+) -> Result<String, LlmError> {
+    let mut llm_output = String::default();
+    let mut reasoning = String::default();
+    while let Some(result) = llm_stream.next().await {
+        match result {
+            Ok(LlmStreamChunk::Text(output)) => {
+                llm_output.push_str(&output);
+                if !ignore_llm_output {
+                    let _ = tx.send((state_name.into(), "token".into(), output)).await;
+                };
+            }
+            Ok(LlmStreamChunk::Reasoning(output)) => {
+                reasoning.push_str(&output);
+                if !ignore_llm_output {
+                    let _ = tx.send((state_name.into(), "reasoning".into(), output)).await;
+                };
+            }
+            Ok(LlmStreamChunk::End {
+                usage: Some(usage), ..
+            }) => {
+                send_usage(tx, state_name, &usage).await;
+            }
+            Ok(_) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    if !reasoning.is_empty() {
+        if save_reasoning {
+            let _ = tx
+                .send((state_name.into(), "save_to:reasoning".into(), reasoning.clone()))
+                .await;
+        }
+        if !ignore_llm_output {
+            let _ = tx
+                .send((state_name.into(), "reasoning_output".into(), reasoning))
+                .await;
+        }
+    }
+    if !ignore_llm_output {
+        let _ = tx
+            .send((state_name.into(), "llm_output".into(), llm_output.clone()))
+            .await;
+    };
+    Ok(llm_output)
 }
 
 pub struct AgentSettings {
@@ -416,10 +666,31 @@ pub struct AgentSettings {
     pub fsm_initial_state: String,
     pub model: String,
     pub api_key: String,
+    pub provider: Option<String>,
     pub tools: Option<HashMap<String, Tool>>,
     pub total_state_transition_limit: Option<u32>,
 }
 
+// the state of an agent's conversation, for inspecting it or saving and restoring a session,
+// without the API key
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct AgentSnapshot {
+    pub fsm_state: Option<String>,
+    #[serde(default)]
+    pub transitions: Vec<String>,
+    #[serde(default)]
+    pub state_history: Vec<String>,
+    #[serde(default)]
+    pub messages: Vec<(String, String)>,
+    #[serde(default)]
+    pub memory: HashMap<String, Vec<Value>>,
+    pub task: Option<String>,
+    pub model: String,
+    pub provider: Option<String>,
+    #[serde(default)]
+    pub breakpoints: Vec<String>,
+}
+
 impl LlmFsmAgent {
     pub fn new(fsm: FiniteStateMachine, agent_settings: AgentSettings) -> Self {
         let total_state_transition_limit = agent_settings
@@ -434,6 +705,12 @@ impl LlmFsmAgent {
             memory: HashMap::default(),
             model: agent_settings.model,
             api_key: agent_settings.api_key,
+            provider: agent_settings.provider,
+            session_id: None,
+            agent_name: None,
+            audit: None,
+            delegation_depth: 0,
+            user_id: None,
             fsm_initial_state: agent_settings.fsm_initial_state,
         };
         // Initialize prompts for each state here
@@ -443,9 +720,45 @@ impl LlmFsmAgent {
             summary_prompt: agent_settings.summary_prompt,
             llm_req_settings: llm_req_setting,
             total_state_transition_limit,
+            breakpoints: HashSet::default(),
         }
     }
 
+    // an agent running on the model and provider of `config`, or on the given ones if the
+    // config does not set them
+    pub fn from_config<S: LlmFsmStateInit + FsmState + 'static>(
+        config: &LlmFsmAgentConfig,
+        model: &str,
+        provider: Option<String>,
+        api_key: &str,
+    ) -> Result<Self, anyhow::Error> {
+        let fsm = LlmFsmBuilder::from_config::<S>(config, HashMap::default())?.build()?;
+        let agent_model = config.model.clone().unwrap_or(model.to_string());
+        let agent_provider = config.provider.clone().or(provider.clone());
+        let api_key = if agent_model == model && agent_provider == provider {
+            api_key.to_string()
+        } else {
+            let provider_name = agent_provider
+                .clone()
+                .unwrap_or_else(|| provider_for_model(&agent_model));
+            provider_api_key(&provider_name).unwrap_or(api_key.to_string())
+        };
+        Ok(Self::new(
+            fsm,
+            AgentSettings {
+                sys_prompt: config.system_prompt.clone(),
+                fsm_prompt: config.fsm_prompt.clone(),
+                summary_prompt: config.summary_prompt.clone(),
+                fsm_initial_state: config.initial_state.clone(),
+                model: agent_model,
+                api_key,
+                provider: agent_provider,
+                tools: config.tools.clone(),
+                total_state_transition_limit: None,
+            },
+        ))
+    }
+
     pub fn append_context(&mut self, key: &str, value: &str) {
         let e = self.llm_req_settings.memory.entry(key.into()).or_default();
         e.push(value.into());
@@ -467,6 +780,124 @@ impl LlmFsmAgent {
         self.fsm.get_current_state_name()
     }
 
+    pub fn snapshot(&self) -> AgentSnapshot {
+        let mut transitions = self
+            .fsm
+            .available_transitions()
+            .map(|t| t.into_iter().collect::<Vec<_>>())
+            .unwrap_or_default();
+        transitions.sort();
+        let mut breakpoints = self.breakpoints.iter().cloned().collect::<Vec<_>>();
+        breakpoints.sort();
+        let settings = &self.llm_req_settings;
+        AgentSnapshot {
+            fsm_state: self.fsm.get_current_state_name(),
+            transitions,
+            state_history: settings.state_history.clone(),
+            messages: settings.messages.clone(),
+            memory: settings.memory.clone(),
+            task: settings.task.clone(),
+            model: settings.model.clone(),
+            provider: settings.provider.clone(),
+            breakpoints,
+        }
+    }
+
+    // the state is set without running its enter actions, the model is kept if the snapshot
+    // does not have one
+    pub async fn restore(&mut self, snapshot: AgentSnapshot) -> Result<(), anyhow::Error> {
+        if let Some(state) = snapshot.fsm_state {
+            self.fsm
+                .set_initial_state(state, false)
+                .await
+                .map_err(|e| anyhow::anyhow!(e))?;
+        }
+        if !snapshot.model.is_empty() {
+            self.set_model(&snapshot.model, snapshot.provider);
+        }
+        let settings = &mut self.llm_req_settings;
+        settings.state_history = snapshot.state_history;
+        settings.messages = snapshot.messages;
+        settings.memory = snapshot.memory;
+        settings.task = snapshot.task;
+        Ok(())
+    }
+
+    // the API key of the new provider is looked up, the current one is kept if there is none
+    pub fn set_model(&mut self, model: &str, provider: Option<String>) {
+        let settings = &mut self.llm_req_settings;
+        if settings.model == model && settings.provider == provider {
+            return;
+        }
+        let provider_name = provider
+            .clone()
+            .unwrap_or_else(|| provider_for_model(model));
+        if let Some(api_key) = provider_api_key(&provider_name) {
+            settings.api_key = api_key;
+        }
+        settings.model = model.to_string();
+        settings.provider = provider;
+    }
+
+    // the inputs for inspecting and changing the agent between messages
+    async fn control(&mut self, msg_type: &str, msg: &str) -> Result<(), anyhow::Error> {
+        match msg_type {
+            "snapshot" => {}
+            // a forced transition, it does not have to be one of the current state's
+            "goto" => {
+                self.fsm
+                    .set_initial_state(msg.to_string(), true)
+                    .await
+                    .map_err(|e| anyhow::anyhow!("goto {}: {}", msg, e))?;
+            }
+            // {"slot": ..., "value": ...}, the value replaces the slot's content
+            "set_memory" => {
+                let input: Value = serde_json::from_str(msg)?;
+                let slot = input
+                    .get("slot")
+                    .and_then(|slot| slot.as_str())
+                    .ok_or(anyhow::anyhow!("set_memory needs a slot"))?;
+                let value = input.get("value").cloned().unwrap_or(Value::Null);
+                self.llm_req_settings
+                    .memory
+                    .insert(slot.to_string(), vec![value]);
+            }
+            // {"model": ..., "provider": ...}
+            "set_model" => {
+                let input: Value = serde_json::from_str(msg)?;
+                let model = input
+                    .get("model")
+                    .and_then(|model| model.as_str())
+                    .ok_or(anyhow::anyhow!("set_model needs a model"))?;
+                let provider = input
+                    .get("provider")
+                    .and_then(|provider| provider.as_str())
+                    .map(|provider| provider.to_string());
+                self.set_model(model, provider);
+            }
+            "restore" => self.restore(serde_json::from_str(msg)?).await?,
+            "breakpoint" if msg != "*" && !self.fsm.states.contains_key(msg) => {
+                return Err(anyhow::anyhow!("the state {} does not exist", msg))
+            }
+            "breakpoint" | "clear_breakpoint" => set_breakpoint(&mut self.breakpoints, msg_type, msg),
+            _ => return Err(anyhow::anyhow!("unknown input {}", msg_type)),
+        }
+        Ok(())
+    }
+
+    // The inputs are ("message" | "task" | "context" | "clear_message" | "clear_context" |
+    // "terminate", content). While a message is processed, "cancel" stops it in the current
+    // state, and "approve" or "reject" answer the `approval_request` event of a state with
+    // `require_approval`. The other inputs wait until the message is processed.
+    //
+    // "snapshot", "goto", "set_memory", "set_model", "restore", "breakpoint" and
+    // "clear_breakpoint" inspect or change the agent, each is answered with a "snapshot" event
+    // (a JSON `AgentSnapshot`) or an "error" event.
+    //
+    // Before a state with a breakpoint, a "paused" event is sent with what the state will use
+    // (see `debug_view`) and the agent waits for "continue" or "step" (pause before the next
+    // state too). "edit_prompt", "skip_llm" (with the output to use instead) and
+    // "set_next_state" change the paused state's run before it continues.
     pub async fn fsm_message_service(
         &mut self,
         mut user_input: Receiver<(String, String)>,
@@ -475,8 +906,18 @@ impl LlmFsmAgent {
     ) -> Result<(), anyhow::Error> {
         self.llm_req_settings.temperature = temperature;
         let total_state_transition_limit = self.total_state_transition_limit;
-
-        while let Some((msg_type, msg)) = user_input.recv().await {
+        let mut pending_input = VecDeque::<(String, String)>::new();
+        let mut terminated = false;
+        let mut stepping = false;
+
+        while !terminated {
+            let input = match pending_input.pop_front() {
+                Some(input) => Some(input),
+                None => user_input.recv().await,
+            };
+            let Some((msg_type, msg)) = input else {
+                break;
+            };
             match msg_type.as_str() {
                 // once a message is sent, we will start to process the message
                 "message" => {
@@ -489,6 +930,13 @@ impl LlmFsmAgent {
                     self.llm_req_settings.task = Some(msg);
                     continue;
                 }
+                // e.g. the retrieval results for the next message
+                "context" => {
+                    let e = self.llm_req_settings.memory.entry("context".into()).or_default();
+                    e.clear();
+                    e.push(Value::String(msg));
+                    continue;
+                }
                 "clear_message" => {
                     self.llm_req_settings.messages.clear();
                     continue;
@@ -497,10 +945,23 @@ impl LlmFsmAgent {
                     self.llm_req_settings.memory.clear();
                     continue;
                 }
+                "snapshot" | "goto" | "set_memory" | "set_model" | "restore" | "breakpoint"
+                | "clear_breakpoint" => {
+                    let event = match self.control(&msg_type, &msg).await {
+                        Ok(()) => (
+                            "snapshot".to_string(),
+                            serde_json::to_string(&self.snapshot())?,
+                        ),
+                        Err(e) => ("error".to_string(), e.to_string()),
+                    };
+                    let _ = tx.send(("".into(), event.0, event.1)).await;
+                    continue;
+                }
+                // nothing to cancel or approve between messages
+                "cancel" | "approve" | "reject" => continue,
                 "terminate" => break,
                 _ => {}
             }
-
             // let current_state_name = self
             //     .fsm
             //     .get_current_state_name()
@@ -515,6 +976,18 @@ impl LlmFsmAgent {
             };
             let tx2 = tx.clone();
             let mut state_transition_count = 0;
+            // the states of a message are traced as the children of a turn
+            let turn_span = tracing::info_span!(
+                "agent_turn",
+                session_id = self.llm_req_settings.session_id.as_deref().unwrap_or(""),
+                model = %self.llm_req_settings.model,
+                delegation_depth = self.llm_req_settings.delegation_depth,
+                fsm_state = tracing::field::Empty,
+                states = tracing::field::Empty,
+                latency_ms = tracing::field::Empty,
+            );
+            let turn_start = Instant::now();
+            let mut state_count = 0_usize;
             loop {
                 let current_state_name = self
                     .fsm
@@ -532,6 +1005,31 @@ impl LlmFsmAgent {
 
                 let current_state = self.fsm.states.get_mut(&current_state_name).unwrap();
 
+                if current_state.get_attribute("require_approval").await.as_deref() == Some("true") {
+                    let _ = tx2
+                        .send((current_state_name.clone(), "approval_request".into(), "".into()))
+                        .await;
+                    match wait_for_approval(&mut user_input, &mut pending_input).await {
+                        Approval::Approved => {}
+                        Approval::Rejected => {
+                            let _ = tx2
+                                .send((current_state_name, "rejected".into(), "".into()))
+                                .await;
+                            break;
+                        }
+                        Approval::Cancelled => {
+                            let _ = tx2
+                                .send((current_state_name, "cancelled".into(), "".into()))
+                                .await;
+                            break;
+                        }
+                        Approval::Terminated => {
+                            terminated = true;
+                            break;
+                        }
+                    }
+                }
+
                 current_state
                     .set_service_context(
                         serde_json::to_value::<LlmReqSetting>(self.llm_req_settings.clone())
@@ -539,15 +1037,94 @@ impl LlmFsmAgent {
                     )
                     .await;
 
+                let mut next_state_override = None;
+                if stepping
+                    || self.breakpoints.contains(&current_state_name)
+                    || self.breakpoints.contains("*")
+                {
+                    let mut view = serde_json::json!({
+                        "state": current_state_name,
+                        "next_states": next_states,
+                        "task": self.llm_req_settings.task,
+                        "messages": self.llm_req_settings.messages,
+                        "memory": self.llm_req_settings.memory,
+                    });
+                    if let Some(Value::Object(state_view)) =
+                        current_state.debug_view(next_states.clone()).await
+                    {
+                        view.as_object_mut().unwrap().extend(state_view);
+                    }
+                    let _ = tx2
+                        .send((current_state_name.clone(), "paused".into(), view.to_string()))
+                        .await;
+                    match wait_for_debug(
+                        &mut user_input,
+                        &mut pending_input,
+                        current_state,
+                        &mut self.breakpoints,
+                    )
+                    .await
+                    {
+                        Debug::Continue { step, next_state } => {
+                            stepping = step;
+                            next_state_override = next_state;
+                        }
+                        Debug::Cancelled => {
+                            stepping = false;
+                            let _ = tx2
+                                .send((current_state_name, "cancelled".into(), "".into()))
+                                .await;
+                            break;
+                        }
+                        Debug::Terminated => {
+                            terminated = true;
+                            break;
+                        }
+                    }
+                }
+
                 let (fsm_tx, fsm_rx) = mpsc::channel::<(String, String, String)>(16);
                 let tx = tx.clone();
                 let handle = get_fsm_state_communication_handle(tx, fsm_rx);
                 self.llm_req_settings
                     .state_history
                     .push(current_state_name.clone());
-                if let Some(next_state_name) =
-                    current_state.start_service(fsm_tx, None, next_states).await
-                {
+                let state_span = tracing::info_span!(
+                    parent: &turn_span,
+                    "agent_state",
+                    state = %current_state_name,
+                    latency_ms = tracing::field::Empty,
+                );
+                let state_start = Instant::now();
+                state_count += 1;
+                let next_state_name = tokio::select! {
+                    result = current_state
+                        .start_service(fsm_tx, None, next_states)
+                        .instrument(state_span.clone()) => {
+                        state_span.record("latency_ms", state_start.elapsed().as_millis() as u64);
+                        match result {
+                            Ok(next_state_name) => next_state_name,
+                            Err(e) => {
+                                // the turn ends in the current state, without its output
+                                let _ = tokio::join!(handle);
+                                let _ = tx2
+                                    .send((current_state_name, "error".into(), e.to_string()))
+                                    .await;
+                                break;
+                            }
+                        }
+                    }
+                    _ = wait_for_cancel(&mut user_input, &mut pending_input) => {
+                        // the state stays the current one, its output is dropped
+                        handle.abort();
+                        let _ = tx2
+                            .send((current_state_name, "cancelled".into(), "".into()))
+                            .await;
+                        break;
+                    }
+                };
+                let next_state_name = next_state_override.or(next_state_name);
+                if let Some(next_state_name) = next_state_name {
                     let (llm_output, new_memory) = tokio::join!(handle).0.unwrap();
                     self.update_message_and_memory(llm_output, new_memory);
                     match self.transition_state(&next_state_name).await {
@@ -599,6 +1176,11 @@ impl LlmFsmAgent {
                     break;
                 }
             }
+            turn_span.record("states", state_count);
+            turn_span.record("latency_ms", turn_start.elapsed().as_millis() as u64);
+            if let Some(state) = self.fsm.get_current_state_name() {
+                turn_span.record("fsm_state", state.as_str());
+            }
             let _ = tx
                 .send(("".into(), "message_processed".into(), "".into()))
                 .await;
@@ -624,6 +1206,10 @@ impl LlmFsmAgent {
         match self.fsm.make_transition_to(next_state.into()).await {
             (TransitionResult::Success, _) => {
                 // tracing::info!("Transitioned to state: {}", next_state);
+                metrics::record_state_transition(
+                    self.llm_req_settings.agent_name.as_deref(),
+                    next_state,
+                );
                 Ok(())
             }
             (TransitionResult::InvalidTransition, _) => Err(anyhow::anyhow!(
@@ -644,6 +1230,99 @@ type AgentResult = (Option<String>, HashMap<String, Vec<Value>>);
 type AgentTask = tokio::task::JoinHandle<AgentResult>;
 
 
+// aborts a task when the future waiting for it is dropped, e.g. the LLM request of a cancelled
+// state
+pub struct AbortOnDrop(pub tokio::task::AbortHandle);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+enum Approval {
+    Approved,
+    Rejected,
+    Cancelled,
+    Terminated,
+}
+
+// the answer to an `approval_request`, the other inputs are kept for later
+async fn wait_for_approval(
+    user_input: &mut Receiver<(String, String)>,
+    pending_input: &mut VecDeque<(String, String)>,
+) -> Approval {
+    while let Some((msg_type, msg)) = user_input.recv().await {
+        match msg_type.as_str() {
+            "approve" => return Approval::Approved,
+            "reject" => return Approval::Rejected,
+            "cancel" => return Approval::Cancelled,
+            "terminate" => return Approval::Terminated,
+            _ => pending_input.push_back((msg_type, msg)),
+        }
+    }
+    Approval::Terminated
+}
+
+fn set_breakpoint(breakpoints: &mut HashSet<String>, msg_type: &str, state: &str) {
+    match (msg_type, state) {
+        ("breakpoint", state) => {
+            breakpoints.insert(state.to_string());
+        }
+        ("clear_breakpoint", "") => breakpoints.clear(),
+        (_, state) => {
+            breakpoints.remove(state);
+        }
+    }
+}
+
+enum Debug {
+    Continue { step: bool, next_state: Option<String> },
+    Cancelled,
+    Terminated,
+}
+
+// the debugger's inputs for a paused state, the other inputs are kept for later
+async fn wait_for_debug(
+    user_input: &mut Receiver<(String, String)>,
+    pending_input: &mut VecDeque<(String, String)>,
+    state: &mut Box<dyn FsmState>,
+    breakpoints: &mut HashSet<String>,
+) -> Debug {
+    let mut next_state = None;
+    while let Some((msg_type, msg)) = user_input.recv().await {
+        match msg_type.as_str() {
+            "continue" => return Debug::Continue { step: false, next_state },
+            "step" => return Debug::Continue { step: true, next_state },
+            "edit_prompt" => state.set_attribute("prompt_override", msg).await,
+            "skip_llm" => state.set_attribute("skip_llm", msg).await,
+            "set_next_state" => next_state = Some(msg).filter(|s| !s.is_empty()),
+            "breakpoint" | "clear_breakpoint" => set_breakpoint(breakpoints, &msg_type, &msg),
+            "cancel" => return Debug::Cancelled,
+            "terminate" => return Debug::Terminated,
+            _ => pending_input.push_back((msg_type, msg)),
+        }
+    }
+    Debug::Terminated
+}
+
+// returns when a "cancel" input comes while a state is running, the other inputs are kept for
+// later
+async fn wait_for_cancel(
+    user_input: &mut Receiver<(String, String)>,
+    pending_input: &mut VecDeque<(String, String)>,
+) {
+    while let Some((msg_type, msg)) = user_input.recv().await {
+        match msg_type.as_str() {
+            "cancel" => return,
+            "approve" | "reject" => {}
+            _ => pending_input.push_back((msg_type, msg)),
+        }
+    }
+    // no more inputs, the state finishes
+    std::future::pending::<()>().await
+}
+
 fn get_fsm_state_communication_handle(
     tx: Sender<(String, String, String)>,
     mut fsm_rx: Receiver<(String, String, String)>,
@@ -790,6 +1469,7 @@ mod tests {
             tools: fsm_config.tools,
             model: "".into(),
             api_key: "".into(),
+            provider: None,
             fsm_initial_state: "Initial".into(),
             total_state_transition_limit: None,
         };
@@ -797,6 +1477,168 @@ mod tests {
         let _agent = LlmFsmAgent::new(fsm, agent_settings);
     }
 
+    #[tokio::test]
+    async fn test_composed_config() {
+        let config_path =
+            std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("dev_config/composed_config.toml");
+        let fsm_config = LlmFsmAgentConfigBuilder::from_toml_file(config_path)
+            .unwrap()
+            .build()
+            .unwrap();
+
+        assert_eq!(fsm_config.states.len(), 4);
+        assert!(fsm_config
+            .transitions
+            .contains(&("Retrieval".to_string(), "Generate".to_string())));
+        assert!(fsm_config.tools.unwrap().contains_key("websearch"));
+
+        // the global (empty) fsm prompt is not used when a state sets its own
+        let generate = fsm_config.state_prompts.get("Generate").unwrap();
+        assert_eq!(generate.system.as_deref(), Some("reviewed answer"));
+
+        let fsm = LlmFsmBuilder::from_config::<DefaultLlmChatState>(&fsm_config, HashMap::default())
+            .unwrap()
+            .build();
+        assert!(fsm.is_ok());
+
+        let with_providers = r#"
+            states = ["Answer"]
+            transitions = []
+            initial_state = "Answer"
+            system_prompt = ""
+            fsm_prompt = ""
+            summary_prompt = ""
+            [state_prompts]
+
+            [providers.local]
+            base_url = "http://localhost:11434/v1/"
+            api_key_env = "OPENAI_API_KEY"
+        "#;
+        assert!(LlmFsmAgentConfigBuilder::from_toml(with_providers).is_err());
+
+        let with_delegate_file = r#"
+            states = ["Answer"]
+            transitions = []
+            initial_state = "Answer"
+            system_prompt = ""
+            fsm_prompt = ""
+            summary_prompt = ""
+            [state_prompts]
+
+            [state_config.Answer]
+            delegate_agent = "/etc/agents/expert.toml"
+        "#;
+        assert!(LlmFsmAgentConfigBuilder::from_toml(with_delegate_file).is_err());
+        let with_delegate_id = with_delegate_file.replace(
+            r#"delegate_agent = "/etc/agents/expert.toml""#,
+            "delegate_agent_id = 1",
+        );
+        assert!(LlmFsmAgentConfigBuilder::from_toml(&with_delegate_id).is_ok());
+    }
+
+    // echoes the last user message, for testing the default `LlmClient` methods
+    struct EchoClient;
+
+    #[async_trait]
+    impl LlmClient for EchoClient {
+        async fn chat(&self, req: &LlmChatRequest) -> Result<LlmChatResponse, anyhow::Error> {
+            let text = req.messages.last().map(|m| m.content.clone()).unwrap_or_default();
+            Ok(LlmChatResponse {
+                text,
+                finish_reason: Some("stop".into()),
+                ..Default::default()
+            })
+        }
+
+        async fn chat_stream(&self, req: &LlmChatRequest) -> LlmChunkStream {
+            let text = req.messages.last().map(|m| m.content.clone()).unwrap_or_default();
+            let chunks = vec![
+                Ok(LlmStreamChunk::Start),
+                Ok(LlmStreamChunk::Reasoning("thinking ".into())),
+                Ok(LlmStreamChunk::Text(text)),
+                Ok(LlmStreamChunk::End {
+                    usage: None,
+                    finish_reason: Some("stop".into()),
+                    latency_ms: Some(0),
+                }),
+            ];
+            Box::pin(futures::stream::iter(chunks))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_llm_client_shorthands() {
+        let msgs = vec![
+            ("user".to_string(), "hello".to_string()),
+            ("tool".to_string(), "ignored".to_string()),
+        ];
+        let req = LlmChatRequest::from_prompt("system prompt", &msgs, Some(0.2));
+        assert_eq!(req.system.as_deref(), Some("system prompt"));
+        assert_eq!(req.messages.len(), 1);
+
+        let client = EchoClient;
+        assert_eq!(client.generate("prompt", &msgs, None).await.unwrap(), "hello");
+        let output = client
+            .generate_stream("prompt", &msgs, None)
+            .await
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+            .join("");
+        assert_eq!(output, "hello");
+
+        // the reasoning is sent as its own events and only saved to memory when asked for
+        let (tx, mut rx) = mpsc::channel::<(String, String, String)>(16);
+        let req = LlmChatRequest::from_prompt("prompt", &msgs, None);
+        let llm_stream = client.chat_stream(&req).await;
+        let output = forward_llm_stream(llm_stream, "Generate", &tx, false, false)
+            .await
+            .unwrap();
+        drop(tx);
+        assert_eq!(output, "hello");
+        let mut events = vec![];
+        while let Some((_, t, r)) = rx.recv().await {
+            events.push((t, r));
+        }
+        assert!(events.contains(&("reasoning_output".into(), "thinking ".into())));
+        assert!(events.contains(&("llm_output".into(), "hello".into())));
+        assert!(!events.iter().any(|(t, _)| t == "save_to:reasoning"));
+    }
+
+    #[test]
+    fn test_state_llm_client() {
+        let llm_req_settings = LlmReqSetting {
+            model: "gpt-4o".into(),
+            api_key: "agent-key".into(),
+            temperature: Some(0.5),
+            ..Default::default()
+        };
+
+        let config = StateConfig::default();
+        let client = config.llm_client(&llm_req_settings);
+        assert_eq!(client.model, "gpt-4o");
+        assert_eq!(client.api_key, "agent-key");
+        assert_eq!(config.temperature(&llm_req_settings), Some(0.5));
+
+        let config = StateConfig {
+            model: Some("gpt-4o-mini".into()),
+            provider: Some("unknown-provider".into()),
+            fsm_model: Some("o3-mini".into()),
+            temperature: Some(0.1),
+            max_tokens: Some(200),
+            ..Default::default()
+        };
+        let client = config.llm_client(&llm_req_settings);
+        assert_eq!(client.model, "gpt-4o-mini");
+        // no key variable for the provider, the agent's key is used
+        assert_eq!(client.api_key, "agent-key");
+        assert_eq!(client.options.max_tokens, Some(200));
+        assert_eq!(config.fsm_llm_client(&llm_req_settings).model, "o3-mini");
+        assert_eq!(config.temperature(&llm_req_settings), Some(0.1));
+    }
+
     #[tokio::test]
     async fn test_fsm_transitions() {
         let mut fsm_builder = LlmFsmBuilder::new();
@@ -904,4 +1746,226 @@ mod tests {
         assert_eq!(result, TransitionResult::Success);
         assert_eq!(new_state, Some("State3".to_string()));
     }
+
+    // answers at once, or after a long time if `slow`
+    struct ControlTestState {
+        name: String,
+        attributes: HashMap<String, String>,
+        slow: bool,
+    }
+
+    #[async_trait]
+    impl FsmState for ControlTestState {
+        async fn start_service(
+            &mut self,
+            tx: Sender<(String, String, String)>,
+            _rx: Option<Receiver<(String, String, String)>>,
+            _next_states: Option<Vec<String>>,
+        ) -> Result<Option<String>, anyhow::Error> {
+            let _ = tx.send((self.name.clone(), "state".into(), self.name.clone())).await;
+            if self.slow {
+                tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+            }
+            let _ = tx.send((self.name.clone(), "llm_output".into(), "done".into())).await;
+            Ok(None)
+        }
+
+        async fn set_service_context(&mut self, _context: Value) {}
+
+        async fn get_attribute(&self, k: &str) -> Option<String> {
+            self.attributes.get(k).cloned()
+        }
+
+        fn name(&self) -> String {
+            self.name.clone()
+        }
+    }
+
+    fn control_test_agent(require_approval: bool, slow: bool) -> LlmFsmAgent {
+        let mut attributes = HashMap::default();
+        if require_approval {
+            attributes.insert("require_approval".into(), "true".into());
+        }
+        let state = ControlTestState {
+            name: "Work".into(),
+            attributes,
+            slow,
+        };
+        let fsm = LlmFsmBuilder::new()
+            .add_state("Work".into(), Box::new(state))
+            .set_initial_state("Work".into())
+            .build()
+            .unwrap();
+        LlmFsmAgent::new(
+            fsm,
+            AgentSettings {
+                sys_prompt: "".into(),
+                fsm_prompt: "".into(),
+                summary_prompt: "".into(),
+                tools: None,
+                model: "".into(),
+                api_key: "".into(),
+                provider: None,
+                fsm_initial_state: "Work".into(),
+                total_state_transition_limit: None,
+            },
+        )
+    }
+
+    // the event types up to `message_processed`
+    async fn next_events(rx: &mut Receiver<(String, String, String)>) -> Vec<String> {
+        let mut events = vec![];
+        while let Some((_, t, _)) = rx.recv().await {
+            if t == "message_processed" {
+                break;
+            }
+            events.push(t);
+        }
+        events
+    }
+
+    #[tokio::test]
+    async fn test_message_service_approval() {
+        let mut agent = control_test_agent(true, false);
+        let (send_msg, rcv_msg) = mpsc::channel::<(String, String)>(8);
+        let (tx, mut rx) = mpsc::channel::<(String, String, String)>(16);
+        let service = tokio::spawn(async move { agent.fsm_message_service(rcv_msg, tx, None).await });
+
+        send_msg.send(("message".into(), "first".into())).await.unwrap();
+        assert_eq!(rx.recv().await.unwrap().1, "approval_request");
+        send_msg.send(("reject".into(), "".into())).await.unwrap();
+        assert_eq!(next_events(&mut rx).await, vec!["rejected"]);
+
+        send_msg.send(("message".into(), "second".into())).await.unwrap();
+        assert_eq!(rx.recv().await.unwrap().1, "approval_request");
+        send_msg.send(("approve".into(), "".into())).await.unwrap();
+        assert_eq!(next_events(&mut rx).await, vec!["state", "llm_output"]);
+
+        send_msg.send(("terminate".into(), "".into())).await.unwrap();
+        service.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_message_service_cancel() {
+        let mut agent = control_test_agent(false, true);
+        let (send_msg, rcv_msg) = mpsc::channel::<(String, String)>(8);
+        let (tx, mut rx) = mpsc::channel::<(String, String, String)>(16);
+        let service = tokio::spawn(async move { agent.fsm_message_service(rcv_msg, tx, None).await });
+
+        send_msg.send(("message".into(), "a long one".into())).await.unwrap();
+        assert_eq!(rx.recv().await.unwrap().1, "state");
+        // a task sent while the message is processed waits, the cancel does not
+        send_msg.send(("task".into(), "later".into())).await.unwrap();
+        send_msg.send(("cancel".into(), "".into())).await.unwrap();
+        assert_eq!(next_events(&mut rx).await, vec!["cancelled"]);
+
+        drop(send_msg);
+        service.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_message_service_control() {
+        let mut agent = control_test_agent(false, false);
+        let review = ControlTestState {
+            name: "Review".into(),
+            attributes: HashMap::default(),
+            slow: false,
+        };
+        agent.fsm.add_state("Review".into(), Box::new(review));
+        agent.fsm.add_transition("Work".into(), "Review".into());
+        let (send_msg, rcv_msg) = mpsc::channel::<(String, String)>(8);
+        let (tx, mut rx) = mpsc::channel::<(String, String, String)>(16);
+        let service = tokio::spawn(async move { agent.fsm_message_service(rcv_msg, tx, None).await });
+
+        let control = |msg_type: &str, msg: &str| {
+            let input = (msg_type.to_string(), msg.to_string());
+            let send_msg = send_msg.clone();
+            async move {
+                send_msg.send(input).await.unwrap();
+            }
+        };
+        control("message", "hello").await;
+        assert_eq!(next_events(&mut rx).await, vec!["state", "llm_output"]);
+
+        control("snapshot", "").await;
+        let (_, t, content) = rx.recv().await.unwrap();
+        assert_eq!(t, "snapshot");
+        let snapshot = serde_json::from_str::<AgentSnapshot>(&content).unwrap();
+        assert_eq!(snapshot.fsm_state.as_deref(), Some("Work"));
+        assert_eq!(snapshot.transitions, vec!["Review"]);
+        assert_eq!(snapshot.state_history, vec!["Work"]);
+        assert_eq!(snapshot.messages.len(), 2);
+
+        control("goto", "Review").await;
+        control("set_memory", r#"{"slot": "notes", "value": "draft"}"#).await;
+        control("goto", "Nowhere").await;
+        assert_eq!(rx.recv().await.unwrap().1, "snapshot");
+        let (_, _, content) = rx.recv().await.unwrap();
+        let changed = serde_json::from_str::<AgentSnapshot>(&content).unwrap();
+        assert_eq!(changed.fsm_state.as_deref(), Some("Review"));
+        assert_eq!(changed.memory.get("notes"), Some(&vec![Value::from("draft")]));
+        assert_eq!(rx.recv().await.unwrap().1, "error");
+
+        control("clear_message", "").await;
+        control("restore", &serde_json::to_string(&snapshot).unwrap()).await;
+        let (_, _, content) = rx.recv().await.unwrap();
+        assert_eq!(serde_json::from_str::<AgentSnapshot>(&content).unwrap(), snapshot);
+
+        drop(send_msg);
+        service.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_message_service_breakpoint() {
+        let mut agent = control_test_agent(false, false);
+        let review = ControlTestState {
+            name: "Review".into(),
+            attributes: HashMap::default(),
+            slow: false,
+        };
+        agent.fsm.add_state("Review".into(), Box::new(review));
+        agent.fsm.add_transition("Work".into(), "Review".into());
+        let (send_msg, rcv_msg) = mpsc::channel::<(String, String)>(8);
+        let (tx, mut rx) = mpsc::channel::<(String, String, String)>(16);
+        let service = tokio::spawn(async move { agent.fsm_message_service(rcv_msg, tx, None).await });
+
+        send_msg.send(("breakpoint".into(), "Work".into())).await.unwrap();
+        let (_, t, content) = rx.recv().await.unwrap();
+        assert_eq!(t, "snapshot");
+        let snapshot = serde_json::from_str::<AgentSnapshot>(&content).unwrap();
+        assert_eq!(snapshot.breakpoints, vec!["Work"]);
+
+        send_msg.send(("message".into(), "hello".into())).await.unwrap();
+        let (state, t, content) = rx.recv().await.unwrap();
+        assert_eq!((state.as_str(), t.as_str()), ("Work", "paused"));
+        let view = serde_json::from_str::<Value>(&content).unwrap();
+        assert_eq!(view["next_states"], serde_json::json!(["Review"]));
+        assert_eq!(view["messages"][0][1], "hello");
+
+        // the state does not choose a next state, the debugger does
+        send_msg.send(("set_next_state".into(), "Review".into())).await.unwrap();
+        send_msg.send(("continue".into(), "".into())).await.unwrap();
+        assert_eq!(
+            next_events(&mut rx).await,
+            vec!["state", "llm_output", "state", "llm_output"]
+        );
+
+        // stepping pauses before the next state without a breakpoint
+        send_msg.send(("goto".into(), "Work".into())).await.unwrap();
+        assert_eq!(rx.recv().await.unwrap().1, "snapshot");
+        send_msg.send(("message".into(), "again".into())).await.unwrap();
+        assert_eq!(rx.recv().await.unwrap().1, "paused");
+        send_msg.send(("set_next_state".into(), "Review".into())).await.unwrap();
+        send_msg.send(("step".into(), "".into())).await.unwrap();
+        let (state, t, _) = rx.recv().await.unwrap();
+        assert_eq!((state.as_str(), t.as_str()), ("Work", "state"));
+        assert_eq!(rx.recv().await.unwrap().1, "llm_output");
+        let (state, t, _) = rx.recv().await.unwrap();
+        assert_eq!((state.as_str(), t.as_str()), ("Review", "paused"));
+        send_msg.send(("cancel".into(), "".into())).await.unwrap();
+        assert_eq!(next_events(&mut rx).await, vec!["cancelled"]);
+
+        drop(send_msg);
+        service.await.unwrap().unwrap();
+    }
 }
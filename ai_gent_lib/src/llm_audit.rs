@@ -0,0 +1,326 @@
+// An audit log of the LLM requests made on behalf of a chat, for reconstructing why an agent
+// answered the way it did.
+//
+// A process-wide log is set with `set_llm_audit_log`. The requests of a `GenaiLlmclient` whose
+// `GenerationOptions` carry an `LlmAuditContext` are then recorded with the rendered prompt, the
+// messages sent, the output, the usage and the latency. The agents pass the context of their
+// `LlmReqSetting` to the clients of their states, which add the state and the purpose
+// (`routing`, `answer` or `summary`). The responses served from a cache are recorded with
+// `cached` (see `LlmClient::record_cache_hit`), the streams dropped before their end (e.g.
+// cancelled states) are not recorded.
+
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgPool;
+
+use crate::llm_service::{
+    GenerationOptions, LlmChatRequest, LlmChatResponse, LlmChunkStream, LlmMessage,
+    LlmStreamChunk, LlmUsage,
+};
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct LlmAuditContext {
+    pub chat_id: i32,
+    // the user message being answered
+    pub message_id: Option<i32>,
+    pub agent_id: Option<i32>,
+    pub fsm_state: Option<String>,
+    pub purpose: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LlmAuditRecord {
+    pub context: LlmAuditContext,
+    pub model: String,
+    pub provider: Option<String>,
+    pub prompt: Option<String>,
+    pub messages: Vec<LlmMessage>,
+    pub output: Option<String>,
+    pub usage: LlmUsage,
+    pub latency_ms: u64,
+    pub error: Option<String>,
+    #[serde(default)]
+    pub cached: bool,
+}
+
+#[async_trait]
+pub trait LlmAuditLog: Send + Sync {
+    async fn record(&self, record: &LlmAuditRecord);
+}
+
+// uses the `llm_audit_log` table, see `database/migrations`
+pub struct PostgresLlmAuditLog {
+    pool: PgPool,
+}
+
+impl PostgresLlmAuditLog {
+    pub fn new(pool: PgPool) -> Self {
+        PostgresLlmAuditLog { pool }
+    }
+
+    // delete the records older than `retention`, returns the number of deleted records
+    pub async fn purge(&self, retention: Duration) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query(
+            "DELETE FROM llm_audit_log WHERE created_at < NOW() - make_interval(secs => $1)",
+        )
+        .bind(retention.as_secs_f64())
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+}
+
+#[async_trait]
+impl LlmAuditLog for PostgresLlmAuditLog {
+    async fn record(&self, record: &LlmAuditRecord) {
+        let context = &record.context;
+        let result = sqlx::query(
+            "INSERT INTO llm_audit_log (chat_id, message_id, agent_id, fsm_state, purpose, model,
+                                        provider, prompt, messages, output, input_tokens,
+                                        output_tokens, total_tokens, latency_ms, error, cached)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16)",
+        )
+        .bind(context.chat_id)
+        .bind(context.message_id)
+        .bind(context.agent_id)
+        .bind(context.fsm_state.as_deref())
+        .bind(context.purpose.as_deref().unwrap_or("answer"))
+        .bind(&record.model)
+        .bind(record.provider.as_deref())
+        .bind(record.prompt.as_deref())
+        .bind(sqlx::types::Json(&record.messages))
+        .bind(record.output.as_deref())
+        .bind(record.usage.input_tokens.map(|t| t as i32))
+        .bind(record.usage.output_tokens.map(|t| t as i32))
+        .bind(record.usage.total_tokens.map(|t| t as i32))
+        .bind(record.latency_ms as i64)
+        .bind(record.error.as_deref())
+        .bind(record.cached)
+        .execute(&self.pool)
+        .await;
+        if let Err(e) = result {
+            tracing::info!(target: "log", "LLM audit log insert error: {}", e);
+        }
+    }
+}
+
+static AUDIT_LOG: Lazy<RwLock<Option<Arc<dyn LlmAuditLog>>>> = Lazy::new(|| RwLock::new(None));
+
+pub fn set_llm_audit_log(log: Arc<dyn LlmAuditLog>) {
+    *AUDIT_LOG.write().unwrap() = Some(log);
+}
+
+// the log and a record to complete, if the request is to be audited
+fn start_record(
+    model: &str,
+    options: &GenerationOptions,
+    req: &LlmChatRequest,
+) -> Option<(Arc<dyn LlmAuditLog>, LlmAuditRecord)> {
+    let context = options.audit.clone()?;
+    let log = AUDIT_LOG.read().unwrap().clone()?;
+    let record = LlmAuditRecord {
+        context,
+        model: model.to_string(),
+        provider: options.provider.clone(),
+        prompt: req.system.clone(),
+        messages: req.messages.clone(),
+        output: None,
+        usage: LlmUsage::default(),
+        latency_ms: 0,
+        error: None,
+        cached: false,
+    };
+    Some((log, record))
+}
+
+fn response_output(response: &LlmChatResponse) -> String {
+    if response.tool_calls.is_empty() {
+        response.text.clone()
+    } else {
+        serde_json::to_string(&response.tool_calls).unwrap_or_default()
+    }
+}
+
+// the insert does not hold up the request
+fn write_record(log: Arc<dyn LlmAuditLog>, record: LlmAuditRecord) {
+    tokio::spawn(async move { log.record(&record).await });
+}
+
+pub(crate) async fn audit_chat<F>(
+    model: &str,
+    options: &GenerationOptions,
+    req: &LlmChatRequest,
+    chat: F,
+) -> Result<LlmChatResponse, anyhow::Error>
+where
+    F: std::future::Future<Output = Result<LlmChatResponse, anyhow::Error>>,
+{
+    let Some((log, mut record)) = start_record(model, options, req) else {
+        return chat.await;
+    };
+    let start = Instant::now();
+    let result = chat.await;
+    record.latency_ms = start.elapsed().as_millis() as u64;
+    match &result {
+        Ok(response) => {
+            record.output = Some(response_output(response));
+            record.usage = response.usage.clone();
+        }
+        Err(e) => record.error = Some(e.to_string()),
+    }
+    write_record(log, record);
+    result
+}
+
+// the usage is the one of the request that filled the cache
+pub(crate) fn audit_cache_hit(
+    model: &str,
+    options: &GenerationOptions,
+    req: &LlmChatRequest,
+    response: &LlmChatResponse,
+) {
+    let Some((log, mut record)) = start_record(model, options, req) else {
+        return;
+    };
+    record.output = Some(response_output(response));
+    record.usage = response.usage.clone();
+    record.cached = true;
+    write_record(log, record);
+}
+
+pub(crate) async fn audit_chat_stream<F>(
+    model: &str,
+    options: &GenerationOptions,
+    req: &LlmChatRequest,
+    chat_stream: F,
+) -> LlmChunkStream
+where
+    F: std::future::Future<Output = LlmChunkStream>,
+{
+    let Some((log, record)) = start_record(model, options, req) else {
+        return chat_stream.await;
+    };
+    let start = Instant::now();
+    let llm_stream = chat_stream.await;
+    let mut output = String::new();
+    let mut record = Some(record);
+    let llm_stream = llm_stream.inspect(move |chunk| {
+        match chunk {
+            Ok(LlmStreamChunk::Text(text)) => output.push_str(text),
+            Ok(LlmStreamChunk::End { usage, .. }) => {
+                if let Some(mut record) = record.take() {
+                    record.latency_ms = start.elapsed().as_millis() as u64;
+                    record.output = Some(std::mem::take(&mut output));
+                    record.usage = usage.clone().unwrap_or_default();
+                    write_record(log.clone(), record);
+                }
+            }
+            Err(e) => {
+                if let Some(mut record) = record.take() {
+                    record.latency_ms = start.elapsed().as_millis() as u64;
+                    record.output = Some(std::mem::take(&mut output)).filter(|o| !o.is_empty());
+                    record.error = Some(e.to_string());
+                    write_record(log.clone(), record);
+                }
+            }
+            _ => {}
+        }
+    });
+    Box::pin(llm_stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct MemoryAuditLog {
+        records: Mutex<Vec<LlmAuditRecord>>,
+    }
+
+    #[async_trait]
+    impl LlmAuditLog for MemoryAuditLog {
+        async fn record(&self, record: &LlmAuditRecord) {
+            self.records.lock().unwrap().push(record.clone());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_audit_chat_stream() {
+        let log = Arc::new(MemoryAuditLog::default());
+        set_llm_audit_log(log.clone());
+
+        let options = GenerationOptions {
+            audit: Some(LlmAuditContext {
+                chat_id: 7,
+                fsm_state: Some("Answer".into()),
+                purpose: Some("answer".into()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let req = LlmChatRequest::from_prompt(
+            "You are a helpful assistant.",
+            &[("user".into(), "hello".into())],
+            Some(0.0),
+        );
+        let chunks: Vec<Result<LlmStreamChunk, crate::llm_service::LlmError>> = vec![
+            Ok(LlmStreamChunk::Start),
+            Ok(LlmStreamChunk::Text("Hi, ".into())),
+            Ok(LlmStreamChunk::Text("there".into())),
+            Ok(LlmStreamChunk::End {
+                usage: Some(LlmUsage {
+                    input_tokens: Some(12),
+                    output_tokens: Some(3),
+                    total_tokens: Some(15),
+                }),
+                finish_reason: Some("stop".into()),
+                latency_ms: Some(420),
+            }),
+        ];
+        let llm_stream = audit_chat_stream("gpt-4o", &options, &req, async {
+            Box::pin(futures::stream::iter(chunks)) as LlmChunkStream
+        })
+        .await;
+        assert_eq!(llm_stream.count().await, 4);
+
+        // without a context, the request is not recorded
+        let response = audit_chat("gpt-4o", &GenerationOptions::default(), &req, async {
+            Ok(LlmChatResponse::default())
+        })
+        .await;
+        assert!(response.is_ok());
+
+        // the records are written by a spawned task
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        let records = log.records.lock().unwrap().clone();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].context.chat_id, 7);
+        assert_eq!(records[0].output.as_deref(), Some("Hi, there"));
+        assert_eq!(records[0].prompt.as_deref(), Some("You are a helpful assistant."));
+        assert_eq!(records[0].usage.total_tokens, Some(15));
+        assert_eq!(records[0].messages.len(), 1);
+        assert!(!records[0].cached);
+
+        let response = LlmChatResponse {
+            text: "Hi, there".into(),
+            ..Default::default()
+        };
+        audit_cache_hit("gpt-4o", &options, &req, &response);
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        let records = log.records.lock().unwrap().clone();
+        assert_eq!(records.len(), 2);
+        assert!(records[1].cached);
+        assert_eq!(records[1].output.as_deref(), Some("Hi, there"));
+    }
+}
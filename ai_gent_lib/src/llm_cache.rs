@@ -0,0 +1,444 @@
+// A cache of LLM responses in front of an `LlmClient`.
+//
+// The responses are keyed by a fingerprint of the client's scope (model and generation options,
+// see `LlmClient::cache_scope`) and the request (rendered prompt, messages, temperature, ...).
+// Only deterministic requests are cached unless the policy says otherwise: a request without
+// a temperature or with a nonzero one bypasses the cache.
+//
+// A process-wide default cache can be set with `set_default_llm_cache`, the agent states use
+// it when `cache_llm` (for the state's own request) or `cache_fsm` (for choosing the next
+// state) is set in their `state_config`.
+//
+// The memory cache holds at most `capacity` responses and evicts the least recently used one.
+// `LlmCache::purge` removes the expired responses, the web server calls it periodically.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use once_cell::sync::Lazy;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::postgres::PgPool;
+
+use crate::llm_agent::LlmClient;
+use crate::llm_service::{LlmChatRequest, LlmChatResponse, LlmChunkStream, LlmStreamChunk};
+
+#[async_trait]
+pub trait LlmCache: Send + Sync {
+    async fn get(&self, fingerprint: &str) -> Option<LlmChatResponse>;
+    async fn put(&self, fingerprint: &str, response: &LlmChatResponse, ttl: Option<Duration>);
+
+    // remove the expired responses, returns the number of removed responses
+    async fn purge(&self) -> Result<u64, anyhow::Error> {
+        Ok(0)
+    }
+}
+
+pub const DEFAULT_MEMORY_CACHE_CAPACITY: usize = 10_000;
+
+#[derive(Debug, Default, Clone)]
+pub struct LlmCachePolicy {
+    pub ttl: Option<Duration>,
+    pub cache_nonzero_temperature: bool,
+}
+
+pub fn request_fingerprint(scope: &str, req: &LlmChatRequest) -> String {
+    let key = json!({ "scope": scope, "request": req }).to_string();
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+struct MemoryCacheEntry {
+    response: LlmChatResponse,
+    expires_at: Option<Instant>,
+    // the `clock` of the last use, for evicting the least recently used entry
+    last_used: u64,
+}
+
+impl MemoryCacheEntry {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+pub struct MemoryLlmCache {
+    entries: Mutex<HashMap<String, MemoryCacheEntry>>,
+    capacity: usize,
+    clock: AtomicU64,
+}
+
+impl Default for MemoryLlmCache {
+    fn default() -> Self {
+        Self::with_capacity(DEFAULT_MEMORY_CACHE_CAPACITY)
+    }
+}
+
+impl MemoryLlmCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        MemoryLlmCache {
+            entries: Mutex::new(HashMap::new()),
+            capacity: capacity.max(1),
+            clock: AtomicU64::new(0),
+        }
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+#[async_trait]
+impl LlmCache for MemoryLlmCache {
+    async fn get(&self, fingerprint: &str) -> Option<LlmChatResponse> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get_mut(fingerprint) {
+            Some(entry) if entry.is_expired(Instant::now()) => {
+                entries.remove(fingerprint);
+                None
+            }
+            Some(entry) => {
+                entry.last_used = self.tick();
+                Some(entry.response.clone())
+            }
+            None => None,
+        }
+    }
+
+    async fn put(&self, fingerprint: &str, response: &LlmChatResponse, ttl: Option<Duration>) {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.contains_key(fingerprint) && entries.len() >= self.capacity {
+            entries.retain(|_, entry| !entry.is_expired(now));
+            if entries.len() >= self.capacity {
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.last_used)
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+        }
+        entries.insert(
+            fingerprint.to_string(),
+            MemoryCacheEntry {
+                response: response.clone(),
+                expires_at: ttl.map(|ttl| now + ttl),
+                last_used: self.tick(),
+            },
+        );
+    }
+
+    async fn purge(&self) -> Result<u64, anyhow::Error> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        let len = entries.len();
+        entries.retain(|_, entry| !entry.is_expired(now));
+        Ok((len - entries.len()) as u64)
+    }
+}
+
+// uses the `llm_cache` table, see `database/migrations`
+pub struct PostgresLlmCache {
+    pool: PgPool,
+    // the responses older than this are purged even without a TTL
+    retention: Option<Duration>,
+}
+
+impl PostgresLlmCache {
+    pub fn new(pool: PgPool) -> Self {
+        PostgresLlmCache {
+            pool,
+            retention: None,
+        }
+    }
+
+    pub fn with_retention(mut self, retention: Duration) -> Self {
+        self.retention = Some(retention);
+        self
+    }
+}
+
+#[async_trait]
+impl LlmCache for PostgresLlmCache {
+    async fn get(&self, fingerprint: &str) -> Option<LlmChatResponse> {
+        let row = sqlx::query_scalar::<_, sqlx::types::Json<LlmChatResponse>>(
+            "SELECT response FROM llm_cache
+             WHERE fingerprint = $1 AND (expires_at IS NULL OR expires_at > NOW())",
+        )
+        .bind(fingerprint)
+        .fetch_optional(&self.pool)
+        .await;
+        match row {
+            Ok(row) => row.map(|response| response.0),
+            Err(e) => {
+                tracing::info!(target: "log", "LLM cache lookup error: {}", e);
+                None
+            }
+        }
+    }
+
+    async fn put(&self, fingerprint: &str, response: &LlmChatResponse, ttl: Option<Duration>) {
+        let result = sqlx::query(
+            "INSERT INTO llm_cache (fingerprint, response, expires_at)
+             VALUES ($1, $2, NOW() + make_interval(secs => $3))
+             ON CONFLICT (fingerprint)
+             DO UPDATE SET response = EXCLUDED.response, expires_at = EXCLUDED.expires_at,
+                           created_at = NOW()",
+        )
+        .bind(fingerprint)
+        .bind(sqlx::types::Json(response))
+        .bind(ttl.map(|ttl| ttl.as_secs_f64()))
+        .execute(&self.pool)
+        .await;
+        if let Err(e) = result {
+            tracing::info!(target: "log", "LLM cache insert error: {}", e);
+        }
+    }
+
+    async fn purge(&self) -> Result<u64, anyhow::Error> {
+        let result = sqlx::query(
+            "DELETE FROM llm_cache
+             WHERE expires_at <= NOW()
+                OR ($1::FLOAT8 IS NOT NULL AND created_at < NOW() - make_interval(secs => $1))",
+        )
+        .bind(self.retention.map(|retention| retention.as_secs_f64()))
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+}
+
+type DefaultCache = (Arc<dyn LlmCache>, LlmCachePolicy);
+
+static DEFAULT_CACHE: Lazy<RwLock<Option<DefaultCache>>> = Lazy::new(|| RwLock::new(None));
+
+pub fn set_default_llm_cache(cache: Arc<dyn LlmCache>, policy: LlmCachePolicy) {
+    *DEFAULT_CACHE.write().unwrap() = Some((cache, policy));
+}
+
+// wrap `client` with the default cache if `enabled`, it passes the requests through otherwise
+pub fn with_default_cache<C: LlmClient>(client: C, enabled: bool) -> CachedLlmClient<C> {
+    let default_cache = if enabled {
+        DEFAULT_CACHE.read().unwrap().clone()
+    } else {
+        None
+    };
+    match default_cache {
+        Some((cache, policy)) => CachedLlmClient::new(client, Some(cache), policy),
+        None => CachedLlmClient::new(client, None, LlmCachePolicy::default()),
+    }
+}
+
+pub struct CachedLlmClient<C: LlmClient> {
+    inner: C,
+    cache: Option<Arc<dyn LlmCache>>,
+    policy: LlmCachePolicy,
+}
+
+impl<C: LlmClient> CachedLlmClient<C> {
+    pub fn new(inner: C, cache: Option<Arc<dyn LlmCache>>, policy: LlmCachePolicy) -> Self {
+        CachedLlmClient {
+            inner,
+            cache,
+            policy,
+        }
+    }
+
+    fn fingerprint(&self, req: &LlmChatRequest) -> Option<(Arc<dyn LlmCache>, String)> {
+        let cache = self.cache.clone()?;
+        let deterministic = req.temperature.is_some_and(|t| t == 0.0);
+        if !deterministic && !self.policy.cache_nonzero_temperature {
+            return None;
+        }
+        Some((cache, request_fingerprint(&self.inner.cache_scope(), req)))
+    }
+}
+
+#[async_trait]
+impl<C: LlmClient> LlmClient for CachedLlmClient<C> {
+    async fn chat(&self, req: &LlmChatRequest) -> Result<LlmChatResponse, anyhow::Error> {
+        let Some((cache, fingerprint)) = self.fingerprint(req) else {
+            return self.inner.chat(req).await;
+        };
+        if let Some(response) = cache.get(&fingerprint).await {
+            self.inner.record_cache_hit(req, &response);
+            return Ok(response);
+        }
+        let response = self.inner.chat(req).await?;
+        cache.put(&fingerprint, &response, self.policy.ttl).await;
+        Ok(response)
+    }
+
+    async fn chat_stream(&self, req: &LlmChatRequest) -> LlmChunkStream {
+        let Some((cache, fingerprint)) = self.fingerprint(req) else {
+            return self.inner.chat_stream(req).await;
+        };
+
+        if let Some(response) = cache.get(&fingerprint).await {
+            self.inner.record_cache_hit(req, &response);
+            let mut chunks = vec![Ok(LlmStreamChunk::Start)];
+            if let Some(reasoning) = response.reasoning {
+                chunks.push(Ok(LlmStreamChunk::Reasoning(reasoning)));
+            }
+            chunks.push(Ok(LlmStreamChunk::Text(response.text)));
+            chunks.push(Ok(LlmStreamChunk::End {
+                usage: Some(response.usage),
+                finish_reason: response.finish_reason,
+                latency_ms: None,
+            }));
+            return Box::pin(futures::stream::iter(chunks));
+        }
+
+        // collect the streamed response and store it when the stream reaches its end
+        let ttl = self.policy.ttl;
+        let collected = Arc::new(Mutex::new(LlmChatResponse::default()));
+        let llm_stream = self.inner.chat_stream(req).await;
+        Box::pin(llm_stream.then(move |chunk| {
+            let cache = cache.clone();
+            let fingerprint = fingerprint.clone();
+            let collected = collected.clone();
+            async move {
+                let finished = match &chunk {
+                    Ok(LlmStreamChunk::Text(text)) => {
+                        collected.lock().unwrap().text.push_str(text);
+                        None
+                    }
+                    Ok(LlmStreamChunk::Reasoning(text)) => {
+                        collected
+                            .lock()
+                            .unwrap()
+                            .reasoning
+                            .get_or_insert_with(String::new)
+                            .push_str(text);
+                        None
+                    }
+                    Ok(LlmStreamChunk::End {
+                        usage,
+                        finish_reason,
+                        latency_ms,
+                    }) => {
+                        let mut response = collected.lock().unwrap().clone();
+                        response.usage = usage.clone().unwrap_or_default();
+                        response.finish_reason = finish_reason.clone();
+                        response.latency_ms = latency_ms.unwrap_or_default();
+                        Some(response)
+                    }
+                    _ => None,
+                };
+                if let Some(response) = finished {
+                    cache.put(&fingerprint, &response, ttl).await;
+                }
+                chunk
+            }
+        }))
+    }
+
+    fn cache_scope(&self) -> String {
+        self.inner.cache_scope()
+    }
+
+    fn record_cache_hit(&self, req: &LlmChatRequest, response: &LlmChatResponse) {
+        self.inner.record_cache_hit(req, response);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Default)]
+    struct CountingClient {
+        calls: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl LlmClient for CountingClient {
+        async fn chat(&self, _req: &LlmChatRequest) -> Result<LlmChatResponse, anyhow::Error> {
+            let n = self.calls.fetch_add(1, Ordering::SeqCst);
+            Ok(LlmChatResponse {
+                text: format!("answer {}", n),
+                ..Default::default()
+            })
+        }
+
+        async fn chat_stream(&self, req: &LlmChatRequest) -> LlmChunkStream {
+            let text = self.chat(req).await.unwrap().text;
+            Box::pin(futures::stream::iter(vec![
+                Ok(LlmStreamChunk::Start),
+                Ok(LlmStreamChunk::Text(text)),
+                Ok(LlmStreamChunk::End {
+                    usage: None,
+                    finish_reason: Some("stop".into()),
+                    latency_ms: Some(0),
+                }),
+            ]))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cached_client() {
+        let cache: Arc<dyn LlmCache> = Arc::new(MemoryLlmCache::new());
+        let client = CachedLlmClient::new(
+            CountingClient::default(),
+            Some(cache),
+            LlmCachePolicy::default(),
+        );
+        let msgs = vec![("user".to_string(), "route me".to_string())];
+
+        // deterministic requests are cached
+        let a = client.generate("fsm prompt", &msgs, Some(0.0)).await.unwrap();
+        let b = client.generate("fsm prompt", &msgs, Some(0.0)).await.unwrap();
+        assert_eq!(a, b);
+
+        // a different prompt or a nonzero temperature is not served from the cache
+        let c = client.generate("other prompt", &msgs, Some(0.0)).await.unwrap();
+        assert_ne!(a, c);
+        let d = client.generate("fsm prompt", &msgs, Some(0.7)).await.unwrap();
+        let e = client.generate("fsm prompt", &msgs, Some(0.7)).await.unwrap();
+        assert_ne!(d, e);
+
+        // streamed responses are stored and replayed
+        let req = LlmChatRequest::from_prompt("stream prompt", &msgs, Some(0.0));
+        let first = client.chat_stream(&req).await.collect::<Vec<_>>().await;
+        let second = client.chat_stream(&req).await.collect::<Vec<_>>().await;
+        assert_eq!(first[1], second[1]);
+        assert_eq!(client.inner.calls.load(Ordering::SeqCst), 5);
+    }
+
+    #[tokio::test]
+    async fn test_memory_cache_ttl() {
+        let cache = MemoryLlmCache::new();
+        let response = LlmChatResponse::default();
+        cache.put("k", &response, Some(Duration::ZERO)).await;
+        assert!(cache.get("k").await.is_none());
+        cache.put("k", &response, None).await;
+        assert!(cache.get("k").await.is_some());
+
+        cache.put("expired", &response, Some(Duration::ZERO)).await;
+        assert_eq!(cache.purge().await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_memory_cache_capacity() {
+        let cache = MemoryLlmCache::with_capacity(2);
+        let response = LlmChatResponse::default();
+        cache.put("a", &response, None).await;
+        cache.put("b", &response, None).await;
+        // "a" is used after "b", so "b" is evicted
+        assert!(cache.get("a").await.is_some());
+        cache.put("c", &response, None).await;
+        assert!(cache.get("a").await.is_some());
+        assert!(cache.get("b").await.is_none());
+        assert!(cache.get("c").await.is_some());
+        assert_eq!(cache.entries.lock().unwrap().len(), 2);
+    }
+}
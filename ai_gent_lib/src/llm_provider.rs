@@ -0,0 +1,274 @@
+// Named LLM providers and the genai clients built for them.
+//
+// Besides the built-in providers (openai, anthropic, gemini, ...), the models file of the server
+// (see `model_registry::load_models_file`) or an agent configuration file can declare its own
+// providers, e.g. a local Ollama or vLLM server speaking the OpenAI protocol:
+//
+//   provider = "local"                       # the default provider of the agent
+//
+//   [providers.local]
+//   kind = "openai"                          # the API protocol, defaults to "openai"
+//   base_url = "http://localhost:11434/v1/"
+//   api_key_env = "LOCAL_LLM_KEY"            # or `api_key = "..."`, may be omitted for local servers
+//   headers = { "X-Team" = "ai-gent" }
+//
+// A declared provider cannot take the name of a built-in one. The configurations submitted through
+// the web server cannot declare providers, see `LlmFsmAgentConfigBuilder::from_toml`.
+//
+// Clients are cached by provider and API key so they are re-used across LLM calls.
+
+use std::collections::HashMap;
+use std::sync::{Mutex, RwLock};
+
+use genai::adapter::AdapterKind;
+use genai::resolver::{AuthData, AuthResolver, Endpoint, ModelMapper, ServiceTargetResolver};
+use genai::{Client, ModelIden, ServiceTarget};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::model_registry::model_info;
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct ProviderConfig {
+    pub kind: Option<String>,
+    pub base_url: Option<String>,
+    pub api_key: Option<String>,
+    pub api_key_env: Option<String>,
+    pub headers: Option<HashMap<String, String>>,
+}
+
+static PROVIDERS: Lazy<RwLock<HashMap<String, ProviderConfig>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+// keyed by (provider name, api key)
+static CLIENTS: Lazy<Mutex<HashMap<(String, String), Client>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub fn register_providers(
+    providers: &HashMap<String, ProviderConfig>,
+) -> Result<(), anyhow::Error> {
+    if let Some(name) = providers.keys().find(|name| is_builtin_provider(name)) {
+        anyhow::bail!("the LLM provider `{}` is a built-in provider", name);
+    }
+    let mut registry = PROVIDERS.write().unwrap();
+    let mut clients = CLIENTS.lock().unwrap();
+    for (name, config) in providers {
+        if registry.get(name) != Some(config) {
+            // a changed provider invalidates the clients built from the old settings
+            clients.retain(|(provider, _), _| provider != name);
+            registry.insert(name.clone(), config.clone());
+        }
+    }
+    Ok(())
+}
+
+pub fn is_builtin_provider(name: &str) -> bool {
+    provider_adapter_kind(name).is_some()
+}
+
+pub fn get_provider(name: &str) -> Option<ProviderConfig> {
+    PROVIDERS.read().unwrap().get(name).cloned()
+}
+
+// the provider of the model in the registry, or the one inferred from the model name
+pub fn provider_for_model(model: &str) -> String {
+    match model_info(model) {
+        Some(info) => info.provider,
+        None => infer_provider(model).to_string(),
+    }
+}
+
+fn infer_provider(model: &str) -> &'static str {
+    if model.starts_with("claude") {
+        "anthropic"
+    } else if model.starts_with("gemini") {
+        "gemini"
+    } else if model.starts_with("command") {
+        "cohere"
+    } else if model.starts_with("grok") {
+        "xai"
+    } else if model.starts_with("deepseek") {
+        "deepseek"
+    } else if model.starts_with("gpt")
+        || model.starts_with("o1")
+        || model.starts_with("o3")
+        || model.starts_with("chatgpt")
+    {
+        "openai"
+    } else {
+        "ollama"
+    }
+}
+
+pub fn provider_key_env_name(provider: &str) -> Option<&'static str> {
+    match provider.to_lowercase().as_str() {
+        "openai" => Some("OPENAI_API_KEY"),
+        "anthropic" => Some("ANTHROPIC_API_KEY"),
+        "gemini" => Some("GEMINI_API_KEY"),
+        "cohere" => Some("COHERE_API_KEY"),
+        "groq" => Some("GROQ_API_KEY"),
+        "xai" => Some("XAI_API_KEY"),
+        "deepseek" => Some("DEEPSEEK_API_KEY"),
+        _ => None,
+    }
+}
+
+// the API key of a registered or a built-in provider, `None` if it is not configured
+pub fn provider_api_key(provider: &str) -> Option<String> {
+    if let Some(config) = get_provider(provider) {
+        config.api_key.or_else(|| {
+            config
+                .api_key_env
+                .and_then(|env_name| std::env::var(env_name).ok())
+        })
+    } else {
+        provider_key_env_name(provider).and_then(|env_name| std::env::var(env_name).ok())
+    }
+}
+
+fn provider_adapter_kind(provider: &str) -> Option<AdapterKind> {
+    match provider.to_lowercase().as_str() {
+        "openai" => Some(AdapterKind::OpenAI),
+        "anthropic" => Some(AdapterKind::Anthropic),
+        "gemini" => Some(AdapterKind::Gemini),
+        "cohere" => Some(AdapterKind::Cohere),
+        "groq" => Some(AdapterKind::Groq),
+        "xai" => Some(AdapterKind::Xai),
+        "deepseek" => Some(AdapterKind::DeepSeek),
+        "ollama" => Some(AdapterKind::Ollama),
+        _ => None,
+    }
+}
+
+pub fn get_client(provider: Option<&str>, api_key: &str) -> Result<Client, anyhow::Error> {
+    let cache_key = (provider.unwrap_or_default().to_string(), api_key.to_string());
+    if let Some(client) = CLIENTS.lock().unwrap().get(&cache_key) {
+        return Ok(client.clone());
+    }
+
+    let client = match provider.map(|name| (name, get_provider(name))) {
+        Some((name, Some(config))) => build_provider_client(name, &config, api_key)?,
+        Some((name, None)) => {
+            let adapter_kind = provider_adapter_kind(name)
+                .ok_or_else(|| anyhow::anyhow!("unknown LLM provider: {}", name))?;
+            build_client(Some(adapter_kind), api_key)
+        }
+        None => build_client(None, api_key),
+    };
+
+    CLIENTS
+        .lock()
+        .unwrap()
+        .insert(cache_key, client.clone());
+    Ok(client)
+}
+
+fn build_client(adapter_kind: Option<AdapterKind>, api_key: &str) -> Client {
+    let model_mapper = ModelMapper::from_mapper_fn(move |model_iden: ModelIden| {
+        if model_iden.model_name.starts_with("o3-mini") {
+            Ok(ModelIden::new(AdapterKind::OpenAI, "o3-mini"))
+        } else if let Some(adapter_kind) = adapter_kind {
+            Ok(ModelIden::new(adapter_kind, model_iden.model_name))
+        } else {
+            Ok(model_iden)
+        }
+    });
+
+    let api_key = api_key.to_string();
+    let auth_resolver =
+        AuthResolver::from_resolver_fn(move |_| Ok(Some(AuthData::from_single(api_key.clone()))));
+
+    Client::builder()
+        .with_auth_resolver(auth_resolver)
+        .with_model_mapper(model_mapper)
+        .build()
+}
+
+fn build_provider_client(
+    name: &str,
+    config: &ProviderConfig,
+    api_key: &str,
+) -> Result<Client, anyhow::Error> {
+    let kind = config.kind.as_deref().unwrap_or("openai");
+    let adapter_kind = provider_adapter_kind(kind).ok_or_else(|| {
+        anyhow::anyhow!("unknown kind `{}` for the LLM provider `{}`", kind, name)
+    })?;
+    // the provider's own key takes precedence, local servers usually do not need one
+    let api_key = provider_api_key(name).unwrap_or_else(|| api_key.to_string());
+
+    let mut builder = Client::builder();
+
+    if let Some(base_url) = config.base_url.clone() {
+        let base_url = if base_url.ends_with('/') {
+            base_url
+        } else {
+            format!("{}/", base_url)
+        };
+        let target_resolver =
+            ServiceTargetResolver::from_resolver_fn(move |service_target: ServiceTarget| {
+                let ServiceTarget { model, .. } = service_target;
+                Ok(ServiceTarget {
+                    endpoint: Endpoint::from_owned(base_url.clone()),
+                    auth: AuthData::from_single(api_key.clone()),
+                    model: ModelIden::new(adapter_kind, model.model_name),
+                })
+            });
+        builder = builder.with_service_target_resolver(target_resolver);
+    } else {
+        let model_mapper = ModelMapper::from_mapper_fn(move |model_iden: ModelIden| {
+            Ok(ModelIden::new(adapter_kind, model_iden.model_name))
+        });
+        let auth_resolver = AuthResolver::from_resolver_fn(move |_| {
+            Ok(Some(AuthData::from_single(api_key.clone())))
+        });
+        builder = builder
+            .with_model_mapper(model_mapper)
+            .with_auth_resolver(auth_resolver);
+    }
+
+    if let Some(headers) = config.headers.as_ref() {
+        let mut header_map = reqwest::header::HeaderMap::new();
+        for (k, v) in headers {
+            header_map.insert(
+                reqwest::header::HeaderName::from_bytes(k.as_bytes())?,
+                reqwest::header::HeaderValue::from_str(v)?,
+            );
+        }
+        let http_client = reqwest::Client::builder()
+            .default_headers(header_map)
+            .build()?;
+        builder = builder.with_reqwest(http_client);
+    }
+
+    Ok(builder.build())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_provider_registry() {
+        std::env::set_var("AI_GENT_TEST_LOCAL_KEY", "local-key");
+        let providers = HashMap::from([(
+            "test-local".to_string(),
+            ProviderConfig {
+                base_url: Some("http://localhost:11434/v1".into()),
+                api_key_env: Some("AI_GENT_TEST_LOCAL_KEY".into()),
+                ..Default::default()
+            },
+        )]);
+        register_providers(&providers).unwrap();
+
+        let shadowing = HashMap::from([("OpenAI".to_string(), ProviderConfig::default())]);
+        assert!(register_providers(&shadowing).is_err());
+        assert!(get_provider("OpenAI").is_none());
+
+        assert!(get_provider("test-local").is_some());
+        assert_eq!(provider_api_key("test-local").as_deref(), Some("local-key"));
+        assert!(get_client(Some("test-local"), "").is_ok());
+        assert!(get_client(Some("not-a-provider"), "").is_err());
+        assert_eq!(provider_for_model("claude-3-haiku-20240307"), "anthropic");
+        assert_eq!(provider_for_model("gpt-4o-mini"), "openai");
+    }
+}
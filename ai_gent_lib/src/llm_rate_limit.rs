@@ -0,0 +1,283 @@
+// Process-wide rate limiting of the LLM calls.
+//
+// Limits are set per provider (`"openai"`) or per provider and model (`"openai/gpt-4o"`),
+// the more specific one wins, `"default"` is for all the others. They are set by the deployment,
+// not by the agents, in the models file of the server (see `model_registry::load_models_file`):
+//
+//   [rate_limits."openai/gpt-4o"]
+//   requests_per_minute = 500
+//   tokens_per_minute = 30000
+//   max_concurrent = 8
+//
+// Without a `"default"` entry, the others are limited by `LLM_REQUESTS_PER_MINUTE` (500 if
+// not set), `LLM_TOKENS_PER_MINUTE` (no limit if not set) and `LLM_MAX_CONCURRENT` (8 if not
+// set), 0 is no limit.
+//
+// Every request waits in a FIFO queue until it fits in the one-minute window and a
+// concurrency slot is free. A chat session has at most one request in the queue at a time,
+// so a busy session can not starve the others.
+
+use std::collections::{HashMap, VecDeque};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+const WINDOW: Duration = Duration::from_secs(60);
+const DEFAULT_KEY: &str = "default";
+const DEFAULT_REQUESTS_PER_MINUTE: u32 = 500;
+const DEFAULT_MAX_CONCURRENT: usize = 8;
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct RateLimits {
+    pub requests_per_minute: Option<u32>,
+    pub tokens_per_minute: Option<u32>,
+    pub max_concurrent: Option<usize>,
+}
+
+struct WindowEntry {
+    id: u64,
+    time: Instant,
+    tokens: u32,
+}
+
+struct Limiter {
+    limits: RateLimits,
+    window: tokio::sync::Mutex<VecDeque<WindowEntry>>,
+    concurrency: Option<Arc<Semaphore>>,
+}
+
+static LIMITS: Lazy<RwLock<HashMap<String, RateLimits>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+// keyed by "provider/model"
+static LIMITERS: Lazy<Mutex<HashMap<String, Arc<Limiter>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+static SESSION_GATES: Lazy<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+static ENTRY_ID: AtomicU64 = AtomicU64::new(0);
+
+static DEFAULT_LIMITS: Lazy<RateLimits> = Lazy::new(|| RateLimits {
+    requests_per_minute: env_limit("LLM_REQUESTS_PER_MINUTE", Some(DEFAULT_REQUESTS_PER_MINUTE)),
+    tokens_per_minute: env_limit("LLM_TOKENS_PER_MINUTE", None),
+    max_concurrent: env_limit("LLM_MAX_CONCURRENT", Some(DEFAULT_MAX_CONCURRENT)),
+});
+
+fn env_limit<T: FromStr + Default + PartialEq>(env_name: &str, default: Option<T>) -> Option<T> {
+    match std::env::var(env_name).ok().and_then(|v| v.parse::<T>().ok()) {
+        Some(limit) if limit == T::default() => None,
+        Some(limit) => Some(limit),
+        None => default,
+    }
+}
+
+pub fn register_rate_limits(limits: &HashMap<String, RateLimits>) {
+    let mut registry = LIMITS.write().unwrap();
+    let mut limiters = LIMITERS.lock().unwrap();
+    for (key, limit) in limits {
+        if registry.get(key) != Some(limit) {
+            // limiters built from the old limits are replaced on the next request
+            limiters.retain(|limiter_key, _| {
+                key != DEFAULT_KEY
+                    && limiter_key != key
+                    && !limiter_key.starts_with(&format!("{}/", key))
+            });
+            registry.insert(key.clone(), limit.clone());
+        }
+    }
+}
+
+fn get_limiter(provider: &str, model: &str) -> Arc<Limiter> {
+    let key = format!("{}/{}", provider, model);
+    let mut limiters = LIMITERS.lock().unwrap();
+    limiters
+        .entry(key.clone())
+        .or_insert_with(|| {
+            let registry = LIMITS.read().unwrap();
+            let limits = registry
+                .get(&key)
+                .or_else(|| registry.get(provider))
+                .or_else(|| registry.get(DEFAULT_KEY))
+                .cloned()
+                .unwrap_or_else(|| DEFAULT_LIMITS.clone());
+            Arc::new(Limiter {
+                concurrency: limits
+                    .max_concurrent
+                    .map(|n| Arc::new(Semaphore::new(n.max(1)))),
+                limits,
+                window: tokio::sync::Mutex::new(VecDeque::new()),
+            })
+        })
+        .clone()
+}
+
+// a rough token count for the requests per minute budget, corrected by `record_usage`
+pub fn estimate_tokens(text_len: usize, max_tokens: Option<u32>) -> u32 {
+    (text_len / 4) as u32 + max_tokens.unwrap_or(0)
+}
+
+// held for the duration of an LLM call, the concurrency slot is released on drop
+pub struct LlmPermit {
+    limiter: Arc<Limiter>,
+    entry_id: u64,
+    _concurrency: Option<OwnedSemaphorePermit>,
+}
+
+impl LlmPermit {
+    pub async fn record_usage(&self, total_tokens: u32) {
+        let mut window = self.limiter.window.lock().await;
+        if let Some(entry) = window.iter_mut().find(|e| e.id == self.entry_id) {
+            entry.tokens = total_tokens;
+        }
+    }
+}
+
+pub async fn acquire_llm_permit(
+    provider: &str,
+    model: &str,
+    session_id: Option<&str>,
+    estimated_tokens: u32,
+) -> LlmPermit {
+    let limiter = get_limiter(provider, model);
+
+    let session_gate = session_id.map(|session_id| {
+        SESSION_GATES
+            .lock()
+            .unwrap()
+            .entry(session_id.to_string())
+            .or_default()
+            .clone()
+    });
+    let session_guard = match session_gate.as_ref() {
+        Some(gate) => Some(gate.clone().lock_owned().await),
+        None => None,
+    };
+
+    let entry_id = ENTRY_ID.fetch_add(1, Ordering::Relaxed);
+    {
+        // the window lock is fair, waiting requests are admitted in arrival order
+        let mut window = limiter.window.lock().await;
+        loop {
+            let now = Instant::now();
+            while window
+                .front()
+                .is_some_and(|e| now.duration_since(e.time) >= WINDOW)
+            {
+                window.pop_front();
+            }
+            let requests = window.len() as u32;
+            let tokens = window.iter().map(|e| e.tokens).sum::<u32>();
+            let over_rpm = limiter
+                .limits
+                .requests_per_minute
+                .is_some_and(|rpm| requests >= rpm);
+            let over_tpm = limiter
+                .limits
+                .tokens_per_minute
+                .is_some_and(|tpm| requests > 0 && tokens + estimated_tokens > tpm);
+            match window.front() {
+                Some(oldest) if over_rpm || over_tpm => {
+                    let wait = WINDOW.saturating_sub(now.duration_since(oldest.time));
+                    tokio::time::sleep(wait).await;
+                }
+                _ => break,
+            }
+        }
+        window.push_back(WindowEntry {
+            id: entry_id,
+            time: Instant::now(),
+            tokens: estimated_tokens,
+        });
+    }
+
+    let concurrency = match limiter.concurrency.as_ref() {
+        Some(semaphore) => semaphore.clone().acquire_owned().await.ok(),
+        None => None,
+    };
+
+    drop(session_guard);
+    if let (Some(session_id), Some(gate)) = (session_id, session_gate) {
+        let mut gates = SESSION_GATES.lock().unwrap();
+        // the map and this function hold the only references, no other request is waiting
+        if Arc::strong_count(&gate) == 2 {
+            gates.remove(session_id);
+        }
+    }
+
+    LlmPermit {
+        limiter,
+        entry_id,
+        _concurrency: concurrency,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_max_concurrent() {
+        let limits = HashMap::from([(
+            "test-provider/test-model".to_string(),
+            RateLimits {
+                max_concurrent: Some(1),
+                ..Default::default()
+            },
+        )]);
+        register_rate_limits(&limits);
+
+        let permit = acquire_llm_permit("test-provider", "test-model", Some("s1"), 10).await;
+        let second = tokio::time::timeout(
+            Duration::from_millis(50),
+            acquire_llm_permit("test-provider", "test-model", Some("s2"), 10),
+        )
+        .await;
+        assert!(second.is_err());
+
+        permit.record_usage(42).await;
+        drop(permit);
+        let second = tokio::time::timeout(
+            Duration::from_millis(50),
+            acquire_llm_permit("test-provider", "test-model", Some("s2"), 10),
+        )
+        .await;
+        assert!(second.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_requests_per_minute() {
+        let limits = HashMap::from([(
+            "test-rpm".to_string(),
+            RateLimits {
+                requests_per_minute: Some(2),
+                ..Default::default()
+            },
+        )]);
+        register_rate_limits(&limits);
+
+        let _p1 = acquire_llm_permit("test-rpm", "any-model", None, 0).await;
+        let _p2 = acquire_llm_permit("test-rpm", "any-model", None, 0).await;
+        let third = tokio::time::timeout(
+            Duration::from_millis(50),
+            acquire_llm_permit("test-rpm", "any-model", None, 0),
+        )
+        .await;
+        assert!(third.is_err());
+    }
+
+    #[test]
+    fn test_default_limits() {
+        let limits = get_limiter("test-unlimited-provider", "any-model").limits.clone();
+        assert_eq!(limits, *DEFAULT_LIMITS);
+
+        assert_eq!(env_limit::<u32>("AI_GENT_TEST_UNSET_LIMIT", Some(3)), Some(3));
+        std::env::set_var("AI_GENT_TEST_NO_LIMIT", "0");
+        assert_eq!(env_limit::<u32>("AI_GENT_TEST_NO_LIMIT", Some(3)), None);
+    }
+}
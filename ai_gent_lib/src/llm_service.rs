@@ -1,146 +1,702 @@
 // use std::sync::Arc;
 
+use std::fmt;
 use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use genai::adapter::AdapterKind;
-use genai::chat::{ChatMessage, ChatOptions, ChatRequest, ChatStreamEvent, StreamChunk};
-use genai::resolver::{AuthData, AuthResolver, ModelMapper};
-use genai::{Client, ModelIden};
+use genai::chat::{
+    ChatMessage, ChatOptions, ChatRequest, ChatResponseFormat, ChatStreamEvent, ContentPart,
+    MessageContent, MetaUsage, ReasoningEffort, StreamChunk, StreamEnd, Tool as GenaiTool,
+    ToolCall, ToolResponse,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::llm_audit::LlmAuditContext;
+use crate::llm_provider::{get_client, provider_for_model};
+use crate::metrics;
+use crate::model_registry::model_info;
+use crate::llm_rate_limit::{acquire_llm_permit, estimate_tokens, LlmPermit};
 
 use futures::{Stream, StreamExt};
 
-pub type LLMStreamOut = Pin<Box<dyn Stream<Item = Option<String>> + Send>>;
+pub type LLMStreamOut = Pin<Box<dyn Stream<Item = Result<String, LlmError>> + Send>>;
+pub type LlmChunkStream = Pin<Box<dyn Stream<Item = Result<LlmStreamChunk, LlmError>> + Send>>;
+
+// transient errors before the first token are retried with exponential backoff
+const MAX_RETRIES: u32 = 3;
+const RETRY_BASE_DELAY_MS: u64 = 500;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum LlmError {
+    Auth(String),
+    RateLimit(String),
+    ContextLength(String),
+    Network(String),
+    Provider(String),
+}
+
+impl fmt::Display for LlmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LlmError::Auth(msg) => write!(f, "LLM authentication error: {}", msg),
+            LlmError::RateLimit(msg) => write!(f, "LLM rate limit error: {}", msg),
+            LlmError::ContextLength(msg) => write!(f, "LLM context length exceeded: {}", msg),
+            LlmError::Network(msg) => write!(f, "LLM network error: {}", msg),
+            LlmError::Provider(msg) => write!(f, "LLM provider error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for LlmError {}
 
-pub async fn genai_stream_service(
-    prompt: &str,
-    msgs: &[(String, String)],
+impl LlmError {
+    // the `kind` label of the error metrics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            LlmError::Auth(_) => "llm_auth",
+            LlmError::RateLimit(_) => "llm_rate_limit",
+            LlmError::ContextLength(_) => "llm_context_length",
+            LlmError::Network(_) => "llm_network",
+            LlmError::Provider(_) => "llm_provider",
+        }
+    }
+
+    pub fn is_transient(&self) -> bool {
+        matches!(self, LlmError::RateLimit(_) | LlmError::Network(_))
+    }
+
+    // by the HTTP status of the provider's response or the kind of the genai error
+    pub fn from_genai(err: genai::Error) -> Self {
+        let msg = err.to_string();
+        match err {
+            genai::Error::WebModelCall { webc_error, .. }
+            | genai::Error::WebAdapterCall { webc_error, .. } => match webc_error {
+                genai::webc::Error::ResponseFailedStatus { status, body } => {
+                    Self::from_status(status.as_u16(), &body, msg)
+                }
+                genai::webc::Error::Reqwest(_) => LlmError::Network(msg),
+                _ => LlmError::Provider(msg),
+            },
+            genai::Error::RequiresApiKey { .. }
+            | genai::Error::NoAuthResolver { .. }
+            | genai::Error::NoAuthData { .. } => LlmError::Auth(msg),
+            genai::Error::WebStream { .. } => LlmError::Network(msg),
+            // the errors in a stream are events with the provider's error type
+            genai::Error::StreamEventError { body, .. } => {
+                match body.pointer("/error/type").and_then(|t| t.as_str()) {
+                    Some("authentication_error") | Some("permission_error") => LlmError::Auth(msg),
+                    Some("rate_limit_error") => LlmError::RateLimit(msg),
+                    Some("overloaded_error") | Some("api_error") => LlmError::Network(msg),
+                    _ => LlmError::Provider(msg),
+                }
+            }
+            _ => LlmError::Provider(msg),
+        }
+    }
+
+    fn from_status(status: u16, body: &str, msg: String) -> Self {
+        match status {
+            401 | 403 => LlmError::Auth(msg),
+            429 => LlmError::RateLimit(msg),
+            413 => LlmError::ContextLength(msg),
+            // the providers only tell an exceeded context length apart in the error body
+            400 if is_context_length_error(body) => LlmError::ContextLength(msg),
+            // timeouts and overloaded or unavailable servers
+            408 | 500 | 502 | 503 | 504 | 529 => LlmError::Network(msg),
+            _ => LlmError::Provider(msg),
+        }
+    }
+}
+
+fn is_context_length_error(body: &str) -> bool {
+    let body = body.to_lowercase();
+    body.contains("context_length_exceeded")
+        || body.contains("maximum context length")
+        || body.contains("prompt is too long")
+}
+
+async fn acquire_permit(
+    req: &LlmChatRequest,
     model: &str,
-    api_key: &str,
-    temperature: f32,
-) -> LLMStreamOut {
-    let mut messages: Vec<ChatMessage> = vec![ChatMessage::system(prompt.to_string())];
+    options: &GenerationOptions,
+) -> LlmPermit {
+    let provider = options
+        .provider
+        .clone()
+        .unwrap_or_else(|| provider_for_model(model));
+    let text_len = req.system.as_ref().map(|s| s.len()).unwrap_or(0)
+        + req.messages.iter().map(|m| m.content.len()).sum::<usize>();
+    let max_tokens = req.max_tokens.or(options.max_tokens);
+    acquire_llm_permit(
+        &provider,
+        model,
+        options.session_id.as_deref(),
+        estimate_tokens(text_len, max_tokens),
+    )
+    .await
+}
+
+fn retry_delay(attempt: u32) -> Duration {
+    Duration::from_millis(RETRY_BASE_DELAY_MS * 2_u64.pow(attempt))
+}
 
-    msgs.iter().for_each(|(role, msg)| match role.as_str() {
-        "user" => {
-            messages.push(ChatMessage::user(msg.clone()));
+// generation parameters beyond the model and the temperature, they can be set per state,
+// `provider` is a built-in provider or one registered in `llm_provider`
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct GenerationOptions {
+    pub provider: Option<String>,
+    pub max_tokens: Option<u32>,
+    pub stop_sequences: Option<Vec<String>>,
+    pub reasoning_effort: Option<String>,
+    // requests of the same session are queued fairly against the other sessions
+    pub session_id: Option<String>,
+    // the requests with a context are recorded in the audit log, see `llm_audit`
+    #[serde(default)]
+    pub audit: Option<LlmAuditContext>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LlmRole {
+    System,
+    User,
+    Assistant,
+    Tool,
+}
+
+// an image given either by URL or as base64 encoded data
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct LlmImage {
+    pub content_type: String,
+    pub url: Option<String>,
+    pub base64: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LlmToolCall {
+    pub call_id: String,
+    pub name: String,
+    pub arguments: Value,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct LlmToolSpec {
+    pub name: String,
+    pub description: Option<String>,
+    pub schema: Option<Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LlmMessage {
+    pub role: LlmRole,
+    pub content: String,
+    #[serde(default)]
+    pub images: Vec<LlmImage>,
+    // for `LlmRole::Tool`, the id of the call this message answers
+    pub tool_call_id: Option<String>,
+    // for `LlmRole::Assistant`, the tool calls requested by the model
+    #[serde(default)]
+    pub tool_calls: Vec<LlmToolCall>,
+}
+
+impl LlmMessage {
+    pub fn new(role: LlmRole, content: impl Into<String>) -> Self {
+        LlmMessage {
+            role,
+            content: content.into(),
+            images: vec![],
+            tool_call_id: None,
+            tool_calls: vec![],
         }
-        "assistant" => {
-            messages.push(ChatMessage::assistant(msg.clone()));
+    }
+
+    pub fn user(content: impl Into<String>) -> Self {
+        Self::new(LlmRole::User, content)
+    }
+
+    pub fn assistant(content: impl Into<String>) -> Self {
+        Self::new(LlmRole::Assistant, content)
+    }
+
+    pub fn tool(call_id: impl Into<String>, content: impl Into<String>) -> Self {
+        LlmMessage {
+            tool_call_id: Some(call_id.into()),
+            ..Self::new(LlmRole::Tool, content)
         }
-        _ => {}
-    });
+    }
+}
 
-    let chat_req = ChatRequest::new(messages);
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct LlmChatRequest {
+    pub system: Option<String>,
+    pub messages: Vec<LlmMessage>,
+    #[serde(default)]
+    pub tools: Vec<LlmToolSpec>,
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<u32>,
+    pub stop: Option<Vec<String>>,
+    #[serde(default)]
+    pub json_mode: bool,
+}
 
-    let model_mapper = ModelMapper::from_mapper_fn(|model_iden: ModelIden| {
-        if model_iden.model_name.starts_with("o3-mini") {
-            Ok(ModelIden::new(AdapterKind::OpenAI, "o3-mini"))
-        } else {
-            Ok(model_iden)
+impl LlmChatRequest {
+    // the request for the `(prompt, [(role, message)], temperature)` form used by the agents,
+    // only the "user" and "assistant" messages are kept
+    pub fn from_prompt(prompt: &str, msgs: &[(String, String)], temperature: Option<f32>) -> Self {
+        let messages = msgs
+            .iter()
+            .filter_map(|(role, msg)| match role.as_str() {
+                "user" => Some(LlmMessage::user(msg.clone())),
+                "assistant" => Some(LlmMessage::assistant(msg.clone())),
+                _ => None,
+            })
+            .collect();
+        LlmChatRequest {
+            system: Some(prompt.to_string()),
+            messages,
+            temperature,
+            ..Default::default()
         }
-    });
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct LlmUsage {
+    pub input_tokens: Option<u32>,
+    pub output_tokens: Option<u32>,
+    pub total_tokens: Option<u32>,
+}
 
-    let api_key = api_key.to_string();
-    let auth_resolver =
-        AuthResolver::from_resolver_fn(|_| Ok(Some(AuthData::from_single(api_key))));
+impl From<MetaUsage> for LlmUsage {
+    fn from(usage: MetaUsage) -> Self {
+        LlmUsage {
+            input_tokens: usage.input_tokens.map(|v| v as u32),
+            output_tokens: usage.output_tokens.map(|v| v as u32),
+            total_tokens: usage.total_tokens.map(|v| v as u32),
+        }
+    }
+}
 
-    let client = Client::builder()
-        .with_auth_resolver(auth_resolver)
-        .with_model_mapper(model_mapper)
-        .build();
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct LlmChatResponse {
+    pub model: String,
+    pub text: String,
+    pub reasoning: Option<String>,
+    pub tool_calls: Vec<LlmToolCall>,
+    pub usage: LlmUsage,
+    // "stop", "length" or "tool_calls", see `finish_reason`
+    pub finish_reason: Option<String>,
+    pub latency_ms: u64,
+}
 
-    let chat_option = if model.starts_with("o3") {
-        ChatOptions::default()
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum LlmStreamChunk {
+    Start,
+    Text(String),
+    Reasoning(String),
+    // `finish_reason` and `latency_ms` as in `LlmChatResponse`, the latency is up to the end of
+    // the stream
+    End {
+        usage: Option<LlmUsage>,
+        finish_reason: Option<String>,
+        latency_ms: Option<u64>,
+    },
+}
+
+// genai does not pass the provider's stop reason through, so it is told from the response:
+// "tool_calls" with tool calls, "length" when the output reached `max_tokens`, "stop" otherwise
+pub fn finish_reason(max_tokens: Option<u32>, usage: &LlmUsage, tool_calls: bool) -> String {
+    if tool_calls {
+        return "tool_calls".into();
+    }
+    match (max_tokens, usage.output_tokens) {
+        (Some(max_tokens), Some(output_tokens)) if output_tokens >= max_tokens => "length".into(),
+        _ => "stop".into(),
+    }
+}
+
+fn get_chat_request(req: &LlmChatRequest) -> ChatRequest {
+    let mut messages: Vec<ChatMessage> = vec![];
+    if let Some(system) = req.system.as_ref() {
+        messages.push(ChatMessage::system(system.clone()));
+    }
+
+    req.messages.iter().for_each(|msg| match msg.role {
+        LlmRole::System => {
+            messages.push(ChatMessage::system(msg.content.clone()));
+        }
+        LlmRole::User if msg.images.is_empty() => {
+            messages.push(ChatMessage::user(msg.content.clone()));
+        }
+        LlmRole::User => {
+            let mut parts = vec![ContentPart::from_text(msg.content.clone())];
+            msg.images.iter().for_each(|image| {
+                if let Some(url) = image.url.as_ref() {
+                    parts.push(ContentPart::from_image_url(
+                        image.content_type.clone(),
+                        url.clone(),
+                    ));
+                } else if let Some(data) = image.base64.as_ref() {
+                    parts.push(ContentPart::from_image_base64(
+                        image.content_type.clone(),
+                        data.clone(),
+                    ));
+                }
+            });
+            messages.push(ChatMessage::user(MessageContent::Parts(parts)));
+        }
+        LlmRole::Assistant if !msg.tool_calls.is_empty() => {
+            let tool_calls = msg
+                .tool_calls
+                .iter()
+                .map(|call| ToolCall {
+                    call_id: call.call_id.clone(),
+                    fn_name: call.name.clone(),
+                    fn_arguments: call.arguments.clone(),
+                })
+                .collect::<Vec<_>>();
+            messages.push(ChatMessage::from(tool_calls));
+        }
+        LlmRole::Assistant => {
+            messages.push(ChatMessage::assistant(msg.content.clone()));
+        }
+        LlmRole::Tool => {
+            let call_id = msg.tool_call_id.clone().unwrap_or_default();
+            messages.push(ChatMessage::from(ToolResponse::new(
+                call_id,
+                msg.content.clone(),
+            )));
+        }
+    });
+
+    let chat_req = ChatRequest::new(messages);
+    if req.tools.is_empty() {
+        chat_req
     } else {
+        let tools = req
+            .tools
+            .iter()
+            .map(|tool| {
+                let mut genai_tool = GenaiTool::new(tool.name.clone());
+                if let Some(description) = tool.description.as_ref() {
+                    genai_tool = genai_tool.with_description(description.clone());
+                }
+                if let Some(schema) = tool.schema.as_ref() {
+                    genai_tool = genai_tool.with_schema(schema.clone());
+                }
+                genai_tool
+            })
+            .collect::<Vec<_>>();
+        chat_req.with_tools(tools)
+    }
+}
+
+// the values in the request take precedence over the ones in `options`
+fn get_chat_options(model: &str, req: &LlmChatRequest, options: &GenerationOptions) -> ChatOptions {
+    // the o3 reasoning models take no temperature, even when they are not in the registry
+    let supports_temperature = model_info(model)
+        .map(|info| info.supports_temperature)
+        .unwrap_or_else(|| !model.starts_with("o3"));
+    let mut chat_option = if supports_temperature {
         ChatOptions {
-            temperature: Some(temperature as f64),
+            temperature: req.temperature.map(|t| t as f64),
             ..Default::default()
         }
+    } else {
+        ChatOptions::default()
     };
+    chat_option.max_tokens = req.max_tokens.or(options.max_tokens);
+    if let Some(stop_sequences) = req.stop.as_ref().or(options.stop_sequences.as_ref()) {
+        chat_option.stop_sequences = stop_sequences.clone();
+    }
+    if req.json_mode {
+        chat_option.response_format = Some(ChatResponseFormat::JsonMode);
+    }
+    chat_option.capture_usage = Some(true);
+    chat_option.reasoning_effort =
+        options
+            .reasoning_effort
+            .as_deref()
+            .and_then(|effort| match effort.to_lowercase().as_str() {
+                "low" => Some(ReasoningEffort::Low),
+                "medium" => Some(ReasoningEffort::Medium),
+                "high" => Some(ReasoningEffort::High),
+                _ => None,
+            });
+    chat_option
+}
+
+fn convert_stream_event(event: ChatStreamEvent) -> LlmStreamChunk {
+    match event {
+        ChatStreamEvent::Start => LlmStreamChunk::Start,
+        ChatStreamEvent::Chunk(StreamChunk { content }) => LlmStreamChunk::Text(content),
+        ChatStreamEvent::ReasoningChunk(StreamChunk { content }) => {
+            LlmStreamChunk::Reasoning(content)
+        }
+        ChatStreamEvent::End(StreamEnd { captured_usage, .. }) => LlmStreamChunk::End {
+            usage: captured_usage.map(LlmUsage::from),
+            finish_reason: None,
+            latency_ms: None,
+        },
+    }
+}
+
+// start the stream and read it up to the first token, so the errors before it can be retried
+async fn start_chat_stream(
+    req: &LlmChatRequest,
+    model: &str,
+    api_key: &str,
+    options: &GenerationOptions,
+) -> Result<LlmChunkStream, LlmError> {
+    let chat_req = get_chat_request(req);
+    let client = get_client(options.provider.as_deref(), api_key)
+        .map_err(|e| LlmError::Provider(e.to_string()))?;
+    let chat_option = get_chat_options(model, req, options);
 
-    let llm_stream = client
-        .exec_chat_stream(model, chat_req.clone(), Some(&chat_option))
+    let mut llm_stream = client
+        .exec_chat_stream(model, chat_req, Some(&chat_option))
         .await
-        .unwrap()
+        .map_err(LlmError::from_genai)?
         .stream;
 
-    let llm_output = StreamExt::then(llm_stream, |result| async {
-        match result {
-            Ok(response) => match response {
-                ChatStreamEvent::Start => Some("".to_string()),
-                ChatStreamEvent::Chunk(StreamChunk { content }) => Some(content.to_string()),
-                ChatStreamEvent::End(_end_event) => None,
-                ChatStreamEvent::ReasoningChunk(StreamChunk { content }) => {
-                    Some(content.to_string())
-                }
-            },
-            Err(_err) => {
-                tracing::info!(target: "log", "LLM stream error");
-                None
-            }
+    let mut head = vec![];
+    while let Some(result) = llm_stream.next().await {
+        let chunk = convert_stream_event(result.map_err(LlmError::from_genai)?);
+        let is_first_token = !matches!(chunk, LlmStreamChunk::Start);
+        head.push(Ok(chunk));
+        if is_first_token {
+            break;
         }
+    }
+
+    let tail = llm_stream.map(|result| {
+        result
+            .map(convert_stream_event)
+            .map_err(LlmError::from_genai)
     });
+    Ok(Box::pin(futures::stream::iter(head).chain(tail)))
+}
+
+// the span ends when the stream is dropped, the usage is recorded with the end of the stream
+#[tracing::instrument(
+    name = "llm_call",
+    skip_all,
+    fields(
+        model = %model,
+        provider = options.provider.as_deref().unwrap_or(""),
+        stream = true,
+        input_tokens = tracing::field::Empty,
+        output_tokens = tracing::field::Empty,
+        total_tokens = tracing::field::Empty,
+        latency_ms = tracing::field::Empty,
+        error = tracing::field::Empty,
+    )
+)]
+pub async fn genai_chat_stream(
+    req: &LlmChatRequest,
+    model: &str,
+    api_key: &str,
+    options: &GenerationOptions,
+) -> LlmChunkStream {
+    let permit = Arc::new(acquire_permit(req, model, options).await);
+    let span = tracing::Span::current();
+    let max_tokens = req.max_tokens.or(options.max_tokens);
+    let start = Instant::now();
+    let mut attempt = 0;
+    loop {
+        match start_chat_stream(req, model, api_key, options).await {
+            Ok(llm_stream) => {
+                // the permit and the span live as long as the stream
+                let model = model.to_string();
+                let llm_stream = llm_stream.then(move |chunk| {
+                    let permit = permit.clone();
+                    let span = span.clone();
+                    let model = model.clone();
+                    async move {
+                        match chunk {
+                            Ok(LlmStreamChunk::End { usage, .. }) => {
+                                let latency_ms = start.elapsed().as_millis() as u64;
+                                span.record("latency_ms", latency_ms);
+                                metrics::record_llm_request(&model, true, latency_ms, usage.as_ref());
+                                if let Some(usage) = usage.as_ref() {
+                                    record_span_usage(&span, usage);
+                                    if let Some(total_tokens) = usage.total_tokens {
+                                        permit.record_usage(total_tokens).await;
+                                    }
+                                }
+                                let finish_reason = finish_reason(
+                                    max_tokens,
+                                    &usage.clone().unwrap_or_default(),
+                                    false,
+                                );
+                                Ok(LlmStreamChunk::End {
+                                    usage,
+                                    finish_reason: Some(finish_reason),
+                                    latency_ms: Some(latency_ms),
+                                })
+                            }
+                            Err(err) => {
+                                span.record("error", tracing::field::display(&err));
+                                metrics::record_llm_error(&model, &err);
+                                Err(err)
+                            }
+                            chunk => chunk,
+                        }
+                    }
+                });
+                return Box::pin(llm_stream);
+            }
+            Err(err) if err.is_transient() && attempt < MAX_RETRIES => {
+                tracing::info!(target: "log", "{}, retry {}/{}", err, attempt + 1, MAX_RETRIES);
+                tokio::time::sleep(retry_delay(attempt)).await;
+                attempt += 1;
+            }
+            Err(err) => {
+                span.record("error", tracing::field::display(&err));
+                metrics::record_llm_error(model, &err);
+                return Box::pin(futures::stream::once(async move { Err(err) }));
+            }
+        }
+    }
+}
 
-    Box::pin(llm_output)
+fn record_span_usage(span: &tracing::Span, usage: &LlmUsage) {
+    if let Some(tokens) = usage.input_tokens {
+        span.record("input_tokens", tokens);
+    }
+    if let Some(tokens) = usage.output_tokens {
+        span.record("output_tokens", tokens);
+    }
+    if let Some(tokens) = usage.total_tokens {
+        span.record("total_tokens", tokens);
+    }
 }
 
-pub async fn genai_service(
-    prompt: &str,
-    msgs: &[(String, String)],
+#[tracing::instrument(
+    name = "llm_call",
+    skip_all,
+    fields(
+        model = %model,
+        provider = options.provider.as_deref().unwrap_or(""),
+        stream = false,
+        input_tokens = tracing::field::Empty,
+        output_tokens = tracing::field::Empty,
+        total_tokens = tracing::field::Empty,
+        latency_ms = tracing::field::Empty,
+        error = tracing::field::Empty,
+    )
+)]
+pub async fn genai_chat(
+    req: &LlmChatRequest,
     model: &str,
     api_key: &str,
-    temperature: f32,
-) -> Result<String, anyhow::Error> {
-    let mut messages: Vec<ChatMessage> = vec![ChatMessage::system(prompt.to_string())];
+    options: &GenerationOptions,
+) -> Result<LlmChatResponse, anyhow::Error> {
+    let chat_req = get_chat_request(req);
+    let client = get_client(options.provider.as_deref(), api_key)?;
+    let chat_option = get_chat_options(model, req, options);
 
-    msgs.iter().for_each(|(role, msg)| match role.as_str() {
-        "user" => {
-            messages.push(ChatMessage::user(msg.clone()));
-        }
-        "assistant" => {
-            messages.push(ChatMessage::assistant(msg.clone()));
+    let permit = acquire_permit(req, model, options).await;
+    let start = Instant::now();
+    let mut attempt = 0;
+    let llm_output = loop {
+        match client
+            .exec_chat(model, chat_req.clone(), Some(&chat_option))
+            .await
+            .map_err(LlmError::from_genai)
+        {
+            Ok(llm_output) => break llm_output,
+            Err(err) if err.is_transient() && attempt < MAX_RETRIES => {
+                tracing::info!(target: "log", "{}, retry {}/{}", err, attempt + 1, MAX_RETRIES);
+                tokio::time::sleep(retry_delay(attempt)).await;
+                attempt += 1;
+            }
+            Err(err) => {
+                tracing::Span::current().record("error", tracing::field::display(&err));
+                metrics::record_llm_error(model, &err);
+                return Err(err.into());
+            }
         }
-        _ => {}
-    });
-
-    let chat_req = ChatRequest::new(messages);
+    };
+    // tracing::info!(target: "tron_app", "in genai_service, llm_output: {:?}", llm_output );
 
-    let model_mapper = ModelMapper::from_mapper_fn(|model_iden: ModelIden| {
-        if model_iden.model_name.starts_with("o3-mini") {
-            Ok(ModelIden::new(AdapterKind::OpenAI, "o3-mini"))
-        } else {
-            Ok(model_iden)
+    let mut response = LlmChatResponse {
+        model: model.to_string(),
+        reasoning: llm_output.reasoning_content,
+        usage: llm_output.usage.into(),
+        latency_ms: start.elapsed().as_millis() as u64,
+        ..Default::default()
+    };
+    let span = tracing::Span::current();
+    span.record("latency_ms", response.latency_ms);
+    record_span_usage(&span, &response.usage);
+    metrics::record_llm_request(model, false, response.latency_ms, Some(&response.usage));
+    if let Some(total_tokens) = response.usage.total_tokens {
+        permit.record_usage(total_tokens).await;
+    }
+    match llm_output.content {
+        Some(MessageContent::Text(text)) => response.text = text,
+        Some(MessageContent::ToolCalls(tool_calls)) => {
+            response.tool_calls = tool_calls
+                .into_iter()
+                .map(|call| LlmToolCall {
+                    call_id: call.call_id,
+                    name: call.fn_name,
+                    arguments: call.fn_arguments,
+                })
+                .collect();
         }
-    });
+        _ => return Err(anyhow::anyhow!("No content text in LLM output")),
+    }
+    response.finish_reason = Some(finish_reason(
+        chat_option.max_tokens,
+        &response.usage,
+        !response.tool_calls.is_empty(),
+    ));
+    Ok(response)
+}
 
-    let api_key = api_key.to_string();
-    let auth_resolver =
-        AuthResolver::from_resolver_fn(|_| Ok(Some(AuthData::from_single(api_key))));
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let client = Client::builder()
-        .with_auth_resolver(auth_resolver)
-        .with_model_mapper(model_mapper)
-        .build();
+    #[test]
+    fn test_transient_errors() {
+        assert!(LlmError::RateLimit("429".into()).is_transient());
+        assert!(LlmError::Network("timeout".into()).is_transient());
+        assert!(!LlmError::Auth("401".into()).is_transient());
+        assert!(!LlmError::ContextLength("too long".into()).is_transient());
+        assert_eq!(retry_delay(0), Duration::from_millis(500));
+        assert_eq!(retry_delay(2), Duration::from_millis(2000));
+    }
 
-    let chat_option = if model.starts_with("o3") {
-        ChatOptions::default()
-    } else {
-        ChatOptions {
-            temperature: Some(temperature as f64),
+    #[test]
+    fn test_finish_reason() {
+        let usage = LlmUsage {
+            output_tokens: Some(256),
             ..Default::default()
-        }
-    };
-
-    let llm_output = client
-        .exec_chat(model, chat_req.clone(), Some(&chat_option))
-        .await;
-    // tracing::info!(target: "tron_app", "in genai_service, llm_output: {:?}", llm_output );
+        };
+        assert_eq!(finish_reason(None, &usage, false), "stop");
+        assert_eq!(finish_reason(Some(1024), &usage, false), "stop");
+        assert_eq!(finish_reason(Some(256), &usage, false), "length");
+        assert_eq!(finish_reason(Some(256), &usage, true), "tool_calls");
+        assert_eq!(finish_reason(Some(256), &LlmUsage::default(), false), "stop");
+    }
 
-    llm_output
-        .map_err(|e| anyhow::anyhow!("LLM output error: {}", e))
-        .and_then(|output| {
-            output
-                .content_text_into_string()
-                .ok_or_else(|| anyhow::anyhow!("No content text in LLM output"))
-        })
+    #[test]
+    fn test_error_status() {
+        let error = |status: u16, body: &str| LlmError::from_status(status, body, String::new());
+        assert_eq!(error(401, ""), LlmError::Auth(String::new()));
+        assert_eq!(error(429, ""), LlmError::RateLimit(String::new()));
+        assert_eq!(
+            error(400, r#"{"error": {"code": "context_length_exceeded"}}"#),
+            LlmError::ContextLength(String::new())
+        );
+        assert_eq!(error(400, "invalid temperature"), LlmError::Provider(String::new()));
+        assert!(error(503, "").is_transient());
+        // a 429 mentioned in the body of another error is not a rate limit
+        assert_eq!(error(404, "model gpt-429 not found"), LlmError::Provider(String::new()));
+    }
 }
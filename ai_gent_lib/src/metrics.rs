@@ -0,0 +1,113 @@
+// Prometheus metrics of the LLM requests and the agents, registered in the default registry,
+// which the web app serves at `/api/metrics`. The models not in the model registry are counted
+// as `other`.
+
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, HistogramVec, IntCounterVec,
+};
+
+use crate::llm_service::{LlmError, LlmUsage};
+use crate::model_registry::model_info;
+
+// the `model` label of the models not in the model registry, so the names sent by the clients
+// (e.g. through the OpenAI-compatible API) don't make new series
+pub const OTHER_MODEL_LABEL: &str = "other";
+
+// seconds, the streamed requests last until their last token
+const LLM_LATENCY_BUCKETS: &[f64] = &[0.25, 0.5, 1.0, 2.0, 4.0, 8.0, 15.0, 30.0, 60.0, 120.0];
+
+pub static LLM_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "ai_gent_llm_requests_total",
+        "LLM requests by model and status (ok or error)",
+        &["model", "status"]
+    )
+    .unwrap()
+});
+
+pub static LLM_REQUEST_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "ai_gent_llm_request_duration_seconds",
+        "Latency of the LLM requests by model, up to the end of the stream for the streamed ones",
+        &["model", "stream"],
+        LLM_LATENCY_BUCKETS.to_vec()
+    )
+    .unwrap()
+});
+
+pub static LLM_TOKENS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "ai_gent_llm_tokens_total",
+        "Tokens of the LLM requests by model and kind (input or output)",
+        &["model", "kind"]
+    )
+    .unwrap()
+});
+
+pub static STATE_TRANSITIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "ai_gent_state_transitions_total",
+        "FSM state transitions by agent and the state transitioned to",
+        &["agent", "state"]
+    )
+    .unwrap()
+});
+
+pub static ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!("ai_gent_errors_total", "Errors by kind", &["kind"]).unwrap()
+});
+
+// the registered name of the model, e.g. "o3-mini" for "o3-mini-high"
+pub fn model_label(model: &str) -> String {
+    model_info(model)
+        .map(|info| info.name)
+        .unwrap_or_else(|| OTHER_MODEL_LABEL.to_string())
+}
+
+pub fn record_llm_request(model: &str, stream: bool, latency_ms: u64, usage: Option<&LlmUsage>) {
+    let model = model_label(model);
+    let model = model.as_str();
+    LLM_REQUESTS.with_label_values(&[model, "ok"]).inc();
+    LLM_REQUEST_DURATION
+        .with_label_values(&[model, if stream { "true" } else { "false" }])
+        .observe(latency_ms as f64 / 1000.0);
+    if let Some(usage) = usage {
+        if let Some(tokens) = usage.input_tokens {
+            LLM_TOKENS.with_label_values(&[model, "input"]).inc_by(tokens as u64);
+        }
+        if let Some(tokens) = usage.output_tokens {
+            LLM_TOKENS.with_label_values(&[model, "output"]).inc_by(tokens as u64);
+        }
+    }
+}
+
+// after the retries
+pub fn record_llm_error(model: &str, err: &LlmError) {
+    LLM_REQUESTS
+        .with_label_values(&[model_label(model).as_str(), "error"])
+        .inc();
+    record_error(err.kind());
+}
+
+pub fn record_state_transition(agent: Option<&str>, state: &str) {
+    STATE_TRANSITIONS
+        .with_label_values(&[agent.unwrap_or(""), state])
+        .inc();
+}
+
+pub fn record_error(kind: &str) {
+    ERRORS.with_label_values(&[kind]).inc();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_model_label() {
+        assert_eq!(model_label("gpt-4o"), "gpt-4o");
+        assert_eq!(model_label("o3-mini-high"), "o3-mini");
+        assert_eq!(model_label("my-finetune-123"), OTHER_MODEL_LABEL);
+    }
+}
@@ -0,0 +1,183 @@
+// The registry of the models the agents can use and what they support.
+//
+// The built-in list is `models.toml` at the root of this crate. A deployment can replace it with
+// its own file (see `load_models_file`), so adding a model does not need code changes:
+//
+//   [providers.local]               # see `llm_provider` for the settings
+//   base_url = "http://localhost:11434/v1/"
+//
+//   [rate_limits.openai]            # see `llm_rate_limit`
+//   requests_per_minute = 500
+//
+//   [[models]]
+//   name = "llama3.2"
+//   provider = "local"              # a built-in provider or one declared in `[providers]`
+//   api_key_env = "LOCAL_LLM_KEY"   # optional
+//   context_length = 128000
+//   supports_temperature = true     # the capabilities default to true for temperature and
+//   supports_streaming = true       # streaming, false for tools and JSON mode
+//   supports_tools = false
+//   supports_json_mode = false
+//   input_price = 0.0               # USD per million tokens
+//   output_price = 0.0
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::RwLock;
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::llm_provider::{register_providers, ProviderConfig};
+use crate::llm_rate_limit::{register_rate_limits, RateLimits};
+use crate::llm_service::LlmUsage;
+
+const BUILTIN_MODELS: &str = include_str!("../models.toml");
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ModelInfo {
+    pub name: String,
+    pub provider: String,
+    pub api_key_env: Option<String>,
+    pub context_length: Option<u32>,
+    #[serde(default = "default_true")]
+    pub supports_temperature: bool,
+    #[serde(default = "default_true")]
+    pub supports_streaming: bool,
+    #[serde(default)]
+    pub supports_tools: bool,
+    #[serde(default)]
+    pub supports_json_mode: bool,
+    pub input_price: Option<f64>,
+    pub output_price: Option<f64>,
+}
+
+impl ModelInfo {
+    // the cost in USD of a request, `None` if the price or the usage is unknown
+    pub fn cost(&self, usage: &LlmUsage) -> Option<f64> {
+        let input = usage.input_tokens? as f64 * self.input_price? / 1_000_000.0;
+        let output = usage.output_tokens? as f64 * self.output_price? / 1_000_000.0;
+        Some(input + output)
+    }
+
+    // the API key from `api_key_env`, or an empty key for the models that do not need one
+    pub fn api_key(&self) -> Result<String, anyhow::Error> {
+        match self.api_key_env.as_ref() {
+            Some(env_name) => std::env::var(env_name).map_err(|_| {
+                anyhow::anyhow!(
+                    "Environment variable {} not set for the model {}",
+                    env_name,
+                    self.name
+                )
+            }),
+            None => Ok(String::default()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct ModelsFile {
+    #[serde(default)]
+    providers: HashMap<String, ProviderConfig>,
+    #[serde(default)]
+    rate_limits: HashMap<String, RateLimits>,
+    models: Vec<ModelInfo>,
+}
+
+fn parse_models(toml_str: &str) -> Result<Vec<ModelInfo>, anyhow::Error> {
+    let models_file: ModelsFile = toml::from_str(toml_str)?;
+    Ok(models_file.models)
+}
+
+static MODELS: Lazy<RwLock<Vec<ModelInfo>>> = Lazy::new(|| {
+    RwLock::new(parse_models(BUILTIN_MODELS).expect("the built-in models.toml is valid"))
+});
+
+// replace the registry with the models in the file and register its providers and rate limits
+pub fn load_models_file<P: AsRef<Path>>(path: P) -> Result<(), anyhow::Error> {
+    let path = path.as_ref();
+    let toml_str = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("fail to open models file {}: {}", path.display(), e))?;
+    let models_file: ModelsFile = toml::from_str(&toml_str)?;
+    register_providers(&models_file.providers)?;
+    register_rate_limits(&models_file.rate_limits);
+    *MODELS.write().unwrap() = models_file.models;
+    Ok(())
+}
+
+pub fn register_models(models: Vec<ModelInfo>) {
+    let mut registry = MODELS.write().unwrap();
+    for model in models {
+        match registry.iter_mut().find(|m| m.name == model.name) {
+            Some(m) => *m = model,
+            None => registry.push(model),
+        }
+    }
+}
+
+pub fn list_models() -> Vec<ModelInfo> {
+    MODELS.read().unwrap().clone()
+}
+
+// an exact match, or the longest registered name the model name starts with
+// (e.g. "o3-mini-high" is "o3-mini")
+pub fn model_info(model: &str) -> Option<ModelInfo> {
+    let registry = MODELS.read().unwrap();
+    registry
+        .iter()
+        .find(|m| m.name == model)
+        .or_else(|| {
+            registry
+                .iter()
+                .filter(|m| model.starts_with(&m.name))
+                .max_by_key(|m| m.name.len())
+        })
+        .cloned()
+}
+
+pub fn model_api_key(model: &str) -> Result<String, anyhow::Error> {
+    model_info(model)
+        .ok_or_else(|| anyhow::anyhow!("Model {} is not in the model registry", model))?
+        .api_key()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_model_registry() {
+        let o3 = model_info("o3-mini-high").unwrap();
+        assert_eq!(o3.name, "o3-mini");
+        assert!(!o3.supports_temperature);
+        assert_eq!(model_info("o3-2025-04-16").unwrap().name, "o3");
+        assert!(!model_info("o3-pro").unwrap().supports_temperature);
+        assert_eq!(model_info("gpt-4o").unwrap().provider, "openai");
+        assert!(model_info("not-a-model").is_none());
+
+        register_models(vec![ModelInfo {
+            name: "test-local-model".into(),
+            provider: "ollama".into(),
+            api_key_env: None,
+            context_length: None,
+            supports_temperature: true,
+            supports_streaming: true,
+            supports_tools: false,
+            supports_json_mode: false,
+            input_price: Some(1.0),
+            output_price: Some(2.0),
+        }]);
+        let local = model_info("test-local-model").unwrap();
+        assert_eq!(local.api_key().unwrap(), "");
+        let usage = LlmUsage {
+            input_tokens: Some(1_000_000),
+            output_tokens: Some(500_000),
+            total_tokens: None,
+        };
+        assert_eq!(local.cost(&usage), Some(2.0));
+    }
+}
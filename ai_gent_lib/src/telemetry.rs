@@ -0,0 +1,116 @@
+// Exporting the tracing spans of the agents over OTLP, e.g. to a local OpenTelemetry collector
+// or Jaeger:
+//
+//   docker run --rm -p 16686:16686 -p 4318:4318 jaegertracing/all-in-one
+//   OTEL_EXPORTER_OTLP_ENDPOINT=http://localhost:4318 fsm_agent -c dev_config/rag.toml
+//
+// The spans are
+//
+//   agent_turn            a message processed by an agent (session_id, model, states, latency_ms)
+//     agent_state         a state's run (state, latency_ms)
+//       llm_call          an LLM request (model, provider, input/output/total_tokens, latency_ms)
+//       code_execution    a docker run of `code` or `fsm_code` (kind, exit_code, latency_ms)
+//
+// and `agent_query` for a chat message in the web app (chat_id, agent_id, user_id, model). The
+// export is over OTLP/HTTP, it is enabled by `OTEL_EXPORTER_OTLP_ENDPOINT` (or
+// `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT`), and `RUST_LOG` filters the spans (info by default).
+
+use std::future::Future;
+use std::sync::OnceLock;
+
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::SpanExporter;
+use opentelemetry_sdk::{runtime, trace::TracerProvider, Resource};
+use tracing::instrument::{WithDispatch, WithSubscriber};
+use tracing::Dispatch;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+static TRACE_DISPATCH: OnceLock<Dispatch> = OnceLock::new();
+
+pub fn otlp_enabled() -> bool {
+    ["OTEL_EXPORTER_OTLP_ENDPOINT", "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT"]
+        .iter()
+        .any(|name| std::env::var(name).is_ok_and(|v| !v.is_empty()))
+}
+
+// flushes the spans not exported yet when dropped, keep it until the program ends
+pub struct TelemetryGuard {
+    provider: TracerProvider,
+}
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        if let Err(e) = self.provider.shutdown() {
+            eprintln!("fail to export the remaining spans: {}", e);
+        }
+    }
+}
+
+// the service name is `OTEL_SERVICE_NAME` if it is set
+fn otlp_provider(service_name: &str) -> Result<(TracerProvider, String), anyhow::Error> {
+    let service_name = std::env::var("OTEL_SERVICE_NAME").unwrap_or(service_name.to_string());
+    // the endpoint comes from the OTEL_EXPORTER_OTLP_* environment variables
+    let exporter = SpanExporter::builder().with_http().build()?;
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_resource(Resource::new(vec![KeyValue::new(
+            "service.name",
+            service_name.clone(),
+        )]))
+        .build();
+    Ok((provider, service_name))
+}
+
+fn env_filter() -> EnvFilter {
+    EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"))
+}
+
+// Install a global tracing subscriber exporting the spans, `None` if the export is not
+// enabled. `with_fmt` also prints the logs to stderr.
+pub fn init_tracing(
+    service_name: &str,
+    with_fmt: bool,
+) -> Result<Option<TelemetryGuard>, anyhow::Error> {
+    if !otlp_enabled() {
+        return Ok(None);
+    }
+    let (provider, service_name) = otlp_provider(service_name)?;
+    let tracer = provider.tracer(service_name);
+    let fmt_layer = with_fmt.then(|| tracing_subscriber::fmt::layer().with_writer(std::io::stderr));
+    tracing_subscriber::registry()
+        .with(env_filter())
+        .with(fmt_layer)
+        .with(tracing_opentelemetry::layer().with_tracer(tracer))
+        .try_init()?;
+    Ok(Some(TelemetryGuard { provider }))
+}
+
+// For a program with its own global subscriber (e.g. the web app, where tron_app sets it up):
+// the spans of the futures run with `traced` are exported, the others are left to the global
+// subscriber. The dispatch replaces the global subscriber within those futures, so it prints
+// their logs to stderr too.
+pub fn init_trace_dispatch(service_name: &str) -> Result<Option<TelemetryGuard>, anyhow::Error> {
+    if !otlp_enabled() {
+        return Ok(None);
+    }
+    let (provider, service_name) = otlp_provider(service_name)?;
+    let tracer = provider.tracer(service_name);
+    let subscriber = tracing_subscriber::registry()
+        .with(env_filter())
+        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
+        .with(tracing_opentelemetry::layer().with_tracer(tracer));
+    TRACE_DISPATCH
+        .set(Dispatch::new(subscriber))
+        .map_err(|_| anyhow::anyhow!("the trace dispatch is already set"))?;
+    Ok(Some(TelemetryGuard { provider }))
+}
+
+// run a future with the exporting dispatch of `init_trace_dispatch`, or the current one
+pub fn traced<F: Future>(future: F) -> WithDispatch<F> {
+    let dispatch = TRACE_DISPATCH
+        .get()
+        .cloned()
+        .unwrap_or_else(|| tracing::dispatcher::get_default(Dispatch::clone));
+    future.with_subscriber(dispatch)
+}
@@ -0,0 +1,180 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::time::Duration;
+
+use ai_gent_lib::eval_grading::{grade_result, graders_from_config, result_passed, GradingConfig};
+use ai_gent_lib::evaluation::{
+    load_dataset, result_csv_record, run_eval_item, EvalResult, EvalSummary, RESULT_CSV_COLUMNS,
+};
+use ai_gent_lib::fsm_chat_state::FSMChatState;
+use ai_gent_lib::llm_agent::{LlmFsmAgent, LlmFsmAgentConfigBuilder};
+use ai_gent_lib::llm_provider::{provider_api_key, provider_for_model, provider_key_env_name};
+use ai_gent_lib::telemetry::init_tracing;
+use anyhow::{anyhow, Context, Result};
+use clap::Parser;
+use futures::StreamExt;
+
+#[derive(Parser)]
+#[command(
+    name = "AI-Gent Batch Evaluation",
+    version = "0.1",
+    about = "Run an agent over a JSONL dataset of tasks or questions"
+)]
+struct Cli {
+    /// Path to the agent config
+    #[arg(short, long)]
+    config_file: String,
+
+    /// The JSONL dataset, one `{"id", "input", "task", "reference"}` item per line
+    #[arg(short, long)]
+    dataset: String,
+
+    /// The results, one JSON line per item
+    #[arg(short, long, default_value = "eval_results.jsonl")]
+    output: String,
+
+    /// Also write the results as CSV
+    #[arg(long)]
+    csv: Option<String>,
+
+    /// The model if the config does not set one
+    #[arg(short, long, default_value = "gpt-4o")]
+    model: String,
+
+    /// The number of items run at the same time
+    #[arg(short = 'j', long, default_value_t = 4)]
+    concurrency: usize,
+
+    #[arg(short, long)]
+    temperature: Option<f32>,
+
+    /// The time limit of an item in seconds
+    #[arg(long, default_value_t = 300)]
+    timeout: u64,
+
+    /// Only run the first items of the dataset
+    #[arg(long)]
+    limit: Option<usize>,
+
+    /// Grade each result with the graders of this TOML file
+    #[arg(short, long)]
+    graders: Option<String>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Cli::parse();
+    // the spans are exported when OTEL_EXPORTER_OTLP_ENDPOINT is set
+    let _telemetry = init_tracing("batch_eval", false)?;
+
+    let fsm_config = LlmFsmAgentConfigBuilder::from_toml_file(&args.config_file)?.build()?;
+    let mut items = load_dataset(&args.dataset)?;
+    if let Some(limit) = args.limit {
+        items.truncate(limit);
+    }
+
+    let model = fsm_config.model.clone().unwrap_or(args.model.clone());
+    let provider = fsm_config
+        .provider
+        .clone()
+        .unwrap_or_else(|| provider_for_model(&model));
+    let api_key = match (provider_api_key(&provider), provider_key_env_name(&provider)) {
+        (Some(api_key), _) => api_key,
+        (None, Some(env_name)) => return Err(anyhow!("environment variable {} is not set", env_name)),
+        (None, None) => "".into(),
+    };
+
+    let graders = match args.graders.as_ref() {
+        Some(path) => graders_from_config(&GradingConfig::from_toml_file(path)?, &model)?,
+        None => vec![],
+    };
+
+    let mut output = BufWriter::new(
+        File::create(&args.output).context(format!("Failed to create {}", args.output))?,
+    );
+    let n_items = items.len();
+    let timeout = Some(Duration::from_secs(args.timeout));
+    let temperature = args.temperature;
+    println!("running {} items of {} with {}", n_items, args.dataset, model);
+
+    let mut runs = futures::stream::iter(items.into_iter().enumerate())
+        .map(|(index, item)| {
+            // a new agent for each item, so the items do not share messages or memory
+            let agent = LlmFsmAgent::from_config::<FSMChatState>(
+                &fsm_config,
+                &model,
+                Some(provider.clone()),
+                &api_key,
+            );
+            async move {
+                match agent {
+                    Ok(agent) => run_eval_item(agent, index, item, temperature, timeout).await,
+                    Err(e) => EvalResult {
+                        index,
+                        id: item.id.unwrap_or_else(|| index.to_string()),
+                        input: item.input,
+                        reference: item.reference,
+                        errors: vec![e.to_string()],
+                        ..Default::default()
+                    },
+                }
+            }
+        })
+        .buffered(args.concurrency.max(1));
+
+    let mut results = Vec::<EvalResult>::with_capacity(n_items);
+    while let Some(mut result) = runs.next().await {
+        grade_result(&graders, &mut result).await;
+        println!(
+            "[{}/{}] {}: {} ({} ms, {} tokens)",
+            result.index + 1,
+            n_items,
+            result.id,
+            if result.failed() {
+                "failed"
+            } else if !result_passed(&result) {
+                "graded as failed"
+            } else {
+                "ok"
+            },
+            result.latency_ms,
+            result.total_tokens
+        );
+        writeln!(output, "{}", serde_json::to_string(&result)?)?;
+        results.push(result);
+    }
+    output.flush()?;
+
+    if let Some(csv_path) = args.csv.as_ref() {
+        let mut writer = csv::Writer::from_path(csv_path)
+            .context(format!("Failed to create {}", csv_path))?;
+        writer.write_record(RESULT_CSV_COLUMNS)?;
+        for result in results.iter() {
+            writer.write_record(result_csv_record(result))?;
+        }
+        writer.flush()?;
+    }
+
+    let summary = EvalSummary::from_results(&results);
+    println!("\n========== Summary ==========");
+    println!("items: {}, failed: {}", summary.items, summary.failed);
+    if !graders.is_empty() {
+        let passed = results.iter().filter(|r| result_passed(r)).count();
+        println!("passed the graders: {}/{}", passed, summary.items);
+    }
+    println!(
+        "tokens: {} in total, {:.0} per item",
+        summary.total_tokens, summary.mean_tokens
+    );
+    println!(
+        "latency: mean {:.0} ms, p50 {} ms, p95 {} ms",
+        summary.mean_latency_ms, summary.p50_latency_ms, summary.p95_latency_ms
+    );
+    let mut final_states = summary.final_states.iter().collect::<Vec<_>>();
+    final_states.sort_by(|a, b| b.1.cmp(a.1));
+    for (state, count) in final_states {
+        println!("final state {}: {}", state, count);
+    }
+    println!("results: {}", args.output);
+    Ok(())
+}
@@ -0,0 +1,79 @@
+use anyhow::{anyhow, Context, Result};
+use clap::Parser;
+use sha2::{Digest, Sha256};
+use sqlx::postgres::PgPool;
+use uuid::Uuid;
+
+#[derive(Parser)]
+#[command(
+    name = "AI-Gent API Token",
+    version = "0.1",
+    about = "Create or revoke a token of the web app's JSON API"
+)]
+struct Cli {
+    /// The user the requests with the token act on behalf of
+    #[arg(short, long)]
+    username: String,
+
+    /// A name to tell the tokens of a user apart
+    #[arg(short, long, default_value = "default")]
+    name: String,
+
+    /// Revoke the tokens of the user with this name instead of creating one
+    #[arg(long)]
+    revoke: bool,
+
+    /// Database URL
+    #[arg(short, long, env("DATABASE_URL"))]
+    database_url: String,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+
+    let pool = PgPool::connect(&cli.database_url)
+        .await
+        .context("Failed to connect to the database")?;
+
+    if cli.revoke {
+        let revoked = sqlx::query(
+            r#"UPDATE api_tokens t SET status = 'revoked'
+            FROM users u
+            WHERE t.user_id = u.user_id AND u.username = $1 AND t.name = $2 AND t.status = 'active'"#,
+        )
+        .bind(&cli.username)
+        .bind(&cli.name)
+        .execute(&pool)
+        .await
+        .context("Failed to revoke the tokens")?;
+        println!("{} token(s) revoked", revoked.rows_affected());
+        return Ok(());
+    }
+
+    // only the hash is stored, the token is shown once
+    let token = format!(
+        "aig-{}{}",
+        Uuid::new_v4().simple(),
+        Uuid::new_v4().simple()
+    );
+    let token_hash = format!("{:x}", Sha256::digest(token.as_bytes()));
+    let inserted = sqlx::query(
+        r#"INSERT INTO api_tokens (user_id, name, token_hash)
+        SELECT user_id, $2, $3
+        FROM users
+        WHERE username = $1"#,
+    )
+    .bind(&cli.username)
+    .bind(&cli.name)
+    .bind(&token_hash)
+    .execute(&pool)
+    .await
+    .context("Failed to store the token")?;
+    if inserted.rows_affected() == 0 {
+        return Err(anyhow!("the user `{}` does not exist", cli.username));
+    }
+
+    println!("{}", token);
+    Ok(())
+}
@@ -0,0 +1,121 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use ai_gent_lib::eval_grading::{
+    compare_runs, grade_result, graders_from_config, result_passed, GradingConfig,
+};
+use ai_gent_lib::evaluation::load_results;
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
+
+#[derive(Parser)]
+#[command(
+    name = "AI-Gent Evaluation Report",
+    version = "0.1",
+    about = "Grade the results of batch_eval and compare two runs"
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Grade (again) the results of a run
+    Grade {
+        /// The results of batch_eval
+        #[arg(short, long)]
+        results: String,
+
+        /// The TOML file of the graders
+        #[arg(short, long)]
+        graders: String,
+
+        /// The graded results, one JSON line per item
+        #[arg(short, long, default_value = "graded_results.jsonl")]
+        output: String,
+
+        /// The judge model of the graders that do not set one
+        #[arg(short, long, default_value = "gpt-4o")]
+        model: String,
+    },
+    /// Compare a candidate run with a baseline run and list the regressions
+    Compare {
+        /// The graded results of the baseline run
+        #[arg(short, long)]
+        baseline: String,
+
+        /// The graded results of the candidate run
+        #[arg(short, long)]
+        candidate: String,
+
+        /// The score drop of an item counted as a regression
+        #[arg(short, long, default_value_t = 0.1)]
+        tolerance: f32,
+
+        /// Write the Markdown report to a file instead of the standard output
+        #[arg(short, long)]
+        output: Option<String>,
+
+        /// Also write the comparison as JSON
+        #[arg(long)]
+        json: Option<String>,
+
+        /// Exit with an error if an item regressed, e.g. in CI
+        #[arg(long)]
+        fail_on_regression: bool,
+    },
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Cli::parse();
+    match args.command {
+        Command::Grade {
+            results,
+            graders,
+            output,
+            model,
+        } => {
+            let graders = graders_from_config(&GradingConfig::from_toml_file(&graders)?, &model)?;
+            let mut results = load_results(&results)?;
+            let mut writer =
+                BufWriter::new(File::create(&output).context(format!("Failed to create {}", output))?);
+            for result in results.iter_mut() {
+                grade_result(&graders, result).await;
+                writeln!(writer, "{}", serde_json::to_string(result)?)?;
+            }
+            writer.flush()?;
+            let passed = results.iter().filter(|r| result_passed(r)).count();
+            println!("passed: {}/{}, graded results: {}", passed, results.len(), output);
+        }
+        Command::Compare {
+            baseline,
+            candidate,
+            tolerance,
+            output,
+            json,
+            fail_on_regression,
+        } => {
+            let comparison =
+                compare_runs(&load_results(&baseline)?, &load_results(&candidate)?, tolerance);
+            let report = comparison.to_markdown();
+            match output {
+                Some(path) => {
+                    std::fs::write(&path, report).context(format!("Failed to write {}", path))?;
+                    println!("report: {}", path);
+                }
+                None => println!("{}", report),
+            }
+            if let Some(path) = json {
+                std::fs::write(&path, serde_json::to_string_pretty(&comparison)?)
+                    .context(format!("Failed to write {}", path))?;
+            }
+            let regressions = comparison.regressions().count();
+            if fail_on_regression && regressions > 0 {
+                anyhow::bail!("{} items regressed", regressions);
+            }
+        }
+    }
+    Ok(())
+}
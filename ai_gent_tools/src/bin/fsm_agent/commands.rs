@@ -0,0 +1,186 @@
+// The slash commands of the REPL, for inspecting and changing the agent between messages.
+// They are sent to the agent's message service as control inputs, which answer with a snapshot
+// of the agent or an error.
+
+use ai_gent_lib::llm_agent::AgentSnapshot;
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+
+const DEFAULT_SESSION_FILE: &str = "fsm_agent_session.json";
+
+pub const HELP: &str = "\
+/state                    the current state
+/transitions              the states the current state can go to
+/history                  the states and the messages so far
+/memory [slot]            the memory slots, or the content of one
+/goto <State>             go to a state, even without a transition to it
+/set <slot> <value>       set a memory slot, the value is JSON or text
+/clear messages|memory    clear the messages or the memory
+/save [file]              save the session (fsm_agent_session.json by default)
+/load [file]              load a saved session
+/model [model [provider]] show or switch the model
+/break [State|*]          pause before a state, or list the breakpoints
+/unbreak [State]          remove a breakpoint, or all of them
+/help                     this help";
+
+pub struct Agent<'a> {
+    pub send_msg: &'a mpsc::Sender<(String, String)>,
+    pub fsm_rx: &'a mut mpsc::Receiver<(String, String, String)>,
+}
+
+impl Agent<'_> {
+    async fn control(&mut self, msg_type: &str, msg: String) -> Result<AgentSnapshot, String> {
+        self.send_msg
+            .send((msg_type.into(), msg))
+            .await
+            .map_err(|_| "the agent has stopped".to_string())?;
+        while let Some((_, t, content)) = self.fsm_rx.recv().await {
+            match t.as_str() {
+                "snapshot" => return serde_json::from_str(&content).map_err(|e| e.to_string()),
+                "error" => return Err(content),
+                _ => {}
+            }
+        }
+        Err("the agent has stopped".into())
+    }
+
+    async fn snapshot(&mut self) -> Result<AgentSnapshot, String> {
+        self.control("snapshot", "".into()).await
+    }
+}
+
+// run a command line starting with `/`
+pub async fn run_command(agent: &mut Agent<'_>, line: &str) -> Result<(), String> {
+    let line = line.trim();
+    let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let rest = rest.trim();
+    let args = rest.split_whitespace().collect::<Vec<_>>();
+
+    match command {
+        "/help" => println!("{}", HELP),
+        "/state" => {
+            let snapshot = agent.snapshot().await?;
+            println!("state: {}", snapshot.fsm_state.unwrap_or("-".into()));
+        }
+        "/transitions" => {
+            let snapshot = agent.snapshot().await?;
+            let state = snapshot.fsm_state.unwrap_or("-".into());
+            if snapshot.transitions.is_empty() {
+                println!("no transition from {}", state);
+            } else {
+                println!("{} -> {}", state, snapshot.transitions.join(", "));
+            }
+        }
+        "/history" => {
+            let snapshot = agent.snapshot().await?;
+            println!("states: {}", snapshot.state_history.join(" > "));
+            if let Some(task) = snapshot.task {
+                println!("task: {}", task);
+            }
+            for (role, message) in snapshot.messages {
+                println!("\n[{}]\n{}", role, message);
+            }
+        }
+        "/memory" => {
+            let snapshot = agent.snapshot().await?;
+            match args.first() {
+                Some(slot) => match snapshot.memory.get(*slot) {
+                    Some(values) => {
+                        for value in values {
+                            match value {
+                                Value::String(s) => println!("{}", s),
+                                value => println!("{}", serde_json::to_string_pretty(value).unwrap()),
+                            }
+                        }
+                    }
+                    None => return Err(format!("no memory slot `{}`", slot)),
+                },
+                None => {
+                    let mut slots = snapshot.memory.iter().collect::<Vec<_>>();
+                    slots.sort_by(|a, b| a.0.cmp(b.0));
+                    if slots.is_empty() {
+                        println!("the memory is empty");
+                    }
+                    for (slot, values) in slots {
+                        println!("{}: {} item(s)", slot, values.len());
+                    }
+                }
+            }
+        }
+        "/goto" => {
+            let state = args.first().ok_or("usage: /goto <State>")?;
+            let snapshot = agent.control("goto", state.to_string()).await?;
+            println!("state: {}", snapshot.fsm_state.unwrap_or("-".into()));
+        }
+        "/set" => {
+            let (slot, value) = rest.split_once(char::is_whitespace).ok_or("usage: /set <slot> <value>")?;
+            let value = value.trim();
+            let value = serde_json::from_str::<Value>(value).unwrap_or(Value::from(value));
+            agent
+                .control("set_memory", json!({ "slot": slot, "value": value }).to_string())
+                .await?;
+            println!("{} is set", slot);
+        }
+        "/clear" => {
+            let input = match args.first().copied() {
+                Some("messages") => "clear_message",
+                Some("memory") => "clear_context",
+                _ => return Err("usage: /clear messages|memory".into()),
+            };
+            let _ = agent.send_msg.send((input.into(), "".into())).await;
+            agent.snapshot().await?;
+            println!("{} cleared", args[0]);
+        }
+        "/save" => {
+            let path = args.first().copied().unwrap_or(DEFAULT_SESSION_FILE);
+            let snapshot = agent.snapshot().await?;
+            let content = serde_json::to_string_pretty(&snapshot).map_err(|e| e.to_string())?;
+            std::fs::write(path, content).map_err(|e| format!("fail to write {}: {}", path, e))?;
+            println!("session saved to {}", path);
+        }
+        "/load" => {
+            let path = args.first().copied().unwrap_or(DEFAULT_SESSION_FILE);
+            let content =
+                std::fs::read_to_string(path).map_err(|e| format!("fail to read {}: {}", path, e))?;
+            let snapshot = agent.control("restore", content).await?;
+            println!(
+                "session loaded from {}, state: {}, {} message(s)",
+                path,
+                snapshot.fsm_state.unwrap_or("-".into()),
+                snapshot.messages.len()
+            );
+        }
+        "/model" => {
+            let snapshot = match args.first() {
+                Some(model) => {
+                    let input = json!({ "model": model, "provider": args.get(1) });
+                    agent.control("set_model", input.to_string()).await?
+                }
+                None => agent.snapshot().await?,
+            };
+            println!(
+                "model: {} ({})",
+                snapshot.model,
+                snapshot.provider.unwrap_or("default provider".into())
+            );
+        }
+        "/break" => {
+            let snapshot = match args.first() {
+                Some(state) => agent.control("breakpoint", state.to_string()).await?,
+                None => agent.snapshot().await?,
+            };
+            if snapshot.breakpoints.is_empty() {
+                println!("no breakpoint");
+            } else {
+                println!("breakpoints: {}", snapshot.breakpoints.join(", "));
+            }
+        }
+        "/unbreak" => {
+            let state = args.first().copied().unwrap_or("");
+            let snapshot = agent.control("clear_breakpoint", state.to_string()).await?;
+            println!("breakpoints: {}", snapshot.breakpoints.join(", "));
+        }
+        _ => return Err(format!("unknown command `{}`, see /help", command)),
+    }
+    Ok(())
+}
@@ -0,0 +1,140 @@
+// The prompt of a state paused at a breakpoint, see `/break` in the REPL.
+
+use rustyline::DefaultEditor;
+use serde_json::Value;
+use tokio::sync::mpsc;
+
+pub const HELP: &str = "\
+c, continue          run the state
+s, step              run the state and pause before the next one
+p, prompt            edit the prompt in $EDITOR before it is sent
+n, next <State>      go to this state after the paused one
+k, skip [output]     skip the LLM request, the output takes its place
+v, view [field]      show the pause again, or one field of it as JSON
+x, cancel            stop the message here
+h, help              this help";
+
+fn print_view(view: &Value) {
+    println!("\n========= Paused before {} =========", view["state"].as_str().unwrap_or("-"));
+    if let Some(next_states) = view["next_states"].as_array() {
+        let next_states = next_states
+            .iter()
+            .filter_map(|s| s.as_str())
+            .collect::<Vec<_>>();
+        println!("next states: {}", next_states.join(", "));
+    }
+    if let Some(task) = view["task"].as_str() {
+        println!("task: {}", task);
+    }
+    if view["delegates"].as_bool() == Some(true) {
+        println!("the state delegates to another agent");
+    } else if view["llm_request"].as_bool() == Some(false) {
+        println!("the state does not send an LLM request");
+    }
+    match &view["prompt"] {
+        Value::String(prompt) => println!("\n--------- prompt\n{}", prompt),
+        Value::Null if view.get("prompt").is_some() => println!("\n--------- no prompt"),
+        _ => {}
+    }
+    if let Some(messages) = view["messages"].as_array() {
+        println!("\n--------- {} message(s)", messages.len());
+        for message in messages {
+            println!(
+                "[{}] {}",
+                message[0].as_str().unwrap_or(""),
+                message[1].as_str().unwrap_or("")
+            );
+        }
+    }
+    if let Some(slots) = view["memory_slots"].as_object().filter(|slots| !slots.is_empty()) {
+        println!("\n--------- memory used by the state");
+        for (slot, value) in slots {
+            println!("{}: {}", slot, value.as_str().unwrap_or(""));
+        }
+    } else if let Some(memory) = view["memory"].as_object() {
+        let slots = memory.keys().cloned().collect::<Vec<_>>();
+        println!("\n--------- memory slots: {}", slots.join(", "));
+    }
+    if let Some(code) = view["code"].as_str() {
+        println!("\n--------- code\n{}", code);
+    }
+    println!("\n(c)ontinue, (s)tep, (p)rompt, (n)ext <State>, s(k)ip [output], (v)iew, (x) cancel, (h)elp");
+}
+
+fn edit_in_editor(text: &str) -> Result<String, String> {
+    let path = std::env::temp_dir().join(format!("fsm_agent_prompt_{}.txt", std::process::id()));
+    std::fs::write(&path, text).map_err(|e| e.to_string())?;
+    let editor = std::env::var("EDITOR").unwrap_or("vi".into());
+    let status = std::process::Command::new(&editor)
+        .arg(&path)
+        .status()
+        .map_err(|e| format!("fail to run {}: {}", editor, e))?;
+    let edited = std::fs::read_to_string(&path).map_err(|e| e.to_string());
+    let _ = std::fs::remove_file(&path);
+    if !status.success() {
+        return Err(format!("{} exited with {}", editor, status));
+    }
+    edited
+}
+
+// ask what to do with the paused state until it continues or is cancelled
+pub async fn pause(send_msg: &mpsc::Sender<(String, String)>, view: &str) {
+    let view = serde_json::from_str::<Value>(view).unwrap_or_default();
+    print_view(&view);
+    let Ok(mut rl) = DefaultEditor::new() else {
+        let _ = send_msg.send(("continue".into(), "".into())).await;
+        return;
+    };
+
+    loop {
+        let line = match rl.readline("debug> ") {
+            Ok(line) => line,
+            Err(_) => {
+                let _ = send_msg.send(("cancel".into(), "".into())).await;
+                return;
+            }
+        };
+        let line = line.trim();
+        let (command, arg) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let arg = arg.trim();
+        let input = match command {
+            "c" | "continue" | "" => ("continue", String::new()),
+            "s" | "step" => ("step", String::new()),
+            "x" | "cancel" => ("cancel", String::new()),
+            "p" | "prompt" => {
+                let Some(prompt) = view["prompt"].as_str() else {
+                    println!("the state has no prompt");
+                    continue;
+                };
+                match edit_in_editor(prompt) {
+                    Ok(prompt) => {
+                        println!("the edited prompt will be sent");
+                        ("edit_prompt", prompt)
+                    }
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        continue;
+                    }
+                }
+            }
+            "n" | "next" if !arg.is_empty() => ("set_next_state", arg.to_string()),
+            "k" | "skip" => ("skip_llm", arg.to_string()),
+            "v" | "view" => {
+                if arg.is_empty() {
+                    print_view(&view);
+                } else {
+                    println!("{}", serde_json::to_string_pretty(&view[arg]).unwrap_or_default());
+                }
+                continue;
+            }
+            _ => {
+                println!("{}", HELP);
+                continue;
+            }
+        };
+        let resumes = matches!(input.0, "continue" | "step" | "cancel");
+        if send_msg.send((input.0.into(), input.1)).await.is_err() || resumes {
+            return;
+        }
+    }
+}
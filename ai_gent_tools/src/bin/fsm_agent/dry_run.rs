@@ -0,0 +1,131 @@
+// `--dry-run`: the prompts and the code of every state rendered with a sample of the messages,
+// the task and the memory, for reviewing prompt changes without calling an LLM or docker.
+//
+// The sample is a JSON file:
+//
+//   {
+//     "task": "Summarize the guidance on sunscreen labels",
+//     "messages": [["user", "What does the guidance say?"]],
+//     "memory": {"context": "...", "code": ["print(1)"]}
+//   }
+//
+// A memory slot is a list of values or a single value.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use ai_gent_lib::fsm_chat_state::{render_prompts, RenderedPrompts};
+use ai_gent_lib::llm_agent::{LlmFsmAgentConfig, LlmReqSetting};
+use serde::Deserialize;
+use serde_json::Value;
+
+#[derive(Deserialize, Default)]
+struct Sample {
+    task: Option<String>,
+    #[serde(default)]
+    messages: Vec<(String, String)>,
+    #[serde(default)]
+    memory: HashMap<String, Value>,
+    #[serde(default)]
+    state_history: Vec<String>,
+}
+
+fn load_sample(path: Option<&String>) -> Result<LlmReqSetting, String> {
+    let sample = match path {
+        Some(path) => {
+            let content =
+                std::fs::read_to_string(path).map_err(|e| format!("fail to read {}: {}", path, e))?;
+            serde_json::from_str::<Sample>(&content).map_err(|e| format!("{}: {}", path, e))?
+        }
+        None => Sample {
+            task: Some("<task>".into()),
+            messages: vec![("user".into(), "<message>".into())],
+            ..Default::default()
+        },
+    };
+    let memory = sample
+        .memory
+        .into_iter()
+        .map(|(slot, value)| match value {
+            Value::Array(values) => (slot, values),
+            value => (slot, vec![value]),
+        })
+        .collect();
+    Ok(LlmReqSetting {
+        task: sample.task,
+        messages: sample.messages,
+        memory,
+        state_history: sample.state_history,
+        ..Default::default()
+    })
+}
+
+fn print_state(rendered: &RenderedPrompts) {
+    println!("\n========== {} ==========", rendered.state);
+    let sections = [
+        ("system prompt", &rendered.system),
+        ("chat prompt", &rendered.chat),
+        ("fsm prompt", &rendered.fsm),
+        ("delegate task", &rendered.delegate_task),
+        ("code", &rendered.code),
+        ("fsm_code", &rendered.fsm_code),
+    ];
+    for (title, text) in sections {
+        if let Some(text) = text {
+            println!("\n--------- {}\n{}", title, text);
+        }
+    }
+}
+
+fn write_state(dir: &Path, rendered: &RenderedPrompts) -> std::io::Result<()> {
+    let files = [
+        ("system.txt", &rendered.system),
+        ("chat.txt", &rendered.chat),
+        ("fsm.txt", &rendered.fsm),
+        ("delegate_task.txt", &rendered.delegate_task),
+        ("code.py", &rendered.code),
+        ("fsm_code.py", &rendered.fsm_code),
+    ];
+    for (suffix, text) in files {
+        if let Some(text) = text {
+            std::fs::write(dir.join(format!("{}.{}", rendered.state, suffix)), text)?;
+        }
+    }
+    Ok(())
+}
+
+// returns the number of template errors
+pub async fn dry_run(
+    config: &LlmFsmAgentConfig,
+    sample: Option<&String>,
+    output_dir: Option<&String>,
+) -> Result<usize, String> {
+    let sample = load_sample(sample)?;
+    let rendered_states = render_prompts(config, &sample).await;
+
+    if let Some(dir) = output_dir {
+        let dir = Path::new(dir);
+        std::fs::create_dir_all(dir).map_err(|e| format!("fail to create {}: {}", dir.display(), e))?;
+        for rendered in rendered_states.iter() {
+            write_state(dir, rendered).map_err(|e| format!("fail to write {}: {}", dir.display(), e))?;
+        }
+        println!("{} states rendered to {}", rendered_states.len(), dir.display());
+    } else {
+        rendered_states.iter().for_each(print_state);
+    }
+
+    let errors = rendered_states
+        .iter()
+        .flat_map(|rendered| {
+            rendered
+                .errors
+                .iter()
+                .map(move |e| format!("{}: {}", rendered.state, e))
+        })
+        .collect::<Vec<_>>();
+    if !errors.is_empty() {
+        eprintln!("\n{} template error(s):", errors.len());
+        errors.iter().for_each(|e| eprintln!("  {}", e));
+    }
+    Ok(errors.len())
+}
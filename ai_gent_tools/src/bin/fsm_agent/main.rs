@@ -1,17 +1,38 @@
+mod commands;
+mod debugger;
+mod dry_run;
+
 use ai_gent_lib::fsm_chat_state::FSMChatState;
 use rustyline::error::ReadlineError;
 use rustyline::DefaultEditor;
 
-use anyhow::Result;
-
 use ai_gent_lib::llm_agent::{
-    AgentSettings, LlmFsmAgent, LlmFsmAgentConfigBuilder, LlmFsmBuilder, 
+    AgentSettings, LlmFsmAgent, LlmFsmAgentConfigBuilder, LlmFsmBuilder,
 };
 
+use ai_gent_lib::llm_provider::{provider_api_key, provider_for_model, provider_key_env_name};
+use ai_gent_lib::telemetry::init_tracing;
+
 use tokio::sync::mpsc;
 
-use clap::Parser;
-use std::fs;
+use clap::{Parser, ValueEnum};
+
+use std::fs::File;
+use std::io::{IsTerminal, Read, Write};
+
+// the exit codes, 2 is also used by clap for invalid arguments
+const EXIT_OK: i32 = 0;
+const EXIT_AGENT_ERROR: i32 = 1;
+const EXIT_CONFIG_ERROR: i32 = 2;
+const EXIT_TRANSITION_LIMIT: i32 = 3;
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum EventFormat {
+    /// The streamed answer and the state changes, for a person
+    Text,
+    /// Every event of the agent as a JSON line `{"state", "type", "content"}`
+    Jsonl,
+}
 
 // Define a struct to represent the command line arguments
 #[derive(Parser)]
@@ -25,104 +46,343 @@ struct Cli {
     /// Path to the file to read
     #[arg(short, long)]
     config_file: String,
+
+    /// The model, overrides the one of the config (gpt-4o if neither sets one)
+    #[arg(short, long)]
+    model: Option<String>,
+
+    /// The provider, overrides the one of the config
+    #[arg(short, long)]
+    provider: Option<String>,
+
+    #[arg(short, long)]
+    temperature: Option<f32>,
+
+    /// The maximum number of state transitions for a message (32 by default)
+    #[arg(long)]
+    max_transitions: Option<u32>,
+
+    /// Run this task once and exit instead of starting the REPL, `-` reads it from stdin
+    #[arg(long)]
+    task: Option<String>,
+
+    /// The message of the task, the task itself if not set
+    #[arg(long, requires = "task")]
+    message: Option<String>,
+
+    /// The output format of the agent events
+    #[arg(long, value_enum, default_value_t = EventFormat::Text)]
+    events: EventFormat,
+
+    /// Append the user messages and the agent answers to this file
+    #[arg(long)]
+    transcript: Option<String>,
+
+    /// Pause before this state to inspect and change its run, `*` for every state
+    #[arg(short, long = "break", value_name = "STATE")]
+    breakpoints: Vec<String>,
+
+    /// Render the prompts and the code of every state and exit, without an LLM request
+    #[arg(long)]
+    dry_run: bool,
+
+    /// The JSON sample of the task, the messages and the memory for `--dry-run`
+    #[arg(long, requires = "dry_run")]
+    sample: Option<String>,
+
+    /// Write the rendered prompts to files in this directory instead of stdout
+    #[arg(long, requires = "dry_run")]
+    output_dir: Option<String>,
 }
 
 use std::collections::HashMap;
 
+#[derive(Default)]
+struct MessageOutcome {
+    answer: Option<String>,
+    errors: usize,
+    transition_limit: bool,
+}
+
+impl MessageOutcome {
+    fn exit_code(&self) -> i32 {
+        if self.transition_limit {
+            EXIT_TRANSITION_LIMIT
+        } else if self.errors > 0 {
+            EXIT_AGENT_ERROR
+        } else {
+            EXIT_OK
+        }
+    }
+}
+
+struct Transcript(Option<File>);
+
+impl Transcript {
+    fn open(path: Option<&String>) -> std::io::Result<Self> {
+        let file = match path {
+            Some(path) => Some(File::options().create(true).append(true).open(path)?),
+            None => None,
+        };
+        Ok(Transcript(file))
+    }
+
+    fn write(&mut self, role: &str, text: &str) {
+        if let Some(file) = self.0.as_mut() {
+            let time = chrono::Local::now().format("%Y-%m-%d %H:%M:%S");
+            let _ = writeln!(file, "[{}] {}:\n{}\n", time, role, text);
+        }
+    }
+}
+
+// print the events of one message until it is processed, `interactive` streams the tokens
+async fn receive_message_events(
+    send_msg: &mpsc::Sender<(String, String)>,
+    fsm_rx: &mut mpsc::Receiver<(String, String, String)>,
+    format: EventFormat,
+    interactive: bool,
+) -> MessageOutcome {
+    let mut outcome = MessageOutcome::default();
+    let mut llm_output = Vec::<String>::new();
+
+    while let Some(message) = fsm_rx.recv().await {
+        if message.1.starts_with("max_total_states") {
+            outcome.transition_limit = true;
+        }
+        if message.1 == "error" {
+            outcome.errors += 1;
+        }
+        if format == EventFormat::Jsonl {
+            let event =
+                serde_json::json!({ "state": message.0, "type": message.1, "content": message.2 });
+            println!("{}", event);
+        }
+        match (message.0.as_str(), message.1.as_str()) {
+            (_, "state") if format == EventFormat::Text => {
+                if interactive {
+                    println!("\n\n--------- Agent State: {}\n", message.2);
+                } else {
+                    eprintln!("--------- Agent State: {}", message.2);
+                }
+            }
+            (s, "token") if s != "MakeSummary" && format == EventFormat::Text && interactive => {
+                print!("{}", message.2);
+            }
+            (s, "reasoning") if s != "MakeSummary" && format == EventFormat::Text && interactive => {
+                // dimmed, to tell the reasoning apart from the answer
+                print!("\x1b[2m{}\x1b[0m", message.2);
+            }
+            (_, "output") => {
+                if format == EventFormat::Text {
+                    print!("{}", message.2);
+                }
+                llm_output.push(message.2);
+            }
+            (state_name, "exec_output") => {
+                if format == EventFormat::Text && interactive {
+                    println!("exec_output received, state:{}, len={}", state_name, message.2.len());
+                    println!("{}", message.2);
+                }
+                llm_output.push(message.2);
+            }
+            (s, "llm_output") if s != "MakeSummary" => {
+                llm_output.push(message.2);
+            }
+            (state_name, "error") if format == EventFormat::Text => {
+                eprintln!("Error received from state '{}': '{}'", state_name, message.2)
+            }
+            (_, t) if t.starts_with("max_total_states") && format == EventFormat::Text => {
+                eprintln!("the agent stopped: {}", t)
+            }
+            (_, "paused") => debugger::pause(send_msg, &message.2).await,
+            (_, "message_processed") => {
+                if format == EventFormat::Text && interactive {
+                    println!("message_processed, wait for the next user input"); // clear rustyline's buffer
+                }
+                break;
+            }
+            _ => {}
+        }
+    }
+    outcome.answer = llm_output.pop();
+    outcome
+}
+
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn main() {
     // Parse the command line arguments
     let args = Cli::parse();
+    // the spans are exported when OTEL_EXPORTER_OTLP_ENDPOINT is set
+    let telemetry = match init_tracing("fsm_agent", false) {
+        Ok(telemetry) => telemetry,
+        Err(e) => {
+            eprintln!("Error: fail to set up the tracing export: {}", e);
+            std::process::exit(EXIT_CONFIG_ERROR);
+        }
+    };
+    let exit_code = match run(args).await {
+        Ok(exit_code) => exit_code,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            EXIT_CONFIG_ERROR
+        }
+    };
+    // `exit` skips the destructors, the remaining spans are flushed first
+    drop(telemetry);
+    std::process::exit(exit_code);
+}
 
-    // Read the file into a string
-    let content = fs::read_to_string(args.config_file)?;
+async fn run(args: Cli) -> Result<i32, Box<dyn std::error::Error>> {
+    // `extends` and `include` in the config file are resolved relative to the file
+    let fsm_config = LlmFsmAgentConfigBuilder::from_toml_file(&args.config_file)?.build()?;
 
-    let fsm_config = LlmFsmAgentConfigBuilder::from_toml(&content)?.build()?;
+    if args.dry_run {
+        let errors =
+            dry_run::dry_run(&fsm_config, args.sample.as_ref(), args.output_dir.as_ref()).await?;
+        return Ok(if errors > 0 { EXIT_AGENT_ERROR } else { EXIT_OK });
+    }
 
     let fsm =
         LlmFsmBuilder::from_config::<FSMChatState>(&fsm_config, HashMap::default())?.build()?;
 
-    let api_key = std::env::var("OPENAI_API_KEY")
-        .map_err(|_| genai::resolver::Error::ApiKeyEnvNotFound {
-            env_name: "OPENAI_API_KEY".to_string(),
-        })
-        .unwrap();
+    // the model and the provider can be set in the config, e.g. for a local OpenAI-compatible server
+    let model = args
+        .model
+        .clone()
+        .or(fsm_config.model.clone())
+        .unwrap_or("gpt-4o".into());
+    let provider = args.provider.clone().or(fsm_config.provider.clone());
+    let key_provider = provider
+        .clone()
+        .unwrap_or_else(|| provider_for_model(&model));
+    let api_key = match (provider_api_key(&key_provider), provider_key_env_name(&key_provider)) {
+        (Some(api_key), _) => api_key,
+        (None, Some(env_name)) => {
+            return Err(format!("environment variable {} is not set", env_name).into())
+        }
+        // registered providers and local servers may not need a key
+        (None, None) => "".into(),
+    };
+
+    // a task from the arguments or piped to stdin runs once, without the REPL
+    let task = match args.task.as_deref() {
+        Some("-") => Some(read_stdin()?),
+        Some(task) => Some(task.to_string()),
+        None if !std::io::stdin().is_terminal() => Some(read_stdin()?),
+        None => None,
+    };
+    if task.as_ref().is_some_and(|task| task.trim().is_empty()) {
+        return Err("the task is empty".into());
+    }
 
     let llm_req_setting = AgentSettings {
         sys_prompt: fsm_config.system_prompt,
         fsm_prompt: fsm_config.fsm_prompt,
         summary_prompt: fsm_config.summary_prompt,
-        model: "gpt-4o".into(),
+        model,
         api_key,
+        provider,
         fsm_initial_state: fsm_config.initial_state,
         tools: fsm_config.tools,
-        total_state_transition_limit: None,
+        total_state_transition_limit: args.max_transitions,
     };
     let mut agent = LlmFsmAgent::new(fsm, llm_req_setting);
-
-    // tracing::info!("agent config: {}", fsm_config.to_json().unwrap());
-
-    //write_agent_config_to_file(&fsm_config);
-
-    println!("\n ========== Welcome to the Ai-gent Smith. ========== \n Type 'exit' to quit.");
-    let mut rl = DefaultEditor::new()?; // Use DefaultEditor instead
+    let mut transcript = Transcript::open(args.transcript.as_ref())?;
 
     let (fsm_tx, mut fsm_rx) = mpsc::channel::<(String, String, String)>(8);
     let (send_msg, rcv_msg) = mpsc::channel::<(String, String)>(8);
+    let temperature = args.temperature;
     let agent_handler = tokio::spawn(async move {
         agent
-            .fsm_message_service(rcv_msg, fsm_tx.clone(), None)
+            .fsm_message_service(rcv_msg, fsm_tx.clone(), temperature)
             .await
     });
 
+    for state in args.breakpoints.iter() {
+        let mut agent = commands::Agent { send_msg: &send_msg, fsm_rx: &mut fsm_rx };
+        commands::run_command(&mut agent, &format!("/break {}", state)).await?;
+    }
+
+    let exit_code = match task {
+        Some(task) => {
+            let message = args.message.clone().unwrap_or(task.clone());
+            transcript.write("user", &message);
+            let _ = send_msg.send(("task".into(), task)).await;
+            let _ = send_msg.send(("message".into(), message)).await;
+
+            let outcome = receive_message_events(&send_msg, &mut fsm_rx, args.events, false).await;
+            if let Some(answer) = outcome.answer.as_ref() {
+                transcript.write("agent", answer);
+                if args.events == EventFormat::Text {
+                    println!("{}", answer);
+                }
+            }
+            let _ = send_msg.send(("terminate".into(), "".into())).await;
+            outcome.exit_code()
+        }
+        None => repl(&send_msg, &mut fsm_rx, args.events, &mut transcript).await?,
+    };
+
+    match agent_handler.await {
+        Ok(Ok(())) => Ok(exit_code),
+        Ok(Err(e)) => {
+            eprintln!("Error: {}", e);
+            Ok(EXIT_AGENT_ERROR)
+        }
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            Ok(EXIT_AGENT_ERROR)
+        }
+    }
+}
+
+fn read_stdin() -> std::io::Result<String> {
+    let mut task = String::new();
+    std::io::stdin().read_to_string(&mut task)?;
+    Ok(task)
+}
+
+async fn repl(
+    send_msg: &mpsc::Sender<(String, String)>,
+    fsm_rx: &mut mpsc::Receiver<(String, String, String)>,
+    format: EventFormat,
+    transcript: &mut Transcript,
+) -> Result<i32, Box<dyn std::error::Error>> {
+    println!(
+        "\n ========== Welcome to the Ai-gent Smith. ========== \n Type 'exit' to quit, '/help' for the commands."
+    );
+    let mut rl = DefaultEditor::new()?; // Use DefaultEditor instead
+
     loop {
         let readline = rl.readline("\n>> ");
         match readline {
             Ok(user_input) => {
                 if user_input.trim().eq_ignore_ascii_case("exit") {
+                    let _ = send_msg.send(("terminate".into(), "".into())).await;
                     println!("Goodbye!");
                     break;
                 }
 
                 let _ = rl.add_history_entry(user_input.as_str());
 
+                if user_input.trim_start().starts_with('/') {
+                    let mut agent = commands::Agent { send_msg, fsm_rx: &mut *fsm_rx };
+                    if let Err(e) = commands::run_command(&mut agent, &user_input).await {
+                        eprintln!("{}", e);
+                    }
+                    continue;
+                }
+                transcript.write("user", &user_input);
+
                 // let _ = send_msg.send(("clear_message".into(), "".into())).await;
                 let _ = send_msg.send(("task".into(), user_input.clone())).await;
 
-                // this should the last command sent, it will trigger the server to start to response 
+                // this should the last command sent, it will trigger the server to start to response
                 let _ = send_msg.send(("message".into(), user_input)).await;
 
-                let mut llm_output = Vec::<String>::new();
-
-                while let Some(message) = fsm_rx.recv().await {
-                    match (message.0.as_str(), message.1.as_str()) {
-                        (_, "state") => {
-                            println!("\n\n--------- Agent State: {}\n", message.2);
-                        }
-                        (s, "token") if s != "MakeSummary" => {
-                            print!("{}", message.2);
-                        }
-                        (_, "output") => {
-                            print!("{}", message.2);
-                            llm_output.push(message.2);
-                        }
-                        (state_name, "exec_output") => {
-                            println!("exec_output received, state:{}, len={}", state_name, message.2.len());
-                            println!("{}", message.2);
-                            llm_output.push(message.2);
-                        }
-                        (_, "llm_output") => {
-                            llm_output.push(message.2);
-                        }
-                        (state_name, "error") => {
-                            eprintln!("Error received from state '{}': '{}'", state_name, message.2)
-                        }
-                        (_, "message_processed") => {
-                            println!("message_processed, wait for the next user input"); // clear rustyline's buffer
-                            break;
-                        }
-                        _ => {}
-                    }
+                let outcome = receive_message_events(send_msg, fsm_rx, format, true).await;
+                if let Some(answer) = outcome.answer.as_ref() {
+                    transcript.write("agent", answer);
                 }
             }
             Err(ReadlineError::Interrupted) => {
@@ -142,7 +402,5 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             }
         }
     }
-    // let _ = tokio::join!(message_handler).0.unwrap();
-    let _ = tokio::join!(agent_handler).0.unwrap();
-    Ok(())
+    Ok(EXIT_OK)
 }
@@ -0,0 +1,101 @@
+use ai_gent_lib::fsm_chat_state::FSMChatState;
+use ai_gent_lib::group_chat::{GroupChat, GroupChatConfig};
+use ai_gent_lib::llm_provider::{provider_api_key, provider_for_model, provider_key_env_name};
+use clap::Parser;
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use tokio::sync::mpsc;
+
+#[derive(Parser)]
+#[command(
+    name = "AI-Gent Group Chat",
+    version = "0.1",
+    about = "Several agents taking turns in one conversation"
+)]
+struct Cli {
+    /// Path to the group chat config, see `dev_config/group_chat.toml`
+    #[arg(short, long)]
+    config_file: String,
+
+    /// The model of the members that do not set one in their configs
+    #[arg(short, long, default_value = "gpt-4o")]
+    model: String,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Cli::parse();
+
+    let group_config = GroupChatConfig::from_toml_file(&args.config_file)?;
+    let provider = provider_for_model(&args.model);
+    let api_key = match (provider_api_key(&provider), provider_key_env_name(&provider)) {
+        (Some(api_key), _) => api_key,
+        (None, Some(env_name)) => {
+            return Err(format!("environment variable {} is not set", env_name).into())
+        }
+        (None, None) => "".into(),
+    };
+    let mut group_chat =
+        GroupChat::from_config::<FSMChatState>(&group_config, &args.model, None, &api_key)?;
+
+    let names = group_chat
+        .members
+        .iter()
+        .map(|member| member.name.clone())
+        .collect::<Vec<_>>()
+        .join(", ");
+    println!("\n ========== Group chat with {} ========== \n Type 'exit' to quit.", names);
+    let mut rl = DefaultEditor::new()?;
+
+    loop {
+        match rl.readline("\n>> ") {
+            Ok(user_input) => {
+                if user_input.trim().eq_ignore_ascii_case("exit") {
+                    println!("Goodbye!");
+                    break;
+                }
+                let _ = rl.add_history_entry(user_input.as_str());
+
+                let (tx, mut rx) = mpsc::channel::<(String, String, String)>(16);
+                let printer = tokio::spawn(async move {
+                    let mut speaker = String::new();
+                    while let Some((state_name, t, r)) = rx.recv().await {
+                        // "<member>/<state>", every message is attributed to both
+                        let member = state_name.split('/').next().unwrap_or_default().to_string();
+                        match t.as_str() {
+                            "state" if member != speaker => {
+                                println!("\n\n========= {} ({})\n", member, r);
+                                speaker = member;
+                            }
+                            "state" => println!("\n\n--------- {}\n", r),
+                            "token" => print!("{}", r),
+                            "reasoning" => print!("\x1b[2m{}\x1b[0m", r),
+                            "exec_output" => println!("{}", r),
+                            "error" => eprintln!("Error received from '{}': '{}'", state_name, r),
+                            _ => {}
+                        }
+                    }
+                });
+                let result = group_chat.send(&user_input, &tx).await;
+                drop(tx);
+                let _ = printer.await;
+                if let Err(e) = result {
+                    eprintln!("Error: {}", e);
+                }
+            }
+            Err(ReadlineError::Interrupted) => {
+                println!("CTRL-C");
+                break;
+            }
+            Err(ReadlineError::Eof) => {
+                println!("CTRL-D");
+                break;
+            }
+            Err(err) => {
+                println!("Error: {:?}", err);
+                break;
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,283 @@
+// A WebSocket session with a stored agent, for the clients that need the full agent protocol
+// of the CLI.
+//
+// `GET /api/v1/agents/{id}/session` (the agent's id or name) upgrades to a WebSocket that
+// runs the messages through `ChatAgent::process_message`, as the agent workspace does (the
+// routing, the state and the summary of each message). The client sends
+//
+//   {"type": "message", "content": "..."}
+//
+// with the types `message`, `task`, `cancel` (stops the message being processed),
+// `clear_message` and `clear_context`, and gets every event of the agent as
+//
+//   {"state": "...", "type": "token", "content": "..."}
+//
+// (`token`, `llm_output`, `message`, `save_to:<slot>`, `cancelled`, `error`,
+// `message_processed`, ...). The first event is a `session` event with the agent id and its
+// state. Closing the socket ends the session. The optional query parameters are `fsm_state`,
+// `temperature`, `top_k` and `threshold`.
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Path, Query,
+    },
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures_util::{SinkExt, StreamExt};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use uuid::Uuid;
+
+use ai_gent_lib::llm_agent::LlmFsmAgent;
+use ai_gent_lib::telemetry::traced;
+
+use crate::agent_workspace::get_search_context_plain_text;
+use crate::api_auth::ApiUser;
+use crate::embedding_service::search_asset;
+use crate::fsm_chat_agent::{new_chat_agent, ChatAgent};
+use crate::metrics::{record_error, AgentSessionGauge};
+use crate::openai_api::get_stored_agent;
+
+// the inputs a client can send, `terminate` is sent when the socket is closed
+const SESSION_INPUTS: [&str; 5] = [
+    "message",
+    "task",
+    "cancel",
+    "clear_message",
+    "clear_context",
+];
+
+#[derive(Deserialize, Default)]
+pub struct SessionQuery {
+    fsm_state: Option<String>,
+    temperature: Option<f32>,
+    top_k: Option<usize>,
+    threshold: Option<f32>,
+}
+
+#[derive(Deserialize)]
+struct SessionInput {
+    r#type: String,
+    #[serde(default)]
+    content: String,
+}
+
+fn session_error(status: StatusCode, message: String) -> Response {
+    let body = json!({ "error": { "message": message, "type": "invalid_request_error" } });
+    (status, Json(body)).into_response()
+}
+
+pub async fn agent_session(
+    user: ApiUser,
+    Path(agent): Path<String>,
+    Query(query): Query<SessionQuery>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    let stored_agent = match get_stored_agent(&user.username, &agent).await {
+        Ok(Some(stored_agent)) => stored_agent,
+        Ok(None) => {
+            return session_error(
+                StatusCode::NOT_FOUND,
+                format!("the agent `{}` does not exist", agent),
+            )
+        }
+        Err(e) => return session_error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    };
+    let agent = match new_chat_agent(
+        stored_agent.user_id,
+        &stored_agent.setting.model_name,
+        &stored_agent.setting.fsm_agent_config,
+        query.fsm_state.clone(),
+    )
+    .await
+    {
+        Ok(agent) => agent,
+        Err(e) => return session_error(StatusCode::SERVICE_UNAVAILABLE, e.to_string()),
+    };
+
+    let agent_id = stored_agent.agent_id;
+    let asset_id = stored_agent.asset_id;
+    ws.on_upgrade(move |socket| run_session(socket, agent, agent_id, asset_id, query))
+}
+
+async fn run_session(
+    socket: WebSocket,
+    mut agent: ChatAgent<LlmFsmAgent>,
+    agent_id: i32,
+    asset_id: i32,
+    query: SessionQuery,
+) {
+    let _gauge = AgentSessionGauge::start();
+    let (mut ws_tx, mut ws_rx) = socket.split();
+    let (input_tx, input_rx) = mpsc::channel::<(String, String)>(16);
+    let (event_tx, mut event_rx) = mpsc::channel::<(String, String, String)>(16);
+
+    let session_id = Uuid::new_v4();
+    agent.llm_req_settings.session_id = Some(format!("session-{}", session_id));
+    agent.llm_req_settings.agent_name = Some(format!("agent-{}", agent_id));
+    let fsm_state = agent.get_current_state().await;
+    let _ = event_tx
+        .send((
+            "".into(),
+            "session".into(),
+            json!({ "session_id": session_id, "agent_id": agent_id, "fsm_state": fsm_state })
+                .to_string(),
+        ))
+        .await;
+
+    let service_tx = event_tx.clone();
+    let temperature = query.temperature;
+    // the turns are exported as traces, see `telemetry`
+    let service = tokio::spawn(traced(session_service(
+        agent,
+        input_rx,
+        service_tx,
+        temperature,
+    )));
+
+    let sender = tokio::spawn(async move {
+        while let Some((state, t, r)) = event_rx.recv().await {
+            let event = json!({ "state": state, "type": t, "content": r });
+            if ws_tx.send(Message::Text(event.to_string().into())).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    while let Some(Ok(message)) = ws_rx.next().await {
+        let text = match message {
+            Message::Text(text) => text,
+            Message::Close(_) => break,
+            _ => continue,
+        };
+        let input = match serde_json::from_str::<SessionInput>(&text) {
+            Ok(input) if SESSION_INPUTS.contains(&input.r#type.as_str()) => input,
+            Ok(input) => {
+                let message = format!("unknown input type `{}`", input.r#type);
+                let _ = event_tx.send(("".into(), "error".into(), message)).await;
+                continue;
+            }
+            Err(e) => {
+                let message = format!("invalid input: {}", e);
+                let _ = event_tx.send(("".into(), "error".into(), message)).await;
+                continue;
+            }
+        };
+
+        // the retrieval from the agent's asset goes to the context before the message
+        if input.r#type == "message" && asset_id != 0 {
+            let search_asset_results = search_asset(
+                &input.content,
+                asset_id,
+                query.top_k.unwrap_or(8),
+                query.threshold.unwrap_or(0.65),
+            )
+            .await;
+            let context = get_search_context_plain_text(&search_asset_results);
+            let _ = input_tx.send(("context".into(), context)).await;
+        }
+        if input_tx.send((input.r#type, input.content)).await.is_err() {
+            break;
+        }
+    }
+
+    let _ = input_tx.send(("terminate".into(), "".into())).await;
+    drop(input_tx);
+    let _ = service.await;
+    drop(event_tx);
+    let _ = sender.await;
+}
+
+// Process the inputs one at a time, a message with `process_message`. While a message is
+// processed, `cancel` drops it (the agent stays in its state) and the other inputs wait.
+async fn session_service(
+    mut agent: ChatAgent<LlmFsmAgent>,
+    mut input_rx: Receiver<(String, String)>,
+    tx: Sender<(String, String, String)>,
+    temperature: Option<f32>,
+) {
+    let mut pending_input = VecDeque::<(String, String)>::new();
+    loop {
+        let input = match pending_input.pop_front() {
+            Some(input) => Some(input),
+            None => input_rx.recv().await,
+        };
+        let Some((msg_type, msg)) = input else {
+            break;
+        };
+        match msg_type.as_str() {
+            "message" => {}
+            "task" => {
+                agent.llm_req_settings.task = Some(msg);
+                continue;
+            }
+            // the retrieval results for the next message
+            "context" => {
+                agent
+                    .llm_req_settings
+                    .memory
+                    .insert("context".into(), vec![Value::String(msg)]);
+                continue;
+            }
+            "clear_message" => {
+                agent.llm_req_settings.messages.clear();
+                continue;
+            }
+            "clear_context" => {
+                agent.llm_req_settings.memory.clear();
+                continue;
+            }
+            "terminate" => break,
+            // nothing to cancel between messages
+            _ => continue,
+        }
+
+        let mut terminated = false;
+        {
+            let processing = agent.process_message(&msg, Some(tx.clone()), temperature);
+            tokio::pin!(processing);
+            loop {
+                tokio::select! {
+                    result = &mut processing => {
+                        if let Err(e) = result {
+                            record_error("agent_session");
+                            let _ = tx.send(("".into(), "error".into(), e.to_string())).await;
+                        }
+                        break;
+                    }
+                    input = input_rx.recv() => match input {
+                        Some((msg_type, _)) if msg_type == "cancel" => {
+                            let _ = tx.send(("".into(), "cancelled".into(), "".into())).await;
+                            break;
+                        }
+                        Some((msg_type, _)) if msg_type == "terminate" => {
+                            terminated = true;
+                            break;
+                        }
+                        Some(input) => pending_input.push_back(input),
+                        None => {
+                            terminated = true;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        if terminated {
+            break;
+        }
+        let fsm_state = agent.get_current_state().await;
+        let _ = tx
+            .send((
+                "".into(),
+                "message_processed".into(),
+                json!({ "fsm_state": fsm_state }).to_string(),
+            ))
+            .await;
+    }
+}
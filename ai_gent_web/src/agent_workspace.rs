@@ -1,17 +1,18 @@
 use ai_gent_lib::fsm::FiniteStateMachine;
 use ai_gent_lib::fsm::FsmState;
 use ai_gent_lib::llm_agent;
-use ai_gent_lib::llm_agent::AgentSettings;
 use ai_gent_lib::llm_agent::LlmClient;
 use ai_gent_lib::llm_agent::LlmFsmAgent;
 use ai_gent_lib::llm_agent::LlmFsmAgentConfig;
 use ai_gent_lib::llm_agent::LlmFsmAgentConfigBuilder;
-use ai_gent_lib::llm_agent::LlmFsmBuilder;
 use ai_gent_lib::llm_agent::LlmFsmStateInit;
 use ai_gent_lib::llm_agent::LlmResponse;
 use ai_gent_lib::llm_agent::StateConfig;
 use ai_gent_lib::llm_agent::StatePrompts;
 use ai_gent_lib::GenaiLlmclient;
+use ai_gent_lib::llm_audit::LlmAuditContext;
+use ai_gent_lib::model_registry::model_api_key;
+use ai_gent_lib::telemetry::traced;
 use futures::StreamExt;
 
 use askama::Template;
@@ -36,6 +37,7 @@ use tokio::sync::mpsc::Receiver;
 use tokio::sync::mpsc::Sender;
 use tokio::sync::RwLock;
 use tokio::task::JoinHandle;
+use tracing::Instrument;
 use tron_app::tron_components::div::clean_div_with_context;
 use tron_app::tron_components::div::update_and_send_div_with_context;
 use tron_app::tron_components::text::append_textarea_value;
@@ -65,6 +67,7 @@ use crate::fsm_chat_agent::*;
 
 pub const AGENT_CHAT_TEXTAREA: &str = "agent_chat_textarea";
 pub const AGENT_STREAM_OUTPUT: &str = "agent_stream_output";
+pub const AGENT_REASONING_OUTPUT: &str = "agent_reasoning_output";
 pub const AGENT_QUERY_TEXT_INPUT: &str = "agent_query_text_input";
 pub const AGENT_QUERY_BUTTON: &str = "agent_query_button";
 pub const ASSET_SEARCH_BUTTON: &str = "asset_search_button";
@@ -91,6 +94,7 @@ struct AgentWorkspaceTemplate {
     chat_textarea: String,
     query_text_input: String,
     stream_output: String,
+    reasoning_output: String,
     query_button: String,
     asset_search_button: String,
     asset_search_output: String,
@@ -137,6 +141,16 @@ impl<'a: 'static> AgentWorkSpaceBuilder<'a> {
             .set_attr("style", r#"resize:none"#)
             .build();
 
+        // the streamed reasoning of the model, muted so it is not taken for the answer
+        let agent_reasoning_output = TnStreamTextArea::builder()
+            .init(AGENT_REASONING_OUTPUT.into(), Vec::new())
+            .set_attr(
+                "class",
+                "min-h-[55px] max-h-[110px] overflow-auto flex-1 border mb-1 border-gray-600 rounded-lg p-1 h-min bg-gray-800 text-gray-400 text-sm italic",
+            )
+            .set_attr("style", r#"resize:none"#)
+            .build();
+
         let query_button = TnButton::builder()
             .init(AGENT_QUERY_BUTTON.into(), "Send".into())
             .set_attr(
@@ -193,6 +207,7 @@ impl<'a: 'static> AgentWorkSpaceBuilder<'a> {
         context.add_component(chat_textarea);
         context.add_component(query_text_input);
         context.add_component(agent_stream_output);
+        context.add_component(agent_reasoning_output);
         context.add_component(query_button);
         context.add_component(asset_search_button);
         context.add_component(asset_search_output);
@@ -250,6 +265,14 @@ where
             .initial_render()
             .await;
 
+        let reasoning_output_html = comp_guard
+            .get(AGENT_REASONING_OUTPUT)
+            .unwrap()
+            .read()
+            .await
+            .initial_render()
+            .await;
+
         let query_text_input_html = comp_guard
             .get(AGENT_QUERY_TEXT_INPUT)
             .unwrap()
@@ -402,6 +425,7 @@ where
             asset_id,
             chat_textarea: chat_textarea_html,
             stream_output: stream_output_html,
+            reasoning_output: reasoning_output_html,
             query_text_input: query_text_input_html,
             asset_search_button: asset_search_button_html,
             query_button: query_button_html,
@@ -417,7 +441,7 @@ where
     async fn post_render(&mut self, _ctx: &TnContextBase) {}
 }
 
-async fn insert_message(
+pub async fn insert_message(
     chat_id: i32,
     user_id: i32,
     agent_id: i32,
@@ -453,13 +477,13 @@ async fn insert_message(
         fsm_state,
         chat_id
     )
-    .fetch_one(&pool)
+    .execute(&pool)
     .await?;
 
     Ok(result.message_id)
 }
 
-async fn get_messages(chat_id: i32) -> Result<Vec<(String, String, String)>, sqlx::Error> {
+pub async fn get_messages(chat_id: i32) -> Result<Vec<(String, String, String)>, sqlx::Error> {
     let pool = DB_POOL.clone();
     let results = sqlx::query!(
         r#"
@@ -480,7 +504,7 @@ async fn get_messages(chat_id: i32) -> Result<Vec<(String, String, String)>, sql
     Ok(messages)
 }
 
-async fn get_chat_summary(chat_id: i32) -> Result<String, sqlx::Error> {
+pub async fn get_chat_summary(chat_id: i32) -> Result<String, sqlx::Error> {
     let pool = DB_POOL.clone();
     let result = sqlx::query!(r#" SELECT summary FROM chats WHERE chat_id = $1 "#, chat_id)
         .fetch_one(&pool)
@@ -490,7 +514,7 @@ async fn get_chat_summary(chat_id: i32) -> Result<String, sqlx::Error> {
     Ok(summary)
 }
 
-async fn update_chat_summary(chat_id: i32, summary: &str) -> Result<i32, sqlx::Error> {
+pub async fn update_chat_summary(chat_id: i32, summary: &str) -> Result<i32, sqlx::Error> {
     let pool = DB_POOL.clone();
     let result = sqlx::query!(
         r#"
@@ -549,6 +573,41 @@ fn query(context: TnContext, event: TnEvent, _payload: Value) -> TnFutureHTMLRes
                             AGENT_STREAM_OUTPUT,
                         )
                         .await;
+                        text::clean_stream_textarea_with_context(
+                            &context_cloned,
+                            AGENT_REASONING_OUTPUT,
+                        )
+                        .await;
+                    }
+                    "reasoning" => {
+                        text::append_and_update_stream_textarea_with_context(
+                            &context_cloned,
+                            AGENT_REASONING_OUTPUT,
+                            &r,
+                        )
+                        .await
+                    }
+                    "reasoning_output" => {
+                        // the reasoning goes to a collapsed panel before the answer
+                        let query_result_area = context_cloned.get_component(AGENT_CHAT_TEXTAREA).await;
+                        let html_output = [
+                            r#"<details class="collapse collapse-arrow bg-gray-800 text-gray-400 p-3">"#.to_string(),
+                            r#"<summary class="collapse-title text-sm">Reasoning</summary>"#.to_string(),
+                            r#"<div class="collapse-content markdown-body">"#.to_string(),
+                            markdown_to_html_with_plugins(&r, &comrak_options, comrak_plugins),
+                            r#"</div></details>"#.to_string(),
+                        ]
+                        .join("\n");
+                        chatbox::append_chatbox_value(query_result_area.clone(), ("bot".into(), html_output)).await;
+                    },
+                    "error" => {
+                        let message = format!("\nLLM Engine Error: {}", r);
+                        text::append_and_update_stream_textarea_with_context(
+                            &context_cloned,
+                            AGENT_STREAM_OUTPUT,
+                            &message,
+                        )
+                        .await
                     }
                     "message" => {
                         let message = format!("\nLLM Engine Message: {}", r);
@@ -627,70 +686,34 @@ fn query(context: TnContext, event: TnEvent, _payload: Value) -> TnFutureHTMLRes
             };
         }
 
-        let fsm_config = LlmFsmAgentConfigBuilder::from_toml(&fsm_agent_config).unwrap().build().unwrap();
-
-        let fsm = LlmFsmBuilder::from_config::<ChatState>(&fsm_config, HashMap::default()).unwrap().build().unwrap();
-
-        // fsm.states.iter_mut().for_each(|(_, v)| v.as_mut()) ;
-
-        let api_key = match llm_name.as_str() {
-            "gpt-4o" | "gpt-4o-mini" | "gpt-3.5-turbo" | "o3-mini" => {
-                if let Ok(open_api_key) = std::env::var("OPENAI_API_KEY") {
-                    open_api_key
-                } else {
-                    let mut h = HeaderMap::new();
-                    h.insert("Hx-Reswap", "innerHTML".parse().unwrap());
-                    h.insert("Hx-Retarget", "#env_var_setting_notification_msg".parse().unwrap());
-                    h.insert("HX-Trigger-After-Swap", "show_env_var_setting_notification".parse().unwrap());
-
-                    return Some(
-                        (h, Html::from("Environment variable OPENAI_API_KEY not set for the agent for the server, please set it up, restart the server, and reload the web app.".to_string())) );
-                }},
-            "claude-3-haiku-20240307" | "claude-3-5-sonnet-20241022" => {
-                if let Ok(open_api_key) = std::env::var("ANTHROPIC_API_KEY") {
-                    open_api_key
-                } else {
-                    let mut h = HeaderMap::new();
-                    h.insert("Hx-Reswap", "innerHTML".parse().unwrap());
-                    h.insert("Hx-Retarget", "#env_var_setting_notification_msg".parse().unwrap());
-                    h.insert("HX-Trigger-After-Swap", "show_env_var_setting_notification".parse().unwrap());
-
-                    return Some(
-                        (h, Html::from("Environment variable ANTHROPIC_API_KEY not set for the agent for the server, please set it up, restart the server, and reload the web app.".to_string())) );
-                }},
-            _ => {"".into()}
-
-        };
-
-
-        let (fsm_state, exec_entry_actions) = {
+        let fsm_state = {
             let asset = context.get_asset_ref().await;
             let asset_guard = asset.read().await;
             if let Some(TnAsset::String(fsm_state)) = asset_guard.get("fsm_state") {
-                (Some(fsm_state.clone()), false)
+                Some(fsm_state.clone())
             } else {
-                (fsm.get_current_state_name(), true) // default initial state from fsm_config
+                None // default initial state from fsm_config
             }
         };
 
-        let agent_settings = AgentSettings {
-            sys_prompt: fsm_config.system_prompt,
-            fsm_prompt: fsm_config.fsm_prompt,
-            summary_prompt: fsm_config.summary_prompt,
-            model: llm_name.clone(),
-            tools: None,
-            api_key,
-            fsm_initial_state: fsm_config.initial_state,
-            total_state_transition_limit: None
+        // we start a new agent every query now, we may want to implement session/static agent
+        let mut agent = match new_chat_agent(user_id, &llm_name, &fsm_agent_config, fsm_state).await {
+            Ok(agent) => agent,
+            Err(e) => {
+                let mut h = HeaderMap::new();
+                h.insert("Hx-Reswap", "innerHTML".parse().unwrap());
+                h.insert("Hx-Retarget", "#env_var_setting_notification_msg".parse().unwrap());
+                h.insert("HX-Trigger-After-Swap", "show_env_var_setting_notification".parse().unwrap());
+
+                return Some(
+                    (h, Html::from(format!("{} for the server, please set it up, restart the server, and reload the web app.", e))) );
+            }
         };
-
-        let mut agent = ChatAgent { base: LlmFsmAgent::new(fsm, agent_settings) }; // we start a new agent every query now, we may want to implement session/static agent
+        // LLM requests of a chat are queued fairly against the other chats
+        agent.base.llm_req_settings.session_id = Some(format!("chat-{}", chat_id));
+        agent.base.llm_req_settings.agent_name = Some(format!("agent-{}", agent_id));
 
         {
-            if let Err(_e) = agent.base.set_current_state(fsm_state.clone(), exec_entry_actions).await {
-                let fsm_state = agent.base.fsm.get_current_state_name();
-                agent.base.set_current_state(fsm_state, true).await.expect("set current state fail");
-            };
             let e = agent.base.llm_req_settings.memory.entry("summary".into()).or_default();
             let summary = get_chat_summary(chat_id).await.unwrap_or_default(); 
             e.clear();
@@ -747,9 +770,34 @@ fn query(context: TnContext, event: TnEvent, _payload: Value) -> TnFutureHTMLRes
             let query_result_area = context.get_component(AGENT_CHAT_TEXTAREA).await;
             chatbox::append_chatbox_value(query_result_area.clone(), ("user".into(), ammonia::clean_text(&query_text))).await;
             context.set_ready_for(AGENT_CHAT_TEXTAREA).await;
-            let _ = insert_message(chat_id, user_id, agent_id, &query_text, "user", "text", None).await;
-
-            match agent.process_message(&query_text, Some(tx), temperature_value).await {
+            let message_id = insert_message(chat_id, user_id, agent_id, &query_text, "user", "text", None).await.ok();
+            agent.base.llm_req_settings.audit = Some(LlmAuditContext {
+                chat_id,
+                message_id,
+                agent_id: Some(agent_id),
+                ..Default::default()
+            });
+
+            // the turn is exported with the spans of its states and LLM calls, see `telemetry`
+            let result = traced(async {
+                let query_span = tracing::info_span!(
+                    "agent_query",
+                    chat_id,
+                    agent_id,
+                    user_id,
+                    model = %llm_name,
+                    latency_ms = tracing::field::Empty,
+                );
+                let start = std::time::Instant::now();
+                let result = agent
+                    .process_message(&query_text, Some(tx), temperature_value)
+                    .instrument(query_span.clone())
+                    .await;
+                query_span.record("latency_ms", start.elapsed().as_millis() as u64);
+                result
+            })
+            .await;
+            match result {
                 Ok(res) => {
                     let current_state = agent.base.get_current_state().await;
                     let _ = insert_message(chat_id, user_id, agent_id, &res, "bot", "text", current_state).await;
@@ -760,6 +808,7 @@ fn query(context: TnContext, event: TnEvent, _payload: Value) -> TnFutureHTMLRes
                 },
                 Err(err) => {
                     tracing::info!(target: "tron_app", "LLM API call error: {:?}", err);
+                    crate::metrics::record_error("agent_query");
 
                     let mut h = HeaderMap::new();
                     h.insert("Hx-Reswap", "innerHTML".parse().unwrap());
@@ -787,7 +836,7 @@ fn query(context: TnContext, event: TnEvent, _payload: Value) -> TnFutureHTMLRes
 }
 
 
-fn get_search_context_plain_text(top_hits: &[ChunkPoint]) -> String {
+pub fn get_search_context_plain_text(top_hits: &[ChunkPoint]) -> String {
     top_hits
         .iter()
         .map(|p| {
@@ -840,33 +889,19 @@ fn get_search_context_html(top_hits: &[ChunkPoint]) -> String {
     .join("")
 }
 
-async fn extend_query_with_llm(query: &str) -> String {
-    let llm_name = "gpt-3.5-turbo";
-    let api_key = match llm_name {
-        "gpt-4o" | "gpt-4o-mini" | "gpt-3.5-turbo" | "o3-mini" => std::env::var("OPENAI_API_KEY")
-            .map_err(|_| genai::resolver::Error::ApiKeyEnvNotFound {
-                env_name: "OPENAI_API_KEY".to_string(),
-            })
-            .unwrap(),
-        "claude-3-haiku-20240307" | "claude-3-5-sonnet-20241022" => {
-            std::env::var("ANTHROPIC_API_KEY")
-                .map_err(|_| genai::resolver::Error::ApiKeyEnvNotFound {
-                    env_name: "ANTHROPIC_API_KEY".to_string(),
-                })
-                .unwrap()
-        }
-        _ => "".into(),
-    };
+// with the model of the agent, `llm_name` is a model of the model registry
+async fn extend_query_with_llm(llm_name: &str, query: &str) -> Result<String, anyhow::Error> {
+    let api_key = model_api_key(llm_name)?;
 
     let llm_client = GenaiLlmclient {
         model: llm_name.to_string(),
         api_key,
+        ..Default::default()
     };
     let prompt = "find the relevant information about the questions and summary it into a small response less in 100 words.";
     llm_client
         .generate(prompt, &[("user".into(), query.into())], Some(0.05))
         .await
-        .unwrap()
 }
 
 fn search_asset_clicked(
@@ -0,0 +1,93 @@
+// Bearer token authentication of the JSON APIs.
+//
+// A token is either one of the `api_tokens` of a user, created with the `create_api_token`
+// tool and stored as its SHA-256 hash, or the token set with `AI_GENT_API_TOKEN`, whose
+// requests act on behalf of `AI_GENT_API_USER` (the default web user if it is not set).
+
+use axum::{
+    extract::FromRequestParts,
+    http::{header, request::Parts, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use crate::{DB_POOL, MOCK_USER};
+
+pub struct ApiUser {
+    pub username: String,
+}
+
+pub struct ApiAuthError(StatusCode, String);
+
+impl IntoResponse for ApiAuthError {
+    fn into_response(self) -> Response {
+        // the same error shape as the OpenAI API, so its clients can show the message
+        let body = json!({ "error": { "message": self.1, "type": "authentication_error" } });
+        (self.0, Json(body)).into_response()
+    }
+}
+
+fn bearer_token(parts: &Parts) -> Option<&str> {
+    parts
+        .headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim())
+}
+
+pub fn hash_api_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+// the user of an active token in `api_tokens`
+async fn token_user(token: &str) -> Result<Option<String>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"UPDATE api_tokens t SET last_used = CURRENT_TIMESTAMP
+        FROM users u
+        WHERE t.user_id = u.user_id AND t.token_hash = $1 AND t.status = 'active'
+        RETURNING u.username"#,
+        hash_api_token(token)
+    )
+    .fetch_optional(&DB_POOL.clone())
+    .await?;
+    Ok(row.map(|row| row.username))
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for ApiUser {
+    type Rejection = ApiAuthError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let Some(token) = bearer_token(parts) else {
+            return Err(ApiAuthError(
+                StatusCode::UNAUTHORIZED,
+                "missing bearer token".into(),
+            ));
+        };
+        match std::env::var("AI_GENT_API_TOKEN") {
+            // the digests are compared, so the time taken does not depend on the token
+            Ok(api_token)
+                if !api_token.is_empty() && hash_api_token(token) == hash_api_token(&api_token) =>
+            {
+                return Ok(ApiUser {
+                    username: std::env::var("AI_GENT_API_USER")
+                        .unwrap_or(MOCK_USER.username.clone()),
+                })
+            }
+            _ => {}
+        }
+        match token_user(token).await {
+            Ok(Some(username)) => Ok(ApiUser { username }),
+            Ok(None) => Err(ApiAuthError(
+                StatusCode::UNAUTHORIZED,
+                "invalid API token".into(),
+            )),
+            Err(e) => Err(ApiAuthError(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                e.to_string(),
+            )),
+        }
+    }
+}
@@ -11,6 +11,7 @@ use tron_app::HtmlAttributes;
 use serde::{Deserialize, Serialize};
 use sqlx::postgres::PgPool;
 use sqlx::{Column, Row, TypeInfo, ValueRef};
+use crate::ingestion_jobs::{job_progress_html, list_unfinished_jobs};
 use crate::MOCK_USER;
 
 use super::DB_POOL;
@@ -56,6 +57,8 @@ impl<'a: 'static> AssetCards<'a> {
 #[template(path = "asset_library.html", escape = "none")] // using the template in this path, relative                                    // to the `templates` dir in the crate root
 struct AssetLibraryTemplate {
     cards: Vec<(i32, String, String)>,
+    // the ingestion jobs still running or failed: id, asset name, progress and status
+    jobs: Vec<(i32, String, String, String)>,
 }
 
 #[async_trait]
@@ -85,7 +88,21 @@ where
             })
             .collect::<Vec<_>>();
         
-        let html = AssetLibraryTemplate { cards };
+        let jobs = list_unfinished_jobs(&self.user_data)
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .map(|job| {
+                (
+                    job.job_id,
+                    ammonia::clean_text(&job.asset_name),
+                    job_progress_html(job.job_id),
+                    job.status,
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let html = AssetLibraryTemplate { cards, jobs };
         html.render().unwrap()
     }
 
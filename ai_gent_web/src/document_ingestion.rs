@@ -0,0 +1,329 @@
+// The ingestion of uploaded documents (PDF, Markdown, HTML or plain text) as the chunks of an
+// asset, without the offline `pdf_to_embedding.py`: the text is extracted, split by
+// `TEXT_CHUNKING_SERVICE` and embedded by `EMBEDDING_SERVICE`, and the chunks are placed on the
+// embedding map by projecting their vectors on the first two principal components.
+
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context};
+use tron_app::TRON_APP;
+
+use crate::embedding_service::{DocumentChunk, EMBEDDING_SERVICE, TEXT_CHUNKING_SERVICE};
+
+const PCA_ITERATIONS: usize = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DocumentKind {
+    Pdf,
+    Html,
+    // Markdown is kept as it is, the LLMs read it well
+    Text,
+}
+
+impl DocumentKind {
+    // by the file extension first, the browsers often send no type for `.md` files
+    pub fn detect(filename: &str, content_type: &str) -> Option<Self> {
+        let extension = Path::new(filename)
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase());
+        match extension.as_deref() {
+            Some("pdf") => return Some(DocumentKind::Pdf),
+            Some("html") | Some("htm") => return Some(DocumentKind::Html),
+            Some("md") | Some("markdown") | Some("txt") | Some("text") => {
+                return Some(DocumentKind::Text)
+            }
+            _ => {}
+        }
+        let content_type = content_type.split(';').next().unwrap_or("").trim();
+        match content_type {
+            "application/pdf" => Some(DocumentKind::Pdf),
+            "text/html" => Some(DocumentKind::Html),
+            "text/markdown" | "text/x-markdown" | "text/plain" => Some(DocumentKind::Text),
+            _ => None,
+        }
+    }
+}
+
+// the chunk files of `pdf_to_embedding.py`, `.jsonl` or `.jsonl.gz`, by their content as the
+// clients may send them as `text/plain`
+pub fn is_chunk_file(filename: &str, data: &[u8]) -> bool {
+    let filename = filename.to_lowercase();
+    if data.starts_with(&[0x1f, 0x8b])
+        || filename.ends_with(".jsonl")
+        || filename.ends_with(".jsonl.gz")
+    {
+        return true;
+    }
+    data.split(|b| *b == b'\n')
+        .find(|line| !line.iter().all(u8::is_ascii_whitespace))
+        .is_some_and(|line| serde_json::from_slice::<DocumentChunk>(line).is_ok())
+}
+
+pub struct UploadedDocument {
+    pub filename: String,
+    pub kind: DocumentKind,
+    pub data: Vec<u8>,
+}
+
+pub fn extract_text(kind: DocumentKind, data: &[u8]) -> anyhow::Result<String> {
+    let text = match kind {
+        DocumentKind::Pdf => {
+            pdf_extract::extract_text_from_mem(data).context("fail to extract the text of the PDF")?
+        }
+        DocumentKind::Html => {
+            html2text::from_read(data, 120).context("fail to extract the text of the HTML")?
+        }
+        DocumentKind::Text => String::from_utf8_lossy(data).into_owned(),
+    };
+    // Postgres does not take NUL in a text
+    Ok(text.replace('\0', ""))
+}
+
+// the title is the file name without its extension, as in `pdf_to_embedding.py`
+fn document_to_chunks(document: &UploadedDocument) -> anyhow::Result<Vec<DocumentChunk>> {
+    let chunking_service = TEXT_CHUNKING_SERVICE
+        .get()
+        .ok_or_else(|| anyhow!("the text chunking service is not initialized"))?;
+    let embedding_service = EMBEDDING_SERVICE
+        .get()
+        .ok_or_else(|| anyhow!("the embedding service is not initialized"))?;
+
+    let text = extract_text(document.kind, &document.data)?;
+    if text.trim().is_empty() {
+        bail!("no text in the document");
+    }
+    let mut chunks = chunking_service.text_to_chunks(&text);
+    chunks.retain(|c| !c.text.trim().is_empty());
+    embedding_service.get_embedding_for_chunks(&mut chunks)?;
+
+    let title = Path::new(&document.filename)
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_else(|| document.filename.clone());
+    Ok(chunks
+        .into_iter()
+        .map(|c| DocumentChunk {
+            text: c.text,
+            span: c.span,
+            token_ids: None,
+            two_d_embedding: None,
+            embedding_vec: c.embedding_vec,
+            filename: document.filename.clone(),
+            title: title.clone(),
+        })
+        .collect())
+}
+
+// the chunks of the documents with their embedding vectors and 2D coordinates, and the file names
+// of the documents that fail with the reason
+pub async fn ingest_documents(
+    documents: Vec<UploadedDocument>,
+) -> (Vec<DocumentChunk>, Vec<(String, String)>) {
+    let mut chunks = Vec::new();
+    let mut failures = Vec::new();
+    for document in documents {
+        let filename = document.filename.clone();
+        // the extraction and the embedding are CPU bound
+        let result = tokio::task::spawn_blocking(move || document_to_chunks(&document)).await;
+        match result {
+            Ok(Ok(document_chunks)) => {
+                tracing::info!(target: TRON_APP, "{} chunks from {}", document_chunks.len(), filename);
+                chunks.extend(document_chunks);
+            }
+            Ok(Err(e)) => {
+                tracing::info!(target: TRON_APP, "fail to ingest {}: {}", filename, e);
+                failures.push((filename, e.to_string()));
+            }
+            Err(e) => {
+                tracing::info!(target: TRON_APP, "fail to ingest {}: {}", filename, e);
+                failures.push((filename, "the ingestion task failed".to_string()));
+            }
+        }
+    }
+    let chunks = tokio::task::spawn_blocking(move || {
+        project_to_2d(&mut chunks);
+        chunks
+    })
+    .await
+    .unwrap_or_default();
+    (chunks, failures)
+}
+
+// the coordinates of the embedding map, the projections of the vectors on their first two
+// principal components, scaled to [-1, 1] like the UMAP coordinates of `pdf_to_embedding.py`
+pub fn project_to_2d(chunks: &mut [DocumentChunk]) {
+    let dim = chunks
+        .iter()
+        .map(|c| c.embedding_vec.as_ref().map_or(0, |v| v.len()))
+        .max()
+        .unwrap_or(0);
+    if dim == 0 {
+        return;
+    }
+    let n = chunks.len() as f64;
+    let mut mean = vec![0.0_f64; dim];
+    for c in chunks.iter() {
+        for (m, x) in mean.iter_mut().zip(c.embedding_vec.iter().flatten()) {
+            *m += *x as f64 / n;
+        }
+    }
+    let centered = chunks
+        .iter()
+        .map(|c| {
+            let v = c.embedding_vec.as_deref().unwrap_or(&[]);
+            (0..dim)
+                .map(|i| v.get(i).copied().unwrap_or(0.0) as f64 - mean[i])
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    let first = principal_component(&centered, None);
+    let second = principal_component(&centered, Some(&first));
+    let xs = centered.iter().map(|v| dot(v, &first)).collect::<Vec<_>>();
+    let ys = centered.iter().map(|v| dot(v, &second)).collect::<Vec<_>>();
+    let (min_x, max_x) = min_max(&xs);
+    let (min_y, max_y) = min_max(&ys);
+    for (c, (x, y)) in chunks.iter_mut().zip(xs.into_iter().zip(ys)) {
+        c.two_d_embedding = Some((scale(x, min_x, max_x), scale(y, min_y, max_y)));
+    }
+}
+
+// power iteration on the covariance of the centered vectors, orthogonal to `other`
+fn principal_component(vectors: &[Vec<f64>], other: Option<&[f64]>) -> Vec<f64> {
+    let dim = vectors[0].len();
+    // a fixed start to get the same map for the same documents
+    let mut component = (0..dim).map(|i| 1.0 + (i % 7) as f64 / 7.0).collect::<Vec<_>>();
+    for _ in 0..PCA_ITERATIONS {
+        if let Some(other) = other {
+            remove_projection(&mut component, other);
+        }
+        normalize(&mut component);
+        let mut next = vec![0.0_f64; dim];
+        for v in vectors {
+            let p = dot(v, &component);
+            for (n, x) in next.iter_mut().zip(v) {
+                *n += p * x;
+            }
+        }
+        component = next;
+    }
+    if let Some(other) = other {
+        remove_projection(&mut component, other);
+    }
+    normalize(&mut component);
+    component
+}
+
+fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+fn normalize(v: &mut [f64]) {
+    let norm = dot(v, v).sqrt();
+    if norm > 0.0 {
+        v.iter_mut().for_each(|x| *x /= norm);
+    }
+}
+
+fn remove_projection(v: &mut [f64], unit: &[f64]) {
+    let p = dot(v, unit);
+    for (x, u) in v.iter_mut().zip(unit) {
+        *x -= p * u;
+    }
+}
+
+fn min_max(values: &[f64]) -> (f64, f64) {
+    values
+        .iter()
+        .fold((f64::MAX, f64::MIN), |(min, max), &v| (min.min(v), max.max(v)))
+}
+
+fn scale(x: f64, min: f64, max: f64) -> f32 {
+    if max > min {
+        (2.0 * (x - min) / (max - min) - 1.0) as f32
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(embedding_vec: Vec<f32>) -> DocumentChunk {
+        DocumentChunk {
+            text: "text".into(),
+            span: (0, 4),
+            token_ids: None,
+            two_d_embedding: None,
+            embedding_vec: Some(embedding_vec),
+            filename: "doc.pdf".into(),
+            title: "doc".into(),
+        }
+    }
+
+    #[test]
+    fn test_detect() {
+        // the extension wins over the content type
+        assert_eq!(
+            DocumentKind::detect("notes.md", "application/octet-stream"),
+            Some(DocumentKind::Text)
+        );
+        assert_eq!(DocumentKind::detect("paper.PDF", "text/plain"), Some(DocumentKind::Pdf));
+        assert_eq!(DocumentKind::detect("page.htm", ""), Some(DocumentKind::Html));
+        assert_eq!(DocumentKind::detect("upload", "application/pdf"), Some(DocumentKind::Pdf));
+        assert_eq!(
+            DocumentKind::detect("upload", "text/html; charset=utf-8"),
+            Some(DocumentKind::Html)
+        );
+        assert_eq!(DocumentKind::detect("upload", "application/octet-stream"), None);
+    }
+
+    #[test]
+    fn test_is_chunk_file() {
+        let line = serde_json::to_string(&chunk(vec![0.1, 0.2])).unwrap();
+        assert!(is_chunk_file("chunks.txt", format!("{}\n{}\n", line, line).as_bytes()));
+        assert!(is_chunk_file("chunks.jsonl.gz", b""));
+        assert!(is_chunk_file("upload", &[0x1f, 0x8b, 0x08]));
+        assert!(!is_chunk_file("notes.txt", b"some notes\n{\"text\": 1}"));
+    }
+
+    #[test]
+    fn test_extract_text() {
+        let text = extract_text(DocumentKind::Text, b"a\0b\0c").unwrap();
+        assert_eq!(text, "abc");
+        let text = extract_text(DocumentKind::Html, b"<p>hello <b>world</b></p>").unwrap();
+        assert!(text.contains("hello") && text.contains("world"));
+        assert!(!text.contains('<'));
+    }
+
+    #[test]
+    fn test_project_to_2d() {
+        let vectors = (0..20)
+            .map(|i| {
+                let x = i as f32;
+                vec![x, 2.0 * x + 1.0, (x * 0.7).sin(), (x * 1.3).cos(), 0.5]
+            })
+            .collect::<Vec<_>>();
+        let mut chunks = vectors.iter().cloned().map(chunk).collect::<Vec<_>>();
+        project_to_2d(&mut chunks);
+        let coordinates = chunks
+            .iter()
+            .map(|c| c.two_d_embedding.unwrap())
+            .collect::<Vec<_>>();
+        for (x, y) in &coordinates {
+            assert!((-1.0..=1.0).contains(x) && (-1.0..=1.0).contains(y));
+        }
+        assert!(coordinates.iter().any(|(x, _)| *x == -1.0));
+        assert!(coordinates.iter().any(|(x, _)| *x == 1.0));
+
+        // the same map for the same vectors
+        let mut again = vectors.into_iter().map(chunk).collect::<Vec<_>>();
+        project_to_2d(&mut again);
+        let again = again
+            .iter()
+            .map(|c| c.two_d_embedding.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(coordinates, again);
+    }
+}
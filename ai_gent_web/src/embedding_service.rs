@@ -10,6 +10,8 @@ use tokenizers::{Encoding, Tokenizer};
 use tokio::sync::OnceCell;
 use tron_app::TRON_APP;
 
+use crate::metrics::{record_error, CHUNKING_DURATION, EMBEDDING_DURATION, PGVECTOR_QUERY_DURATION};
+
 use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::fs::{self, File};
@@ -87,6 +89,7 @@ impl TextChunkingService {
     }
 
     pub fn text_to_chunks(&self, text: &str) -> Vec<EmbeddingChunk> {
+        let _timer = CHUNKING_DURATION.start_timer();
         let text = text.chars().collect::<Vec<char>>();
         let mut segment_start = 0_usize;
         let mut segment_end = self.tokenize_max_tokens * 2; // assume average token length is greater than 2
@@ -233,6 +236,7 @@ impl EmbeddingService {
     }
 
     pub fn get_embedding_for_chunks(&self, chunks: &mut [EmbeddingChunk]) -> anyhow::Result<()> {
+        let _timer = EMBEDDING_DURATION.start_timer();
         let device = &self.model.device;
 
         let embeddings = chunks
@@ -472,6 +476,7 @@ pub async fn vector_query_and_sort_points(
     let db_pool = DB_POOL.clone();
     let threshold = threshold.unwrap_or( 0.0_f32);
 
+    let timer = PGVECTOR_QUERY_DURATION.start_timer();
     let results = if let Some(top_k) = top_k {
         sqlx::query(
             r#"WITH similarity_cte AS (
@@ -514,10 +519,18 @@ pub async fn vector_query_and_sort_points(
         .await
     };
 
-    if let Ok(rows) = results {
-        for r in rows {
-            let p = pgrow_to_point(r);
-            all_points.push(p);
+    timer.observe_duration();
+
+    match results {
+        Ok(rows) => {
+            for r in rows {
+                let p = pgrow_to_point(r);
+                all_points.push(p);
+            }
+        }
+        Err(e) => {
+            record_error("pgvector_query");
+            tracing::info!(target: TRON_APP, "vector query error: {}", e);
         }
     }
 
@@ -532,7 +545,7 @@ pub async fn search_asset(query: &str, asset_id: i32, top_k: usize, threshold: f
     };
 
     // use LLM to extend the context for simple question
-    // let query = &extend_query_with_llm(query).await;
+    // let query = &extend_query_with_llm(llm_name, query).await.unwrap_or(query.to_string());
     // tracing::info!(target: TRON_APP, "extended query: {}", query);
 
     let tk_service = TextChunkingService::new(None, 128, 0, 4096);
@@ -2,12 +2,14 @@ use std::collections::HashMap;
 use std::ops::Deref;
 use std::ops::DerefMut;
 
-use ai_gent_lib::{fsm::FsmState, llm_agent::* , GenaiLlmclient};
+use ai_gent_lib::{model_registry::model_api_key, agent_delegation::{render_delegate_task, run_delegate_agent, AgentConfigLoader}, fsm::FsmState, llm_agent::* , llm_cache::with_default_cache, llm_service::{GenerationOptions, LlmChatRequest, LlmError}, GenaiLlmclient};
 use async_trait::async_trait;
-use futures::StreamExt;
+use serde_json::Value;
 use tokio::sync::mpsc::Receiver;
 use tokio::sync::mpsc::Sender;
 use tokio::task::JoinHandle;
+use tracing::instrument::WithSubscriber;
+use tracing::Instrument;
 use tron_app::TRON_APP;
 
 #[derive(Default)]
@@ -16,15 +18,24 @@ pub struct ChatState {
     prompts: StatePrompts,
     config: StateConfig,
     attributes: HashMap<String, String>,
-    handle: Option<JoinHandle<String>>,
+    handle: Option<JoinHandle<Result<String, LlmError>>>,
 }
 
 impl LlmFsmStateInit for ChatState {
     fn new(name: &str, prompts: StatePrompts, config: StateConfig) -> Self {
+        let mut attributes = HashMap::<String, String>::default();
+        if config.require_approval.unwrap_or(false) {
+            attributes.insert("require_approval".into(), "true".into());
+        }
+        // the agent routes and summarizes, it reads the cache setting from the state
+        if config.cache_fsm.unwrap_or(false) {
+            attributes.insert("cache_fsm".into(), "true".into());
+        }
         ChatState {
             name: name.to_string(),
             prompts,
             config,
+            attributes,
             ..Default::default()
         }
     }
@@ -53,9 +64,39 @@ impl FsmState for ChatState {
         tx: Sender<(String, String, String)>,
         _rx: Option<Receiver<(String, String, String)>>,
         next_states: Option<Vec<String>>,
-    ) -> Option<String> {
+    ) -> Result<Option<String>, anyhow::Error> {
         let llm_req_setting: LlmReqSetting =
             serde_json::from_str(&self.get_attribute("llm_req_setting").await.unwrap()).unwrap();
+
+        // the state hands the query over to another agent, see `ai_gent_lib::agent_delegation`
+        if self.config.delegates() {
+            let result = match render_delegate_task(&self.config, &llm_req_setting) {
+                Ok(task) => {
+                    run_delegate_agent::<ChatState>(
+                        &self.name,
+                        &self.config,
+                        &llm_req_setting,
+                        task,
+                        &tx,
+                    )
+                    .await
+                }
+                Err(e) => Err(e),
+            };
+            let llm_output = result.map_err(|e| anyhow::anyhow!("delegation failed: {}", e))?;
+            let _ = tx
+                .send(("".into(), "llm_output".into(), llm_output.clone()))
+                .await;
+            self.set_attribute("llm_output", llm_output).await;
+            return Ok(next_states.and_then(|next_states| {
+                if next_states.len() == 1 {
+                    next_states.first().cloned()
+                } else {
+                    None
+                }
+            }));
+        }
+
         let prompt = self.prompts.chat.clone();
         let system_prompt = self.prompts.system.clone().unwrap_or("".into());
         let summary = llm_req_setting
@@ -104,17 +145,16 @@ impl FsmState for ChatState {
             None => "".into(),
         };
         if full_prompt.is_empty() {
-            let _ = tx
-                .send(("".into(), "error".into(), "no state prompt".into()))
-                .await;
-            return None;
-        };
-        let llm_client = GenaiLlmclient {
-            model: llm_req_setting.model,
-            api_key: llm_req_setting.api_key,
+            return Err(anyhow::anyhow!("no state prompt"));
         };
+        let llm_client = with_default_cache(
+            self.config
+                .llm_client(&llm_req_setting)
+                .with_audit_purpose(&self.name, "answer"),
+            self.config.cache_llm.unwrap_or(false),
+        );
+        let temperature = self.config.temperature(&llm_req_setting);
         let messages = llm_req_setting.messages;
-        let temperature = llm_req_setting.temperature;
         self.handle = Some(tokio::spawn(async move {
             let _ = tx
                 .send((
@@ -123,40 +163,38 @@ impl FsmState for ChatState {
                     "LLM request sent, waiting for response\n".into(),
                 ))
                 .await;
-            let mut llm_output = String::default();
-            let mut llm_stream = llm_client
-                .generate_stream(&full_prompt, &messages, temperature)
-                .await;
-            while let Some(result) = llm_stream.next().await {
-                if let Some(output) = result {
-                    llm_output.push_str(&output);
-                    let _ = tx.send(("".into(), "token".into(), output)).await;
-                };
-            }
-            let _ = tx
-                .send(("".into(), "llm_output".into(), llm_output.clone()))
-                .await;
-            llm_output
-        }));
+            let req = LlmChatRequest::from_prompt(&full_prompt, &messages, temperature);
+            let llm_stream = llm_client.chat_stream(&req).await;
+            forward_llm_stream(llm_stream, "", &tx, false, false).await
+        }
+        .in_current_span()
+        .with_current_subscriber()));
 
         if let Some(handle) = self.handle.take() {
-            let llm_output = tokio::join!(handle);
-            let llm_output = llm_output.0.unwrap();
+            let _abort_on_drop = AbortOnDrop(handle.abort_handle());
+            // a failed request ends the turn, its (empty) output is not used
+            let llm_output = handle.await??;
             self.set_attribute("llm_output", llm_output).await;
         } else {
             self.set_attribute("llm_output", "".into()).await;
         };
         if let Some(next_states) = next_states {
             if next_states.len() == 1 {
-                Some(next_states.first().unwrap().clone())
+                Ok(Some(next_states.first().unwrap().clone()))
             } else {
-                None
+                Ok(None)
             }
         } else {
-            None
+            Ok(None)
         }
     }
 
+    // the agent's settings when the state runs under `fsm_message_service`, e.g. as a delegate
+    // agent, `ChatAgent::process_message` sets the `llm_req_setting` attribute itself
+    async fn set_service_context(&mut self, context: Value) {
+        self.set_attribute("llm_req_setting", context.to_string()).await;
+    }
+
     async fn set_attribute(&mut self, k: &str, v: String) {
         self.attributes.insert(k.to_string(), v);
     }
@@ -174,6 +212,87 @@ impl FsmState for ChatState {
     }
 }
 
+// loads the agents stored in the `agents` table for `delegate_agent_id`, only the agents of the
+// user of the delegating agent
+pub struct DbAgentConfigLoader;
+
+#[async_trait]
+impl AgentConfigLoader for DbAgentConfigLoader {
+    async fn load_agent_config(
+        &self,
+        user_id: Option<i32>,
+        agent_id: i32,
+    ) -> Result<LlmFsmAgentConfig, anyhow::Error> {
+        let user_id =
+            user_id.ok_or_else(|| anyhow::anyhow!("agent {}: no user to delegate for", agent_id))?;
+        let configuration = sqlx::query_scalar::<_, Value>(
+            "SELECT configuration FROM agents
+            WHERE agent_id = $1 AND user_id = $2 AND status = 'active'",
+        )
+        .bind(agent_id)
+        .bind(user_id)
+        .fetch_optional(&crate::DB_POOL.clone())
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("agent {} not found", agent_id))?;
+
+        let agent_setting: crate::AgentSetting = serde_json::from_value(configuration)?;
+        let mut config =
+            LlmFsmAgentConfigBuilder::from_toml(&agent_setting.fsm_agent_config)?.build()?;
+        // the model is a setting of the stored agent, not of its FSM config
+        config.model = config.model.or(Some(agent_setting.model_name));
+        Ok(config)
+    }
+}
+
+// a chat agent of a stored agent of the user, it resumes in `fsm_state` if that is one of its
+// states and starts in the initial state of `fsm_agent_config` otherwise
+pub async fn new_chat_agent(
+    user_id: i32,
+    llm_name: &str,
+    fsm_agent_config: &str,
+    fsm_state: Option<String>,
+) -> Result<ChatAgent<LlmFsmAgent>, anyhow::Error> {
+    let fsm_config = LlmFsmAgentConfigBuilder::from_toml(fsm_agent_config)?.build()?;
+    let fsm = LlmFsmBuilder::from_config::<ChatState>(&fsm_config, HashMap::default())?.build()?;
+    let api_key = model_api_key(llm_name)?;
+
+    let (fsm_state, exec_entry_actions) = match fsm_state {
+        Some(fsm_state) => (Some(fsm_state), false),
+        None => (fsm.get_current_state_name(), true),
+    };
+
+    let agent_settings = AgentSettings {
+        sys_prompt: fsm_config.system_prompt,
+        fsm_prompt: fsm_config.fsm_prompt,
+        summary_prompt: fsm_config.summary_prompt,
+        model: llm_name.to_string(),
+        tools: None,
+        api_key,
+        provider: fsm_config.provider,
+        fsm_initial_state: fsm_config.initial_state,
+        total_state_transition_limit: None,
+    };
+
+    let mut agent = ChatAgent {
+        base: LlmFsmAgent::new(fsm, agent_settings),
+    };
+    agent.base.llm_req_settings.user_id = Some(user_id);
+    if agent
+        .base
+        .set_current_state(fsm_state, exec_entry_actions)
+        .await
+        .is_err()
+    {
+        let fsm_state = agent.base.fsm.get_current_state_name();
+        agent
+            .base
+            .set_current_state(fsm_state, true)
+            .await
+            .map_err(|e| anyhow::anyhow!(e))?;
+    }
+    Ok(agent)
+}
+
 pub struct ChatAgent<LLMAgent> {
     pub base: LLMAgent,
 }
@@ -192,6 +311,14 @@ impl<LLMAgent> DerefMut for ChatAgent<LLMAgent> {
 }
 
 impl ChatAgent<LlmFsmAgent> {
+    // `cache_fsm` of the state, off by default as for the states of the library
+    async fn cache_fsm(&self, state_name: &str) -> bool {
+        match self.fsm.states.get(state_name) {
+            Some(state) => state.get_attribute("cache_fsm").await.is_some(),
+            None => false,
+        }
+    }
+
     pub async fn process_message(
         &mut self,
         user_input: &str,
@@ -246,17 +373,33 @@ impl ChatAgent<LlmFsmAgent> {
         );
 
         let fsm_prompt = [self.fsm_prompt.as_str(), msg.as_str()].join("\n");
-        let llm_client = GenaiLlmclient {
+        let fsm_llm_client = GenaiLlmclient {
             model: self.llm_req_settings.model.clone(),
             api_key: self.llm_req_settings.api_key.clone(),
+            options: GenerationOptions {
+                provider: self.llm_req_settings.provider.clone(),
+                session_id: self.llm_req_settings.session_id.clone(),
+                audit: self.llm_req_settings.audit.clone(),
+                ..Default::default()
+            },
         };
-        let next_state = llm_client
-            .generate(
-                &fsm_prompt,
-                &self.llm_req_settings.messages,
-                self.llm_req_settings.temperature,
-            )
-            .await?;
+        let cache_fsm = self.cache_fsm(&current_state_name).await;
+        let llm_client = with_default_cache(
+            fsm_llm_client
+                .clone()
+                .with_audit_purpose(&current_state_name, "routing"),
+            cache_fsm,
+        );
+        let req = LlmChatRequest::from_prompt(
+            &fsm_prompt,
+            &self.llm_req_settings.messages,
+            self.llm_req_settings.temperature,
+        );
+        let response = llm_client.chat(&req).await?;
+        if let Some(tx) = tx.as_ref() {
+            send_usage(tx, &current_state_name, &response.usage).await;
+        }
+        let next_state = response.text;
 
         let next_fsm_step_response: LlmResponse = serde_json::from_str(&next_state)
             .map_err(|e| anyhow::anyhow!("Failed to parse LLM output: {e}, {}", next_state))?;
@@ -286,7 +429,19 @@ impl ChatAgent<LlmFsmAgent> {
                 .await;
             let next_state = if let Some(tx) = tx.clone() {
                 // call LLM through the next_state.serve()
-                new_state.start_service(tx, None, Some(next_states)).await
+                let state_span = tracing::info_span!(
+                    "agent_state",
+                    state = %new_state_name,
+                    latency_ms = tracing::field::Empty,
+                );
+                let state_start = std::time::Instant::now();
+                let next_state = new_state
+                    .start_service(tx, None, Some(next_states))
+                    .instrument(state_span.clone())
+                    .await;
+                state_span.record("latency_ms", state_start.elapsed().as_millis() as u64);
+                // a failed LLM request ends the turn, the caller reports the error
+                next_state?
             } else {
                 None
             };
@@ -317,6 +472,12 @@ impl ChatAgent<LlmFsmAgent> {
                 .await;
         };
 
+        // the summary uses the model of the routing, it is audited apart from it
+        let cache_fsm = self.cache_fsm(&new_state_name).await;
+        let summary_llm_client = with_default_cache(
+            fsm_llm_client.with_audit_purpose(&new_state_name, "summary"),
+            cache_fsm,
+        );
         let summary_prompt = self.summary_prompt.clone();
         let temperature = self.llm_req_settings.temperature;
         {
@@ -335,10 +496,12 @@ impl ChatAgent<LlmFsmAgent> {
                 "</summary>",
             ]
             .join("\n");
-            let updated_summary = llm_client
-                .generate(&summary_prompt, &last_message, temperature)
-                .await?;
-            summary.push(serde_json::from_str(&updated_summary).unwrap_or_default());
+            let req = LlmChatRequest::from_prompt(&summary_prompt, &last_message, temperature);
+            let response = summary_llm_client.chat(&req).await?;
+            if let Some(tx) = tx.as_ref() {
+                send_usage(tx, &new_state_name, &response.usage).await;
+            }
+            summary.push(serde_json::from_str(&response.text).unwrap_or_default());
         }
         if let Some(tx) = tx {
             let _ = tx
@@ -362,3 +525,47 @@ impl ChatAgent<LlmFsmAgent> {
         Ok(llm_output)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    // a state without a chat prompt answers with an error event, without a LLM request
+    #[tokio::test]
+    async fn test_chat_state_message_service() {
+        let fsm_config = LlmFsmAgentConfigBuilder::from_toml(
+            r#"
+            states = ["Answer"]
+            transitions = []
+            initial_state = "Answer"
+            system_prompt = ""
+            fsm_prompt = ""
+            summary_prompt = ""
+            [state_prompts]
+            "#,
+        )
+        .unwrap()
+        .build()
+        .unwrap();
+        let mut agent =
+            LlmFsmAgent::from_config::<ChatState>(&fsm_config, "gpt-4o", None, "test-key").unwrap();
+
+        let (tx, mut rx) = mpsc::channel::<(String, String, String)>(16);
+        let (send_msg, rcv_msg) = mpsc::channel::<(String, String)>(4);
+        let service = tokio::spawn(async move { agent.fsm_message_service(rcv_msg, tx, None).await });
+        send_msg.send(("message".into(), "hello".into())).await.unwrap();
+
+        let mut events = vec![];
+        while let Some((_, t, r)) = rx.recv().await {
+            if t == "message_processed" {
+                break;
+            }
+            events.push((t, r));
+        }
+        assert!(events.contains(&("error".to_string(), "no state prompt".to_string())));
+
+        send_msg.send(("terminate".into(), "".into())).await.unwrap();
+        assert!(service.await.unwrap().is_ok());
+    }
+}
@@ -0,0 +1,621 @@
+// Background jobs ingesting the uploaded files of a new asset.
+//
+// The files are stored with the job in `ingestion_job_files` and the asset is created with the
+// `ingesting` status, hidden from the asset library until the job completes. The job parses the
+// files (see `document_ingestion`), then inserts the chunks in batches, each batch in a
+// transaction with the job's `processed_chunks`. A failed job is resumed from its last batch: the
+// files are parsed again and the chunks already stored are skipped. The progress is streamed to
+// the UI over SSE from `/api/asset/job/{id}/events`.
+//
+// A running job renews its `heartbeat_at` while it runs. Several servers can share the
+// database, so only the jobs whose heartbeat is older than `JOB_LEASE` are marked failed, at
+// the start of the server and periodically after it.
+
+use std::convert::Infallible;
+use std::ops::Bound;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::bail;
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Html, IntoResponse, Response,
+    },
+};
+use pgvector::Vector;
+use serde::Serialize;
+use sqlx::postgres::types::PgRange;
+use sqlx::{Postgres, QueryBuilder, Row};
+use tower_sessions::Session;
+use tron_app::{
+    tron_components::{TnComponentBaseRenderTrait, TnComponentRenderTrait, TnContext},
+    AppData, TRON_APP,
+};
+
+use crate::document_ingestion::{ingest_documents, is_chunk_file, DocumentKind, UploadedDocument};
+use crate::embedding_service::{DocumentChunk, DocumentChunks};
+use crate::metrics::record_error;
+use crate::{ASSET_CARDS, DB_POOL, MOCK_USER};
+
+// 7 parameters a row, well under the 65535 parameters of a Postgres statement
+const INSERT_BATCH_SIZE: usize = 256;
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+// a job without a heartbeat for this long is not run by any server anymore
+const JOB_LEASE: Duration = Duration::from_secs(5 * 60);
+
+pub struct UploadedFile {
+    pub filename: String,
+    pub content_type: String,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct IngestionJob {
+    pub job_id: i32,
+    pub asset_id: i32,
+    pub asset_name: String,
+    // pending, running, completed, failed or discarded
+    pub status: String,
+    pub total_chunks: Option<i32>,
+    pub processed_chunks: i32,
+    // the chunks the database refused, e.g. with an embedding of another dimension
+    pub skipped_chunks: i32,
+    pub error: Option<String>,
+    pub skipped_files: Vec<(String, String)>,
+}
+
+impl IngestionJob {
+    fn is_finished(&self) -> bool {
+        matches!(self.status.as_str(), "completed" | "failed" | "discarded")
+    }
+}
+
+// creates the asset and its job, the job is started with `start_ingestion_job`
+pub async fn create_ingestion_job(
+    username: &str,
+    name: &str,
+    description: &str,
+    files: Vec<UploadedFile>,
+) -> anyhow::Result<(i32, i32)> {
+    // the files of a job are identified by their names
+    for (i, file) in files.iter().enumerate() {
+        if files[..i].iter().any(|f| f.filename == file.filename) {
+            bail!("the file {} is uploaded more than once", file.filename);
+        }
+    }
+
+    let mut tx = DB_POOL.begin().await?;
+    let row = sqlx::query(
+        r#"INSERT INTO assets (user_id, name, description, status)
+           SELECT user_id, $2, $3, 'ingesting'
+           FROM users
+           WHERE username = $1
+           RETURNING asset_id, user_id"#,
+    )
+    .bind(username)
+    .bind(name)
+    .bind(description)
+    .fetch_one(&mut *tx)
+    .await?;
+    let asset_id: i32 = row.get("asset_id");
+    let user_id: i32 = row.get("user_id");
+
+    let job_id: i32 = sqlx::query_scalar(
+        "INSERT INTO ingestion_jobs (asset_id, user_id) VALUES ($1, $2) RETURNING job_id",
+    )
+    .bind(asset_id)
+    .bind(user_id)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    for file in files {
+        sqlx::query(
+            r#"INSERT INTO ingestion_job_files (job_id, filename, content_type, data)
+               VALUES ($1, $2, $3, $4)"#,
+        )
+        .bind(job_id)
+        .bind(&file.filename)
+        .bind(&file.content_type)
+        .bind(&file.data)
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+    Ok((job_id, asset_id))
+}
+
+// runs a pending or failed job in the background, returns false if the job is not in one of them
+pub async fn start_ingestion_job(job_id: i32) -> Result<bool, sqlx::Error> {
+    let claimed = sqlx::query(
+        r#"UPDATE ingestion_jobs
+           SET status = 'running', error = NULL, heartbeat_at = NOW(), updated_at = NOW()
+           WHERE job_id = $1 AND status IN ('pending', 'failed')"#,
+    )
+    .bind(job_id)
+    .execute(&DB_POOL.clone())
+    .await?
+    .rows_affected()
+        == 1;
+    if !claimed {
+        return Ok(false);
+    }
+    tokio::spawn(async move {
+        let heartbeat = tokio::spawn(renew_heartbeat(job_id));
+        let result = run_ingestion_job(job_id).await;
+        heartbeat.abort();
+        if let Err(e) = result {
+            record_error("asset_ingestion");
+            tracing::info!(target: TRON_APP, "ingestion job {} failed: {}", job_id, e);
+            let _ = sqlx::query(
+                "UPDATE ingestion_jobs SET status = 'failed', error = $2, updated_at = NOW() WHERE job_id = $1",
+            )
+            .bind(job_id)
+            .bind(e.to_string())
+            .execute(&DB_POOL.clone())
+            .await;
+        }
+    });
+    Ok(true)
+}
+
+async fn renew_heartbeat(job_id: i32) {
+    let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+    loop {
+        interval.tick().await;
+        let result = sqlx::query(
+            "UPDATE ingestion_jobs SET heartbeat_at = NOW() WHERE job_id = $1 AND status = 'running'",
+        )
+        .bind(job_id)
+        .execute(&DB_POOL.clone())
+        .await;
+        if let Err(e) = result {
+            tracing::info!(target: TRON_APP, "ingestion job {}: fail to renew the heartbeat: {}", job_id, e);
+        }
+    }
+}
+
+// the jobs left by a stopped server, running or never started, are marked failed to be resumed
+// by their users
+async fn fail_abandoned_jobs() -> Result<u64, sqlx::Error> {
+    let result = sqlx::query(
+        r#"UPDATE ingestion_jobs
+           SET status = 'failed', error = 'interrupted by a restart of the server', updated_at = NOW()
+           WHERE status IN ('pending', 'running')
+             AND COALESCE(heartbeat_at, updated_at) < NOW() - make_interval(secs => $1)"#,
+    )
+    .bind(JOB_LEASE.as_secs_f64())
+    .execute(&DB_POOL.clone())
+    .await?;
+    Ok(result.rows_affected())
+}
+
+pub async fn init_ingestion_jobs() {
+    match fail_abandoned_jobs().await {
+        Ok(n) if n > 0 => eprintln!("{} interrupted ingestion jobs can be resumed.", n),
+        Ok(_) => {}
+        Err(e) => eprintln!("Fail to check the ingestion jobs: {}", e),
+    }
+    // the jobs of this server that were running when it stopped expire after the start
+    tokio::spawn(async {
+        let mut interval = tokio::time::interval(JOB_LEASE);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            match fail_abandoned_jobs().await {
+                Ok(n) if n > 0 => {
+                    tracing::info!(target: TRON_APP, "{} interrupted ingestion jobs can be resumed", n)
+                }
+                Ok(_) => {}
+                Err(e) => tracing::info!(target: TRON_APP, "fail to check the ingestion jobs: {}", e),
+            }
+        }
+    });
+}
+
+async fn run_ingestion_job(job_id: i32) -> anyhow::Result<()> {
+    let db_pool = DB_POOL.clone();
+    let job = sqlx::query(
+        "SELECT asset_id, processed_chunks, skipped_chunks FROM ingestion_jobs WHERE job_id = $1",
+    )
+    .bind(job_id)
+    .fetch_one(&db_pool)
+    .await?;
+    let asset_id: i32 = job.get("asset_id");
+    let processed_chunks: i32 = job.get("processed_chunks");
+    let mut skipped: i32 = job.get("skipped_chunks");
+
+    // in the same order on each run, so that the chunks already stored are the first ones
+    let files = sqlx::query(
+        "SELECT filename, content_type, data FROM ingestion_job_files WHERE job_id = $1 ORDER BY filename",
+    )
+    .bind(job_id)
+    .fetch_all(&db_pool)
+    .await?
+    .into_iter()
+    .map(|row| UploadedFile {
+        filename: row.get("filename"),
+        content_type: row.get("content_type"),
+        data: row.get("data"),
+    })
+    .collect::<Vec<_>>();
+
+    let (chunks, skipped_files) = parse_files(files).await;
+    tracing::info!(target: TRON_APP, "ingestion job {}: {} chunks parsed", job_id, chunks.len());
+    sqlx::query(
+        r#"UPDATE ingestion_jobs SET total_chunks = $2, skipped_files = $3, updated_at = NOW()
+           WHERE job_id = $1"#,
+    )
+    .bind(job_id)
+    .bind(chunks.len() as i32)
+    .bind(sqlx::types::Json(&skipped_files))
+    .execute(&db_pool)
+    .await?;
+    if chunks.is_empty() {
+        bail!("no valid asset data in the uploaded files");
+    }
+
+    let mut processed = (processed_chunks.max(0) as usize).min(chunks.len());
+    for batch in chunks[processed..].chunks(INSERT_BATCH_SIZE) {
+        let mut tx = db_pool.begin().await?;
+        match insert_chunks(&mut *tx, asset_id, batch).await {
+            Ok(()) => {
+                processed += batch.len();
+                update_progress(&mut *tx, job_id, processed, skipped).await?;
+                tx.commit().await?;
+            }
+            // a row refused by the database fails its batch, as the inserts one row at a time
+            // did, only the rows that can't be stored are skipped
+            Err(sqlx::Error::Database(e)) => {
+                tx.rollback().await?;
+                tracing::info!(target: TRON_APP, "ingestion job {}: batch insert error {}, inserting one by one", job_id, e);
+                for chunk in batch {
+                    match insert_chunks(&db_pool, asset_id, std::slice::from_ref(chunk)).await {
+                        Ok(()) => {}
+                        Err(sqlx::Error::Database(e)) => {
+                            skipped += 1;
+                            tracing::info!(target: TRON_APP, "ingestion job {}: skip a chunk of {}: {}", job_id, chunk.filename, e);
+                        }
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+                processed += batch.len();
+                update_progress(&db_pool, job_id, processed, skipped).await?;
+            }
+            // e.g. the connection is lost, the job fails and can be resumed from this batch
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    let mut tx = db_pool.begin().await?;
+    sqlx::query("UPDATE assets SET status = 'active' WHERE asset_id = $1 AND status = 'ingesting'")
+        .bind(asset_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query(
+        "UPDATE ingestion_jobs SET status = 'completed', error = NULL, updated_at = NOW() WHERE job_id = $1",
+    )
+    .bind(job_id)
+    .execute(&mut *tx)
+    .await?;
+    // the files are only kept to resume the job
+    sqlx::query("DELETE FROM ingestion_job_files WHERE job_id = $1")
+        .bind(job_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+// the chunks of the files and the documents that can't be read
+async fn parse_files(files: Vec<UploadedFile>) -> (Vec<DocumentChunk>, Vec<(String, String)>) {
+    let mut chunks = Vec::new();
+    let mut documents = Vec::new();
+    for file in files {
+        let kind = if is_chunk_file(&file.filename, &file.data) {
+            None
+        } else {
+            DocumentKind::detect(&file.filename, &file.content_type)
+        };
+        if let Some(kind) = kind {
+            documents.push(UploadedDocument {
+                filename: file.filename,
+                kind,
+                data: file.data,
+            });
+            continue;
+        }
+        // the chunk files from the embedding tool, gzipped or not
+        let document_chunks = if file.data.starts_with(&[0x1f, 0x8b]) {
+            DocumentChunks::from_gz_data(&file.data)
+        } else {
+            DocumentChunks::from_data(&file.data)
+        };
+        if let Some(document_chunks) = document_chunks {
+            chunks.extend(document_chunks.chunks);
+        }
+    }
+    let (ingested_chunks, skipped_files) = ingest_documents(documents).await;
+    chunks.extend(ingested_chunks);
+    (chunks, skipped_files)
+}
+
+async fn insert_chunks<'c, E>(
+    executor: E,
+    asset_id: i32,
+    chunks: &[DocumentChunk],
+) -> Result<(), sqlx::Error>
+where
+    E: sqlx::Executor<'c, Database = Postgres>,
+{
+    let mut query_builder = QueryBuilder::<Postgres>::new(
+        "INSERT INTO text_embedding (asset_id, text, span, embedding_vector, two_d_embedding, filename, title) ",
+    );
+    query_builder.push_values(chunks, |mut row, c| {
+        let span = PgRange {
+            start: Bound::Included(c.span.0 as i32),
+            end: Bound::Excluded(c.span.1 as i32),
+        };
+        let embedding_vector = Vector::from(c.embedding_vec.clone().unwrap_or_default());
+        let two_d_embedding = match c.two_d_embedding {
+            Some(v) => Vector::from(vec![v.0, v.1]),
+            None => Vector::from(vec![]),
+        };
+        row.push_bind(asset_id)
+            .push_bind(c.text.clone())
+            .push_bind(span)
+            .push_bind(embedding_vector)
+            .push_bind(two_d_embedding)
+            .push_bind(c.filename.clone())
+            .push_bind(c.title.clone());
+    });
+    // the same chunk may come twice, and again when a job is resumed
+    query_builder.push(" ON CONFLICT DO NOTHING");
+    query_builder.build().execute(executor).await?;
+    Ok(())
+}
+
+async fn update_progress<'c, E>(
+    executor: E,
+    job_id: i32,
+    processed: usize,
+    skipped: i32,
+) -> Result<(), sqlx::Error>
+where
+    E: sqlx::Executor<'c, Database = Postgres>,
+{
+    sqlx::query(
+        r#"UPDATE ingestion_jobs SET processed_chunks = $2, skipped_chunks = $3, updated_at = NOW()
+           WHERE job_id = $1"#,
+    )
+    .bind(job_id)
+    .bind(processed as i32)
+    .bind(skipped)
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+fn row_to_job(row: sqlx::postgres::PgRow) -> IngestionJob {
+    IngestionJob {
+        job_id: row.get("job_id"),
+        asset_id: row.get("asset_id"),
+        asset_name: row.get("asset_name"),
+        status: row.get("status"),
+        total_chunks: row.get("total_chunks"),
+        processed_chunks: row.get("processed_chunks"),
+        skipped_chunks: row.get("skipped_chunks"),
+        error: row.get("error"),
+        skipped_files: row
+            .get::<sqlx::types::Json<Vec<(String, String)>>, _>("skipped_files")
+            .0,
+    }
+}
+
+const JOB_COLUMNS: &str = "j.job_id, j.asset_id, a.name AS asset_name, j.status, j.total_chunks,
+    j.processed_chunks, j.skipped_chunks, j.error, j.skipped_files";
+
+pub async fn get_ingestion_job(job_id: i32, username: &str) -> Result<Option<IngestionJob>, sqlx::Error> {
+    let row = sqlx::query(&format!(
+        r#"SELECT {}
+           FROM ingestion_jobs j
+           JOIN assets a ON a.asset_id = j.asset_id
+           JOIN users u ON j.user_id = u.user_id
+           WHERE j.job_id = $1 AND u.username = $2"#,
+        JOB_COLUMNS
+    ))
+    .bind(job_id)
+    .bind(username)
+    .fetch_optional(&DB_POOL.clone())
+    .await?;
+    Ok(row.map(row_to_job))
+}
+
+// the jobs shown in the asset library
+pub async fn list_unfinished_jobs(username: &str) -> Result<Vec<IngestionJob>, sqlx::Error> {
+    let rows = sqlx::query(&format!(
+        r#"SELECT {}
+           FROM ingestion_jobs j
+           JOIN assets a ON a.asset_id = j.asset_id
+           JOIN users u ON j.user_id = u.user_id
+           WHERE u.username = $1 AND j.status IN ('pending', 'running', 'failed')
+           ORDER BY j.job_id ASC"#,
+        JOB_COLUMNS
+    ))
+    .bind(username)
+    .fetch_all(&DB_POOL.clone())
+    .await?;
+    Ok(rows.into_iter().map(row_to_job).collect())
+}
+
+// a progress bar following the job over SSE
+pub fn job_progress_html(job_id: i32) -> String {
+    format!(
+        r#"<div class="ingestion-job">
+  <progress class="progress progress-primary w-full" value="0" max="1"></progress>
+  <p class="text-sm">waiting for the job</p>
+</div>
+<script>
+(function () {{
+  const el = document.currentScript.previousElementSibling;
+  const bar = el.querySelector("progress");
+  const msg = el.querySelector("p");
+  const source = new EventSource("/api/asset/job/{}/events");
+  source.onmessage = function (e) {{
+    const job = JSON.parse(e.data);
+    if (job.total_chunks !== null) {{
+      bar.max = Math.max(job.total_chunks, 1);
+      bar.value = job.processed_chunks;
+    }}
+    let text = job.status === "completed" ? "completed, the asset is in the Asset Library"
+      : job.status === "failed" ? "failed: " + job.error
+      : job.total_chunks === null ? "processing the uploaded files"
+      : job.processed_chunks + " / " + job.total_chunks + " chunks stored";
+    if (job.skipped_chunks > 0) {{
+      text += " (" + job.skipped_chunks + " chunks could not be stored)";
+    }}
+    if (job.skipped_files.length > 0) {{
+      text += " (skipped: " + job.skipped_files.map(f => f[0] + ": " + f[1]).join(", ") + ")";
+    }}
+    msg.textContent = text;
+    if (["completed", "failed", "discarded"].includes(job.status)) {{
+      source.close();
+    }}
+  }};
+  source.onerror = function () {{ source.close(); }};
+}})();
+</script>"#,
+        job_id
+    )
+}
+
+async fn session_context(appdata: &AppData, session: &Session) -> Option<TnContext> {
+    let session_id = session.id()?;
+    appdata.context_store.read().await.get(&session_id).cloned()
+}
+
+async fn context_username(ctx: &TnContext) -> String {
+    let ctx_guard = ctx.read().await;
+    ctx_guard
+        .get_user_data()
+        .await
+        .unwrap_or(MOCK_USER.clone())
+        .username
+}
+
+fn not_authorized() -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        [(header::CONTENT_TYPE, "text/html")],
+        "Not Authorized",
+    )
+        .into_response()
+}
+
+pub async fn ingestion_job_events(
+    State(appdata): State<Arc<AppData>>,
+    Path(job_id): Path<i32>,
+    session: Session,
+) -> Response {
+    let Some(ctx) = session_context(&appdata, &session).await else {
+        return not_authorized();
+    };
+    let username = context_username(&ctx).await;
+    match get_ingestion_job(job_id, &username).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+
+    // (first event, job finished)
+    let events = futures::stream::unfold((true, false), move |(first, finished)| {
+        let username = username.clone();
+        async move {
+            if finished {
+                return None;
+            }
+            if !first {
+                tokio::time::sleep(PROGRESS_INTERVAL).await;
+            }
+            let job = get_ingestion_job(job_id, &username).await.ok().flatten()?;
+            let finished = job.is_finished();
+            let event = Event::default().data(serde_json::to_string(&job).unwrap_or_default());
+            Some((Ok::<_, Infallible>(event), (false, finished)))
+        }
+    });
+    Sse::new(events).keep_alive(KeepAlive::default()).into_response()
+}
+
+async fn render_asset_cards(ctx: &TnContext) -> Response {
+    let mut h = HeaderMap::new();
+    h.insert("Hx-Reswap", "outerHTML show:top".parse().unwrap());
+    h.insert("Hx-Retarget", "#workspace".parse().unwrap());
+    let out_html = {
+        let ctx_guard = ctx.read().await;
+        let component_guard = ctx_guard.components.read().await;
+        let mut asset_cards = component_guard.get(ASSET_CARDS).unwrap().write().await;
+        asset_cards.pre_render(&ctx_guard).await;
+        asset_cards.render().await
+    };
+    (StatusCode::OK, h, Html::from(out_html)).into_response()
+}
+
+pub async fn resume_ingestion_job(
+    State(appdata): State<Arc<AppData>>,
+    Path(job_id): Path<i32>,
+    session: Session,
+) -> Response {
+    let Some(ctx) = session_context(&appdata, &session).await else {
+        return not_authorized();
+    };
+    let username = context_username(&ctx).await;
+    if let Ok(Some(_)) = get_ingestion_job(job_id, &username).await {
+        if let Err(e) = start_ingestion_job(job_id).await {
+            tracing::info!(target: TRON_APP, "fail to resume the ingestion job {}: {}", job_id, e);
+        }
+    }
+    render_asset_cards(&ctx).await
+}
+
+// gives up a failed job, its asset is deactivated
+pub async fn discard_ingestion_job(
+    State(appdata): State<Arc<AppData>>,
+    Path(job_id): Path<i32>,
+    session: Session,
+) -> Response {
+    let Some(ctx) = session_context(&appdata, &session).await else {
+        return not_authorized();
+    };
+    let username = context_username(&ctx).await;
+    if let Ok(Some(job)) = get_ingestion_job(job_id, &username).await {
+        if job.status == "failed" {
+            let result = discard_job(job_id, job.asset_id).await;
+            if let Err(e) = result {
+                tracing::info!(target: TRON_APP, "fail to discard the ingestion job {}: {}", job_id, e);
+            }
+        }
+    }
+    render_asset_cards(&ctx).await
+}
+
+async fn discard_job(job_id: i32, asset_id: i32) -> Result<(), sqlx::Error> {
+    let mut tx = DB_POOL.begin().await?;
+    sqlx::query(
+        "UPDATE ingestion_jobs SET status = 'discarded', updated_at = NOW() WHERE job_id = $1 AND status = 'failed'",
+    )
+    .bind(job_id)
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query("UPDATE assets SET status = 'inactive' WHERE asset_id = $1 AND status = 'ingesting'")
+        .bind(asset_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM ingestion_job_files WHERE job_id = $1")
+        .bind(job_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await
+}
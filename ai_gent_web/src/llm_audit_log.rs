@@ -0,0 +1,181 @@
+// The audit log of the LLM requests of the chats, see `ai_gent_lib::llm_audit`.
+//
+// LLM_AUDIT=off disables it, LLM_AUDIT_RETENTION_DAYS sets how long the records are kept (30 days
+// by default, 0 keeps them). `/api/chat/{id}/llm_calls` shows the requests of a chat.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use ai_gent_lib::llm_audit::{set_llm_audit_log, PostgresLlmAuditLog};
+use ammonia::clean_text;
+use axum::{
+    extract::{Path, State},
+    http::{header, StatusCode},
+    response::IntoResponse,
+};
+use sqlx::Row;
+use tower_sessions::Session;
+use tron_app::AppData;
+
+use crate::{DB_POOL, MOCK_USER};
+
+const DEFAULT_RETENTION_DAYS: u64 = 30;
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+pub fn init_llm_audit_log() {
+    if std::env::var("LLM_AUDIT").is_ok_and(|v| v == "off") {
+        eprintln!("The audit log of the LLM requests is disabled.");
+        return;
+    }
+    set_llm_audit_log(Arc::new(PostgresLlmAuditLog::new(DB_POOL.clone())));
+
+    let retention_days = std::env::var("LLM_AUDIT_RETENTION_DAYS")
+        .ok()
+        .and_then(|days| days.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_RETENTION_DAYS);
+    if retention_days == 0 {
+        return;
+    }
+    let retention = Duration::from_secs(retention_days * 24 * 60 * 60);
+    tokio::spawn(async move {
+        let audit_log = PostgresLlmAuditLog::new(DB_POOL.clone());
+        let mut interval = tokio::time::interval(PURGE_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = audit_log.purge(retention).await {
+                tracing::info!(target: "log", "LLM audit log purge error: {}", e);
+            }
+        }
+    });
+}
+
+fn html_section(title: &str, text: &str) -> String {
+    format!(
+        r#"<details style="margin: 4px 0;"><summary>{}</summary><pre style="white-space: pre-wrap;">{}</pre></details>"#,
+        title,
+        clean_text(text)
+    )
+}
+
+pub async fn show_chat_llm_calls(
+    State(appdata): State<Arc<AppData>>,
+    Path(chat_id): Path<i32>,
+    session: Session,
+) -> impl IntoResponse {
+    let Some(session_id) = session.id() else {
+        return (
+            StatusCode::UNAUTHORIZED,
+            [(header::CONTENT_TYPE, "text/html")],
+            "Not Authorized".to_string(),
+        );
+    };
+    let user_data = {
+        let ctx_store_guard = appdata.context_store.read().await;
+        let ctx = ctx_store_guard.get(&session_id).unwrap();
+        let ctx_guard = ctx.read().await;
+        ctx_guard.get_user_data().await.unwrap_or(MOCK_USER.clone())
+    };
+
+    // only the records of the user's own chats
+    let rows = sqlx::query(
+        "SELECT l.created_at, l.message_id, l.fsm_state, l.purpose, l.model, l.provider, l.prompt,
+                l.messages, l.output, l.input_tokens, l.output_tokens, l.latency_ms, l.error, l.cached
+         FROM llm_audit_log l
+         JOIN chats c ON c.chat_id = l.chat_id
+         JOIN users u ON c.user_id = u.user_id
+         WHERE l.chat_id = $1 AND u.username = $2
+         ORDER BY l.created_at ASC, l.audit_id ASC",
+    )
+    .bind(chat_id)
+    .bind(&user_data.username)
+    .fetch_all(&DB_POOL.clone())
+    .await;
+    let rows = match rows {
+        Ok(rows) => rows,
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                [(header::CONTENT_TYPE, "text/html")],
+                clean_text(&e.to_string()),
+            )
+        }
+    };
+
+    let records = rows
+        .into_iter()
+        .map(|row| {
+            let created_at = row
+                .get::<Option<chrono::DateTime<chrono::Utc>>, _>("created_at")
+                .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+                .unwrap_or_default();
+            let message_id = row
+                .get::<Option<i32>, _>("message_id")
+                .map(|id| format!("message {}", id))
+                .unwrap_or_default();
+            let tokens = match (
+                row.get::<Option<i32>, _>("input_tokens"),
+                row.get::<Option<i32>, _>("output_tokens"),
+            ) {
+                (Some(input), Some(output)) => format!("{} in / {} out tokens", input, output),
+                _ => "no usage".into(),
+            };
+            let latency = if row.get::<bool, _>("cached") {
+                "from the cache".to_string()
+            } else {
+                format!("{} ms", row.get::<i64, _>("latency_ms"))
+            };
+            let title = format!(
+                "<b>{}</b> {} {} &middot; {} &middot; {} ({}) &middot; {} &middot; {}",
+                clean_text(&row.get::<String, _>("purpose")),
+                created_at,
+                message_id,
+                clean_text(&row.get::<Option<String>, _>("fsm_state").unwrap_or("-".into())),
+                clean_text(&row.get::<String, _>("model")),
+                clean_text(&row.get::<Option<String>, _>("provider").unwrap_or("default".into())),
+                tokens,
+                latency,
+            );
+            let messages = row
+                .get::<Option<serde_json::Value>, _>("messages")
+                .map(|messages| serde_json::to_string_pretty(&messages).unwrap_or_default())
+                .unwrap_or_default();
+            let mut sections = vec![
+                html_section("prompt", &row.get::<Option<String>, _>("prompt").unwrap_or_default()),
+                html_section("messages", &messages),
+                html_section("output", &row.get::<Option<String>, _>("output").unwrap_or_default()),
+            ];
+            if let Some(error) = row.get::<Option<String>, _>("error") {
+                sections.push(format!(
+                    r#"<div style="color: #FF8888;">error: {}</div>"#,
+                    clean_text(&error)
+                ));
+            }
+            format!(
+                r#"<div style="padding: 10px; border-radius: 8px; margin: 8px; background-color: #333333;">{}{}</div>"#,
+                title,
+                sections.join("\n")
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let body = if records.is_empty() {
+        "<p>No LLM request is recorded for this chat.</p>".to_string()
+    } else {
+        records.join("\n")
+    };
+    let html = format!(
+        r#"<html>
+      <head><title>LLM requests of chat {}</title></head>
+      <body style="background-color:rgb(75, 75, 75); color: #CCCCCC; font-family: sans-serif;">
+      <div style="max-width: 1000px; margin: 0 auto; word-wrap: break-word; overflow-wrap: break-word;">
+      <h2>LLM requests of chat {}</h2>
+      {}
+      </div></body></html>"#,
+        chat_id, chat_id, body
+    );
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "text/html; charset=utf-8")],
+        html,
+    )
+}
@@ -2,21 +2,37 @@
 #![allow(unused_imports)]
 
 mod agent_cards;
+mod agent_session;
 mod agent_workspace;
+mod api_auth;
 mod asset_cards;
+mod document_ingestion;
 mod embedding_service;
+mod ingestion_jobs;
 mod services;
 mod session_cards;
 mod show_single_asset;
 mod fsm_chat_agent;
+mod llm_audit_log;
+mod metrics;
+mod openai_api;
+mod rest_api;
 
 use agent_cards::{LibraryCards, LibraryCardsBuilder};
 use agent_workspace::*;
+use ai_gent_lib::agent_delegation::set_agent_config_loader;
 use ai_gent_lib::llm_agent::{LlmFsmAgentConfig, LlmFsmAgentConfigBuilder, StatePrompts};
+use ai_gent_lib::model_registry::{list_models, load_models_file};
+use ai_gent_lib::llm_cache::{
+    set_default_llm_cache, LlmCache, LlmCachePolicy, MemoryLlmCache, PostgresLlmCache,
+    DEFAULT_MEMORY_CACHE_CAPACITY,
+};
+use ai_gent_lib::telemetry::init_trace_dispatch;
 use ammonia::clean_text;
 use askama::Template;
 use asset_cards::{AssetCards, AssetCardsBuilder};
 use candle_core::D;
+use ingestion_jobs::{create_ingestion_job, job_progress_html, start_ingestion_job, UploadedFile};
 use embedding_service::{DocumentChunk, DocumentChunks};
 use futures_util::Future;
 use pgvector::Vector;
@@ -25,10 +41,11 @@ use show_single_asset::AssetSpacePlot;
 
 use axum::{
     body::Body,
-    extract::{Json, Path, State},
+    extract::{DefaultBodyLimit, Json, Path, State},
     http::{header, HeaderMap, Method, StatusCode},
     response::{Html, IntoResponse, Redirect},
     routing::{get, post, trace},
+    handler::Handler,
     Router,
 };
 use serde::{Deserialize, Serialize};
@@ -123,14 +140,10 @@ pub static DB_POOL: Lazy<PgPool> = Lazy::new(|| {
         .expect("Failed to create database connection pool")
 });
 
-pub static SUPPORTED_MODELS: &[(&str, &str)] = &[
-    ("gpt-3.5-turbo", "OPENAI_API_KEY"),
-    ("gpt-4o", "OPENAI_API_KEY"),
-    ("gpt-4o-mini", "OPENAI_API_KEY"),
-    ("o3-mini", "OPENAI_API_KEY"),
-    ("claude-3-haiku-20240307", "ANTHROPIC_API_KEY"),
-    ("claude-3-5-sonnet-20241022", "ANTHROPIC_API_KEY"),
-];
+// the models in the model registry, see `ai_gent_lib/models.toml`
+pub fn supported_model_names() -> Vec<String> {
+    list_models().into_iter().map(|m| m.name).collect()
+}
 
 static MOCK_USER: Lazy<UserData> = Lazy::new(|| UserData {
     username: "user".into(),
@@ -159,23 +172,31 @@ Please set it up and restart the server."#);
 Environment variable 'DATABASE_URL' found."#);
     };
 
-    if std::env::var("OPENAI_API_KEY").is_err() && std::env::var("ANTHROPIC_API_KEY").is_err() {
-        eprintln!(r#"
-Neither 'OPENAI_API_KEY' nor 'ANTHROPIC_API_KEY' environment variable is set up. 
-Please set up at least one of them to start the server."#);
-        return;
+    if let Ok(models_config) = std::env::var("AI_GENT_MODELS_CONFIG") {
+        if let Err(e) = load_models_file(&models_config) {
+            eprintln!(r#"
+Fail to load the model registry from 'AI_GENT_MODELS_CONFIG': {}"#, e);
+            return;
+        }
     };
 
-    if std::env::var("OPENAI_API_KEY").is_ok() {
-        eprintln!(r#"
-Environment variable 'OPENAI_API_KEY' found."#);
-    };
+    let available_models = list_models()
+        .into_iter()
+        .filter(|m| m.api_key().is_ok())
+        .map(|m| m.name)
+        .collect::<Vec<_>>();
 
-    if std::env::var("ANTHROPIC_API_KEY").is_ok() {
+    if available_models.is_empty() {
         eprintln!(r#"
-Environment variable 'ANTHROPIC_API_KEY' found."#);
+None of the API key environment variables of the models in the model registry is set up
+(e.g. 'OPENAI_API_KEY' or 'ANTHROPIC_API_KEY').
+Please set up at least one of them to start the server."#);
+        return;
     };
 
+    eprintln!(r#"
+Models with the API key found: {}"#, available_models.join(", "));
+
     eprintln!(r#"
 Loading the deep learning models for tokenization and 
 generating embedding vector. The models are downloaded from 
@@ -184,6 +205,77 @@ Huggingface, it may take a while depending on your network speed.
 "#); 
     embedding_service::initialize_embedding_model().await;
 
+    // LLM_CACHE=memory|postgres enables caching deterministic LLM requests,
+    // LLM_CACHE_TTL_SECS sets how long the responses are kept, LLM_CACHE_CAPACITY how many
+    // responses the memory cache holds (10000 by default) and LLM_CACHE_RETENTION_DAYS how long
+    // the database keeps the responses without a TTL (30 days by default, 0 keeps them)
+    let llm_cache_ttl = std::env::var("LLM_CACHE_TTL_SECS")
+        .ok()
+        .and_then(|ttl| ttl.parse::<u64>().ok())
+        .map(std::time::Duration::from_secs);
+    let llm_cache_policy = LlmCachePolicy { ttl: llm_cache_ttl, ..Default::default() };
+    let llm_cache: Option<Arc<dyn LlmCache>> = match std::env::var("LLM_CACHE").as_deref() {
+        Ok("memory") => {
+            eprintln!("Caching LLM responses in memory.");
+            let capacity = std::env::var("LLM_CACHE_CAPACITY")
+                .ok()
+                .and_then(|capacity| capacity.parse::<usize>().ok())
+                .unwrap_or(DEFAULT_MEMORY_CACHE_CAPACITY);
+            Some(Arc::new(MemoryLlmCache::with_capacity(capacity)))
+        }
+        Ok("postgres") => {
+            eprintln!("Caching LLM responses in the database.");
+            let retention_days = std::env::var("LLM_CACHE_RETENTION_DAYS")
+                .ok()
+                .and_then(|days| days.parse::<u64>().ok())
+                .unwrap_or(30);
+            let cache = PostgresLlmCache::new(DB_POOL.clone());
+            Some(Arc::new(if retention_days > 0 {
+                cache.with_retention(std::time::Duration::from_secs(retention_days * 24 * 60 * 60))
+            } else {
+                cache
+            }))
+        }
+        _ => None,
+    };
+    if let Some(llm_cache) = llm_cache {
+        set_default_llm_cache(llm_cache.clone(), llm_cache_policy);
+        // the expired responses are purged every hour
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(60 * 60));
+            loop {
+                interval.tick().await;
+                if let Err(e) = llm_cache.purge().await {
+                    tracing::info!(target: TRON_APP, "LLM cache purge error: {}", e);
+                }
+            }
+        });
+    }
+
+    // OTEL_EXPORTER_OTLP_ENDPOINT enables exporting the traces of the agent queries, tron_app
+    // installs the global tracing subscriber, so they are exported through their own dispatch
+    let _telemetry = match init_trace_dispatch("ai_gent_web") {
+        Ok(telemetry) => {
+            if telemetry.is_some() {
+                eprintln!("Exporting the traces of the agent queries over OTLP.");
+            }
+            telemetry
+        }
+        Err(e) => {
+            eprintln!("Fail to set up the trace export: {}", e);
+            None
+        }
+    };
+
+    // the LLM requests of the chats are recorded in `llm_audit_log`
+    llm_audit_log::init_llm_audit_log();
+
+    // the ingestion jobs interrupted by a restart are left to be resumed
+    ingestion_jobs::init_ingestion_jobs().await;
+
+    // states with `delegate_agent_id` run the agents stored in the database
+    set_agent_config_loader(Arc::new(fsm_chat_agent::DbAgentConfigLoader));
+
     let ui_action_routes = Router::<Arc<AppData>>::new()
         .route("/service/session-check", get(session_check))
         .route("/agent/create", post(create_basic_agent))
@@ -197,9 +289,13 @@ Huggingface, it may take a while depending on your network speed.
         .route("/chat/{id}/show", get(show_chat))
         .route("/chat/{id}/download", get(download_chat))
         .route("/chat/{id}/download_html", get(download_chat_message_html))
+        .route("/chat/{id}/llm_calls", get(llm_audit_log::show_chat_llm_calls))
         .route("/asset/{id}/show", get(show_asset))
         .route("/asset/create", post(create_asset))
         .route("/asset/{id}/delete", get(delete_asset))
+        .route("/asset/job/{id}/events", get(ingestion_jobs::ingestion_job_events))
+        .route("/asset/job/{id}/resume", get(ingestion_jobs::resume_ingestion_job))
+        .route("/asset/job/{id}/discard", get(ingestion_jobs::discard_ingestion_job))
         .route("/check_user", get(check_user))
         .route(
             "/service/text_to_embedding",
@@ -208,7 +304,49 @@ Huggingface, it may take a while depending on your network speed.
         .route(
             "/service/query_for_chunks",
             post(services::query_for_chunks),
-        );
+        )
+        // OpenAI-compatible, e.g. with the base URL `http://localhost:8080/api/v1`
+        .route("/v1/chat/completions", post(openai_api::chat_completions))
+        .route("/v1/models", get(openai_api::list_models))
+        // the REST API, see `rest_api`
+        .route(
+            "/v1/agents",
+            get(rest_api::list_agents).post(rest_api::create_agent),
+        )
+        .route(
+            "/v1/agents/{id}",
+            get(rest_api::get_agent)
+                .put(rest_api::update_agent)
+                .delete(rest_api::delete_agent),
+        )
+        .route(
+            "/v1/assets",
+            get(rest_api::list_assets)
+                // the chunk files are larger than the default body limit
+                .post(rest_api::create_asset.layer(DefaultBodyLimit::max(512 * 1024 * 1024))),
+        )
+        .route(
+            "/v1/assets/{id}",
+            get(rest_api::get_asset).delete(rest_api::delete_asset),
+        )
+        .route("/v1/ingestion_jobs/{id}", get(rest_api::get_ingestion_job))
+        .route(
+            "/v1/ingestion_jobs/{id}/resume",
+            post(rest_api::resume_ingestion_job),
+        )
+        .route(
+            "/v1/chats",
+            get(rest_api::list_chats).post(rest_api::create_chat),
+        )
+        .route(
+            "/v1/chats/{id}",
+            get(rest_api::get_chat).delete(rest_api::delete_chat),
+        )
+        .route("/v1/chats/{id}/messages", post(rest_api::post_message))
+        // a WebSocket with the agent's events, see `agent_session`
+        .route("/v1/agents/{id}/session", get(agent_session::agent_session))
+        // Prometheus metrics, see `metrics`
+        .route("/metrics", get(metrics::metrics));
 
     let app_config = tron_app::AppConfigure {
         cognito_login: false,
@@ -383,7 +521,7 @@ fn add_dnd_file_upload(context: &mut TnContextBase, tnid: &str) {
     TnDnDFileUpload::builder()
         .init(
             tnid.into(),
-            "Drop An Asset JSON File".into(),
+            "Drop Asset Files (JSONL, PDF, Markdown, HTML or Text)".into(),
             button_attributes,
         )
         .set_action(TnActionExecutionMethod::Await, handle_file_upload)
@@ -490,7 +628,7 @@ fn change_workspace(context: TnContext, event: TnEvent, _payload: Value) -> TnFu
 
             BASIC_AGENT_DESIGN_BTN => {
 
-                let model_options = SUPPORTED_MODELS.iter().map( | (model_name, _) |
+                let model_options = supported_model_names().iter().map( |model_name|
                     format!(r#" <option value="{}">{}</option>"#, model_name, model_name) ).collect::<Vec<String>>();
 
                 let ctx_guard = context.read().await;
@@ -504,7 +642,7 @@ fn change_workspace(context: TnContext, event: TnEvent, _payload: Value) -> TnFu
             },
 
             ADV_AGENT_DESIGN_BTN => {
-                let model_options = SUPPORTED_MODELS.iter().map( | (model_name, _) |
+                let model_options = supported_model_names().iter().map( |model_name|
                     format!(r#" <option value="{}">{}</option>"#, model_name, model_name) ).collect::<Vec<String>>();
 
                 let ctx_guard = context.read().await;
@@ -731,9 +869,9 @@ fn show_basic_agent_setting(
     h.insert("Hx-Reswap", "outerHTML show:top".parse().unwrap());
     h.insert("Hx-Retarget", "#workspace".parse().unwrap());
 
-    let model_options = SUPPORTED_MODELS
+    let model_options = supported_model_names()
         .iter()
-        .map(|(this_model_name, _)| {
+        .map(|this_model_name| {
             if this_model_name == &model_name {
                 format!(
                     r#" <option value="{}" selected>{}</option>"#,
@@ -791,9 +929,9 @@ fn show_adv_agent_setting(
     h.insert("Hx-Reswap", "outerHTML show:top".parse().unwrap());
     h.insert("Hx-Retarget", "#workspace".parse().unwrap());
 
-    let model_options = SUPPORTED_MODELS
+    let model_options = supported_model_names()
         .iter()
-        .map(|(this_model_name, _)| {
+        .map(|this_model_name| {
             if this_model_name == &model_name {
                 format!(
                     r#" <option value="{}" selected>{}</option>"#,
@@ -1105,7 +1243,7 @@ async fn create_adv_agent(
         None
     };
 
-    if toml::from_str::<LlmFsmAgentConfig>(&agent_setting_form.fsm_agent_config).is_err() {
+    if LlmFsmAgentConfigBuilder::from_toml(&agent_setting_form.fsm_agent_config).is_err() {
         return Html::from(
             r##"
         <div id="update_agent_notification_msg">
@@ -1300,7 +1438,7 @@ async fn update_adv_agent(
         None
     };
 
-    if toml::from_str::<LlmFsmAgentConfig>(&agent_setting_form.fsm_agent_config).is_err() {
+    if LlmFsmAgentConfigBuilder::from_toml(&agent_setting_form.fsm_agent_config).is_err() {
         let html = Html::from(
             r##"
         <div id="update_agent_notification_msg">
@@ -1934,9 +2072,10 @@ async fn create_asset(
     };
     let ctx = ctx_store_guard.get(&session_id).unwrap();
 
-    let document_chunks = {
+    // the files are parsed and stored by a background job, see `ingestion_jobs`
+    let files = {
         let asset_ref = ctx.get_asset_ref().await;
-        let mut chunks = DocumentChunks { chunks: vec![] };
+        let mut files = Vec::new();
         let guard = asset_ref.read().await;
         if let Some(TnAsset::VecString2(asset_files)) = guard.get("asset_files") {
             for (filename, t) in asset_files {
@@ -1948,21 +2087,17 @@ async fn create_asset(
                     None
                 };
                 if let Some(data) = file_data {
-                    let c = match t.as_str() {
-                        "application/x-gzip" => DocumentChunks::from_gz_data(data),
-                        "application/json" | "" => DocumentChunks::from_data(data), // we may use .jsonl than just .json
-                        _ => None,
-                    };
-                    if let Some(c) = c {
-                        chunks.chunks.extend(c.chunks);
-                    }
+                    files.push(UploadedFile {
+                        filename: filename.clone(),
+                        content_type: t.clone(),
+                        data: data.clone(),
+                    });
                 }
             }
         };
-        chunks
+        files
     };
 
-    tracing::info!(target: TRON_APP, "number of chunks parsed: {}", document_chunks.chunks.len());
     // clear the upload buffer
     {
         let asset_ref = ctx.get_asset_ref().await;
@@ -1972,61 +2107,33 @@ async fn create_asset(
         }
     }
 
-    let html = if !document_chunks.chunks.is_empty() {
+    let html = if !files.is_empty() {
         let ctx_guard = ctx.read().await;
         let user_data = ctx_guard.get_user_data().await.unwrap_or(MOCK_USER.clone());
 
-        let db_pool = DB_POOL.clone();
-        // TODO: make sure the string is proper avoiding SQL injection
-        let query_result = sqlx::query!(
-            r#"INSERT INTO assets (user_id, name, description, status)
-        SELECT user_id, $2, $3, $4
-        FROM users
-        WHERE username = $1
-        RETURNING asset_id"#,
-            user_data.username,
-            asset_setting_form.name,
-            asset_setting_form.description,
-            "active",
+        let job = create_ingestion_job(
+            &user_data.username,
+            &asset_setting_form.name,
+            &asset_setting_form.description,
+            files,
         )
-        .fetch_one(&db_pool)
-        .await
-        .unwrap();
-
-        for c in document_chunks.chunks.into_iter() {
-            let span = PgRange {
-                start: Bound::Included(c.span.0 as i32),
-                end: Bound::Excluded(c.span.1 as i32),
-            };
-            let embedding_vector = if let Some(v) = c.embedding_vec {
-                Vector::from(v)
-            } else {
-                Vector::from(vec![])
-            };
-            let two_d_embedding = if let Some(v) = c.two_d_embedding {
-                Vector::from(vec![v.0, v.1])
-            } else {
-                Vector::from(vec![])
-            };
-            let _res = sqlx::query(
-             r#"INSERT INTO text_embedding (asset_id, text, span, embedding_vector, two_d_embedding, filename, title)
-             VALUES ($1, $2, $3, $4, $5, $6, $7)
-             RETURNING id"#   
-            ).bind(query_result.asset_id)
-            .bind(&c.text)
-            .bind(span)
-            .bind(embedding_vector)
-            .bind(two_d_embedding)
-            .bind(&c.filename)
-            .bind(&c.title).fetch_one(&db_pool).await;
-            // tracing::info!(target: TRON_APP, "insert embedding {:?}", _res);
+        .await;
+        match job {
+            Ok((job_id, _asset_id)) => {
+                if let Err(e) = start_ingestion_job(job_id).await {
+                    tracing::info!(target: TRON_APP, "fail to start the ingestion job {}: {}", job_id, e);
+                }
+                Html::from(format!(
+                    r#"<p class="py-4">The Asset Collection "{}" is being created</p>{}"#,
+                    clean_text(&asset_setting_form.name),
+                    job_progress_html(job_id)
+                ))
+            }
+            Err(e) => {
+                tracing::info!(target: TRON_APP, "fail to create the ingestion job: {}", e);
+                Html::from(r#"<p class="py-4">Asset Collection Creation Fails</p>"#.to_string())
+            }
         }
-
-        //let uuid = Uuid::new_v4();
-        Html::from(format!(
-            r#"<p class="py-4">An Asset Collection "{}" is created </p>"#,
-            clean_text(&asset_setting_form.name)
-        ))
     } else {
         Html::from(r#"<p class="py-4">No Valid Asset Data Uploaded</p>"#.to_string())
     };
@@ -0,0 +1,130 @@
+// `/api/metrics`, the Prometheus metrics of the server (tron_app serves the API routes under
+// `/api`). The LLM requests, the state transitions and the LLM errors are counted by
+// `ai_gent_lib::metrics`, the embedding, the chunking, the pgvector queries and the sessions here.
+//
+// The metrics are served only with the bearer token set with `METRICS_TOKEN`:
+//
+//   scrape_configs:
+//     - job_name: ai_gent
+//       metrics_path: /api/metrics
+//       authorization:
+//         credentials: <METRICS_TOKEN>
+//       static_configs:
+//         - targets: ["localhost:8080"]
+
+use std::sync::Arc;
+
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_histogram, register_int_gauge_vec, Encoder, Histogram, IntGaugeVec, TextEncoder,
+};
+use tron_app::AppData;
+
+pub use ai_gent_lib::metrics::record_error;
+
+// seconds
+const DURATION_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+pub static EMBEDDING_DURATION: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "ai_gent_embedding_duration_seconds",
+        "Duration of computing the embedding vectors of a batch of chunks",
+        DURATION_BUCKETS.to_vec()
+    )
+    .unwrap()
+});
+
+pub static CHUNKING_DURATION: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "ai_gent_chunking_duration_seconds",
+        "Duration of splitting a text into chunks",
+        DURATION_BUCKETS.to_vec()
+    )
+    .unwrap()
+});
+
+pub static PGVECTOR_QUERY_DURATION: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "ai_gent_pgvector_query_duration_seconds",
+        "Latency of the vector similarity queries of the asset searches",
+        DURATION_BUCKETS.to_vec()
+    )
+    .unwrap()
+});
+
+// `web` for the sessions of the web app, `agent_session` for the agent WebSockets
+pub static ACTIVE_SESSIONS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!("ai_gent_active_sessions", "Active sessions by kind", &["kind"]).unwrap()
+});
+
+// counts an agent WebSocket session while it is alive
+pub struct AgentSessionGauge;
+
+impl AgentSessionGauge {
+    pub fn start() -> Self {
+        ACTIVE_SESSIONS.with_label_values(&["agent_session"]).inc();
+        AgentSessionGauge
+    }
+}
+
+impl Drop for AgentSessionGauge {
+    fn drop(&mut self) {
+        ACTIVE_SESSIONS.with_label_values(&["agent_session"]).dec();
+    }
+}
+
+// without `METRICS_TOKEN` the route is disabled
+fn metrics_status(headers: &HeaderMap) -> StatusCode {
+    let Ok(metrics_token) = std::env::var("METRICS_TOKEN") else {
+        return StatusCode::NOT_FOUND;
+    };
+    if metrics_token.is_empty() {
+        return StatusCode::NOT_FOUND;
+    }
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim());
+    if token == Some(metrics_token.as_str()) {
+        StatusCode::OK
+    } else {
+        StatusCode::UNAUTHORIZED
+    }
+}
+
+pub async fn metrics(State(appdata): State<Arc<AppData>>, headers: HeaderMap) -> impl IntoResponse {
+    let status = metrics_status(&headers);
+    if status != StatusCode::OK {
+        return (
+            status,
+            [(header::CONTENT_TYPE, "text/plain".to_string())],
+            status.canonical_reason().unwrap_or_default().as_bytes().to_vec(),
+        );
+    }
+
+    let web_sessions = appdata.context_store.read().await.len();
+    ACTIVE_SESSIONS
+        .with_label_values(&["web"])
+        .set(web_sessions as i64);
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            [(header::CONTENT_TYPE, "text/plain".to_string())],
+            e.to_string().into_bytes(),
+        );
+    }
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, encoder.format_type().to_string())],
+        buffer,
+    )
+}
@@ -0,0 +1,343 @@
+// OpenAI-compatible endpoints for the stored agents.
+//
+// `POST /api/v1/chat/completions` runs the agent named by `model` (its name or its id) through
+// its FSM, with the asset retrieval of the web workspace, and answers in the chat completion
+// format, streamed as server-sent events if `stream` is set. The state of the agent is returned
+// in the `ai_gent` extension field; pass `ai_gent.fsm_state` back to continue in that state.
+// A failed agent (e.g. a failed LLM request) is a 502 with an `error` body, or an `error` event
+// that ends the stream. `GET /api/v1/models` lists the agents.
+//
+// The requests need `Authorization: Bearer <token>`, see `api_auth`.
+
+use std::convert::Infallible;
+
+use axum::{
+    http::StatusCode,
+    response::{
+        sse::{Event, Sse},
+        IntoResponse, Response,
+    },
+    Json,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::agent_workspace::get_search_context_plain_text;
+use crate::api_auth::ApiUser;
+use crate::embedding_service::search_asset;
+use crate::fsm_chat_agent::new_chat_agent;
+use crate::{AgentSetting, DB_POOL};
+
+#[derive(Deserialize)]
+pub struct ChatCompletionMessage {
+    role: String,
+    #[serde(default)]
+    content: Value,
+}
+
+#[derive(Deserialize, Default)]
+pub struct AiGentRequestExt {
+    fsm_state: Option<String>,
+    top_k: Option<usize>,
+    threshold: Option<f32>,
+}
+
+#[derive(Deserialize)]
+pub struct ChatCompletionRequest {
+    model: String,
+    messages: Vec<ChatCompletionMessage>,
+    #[serde(default)]
+    stream: bool,
+    temperature: Option<f32>,
+    #[serde(default)]
+    ai_gent: Option<AiGentRequestExt>,
+}
+
+fn openai_error(status: StatusCode, message: String) -> Response {
+    let body = json!({ "error": { "message": message, "type": "invalid_request_error" } });
+    (status, Json(body)).into_response()
+}
+
+// the errors of the agent, e.g. a failed LLM request
+fn api_error_json(message: String) -> Value {
+    json!({ "error": { "message": message, "type": "api_error" } })
+}
+
+// the text of a message, the content can be a string or a list of parts
+fn message_text(content: &Value) -> String {
+    match content {
+        Value::String(text) => text.clone(),
+        Value::Array(parts) => parts
+            .iter()
+            .filter_map(|part| part.get("text").and_then(|text| text.as_str()))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        _ => "".into(),
+    }
+}
+
+pub struct StoredAgent {
+    pub agent_id: i32,
+    pub user_id: i32,
+    pub name: String,
+    pub asset_id: i32,
+    pub setting: AgentSetting,
+}
+
+// an active agent of the user by its name or its id
+pub async fn get_stored_agent(username: &str, model: &str) -> Result<Option<StoredAgent>, anyhow::Error> {
+    let row = sqlx::query!(
+        "SELECT a.agent_id, a.user_id, a.name, a.configuration,
+                COALESCE(a.asset_id, 0) as asset_id,
+                COALESCE(assets.status, 'active') as asset_status
+        FROM agents a
+        JOIN users u ON a.user_id = u.user_id
+        LEFT JOIN assets ON assets.asset_id = a.asset_id
+        WHERE u.username = $1 AND a.status = 'active' AND (a.name = $2 OR a.agent_id::text = $2)
+        ORDER BY a.agent_id DESC
+        LIMIT 1;",
+        username,
+        model
+    )
+    .fetch_optional(&DB_POOL.clone())
+    .await?;
+
+    let Some(row) = row else {
+        return Ok(None);
+    };
+    let setting = serde_json::from_value::<AgentSetting>(row.configuration.unwrap_or_default())?;
+    let asset_id = match (row.asset_id, row.asset_status.as_deref()) {
+        (Some(asset_id), Some("active")) => asset_id,
+        _ => 0,
+    };
+    Ok(Some(StoredAgent {
+        agent_id: row.agent_id,
+        user_id: row.user_id,
+        name: row.name,
+        asset_id,
+        setting,
+    }))
+}
+
+pub async fn list_models(user: ApiUser) -> Response {
+    let rows = sqlx::query!(
+        "SELECT a.agent_id, a.name, a.created_at
+        FROM agents a
+        JOIN users u ON a.user_id = u.user_id
+        WHERE u.username = $1 AND a.status = 'active'
+        ORDER BY a.agent_id",
+        user.username
+    )
+    .fetch_all(&DB_POOL.clone())
+    .await;
+
+    match rows {
+        Ok(rows) => {
+            let data = rows
+                .into_iter()
+                .map(|row| {
+                    json!({
+                        "id": row.name,
+                        "object": "model",
+                        "created": row.created_at.map(|t| t.timestamp()).unwrap_or_default(),
+                        "owned_by": user.username,
+                        "ai_gent": { "agent_id": row.agent_id },
+                    })
+                })
+                .collect::<Vec<_>>();
+            Json(json!({ "object": "list", "data": data })).into_response()
+        }
+        Err(e) => openai_error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    }
+}
+
+pub async fn chat_completions(user: ApiUser, Json(req): Json<ChatCompletionRequest>) -> Response {
+    let Some(last_user_message) = req
+        .messages
+        .iter()
+        .rposition(|message| message.role == "user")
+    else {
+        return openai_error(StatusCode::BAD_REQUEST, "no user message".into());
+    };
+    let query = message_text(&req.messages[last_user_message].content);
+
+    let stored_agent = match get_stored_agent(&user.username, &req.model).await {
+        Ok(Some(stored_agent)) => stored_agent,
+        Ok(None) => {
+            return openai_error(
+                StatusCode::NOT_FOUND,
+                format!("the agent `{}` does not exist", req.model),
+            )
+        }
+        Err(e) => return openai_error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    };
+
+    let ext = req.ai_gent.unwrap_or_default();
+    let mut agent = match new_chat_agent(
+        stored_agent.user_id,
+        &stored_agent.setting.model_name,
+        &stored_agent.setting.fsm_agent_config,
+        ext.fsm_state,
+    )
+    .await
+    {
+        Ok(agent) => agent,
+        Err(e) => return openai_error(StatusCode::SERVICE_UNAVAILABLE, e.to_string()),
+    };
+
+    // the messages before the last user message are the history of the conversation
+    agent.base.llm_req_settings.messages = req.messages[..last_user_message]
+        .iter()
+        .filter(|message| message.role == "user" || message.role == "assistant")
+        .map(|message| (message.role.clone(), message_text(&message.content)))
+        .collect();
+    agent.base.llm_req_settings.session_id = Some(format!("api-{}", user.username));
+    agent.base.llm_req_settings.agent_name = Some(format!("agent-{}", stored_agent.agent_id));
+
+    let search_asset_results = search_asset(
+        &query,
+        stored_agent.asset_id,
+        ext.top_k.unwrap_or(8),
+        ext.threshold.unwrap_or(0.65),
+    )
+    .await;
+    agent.base.llm_req_settings.memory.insert(
+        "context".into(),
+        vec![Value::String(get_search_context_plain_text(&search_asset_results))],
+    );
+
+    let previous_state = agent.base.get_current_state().await;
+    let (tx, mut rx) = mpsc::channel::<(String, String, String)>(16);
+    let temperature = req.temperature;
+    let agent_handle = tokio::spawn(async move {
+        let result = agent.process_message(&query, Some(tx), temperature).await;
+        let fsm_state = agent.base.get_current_state().await;
+        (result, fsm_state)
+    });
+
+    let id = format!("chatcmpl-{}", Uuid::new_v4());
+    let created = chrono::Utc::now().timestamp();
+    let model = stored_agent.name.clone();
+    let agent_id = stored_agent.agent_id;
+    let ai_gent_ext = move |fsm_state: Option<String>| {
+        json!({
+            "agent_id": agent_id,
+            "previous_state": previous_state,
+            "fsm_state": fsm_state,
+        })
+    };
+
+    if !req.stream {
+        // only the errors are needed from the events, but the channel has to be drained
+        let mut error = None;
+        while let Some((_, t, r)) = rx.recv().await {
+            if t == "error" && error.is_none() {
+                error = Some(r);
+            }
+        }
+        return match agent_handle.await {
+            Ok((Ok(_), _)) if error.is_some() => (
+                StatusCode::BAD_GATEWAY,
+                Json(api_error_json(error.unwrap_or_default())),
+            )
+                .into_response(),
+            Ok((Ok(llm_output), fsm_state)) => Json(json!({
+                "id": id,
+                "object": "chat.completion",
+                "created": created,
+                "model": model,
+                "choices": [{
+                    "index": 0,
+                    "message": { "role": "assistant", "content": llm_output },
+                    "finish_reason": "stop",
+                }],
+                "ai_gent": ai_gent_ext(fsm_state),
+            }))
+            .into_response(),
+            Ok((Err(e), _)) => {
+                (StatusCode::BAD_GATEWAY, Json(api_error_json(e.to_string()))).into_response()
+            }
+            Err(e) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(api_error_json(e.to_string())),
+            )
+                .into_response(),
+        };
+    }
+
+    let (sse_tx, mut sse_rx) = mpsc::channel::<Event>(16);
+    tokio::spawn(async move {
+        let chunk = |delta: Value, finish_reason: Option<&str>, ext: Option<Value>| {
+            let mut chunk = json!({
+                "id": id,
+                "object": "chat.completion.chunk",
+                "created": created,
+                "model": model,
+                "choices": [{ "index": 0, "delta": delta, "finish_reason": finish_reason }],
+            });
+            if let Some(ext) = ext {
+                chunk["ai_gent"] = ext;
+            }
+            Event::default().data(chunk.to_string())
+        };
+
+        let _ = sse_tx
+            .send(chunk(json!({ "role": "assistant" }), None, None))
+            .await;
+        let mut streamed = false;
+        let mut error = None;
+        while let Some((state_name, t, r)) = rx.recv().await {
+            // the events of delegate agents are named "<state>/<sub state>", only the answer
+            // of the agent itself is the completion
+            match (state_name.as_str(), t.as_str()) {
+                (_, "error") => {
+                    // the stream ends with the first error, the agent is not waited for
+                    error = Some(r);
+                    agent_handle.abort();
+                    break;
+                }
+                ("", "token") => {
+                    streamed = true;
+                    let _ = sse_tx.send(chunk(json!({ "content": r }), None, None)).await;
+                }
+                ("", "llm_output") if !streamed => {
+                    streamed = true;
+                    let _ = sse_tx.send(chunk(json!({ "content": r }), None, None)).await;
+                }
+                _ => {}
+            }
+        }
+
+        let error = match error {
+            Some(error) => Some(error),
+            None => match agent_handle.await {
+                Ok((Ok(_), fsm_state)) => {
+                    let _ = sse_tx
+                        .send(chunk(json!({}), Some("stop"), Some(ai_gent_ext(fsm_state))))
+                        .await;
+                    None
+                }
+                Ok((Err(e), _)) => Some(e.to_string()),
+                Err(e) => Some(e.to_string()),
+            },
+        };
+        // an error is the last event of the stream
+        if let Some(error) = error {
+            let _ = sse_tx
+                .send(Event::default().data(api_error_json(error).to_string()))
+                .await;
+            return;
+        }
+        let _ = sse_tx.send(Event::default().data("[DONE]")).await;
+    });
+
+    let stream = futures::stream::unfold(sse_rx, |mut sse_rx| async move {
+        sse_rx
+            .recv()
+            .await
+            .map(|event| (Ok::<_, Infallible>(event), sse_rx))
+    });
+    Sse::new(stream).into_response()
+}
@@ -0,0 +1,794 @@
+// A JSON REST API for the agents, assets and chats of a user, served under `/api/v1`.
+//
+//   GET    /agents                  the active agents
+//   POST   /agents                  create an agent, `prompt` for a basic one or
+//                                   `fsm_agent_config` (TOML) for an advanced one
+//   GET    /agents/{id}             PUT /agents/{id}     DELETE /agents/{id}
+//   GET    /assets                  the active assets
+//   POST   /assets?name=..&description=..&filename=..
+//                                   create an asset from a `.jsonl` or `.jsonl.gz` body, or from
+//                                   a PDF, Markdown, HTML or text document, by `filename` or
+//                                   `Content-Type`, returns the ingestion job storing it
+//   GET    /assets/{id}             DELETE /assets/{id}
+//   GET    /ingestion_jobs/{id}     the status and the progress of an ingestion job
+//   POST   /ingestion_jobs/{id}/resume
+//                                   resume a failed ingestion job
+//   GET    /chats                   POST /chats with an `agent_id`
+//   GET    /chats/{id}              the chat with its messages
+//   DELETE /chats/{id}
+//   POST   /chats/{id}/messages     send a message to the agent of the chat, the answer is
+//                                   returned when the agent is done
+//
+// Deleting only deactivates, as in the web UI. The requests need `Authorization: Bearer
+// <token>`, see `api_auth`.
+
+use axum::{
+    body::Bytes,
+    extract::{Path, Query},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use ai_gent_lib::llm_agent::LlmFsmAgentConfigBuilder;
+use ai_gent_lib::llm_audit::LlmAuditContext;
+use ai_gent_lib::model_registry::model_info;
+
+use crate::agent_workspace::{
+    get_chat_summary, get_search_context_plain_text, insert_message, update_chat_summary,
+};
+use crate::api_auth::ApiUser;
+use crate::ingestion_jobs::{self, create_ingestion_job, start_ingestion_job, UploadedFile};
+use crate::embedding_service::search_asset;
+use crate::fsm_chat_agent::new_chat_agent;
+use crate::{get_basic_fsm_agent_config_toml_string, AgentSetting, DB_POOL};
+
+pub struct ApiError(StatusCode, String);
+
+impl ApiError {
+    fn bad_request(message: impl Into<String>) -> Self {
+        ApiError(StatusCode::BAD_REQUEST, message.into())
+    }
+
+    fn not_found(what: &str, id: i32) -> Self {
+        ApiError(StatusCode::NOT_FOUND, format!("{} {} does not exist", what, id))
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let error_type = if self.0.is_client_error() {
+            "invalid_request_error"
+        } else {
+            "api_error"
+        };
+        let body = json!({ "error": { "message": self.1, "type": error_type } });
+        (self.0, Json(body)).into_response()
+    }
+}
+
+impl From<sqlx::Error> for ApiError {
+    fn from(e: sqlx::Error) -> Self {
+        ApiError(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        ApiError(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+    }
+}
+
+type ApiResult = Result<Response, ApiError>;
+
+// agents
+
+#[derive(Deserialize)]
+pub struct AgentRequest {
+    name: String,
+    #[serde(default)]
+    description: String,
+    model_name: String,
+    asset_id: Option<i32>,
+    prompt: Option<String>,
+    follow_up_prompt: Option<String>,
+    fsm_agent_config: Option<String>,
+}
+
+// the agent setting and class of a request, checking the model and the FSM config
+fn agent_setting(req: &AgentRequest) -> Result<(AgentSetting, &'static str), ApiError> {
+    if req.name.trim().is_empty() {
+        return Err(ApiError::bad_request("the agent needs a name"));
+    }
+    if model_info(&req.model_name).is_none() {
+        return Err(ApiError::bad_request(format!(
+            "the model `{}` is not in the model registry",
+            req.model_name
+        )));
+    }
+    let (fsm_agent_config, class) = match (&req.prompt, &req.fsm_agent_config) {
+        (Some(prompt), None) => (
+            get_basic_fsm_agent_config_toml_string(prompt.clone(), req.follow_up_prompt.clone()),
+            "basic",
+        ),
+        (None, Some(fsm_agent_config)) => {
+            LlmFsmAgentConfigBuilder::from_toml(fsm_agent_config)
+                .and_then(|builder| builder.build())
+                .map_err(|e| ApiError::bad_request(format!("invalid fsm_agent_config: {}", e)))?;
+            (fsm_agent_config.clone(), "advanced")
+        }
+        _ => {
+            return Err(ApiError::bad_request(
+                "set either `prompt` or `fsm_agent_config`",
+            ))
+        }
+    };
+    let setting = AgentSetting {
+        name: req.name.clone(),
+        description: req.description.clone(),
+        model_name: req.model_name.clone(),
+        fsm_agent_config,
+    };
+    Ok((setting, class))
+}
+
+// an asset id of 0 is no asset, as in the agent forms
+fn agent_asset_id(asset_id: Option<i32>) -> Option<i32> {
+    asset_id.filter(|asset_id| *asset_id != 0)
+}
+
+// the asset of an agent has to be an active asset of the user
+async fn check_agent_asset(username: &str, asset_id: Option<i32>) -> Result<(), ApiError> {
+    let Some(asset_id) = agent_asset_id(asset_id) else {
+        return Ok(());
+    };
+    sqlx::query!(
+        "SELECT a.asset_id
+        FROM assets a
+        JOIN users u ON a.user_id = u.user_id
+        WHERE u.username = $1 AND a.asset_id = $2 AND a.status = 'active'",
+        username,
+        asset_id
+    )
+    .fetch_optional(&DB_POOL.clone())
+    .await?
+    .ok_or(ApiError::not_found("asset", asset_id))?;
+    Ok(())
+}
+
+fn agent_json(
+    agent_id: i32,
+    name: String,
+    description: Option<String>,
+    class: String,
+    asset_id: Option<i32>,
+    configuration: Option<Value>,
+) -> Value {
+    let setting = configuration
+        .and_then(|conf| serde_json::from_value::<AgentSetting>(conf).ok())
+        .unwrap_or_default();
+    json!({
+        "agent_id": agent_id,
+        "name": name,
+        "description": description.unwrap_or_default(),
+        "class": class,
+        "asset_id": asset_id,
+        "model_name": setting.model_name,
+        "fsm_agent_config": setting.fsm_agent_config,
+    })
+}
+
+pub async fn list_agents(user: ApiUser) -> ApiResult {
+    let rows = sqlx::query!(
+        "SELECT a.agent_id, a.name, a.description, a.class, a.asset_id, a.configuration
+        FROM agents a
+        JOIN users u ON a.user_id = u.user_id
+        WHERE u.username = $1 AND a.status = 'active'
+        ORDER BY a.agent_id",
+        user.username
+    )
+    .fetch_all(&DB_POOL.clone())
+    .await?;
+    let agents = rows
+        .into_iter()
+        .map(|row| {
+            agent_json(
+                row.agent_id,
+                row.name,
+                row.description,
+                row.class,
+                row.asset_id,
+                row.configuration,
+            )
+        })
+        .collect::<Vec<_>>();
+    Ok(Json(json!({ "agents": agents })).into_response())
+}
+
+pub async fn get_agent(user: ApiUser, Path(agent_id): Path<i32>) -> ApiResult {
+    let row = sqlx::query!(
+        "SELECT a.agent_id, a.name, a.description, a.class, a.asset_id, a.configuration
+        FROM agents a
+        JOIN users u ON a.user_id = u.user_id
+        WHERE u.username = $1 AND a.agent_id = $2 AND a.status = 'active'",
+        user.username,
+        agent_id
+    )
+    .fetch_optional(&DB_POOL.clone())
+    .await?
+    .ok_or(ApiError::not_found("agent", agent_id))?;
+    Ok(Json(agent_json(
+        row.agent_id,
+        row.name,
+        row.description,
+        row.class,
+        row.asset_id,
+        row.configuration,
+    ))
+    .into_response())
+}
+
+pub async fn create_agent(user: ApiUser, Json(req): Json<AgentRequest>) -> ApiResult {
+    let (setting, class) = agent_setting(&req)?;
+    check_agent_asset(&user.username, req.asset_id).await?;
+    let row = sqlx::query!(
+        r#"INSERT INTO agents (user_id, name, description, status, configuration, class, asset_id)
+        SELECT user_id, $2, $3, $4, $5, $6, $7
+        FROM users
+        WHERE username = $1
+        RETURNING agent_id"#,
+        user.username,
+        req.name,
+        req.description,
+        "active",
+        serde_json::to_value(&setting).unwrap(),
+        class,
+        agent_asset_id(req.asset_id)
+    )
+    .fetch_one(&DB_POOL.clone())
+    .await?;
+    let body = agent_json(
+        row.agent_id,
+        setting.name.clone(),
+        Some(setting.description.clone()),
+        class.into(),
+        agent_asset_id(req.asset_id),
+        serde_json::to_value(&setting).ok(),
+    );
+    Ok((StatusCode::CREATED, Json(body)).into_response())
+}
+
+pub async fn update_agent(
+    user: ApiUser,
+    Path(agent_id): Path<i32>,
+    Json(req): Json<AgentRequest>,
+) -> ApiResult {
+    let (setting, class) = agent_setting(&req)?;
+    check_agent_asset(&user.username, req.asset_id).await?;
+    let row = sqlx::query!(
+        r#"UPDATE agents
+        SET name = $3, description = $4, configuration = $5, class = $6, asset_id = $7
+        WHERE agent_id = $2 AND status = 'active'
+            AND user_id = (SELECT user_id FROM users WHERE username = $1)
+        RETURNING agent_id"#,
+        user.username,
+        agent_id,
+        req.name,
+        req.description,
+        serde_json::to_value(&setting).unwrap(),
+        class,
+        agent_asset_id(req.asset_id)
+    )
+    .fetch_optional(&DB_POOL.clone())
+    .await?
+    .ok_or(ApiError::not_found("agent", agent_id))?;
+    let body = agent_json(
+        row.agent_id,
+        setting.name.clone(),
+        Some(setting.description.clone()),
+        class.into(),
+        agent_asset_id(req.asset_id),
+        serde_json::to_value(&setting).ok(),
+    );
+    Ok(Json(body).into_response())
+}
+
+pub async fn delete_agent(user: ApiUser, Path(agent_id): Path<i32>) -> ApiResult {
+    sqlx::query!(
+        r#"UPDATE agents SET status = 'inactive'
+        WHERE agent_id = $2 AND status = 'active'
+            AND user_id = (SELECT user_id FROM users WHERE username = $1)
+        RETURNING agent_id"#,
+        user.username,
+        agent_id
+    )
+    .fetch_optional(&DB_POOL.clone())
+    .await?
+    .ok_or(ApiError::not_found("agent", agent_id))?;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+// assets
+
+#[derive(Deserialize)]
+pub struct AssetQuery {
+    name: String,
+    #[serde(default)]
+    description: String,
+    // the name of an uploaded document, also used as its title
+    filename: Option<String>,
+}
+
+pub async fn list_assets(user: ApiUser) -> ApiResult {
+    let rows = sqlx::query!(
+        "SELECT a.asset_id, a.name, a.description, a.created_at
+        FROM assets a
+        JOIN users u ON a.user_id = u.user_id
+        WHERE u.username = $1 AND a.status = 'active'
+        ORDER BY a.asset_id",
+        user.username
+    )
+    .fetch_all(&DB_POOL.clone())
+    .await?;
+    let assets = rows
+        .into_iter()
+        .map(|row| {
+            json!({
+                "asset_id": row.asset_id,
+                "name": row.name,
+                "description": row.description.unwrap_or_default(),
+                "created_at": row.created_at,
+            })
+        })
+        .collect::<Vec<_>>();
+    Ok(Json(json!({ "assets": assets })).into_response())
+}
+
+pub async fn get_asset(user: ApiUser, Path(asset_id): Path<i32>) -> ApiResult {
+    let row = sqlx::query!(
+        "SELECT a.asset_id, a.name, a.description, a.created_at,
+            (SELECT COUNT(*) FROM text_embedding t WHERE t.asset_id = a.asset_id) as chunks
+        FROM assets a
+        JOIN users u ON a.user_id = u.user_id
+        WHERE u.username = $1 AND a.asset_id = $2 AND a.status = 'active'",
+        user.username,
+        asset_id
+    )
+    .fetch_optional(&DB_POOL.clone())
+    .await?
+    .ok_or(ApiError::not_found("asset", asset_id))?;
+    Ok(Json(json!({
+        "asset_id": row.asset_id,
+        "name": row.name,
+        "description": row.description.unwrap_or_default(),
+        "created_at": row.created_at,
+        "chunks": row.chunks.unwrap_or_default(),
+    }))
+    .into_response())
+}
+
+pub async fn create_asset(
+    user: ApiUser,
+    Query(query): Query<AssetQuery>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> ApiResult {
+    if query.name.trim().is_empty() {
+        return Err(ApiError::bad_request("the asset needs a name"));
+    }
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+    if body.is_empty() {
+        return Err(ApiError::bad_request("no asset data in the request body"));
+    }
+    // parsed and stored by a background job, followed with `/ingestion_jobs/{id}`
+    let file = UploadedFile {
+        filename: query.filename.clone().unwrap_or(query.name.clone()),
+        content_type: content_type.to_string(),
+        data: body.to_vec(),
+    };
+    let (job_id, asset_id) =
+        create_ingestion_job(&user.username, &query.name, &query.description, vec![file]).await?;
+    start_ingestion_job(job_id).await?;
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(json!({
+            "asset_id": asset_id,
+            "job_id": job_id,
+            "name": query.name,
+            "description": query.description,
+        })),
+    )
+        .into_response())
+}
+
+pub async fn get_ingestion_job(user: ApiUser, Path(job_id): Path<i32>) -> ApiResult {
+    let job = ingestion_jobs::get_ingestion_job(job_id, &user.username)
+        .await?
+        .ok_or(ApiError::not_found("ingestion job", job_id))?;
+    Ok(Json(json!(job)).into_response())
+}
+
+pub async fn resume_ingestion_job(user: ApiUser, Path(job_id): Path<i32>) -> ApiResult {
+    let job = ingestion_jobs::get_ingestion_job(job_id, &user.username)
+        .await?
+        .ok_or(ApiError::not_found("ingestion job", job_id))?;
+    if !start_ingestion_job(job_id).await? {
+        return Err(ApiError::bad_request(format!(
+            "the ingestion job {} is {}, only a failed job can be resumed",
+            job_id, job.status
+        )));
+    }
+    Ok((StatusCode::ACCEPTED, Json(json!({ "job_id": job_id, "status": "running" }))).into_response())
+}
+
+pub async fn delete_asset(user: ApiUser, Path(asset_id): Path<i32>) -> ApiResult {
+    sqlx::query!(
+        r#"UPDATE assets SET status = 'inactive'
+        WHERE asset_id = $2 AND status = 'active'
+            AND user_id = (SELECT user_id FROM users WHERE username = $1)
+        RETURNING asset_id"#,
+        user.username,
+        asset_id
+    )
+    .fetch_optional(&DB_POOL.clone())
+    .await?
+    .ok_or(ApiError::not_found("asset", asset_id))?;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+// chats
+
+#[derive(Deserialize)]
+pub struct ChatRequest {
+    agent_id: i32,
+    title: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct MessageRequest {
+    content: String,
+    temperature: Option<f32>,
+    top_k: Option<usize>,
+    threshold: Option<f32>,
+}
+
+pub async fn list_chats(user: ApiUser) -> ApiResult {
+    let rows = sqlx::query!(
+        "SELECT c.chat_id, c.agent_id, c.title, c.summary, c.last_fsm_state, c.created_at, c.updated_at
+        FROM chats c
+        JOIN users u ON c.user_id = u.user_id
+        WHERE u.username = $1 AND c.status = 'active'
+        ORDER BY c.updated_at DESC",
+        user.username
+    )
+    .fetch_all(&DB_POOL.clone())
+    .await?;
+    let chats = rows
+        .into_iter()
+        .map(|row| {
+            json!({
+                "chat_id": row.chat_id,
+                "agent_id": row.agent_id,
+                "title": row.title,
+                "summary": row.summary,
+                "fsm_state": row.last_fsm_state,
+                "created_at": row.created_at,
+                "updated_at": row.updated_at,
+            })
+        })
+        .collect::<Vec<_>>();
+    Ok(Json(json!({ "chats": chats })).into_response())
+}
+
+pub async fn get_chat(user: ApiUser, Path(chat_id): Path<i32>) -> ApiResult {
+    let db_pool = DB_POOL.clone();
+    let row = sqlx::query!(
+        "SELECT c.chat_id, c.agent_id, c.title, c.summary, c.last_fsm_state, c.created_at, c.updated_at
+        FROM chats c
+        JOIN users u ON c.user_id = u.user_id
+        WHERE u.username = $1 AND c.chat_id = $2 AND c.status = 'active'",
+        user.username,
+        chat_id
+    )
+    .fetch_optional(&db_pool)
+    .await?
+    .ok_or(ApiError::not_found("chat", chat_id))?;
+    let messages = sqlx::query!(
+        "SELECT message_id, role, message_type, content, fsm_state, timestamp
+        FROM messages
+        WHERE chat_id = $1
+        ORDER BY timestamp ASC",
+        chat_id
+    )
+    .fetch_all(&db_pool)
+    .await?
+    .into_iter()
+    .map(|m| {
+        json!({
+            "message_id": m.message_id,
+            "role": m.role,
+            "message_type": m.message_type,
+            "content": m.content,
+            "fsm_state": m.fsm_state,
+            "timestamp": m.timestamp,
+        })
+    })
+    .collect::<Vec<_>>();
+    Ok(Json(json!({
+        "chat_id": row.chat_id,
+        "agent_id": row.agent_id,
+        "title": row.title,
+        "summary": row.summary,
+        "fsm_state": row.last_fsm_state,
+        "created_at": row.created_at,
+        "updated_at": row.updated_at,
+        "messages": messages,
+    }))
+    .into_response())
+}
+
+pub async fn create_chat(user: ApiUser, Json(req): Json<ChatRequest>) -> ApiResult {
+    let db_pool = DB_POOL.clone();
+    let agent = sqlx::query!(
+        "SELECT a.name
+        FROM agents a
+        JOIN users u ON a.user_id = u.user_id
+        WHERE u.username = $1 AND a.agent_id = $2 AND a.status = 'active'",
+        user.username,
+        req.agent_id
+    )
+    .fetch_optional(&db_pool)
+    .await?
+    .ok_or(ApiError::not_found("agent", req.agent_id))?;
+    let title = req
+        .title
+        .unwrap_or_else(|| format!("{}:{}", agent.name, Uuid::new_v4()));
+    let row = sqlx::query!(
+        r#"INSERT INTO chats (user_id, agent_id, title)
+        SELECT u.user_id, $2, $3
+        FROM users u
+        WHERE u.username = $1
+        RETURNING chat_id, created_at"#,
+        user.username,
+        req.agent_id,
+        title
+    )
+    .fetch_one(&db_pool)
+    .await?;
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({
+            "chat_id": row.chat_id,
+            "agent_id": req.agent_id,
+            "title": title,
+            "created_at": row.created_at,
+        })),
+    )
+        .into_response())
+}
+
+pub async fn delete_chat(user: ApiUser, Path(chat_id): Path<i32>) -> ApiResult {
+    sqlx::query!(
+        r#"UPDATE chats SET status = 'inactive'
+        WHERE chat_id = $2 AND status = 'active'
+            AND user_id = (SELECT user_id FROM users WHERE username = $1)
+        RETURNING chat_id"#,
+        user.username,
+        chat_id
+    )
+    .fetch_optional(&DB_POOL.clone())
+    .await?
+    .ok_or(ApiError::not_found("chat", chat_id))?;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+// the same steps as a query in the agent workspace: the agent starts from the last state of
+// the chat with its summary and the context retrieved from the asset of the agent
+pub async fn post_message(
+    user: ApiUser,
+    Path(chat_id): Path<i32>,
+    Json(req): Json<MessageRequest>,
+) -> ApiResult {
+    if req.content.trim().is_empty() {
+        return Err(ApiError::bad_request("the message is empty"));
+    }
+    let row = sqlx::query!(
+        "SELECT c.user_id, a.agent_id, c.last_fsm_state, a.configuration,
+                COALESCE(a.asset_id, 0) as asset_id,
+                COALESCE(assets.status, 'active') as asset_status
+        FROM chats c
+        JOIN users u ON c.user_id = u.user_id
+        JOIN agents a ON a.agent_id = c.agent_id
+        LEFT JOIN assets ON assets.asset_id = a.asset_id
+        WHERE u.username = $1 AND c.chat_id = $2 AND c.status = 'active'",
+        user.username,
+        chat_id
+    )
+    .fetch_optional(&DB_POOL.clone())
+    .await?
+    .ok_or(ApiError::not_found("chat", chat_id))?;
+
+    let (user_id, agent_id) = (row.user_id, row.agent_id);
+    let setting = serde_json::from_value::<AgentSetting>(row.configuration.unwrap_or_default())
+        .map_err(|e| anyhow::anyhow!("agent {}: {}", agent_id, e))?;
+    let asset_id = match (row.asset_id, row.asset_status.as_deref()) {
+        (Some(asset_id), Some("active")) => asset_id,
+        _ => 0,
+    };
+
+    let mut agent = new_chat_agent(
+        user_id,
+        &setting.model_name,
+        &setting.fsm_agent_config,
+        row.last_fsm_state,
+    )
+    .await
+    .map_err(|e| ApiError(StatusCode::SERVICE_UNAVAILABLE, e.to_string()))?;
+    agent.base.llm_req_settings.session_id = Some(format!("chat-{}", chat_id));
+    agent.base.llm_req_settings.agent_name = Some(format!("agent-{}", agent_id));
+
+    let summary = get_chat_summary(chat_id).await.unwrap_or_default();
+    agent
+        .base
+        .llm_req_settings
+        .memory
+        .insert("summary".into(), vec![Value::String(summary)]);
+    let search_asset_results = search_asset(
+        &req.content,
+        asset_id,
+        req.top_k.unwrap_or(8),
+        req.threshold.unwrap_or(0.65),
+    )
+    .await;
+    agent.base.llm_req_settings.memory.insert(
+        "context".into(),
+        vec![Value::String(get_search_context_plain_text(&search_asset_results))],
+    );
+
+    let user_message_id =
+        insert_message(chat_id, user_id, agent_id, &req.content, "user", "text", None).await?;
+    agent.base.llm_req_settings.audit = Some(LlmAuditContext {
+        chat_id,
+        message_id: Some(user_message_id),
+        agent_id: Some(agent_id),
+        ..Default::default()
+    });
+
+    // only the errors are needed from the events, but the channel has to be drained
+    let (tx, mut rx) = mpsc::channel::<(String, String, String)>(16);
+    let drain = tokio::spawn(async move {
+        let mut error = None;
+        while let Some((_, t, r)) = rx.recv().await {
+            if t == "error" && error.is_none() {
+                error = Some(r);
+            }
+        }
+        error
+    });
+    let result = agent
+        .process_message(&req.content, Some(tx), req.temperature)
+        .await;
+    let error = drain.await.unwrap_or_default();
+    // the reply of a failed agent is not stored
+    let content = result.map_err(|e| ApiError(StatusCode::BAD_GATEWAY, e.to_string()))?;
+    if let Some(error) = error {
+        return Err(ApiError(StatusCode::BAD_GATEWAY, error));
+    }
+
+    let fsm_state = agent.base.get_current_state().await;
+    let message_id = insert_message(
+        chat_id,
+        user_id,
+        agent_id,
+        &content,
+        "bot",
+        "text",
+        fsm_state.clone(),
+    )
+    .await?;
+    let summary = agent
+        .base
+        .llm_req_settings
+        .memory
+        .get("summary")
+        .and_then(|summary| summary.last().cloned())
+        .and_then(|summary| serde_json::from_value::<String>(summary).ok())
+        .unwrap_or_default();
+    update_chat_summary(chat_id, &summary).await?;
+
+    Ok(Json(json!({
+        "message_id": message_id,
+        "chat_id": chat_id,
+        "role": "bot",
+        "content": content,
+        "fsm_state": fsm_state,
+    }))
+    .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn response_json(response: Response) -> Value {
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a migrated database in DATABASE_URL and OPENAI_API_KEY"]
+    async fn test_post_message() {
+        let username = format!("api-test-{}", Uuid::new_v4());
+        sqlx::query!(
+            "INSERT INTO users (username, email, password_hash) VALUES ($1, $2, '')",
+            username,
+            format!("{}@localhost", username)
+        )
+        .execute(&DB_POOL.clone())
+        .await
+        .unwrap();
+        let user = || ApiUser {
+            username: username.clone(),
+        };
+
+        // an asset of another user, or no asset at all, is not found
+        let agent_request = |asset_id: Option<i32>| -> AgentRequest {
+            serde_json::from_value(json!({
+                "name": "test agent",
+                "model_name": "gpt-4o-mini",
+                "asset_id": asset_id,
+                "prompt": "Answer in one word.",
+            }))
+            .unwrap()
+        };
+        let error = create_agent(user(), Json(agent_request(Some(i32::MAX))))
+            .await
+            .unwrap_err();
+        assert_eq!(error.0, StatusCode::NOT_FOUND);
+
+        let response = create_agent(user(), Json(agent_request(None)))
+            .await
+            .unwrap_or_else(|e| panic!("{}", e.1));
+        let agent_id = response_json(response).await["agent_id"].as_i64().unwrap() as i32;
+        let chat_request = serde_json::from_value(json!({ "agent_id": agent_id })).unwrap();
+        let response = create_chat(user(), Json(chat_request))
+            .await
+            .unwrap_or_else(|e| panic!("{}", e.1));
+        let chat_id = response_json(response).await["chat_id"].as_i64().unwrap() as i32;
+
+        let message_request =
+            serde_json::from_value(json!({ "content": "What color is the sky?" })).unwrap();
+        let response = post_message(user(), Path(chat_id), Json(message_request))
+            .await
+            .unwrap_or_else(|e| panic!("{}", e.1));
+        assert_eq!(response.status(), StatusCode::OK);
+        let message = response_json(response).await;
+        assert_eq!(message["chat_id"], chat_id);
+        assert_eq!(message["role"], "bot");
+        assert!(!message["content"].as_str().unwrap().is_empty());
+
+        let count = sqlx::query_scalar!(
+            "SELECT COUNT(*) FROM messages WHERE chat_id = $1 AND message_id = $2",
+            chat_id,
+            message["message_id"].as_i64().unwrap() as i32
+        )
+        .fetch_one(&DB_POOL.clone())
+        .await
+        .unwrap();
+        assert_eq!(count, Some(1));
+
+        sqlx::query!("DELETE FROM users WHERE username = $1", username)
+            .execute(&DB_POOL.clone())
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,9 @@
+-- Add migration script here
+CREATE TABLE llm_cache (
+    fingerprint VARCHAR(64) PRIMARY KEY,
+    response JSONB NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX idx_llm_cache_expires_at ON llm_cache (expires_at);
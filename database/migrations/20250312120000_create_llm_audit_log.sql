@@ -0,0 +1,26 @@
+-- Add migration script here
+CREATE TABLE llm_audit_log (
+    audit_id SERIAL PRIMARY KEY,
+    chat_id INTEGER NOT NULL,
+    message_id INTEGER,
+    agent_id INTEGER,
+    fsm_state VARCHAR(100),
+    purpose VARCHAR(20) NOT NULL,
+    model VARCHAR(100) NOT NULL,
+    provider VARCHAR(100),
+    prompt TEXT,
+    messages JSONB NOT NULL,
+    output TEXT,
+    input_tokens INTEGER,
+    output_tokens INTEGER,
+    total_tokens INTEGER,
+    latency_ms BIGINT NOT NULL,
+    error TEXT,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (chat_id) REFERENCES chats(chat_id) ON DELETE CASCADE,
+    FOREIGN KEY (message_id) REFERENCES messages(message_id) ON DELETE SET NULL,
+    FOREIGN KEY (agent_id) REFERENCES agents(agent_id) ON DELETE SET NULL
+);
+
+CREATE INDEX idx_llm_audit_log_chat_id ON llm_audit_log (chat_id, created_at);
+CREATE INDEX idx_llm_audit_log_created_at ON llm_audit_log (created_at);
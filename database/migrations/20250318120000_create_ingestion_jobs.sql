@@ -0,0 +1,35 @@
+-- Add migration script here
+-- the assets being ingested are hidden until their job completes
+ALTER TABLE assets DROP CONSTRAINT assets_status_check;
+ALTER TABLE assets ADD CONSTRAINT assets_status_check
+    CHECK (status IN ('active', 'inactive', 'deleted', 'ingesting'));
+
+CREATE TABLE ingestion_jobs (
+    job_id SERIAL PRIMARY KEY,
+    asset_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    status VARCHAR(20) NOT NULL DEFAULT 'pending',
+    -- unknown until the uploaded files are parsed
+    total_chunks INTEGER,
+    processed_chunks INTEGER NOT NULL DEFAULT 0,
+    error TEXT,
+    -- the documents that could not be read, [[filename, reason], ...]
+    skipped_files JSONB NOT NULL DEFAULT '[]',
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (asset_id) REFERENCES assets(asset_id) ON DELETE CASCADE,
+    FOREIGN KEY (user_id) REFERENCES users(user_id) ON DELETE CASCADE,
+    CHECK (status IN ('pending', 'running', 'completed', 'failed', 'discarded'))
+);
+
+CREATE INDEX idx_ingestion_jobs_user_id ON ingestion_jobs (user_id, status);
+
+-- the uploaded files of a job, kept until it completes so that a failed job can be resumed
+CREATE TABLE ingestion_job_files (
+    job_id INTEGER NOT NULL,
+    filename VARCHAR(1024) NOT NULL,
+    content_type VARCHAR(255) NOT NULL,
+    data BYTEA NOT NULL,
+    PRIMARY KEY (job_id, filename),
+    FOREIGN KEY (job_id) REFERENCES ingestion_jobs(job_id) ON DELETE CASCADE
+);
@@ -0,0 +1,3 @@
+-- Add migration script here
+-- the chunks that could not be stored and were skipped by an ingestion job
+ALTER TABLE ingestion_jobs ADD COLUMN skipped_chunks INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,3 @@
+-- Add migration script here
+-- the responses served from the LLM cache, no request was sent to the provider
+ALTER TABLE llm_audit_log ADD COLUMN cached BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,3 @@
+-- Add migration script here
+-- renewed by the server running the job, the jobs without a recent heartbeat are abandoned
+ALTER TABLE ingestion_jobs ADD COLUMN heartbeat_at TIMESTAMP WITH TIME ZONE;